@@ -35,6 +35,319 @@ description = number of hours until ping again
 type = unsigned_long
 default = 24
 
+[cli/profile]
+description = Elektra profile to use, i.e. the configuration below /sw/libelektra/opensesame/#<instance>/<profile> and the state below /state/libelektra/opensesame/#<instance>/<profile>. Resolved before the configuration gets read, so it only works as argument or environment variable.
+opt = p
+opt/long = profile
+opt/arg = required
+env = OPENSESAME_PROFILE
+default = current
+
+[cli/instance]
+description = instance number, i.e. the configuration below /sw/libelektra/opensesame/#<instance>/<profile>. Together with the profile it names the instance, e.g. when running the garage and the house door instance side by side. Resolved before the configuration gets read, so it only works as argument or environment variable.
+opt = i
+opt/long = instance
+opt/arg = required
+env = OPENSESAME_INSTANCE
+type = unsigned_short
+default = 0
+
+[cli/signal]
+description = send this signal to the running instance given by --instance and --profile and exit, e.g. usr2 to ring the bell. Replaces `killall`, which would signal all instances.
+opt/long = signal
+opt/arg = required
+type = enum
+check/enum = #5
+check/enum/#0 = hup
+check/enum/#1 = usr1
+check/enum/#2 = usr2
+check/enum/#3 = alrm
+check/enum/#4 = term
+check/enum/#5 = int
+
+[cli/only]
+description = comma-separated names of the modules to start, e.g. nextcloud,audio. Other modules are treated as disabled.
+opt/long = only
+opt/arg = required
+env = OPENSESAME_ONLY
+
+[cli/dry-run]
+description = log messages and status updates for Nextcloud instead of sending them
+opt = n
+opt/long = dry-run
+opt/arg = none
+env = OPENSESAME_DRY_RUN
+type = boolean
+default = 0
+
+[cli/check-config]
+description = check the configuration of all enabled modules, print a report and exit (1 if there are errors)
+opt/long = check-config
+opt/arg = none
+env = OPENSESAME_CHECK_CONFIG
+type = boolean
+default = 0
+
+[cli/simulate]
+description = do not start modules which need the hardware of the board (GPIO, I2C, serial devices), e.g. to try chat commands on a development machine
+opt/long = simulate
+opt/arg = none
+env = OPENSESAME_SIMULATE
+type = boolean
+default = 0
+
+[cli/help]
+description = print the available options and exit
+opt = h
+opt/long = help
+opt/arg = none
+type = boolean
+default = 0
+
+[log/level]
+description = what gets logged to stderr
+opt = l
+opt/long = log-level
+opt/arg = required
+env = OPENSESAME_LOG_LEVEL
+type = enum
+check/enum = #3
+check/enum/#0 = error
+check/enum/#1 = warning
+check/enum/#2 = info
+check/enum/#3 = debug
+default = info
+
+[elektra/timeout]
+description = seconds to wait for Elektra reading or writing configuration and state at runtime, afterwards an error gets reported instead of waiting forever
+type = unsigned_short
+default = 10
+
+[state/flush/interval]
+description = seconds between writes of the state (e.g. ping/counter, environment/baseline) to the SD card. Updates are collected in the runtime directory until then, and written on shutdown, too.
+type = unsigned_long
+default = 3600
+
+[instance/runtime]
+description = directory for the PID files of the instances, used to signal a specific instance (`--signal`), and their control sockets (`opensesamectl`). Must be writable, see RuntimeDirectory in the systemd unit.
+default = /run/opensesame
+
+[control/enable]
+description = If opensesamectl can control opensesame via the socket <instance/runtime>/<instance>-<profile>.sock, see doc/Control.md
+type = boolean
+default = 1
+
+[api/enable]
+description = If the HTTP API with status, readings and commands is served, see doc/API.md
+type = boolean
+default = 0
+
+[api/listen]
+description = address and port of the HTTP API, use a reverse proxy with TLS to reach it from outside
+default = 127.0.0.1:8080
+
+[api/token]
+description = secret for POST requests (Authorization: Bearer <token>), at least 16 characters
+default =
+
+[mqtt/enable]
+description = If readings and events are published to an MQTT broker, e.g. for Home Assistant, and commands are received from it, see doc/MQTT.md
+type = boolean
+default = 0
+
+[mqtt/host]
+description = host name or address of the MQTT broker
+default = localhost
+
+[mqtt/port]
+description = port of the MQTT broker, without TLS
+type = unsigned_short
+default = 1883
+
+[mqtt/user]
+description = user name for the MQTT broker, empty for anonymous access
+default =
+
+[mqtt/password]
+description = password for the MQTT broker
+default =
+
+[mqtt/topic]
+description = prefix of all topics, e.g. opensesame/garage, commands are received on <mqtt/topic>/command/#
+default = opensesame
+
+[mqtt/discovery]
+description = prefix of the Home Assistant discovery topics, empty to disable discovery
+default = homeassistant
+
+[history/enable]
+description = If the readings are recorded for `\history` and the CSV export, see doc/History.md
+type = boolean
+default = 0
+
+[history/dir]
+description = directory of the ring buffer files, about 440 kB per reading with history/raw 10, see doc/History.md, in a subdirectory per instance, e.g. 0-current. Must be writable, see StateDirectory in the systemd unit.
+default = /var/lib/opensesame/history
+
+[history/raw]
+description = seconds per value in the history of the last 24 hours, readings in between are averaged
+type = enum
+check/enum = #5
+check/enum/#0 = 1
+check/enum/#1 = 5
+check/enum/#2 = 10
+check/enum/#3 = 15
+check/enum/#4 = 30
+check/enum/#5 = 60
+default = 10
+
+[history/flush/interval]
+description = seconds between writes of the history to the SD card, and on shutdown. Values since the last write are lost on power failure.
+type = unsigned_long
+default = 3600
+
+[webhook/enable]
+description = If events are posted as JSON to the URLs of webhook/hook, see doc/Webhook.md
+type = boolean
+default = 0
+
+[webhook/hook]
+description = the hooks to post to
+
+[webhook/hook/#/url]
+description = http(s) URL to post to
+type = string
+
+[webhook/hook/#/events]
+description = events posted to the URL
+
+[webhook/hook/#/events/#]
+description = door (opened), bell (pressed), alarm (raised), battery (low) or notification (routed to webhook, see doc/Routing.md)
+type = enum
+check/enum = #4
+check/enum/#0 = door
+check/enum/#1 = bell
+check/enum/#2 = alarm
+check/enum/#3 = battery
+check/enum/#4 = notification
+
+[webhook/hook/#/template]
+description = body with the placeholders {event}, {text}, {module}, {severity}, {instance} and {time}, empty for the default JSON
+type = string
+default =
+
+[webhook/hook/#/secret]
+description = key of the HMAC-SHA256 signature in the header X-Opensesame-Signature, empty to not sign
+type = string
+default =
+
+[webhook/queue/size]
+description = deliveries kept per hook while its server is unreachable, the oldest get dropped
+type = unsigned_long
+default = 1000
+
+[email/enable]
+description = If alarms and the notifications routed to email are mailed via SMTP, see doc/Email.md
+type = boolean
+default = 0
+
+[email/host]
+description = host name of the SMTP server, for TLS the name in its certificate
+type = string
+default = localhost
+
+[email/port]
+description = port of the SMTP server, usually 587 for starttls, 465 for tls and 25 for none
+type = unsigned_short
+default = 587
+
+[email/security]
+description = starttls to upgrade the connection to TLS, tls for TLS from the start, none for plain text (only for servers on localhost)
+type = enum
+check/enum = #2
+check/enum/#0 = none
+check/enum/#1 = starttls
+check/enum/#2 = tls
+default = starttls
+
+[email/user]
+description = user to authenticate at the SMTP server, empty to not authenticate
+type = string
+default =
+
+[email/password]
+description = password of email/user
+type = string
+default =
+
+[email/from]
+description = sender of the mails, e.g. Opensesame <opensesame@example.org>
+type = string
+default = opensesame@localhost
+
+[email/to]
+description = recipients of the mails
+
+[email/to/#]
+description = mail address of a recipient
+type = string
+
+[email/digest]
+description = seconds between the digests of the notifications routed to email, 0 to mail them right away. Alarms are always mailed right away.
+type = unsigned_long
+default = 86400
+
+[analytics/enable]
+description = If readings are published to reports of Nextcloud Analytics, using the credentials of nextcloud/url, see doc/Analytics.md
+type = boolean
+default = 0
+
+[analytics/interval]
+description = seconds between the rows added to the reports
+type = unsigned_long
+default = 3600
+
+[analytics/report/environment]
+description = ID of the Analytics report (of type "internal database") for the environment readings, 0 to not publish them
+type = unsigned_long
+default = 0
+
+[analytics/report/weatherstation]
+description = ID of the Analytics report for the weather station readings, 0 to not publish them
+type = unsigned_long
+default = 0
+
+[analytics/report/battery]
+description = ID of the Analytics report for the battery capacity, 0 to not publish it
+type = unsigned_long
+default = 0
+
+[peer/enable]
+description = If messages (fire alarm and bell) are exchanged with other opensesame instances, see doc/Peers.md
+type = boolean
+default = 0
+
+[peer/name]
+description = how this opensesame is called in messages to its peers, e.g. garage
+default = opensesame
+
+[peer/listen]
+description = address and port to accept messages from peers on, e.g. 0.0.0.0:7811. Empty to only send messages.
+type = string
+default =
+
+[peer/key]
+description = pre-shared key to sign and check messages, the same for all peers, at least 16 characters
+type = string
+default =
+
+[peer/addresses]
+description = list of the peers to send messages to
+
+[peer/addresses/#]
+description = host:port of a peer, e.g. 192.168.178.53:7811
+type = string
+
 [nextcloud/url]
 description = URL to be used for sending messages.
 required =
@@ -67,6 +380,89 @@ description = which chat is used to send commands to Opensesame.
 required =
 check/length/max = 8
 
+[nextcloud/outbox]
+description = directory of the file keeping the messages until Nextcloud accepted them, so they survive outages and restarts, see doc/Nextcloud.md. The file is named after the instance, e.g. 0-current.outbox.json. Must be writable, see StateDirectory in the systemd unit. Empty to keep them in memory only.
+default = /var/lib/opensesame
+
+[nextcloud/outbox/size]
+description = maximum number of undelivered messages, the oldest ones get dropped first, alarms last
+type = unsigned_long
+default = 1000
+
+[notify/dedup]
+description = seconds in which identical chat messages are sent only once, the repetitions are counted in a summary afterwards, see doc/Nextcloud.md. 0 to send all.
+type = unsigned_long
+default = 60
+
+[notify/air/limit]
+description = air quality messages sent per notify/air/period, the others are summarized at its end
+type = unsigned_long
+default = 4
+
+[notify/air/period]
+description = seconds of the rate limit for air quality messages
+type = unsigned_long
+default = 3600
+
+[notify/weather/limit]
+description = weather station messages sent per notify/weather/period, the others are summarized at its end
+type = unsigned_long
+default = 3
+
+[notify/weather/period]
+description = seconds of the rate limit for weather station messages
+type = unsigned_long
+default = 3600
+
+[notify/hardware/limit]
+description = hardware error messages (e.g. reading buttons, environment or ModIR) sent per notify/hardware/period, the others are summarized at its end
+type = unsigned_long
+default = 3
+
+[notify/hardware/period]
+description = seconds of the rate limit for hardware error messages
+type = unsigned_long
+default = 600
+
+[notify/route]
+description = routing table of the notifications, see doc/Routing.md. Without routes, notifications go to the Talk room chosen by the module.
+
+[notify/route/#]
+description = a route, all routes matching a notification apply
+
+[notify/route/#/severity]
+description = lowest severity of the notifications taken by the route
+type = enum
+check/enum = #3
+check/enum/#0 = debug
+check/enum/#1 = info
+check/enum/#2 = warning
+check/enum/#3 = alarm
+default = info
+
+[notify/route/#/module]
+description = name of the module, e.g. Environment, empty for all modules
+type = string
+default =
+
+[notify/route/#/to]
+description = destination: talk for the room chosen by the module, talk/default, talk/ping, talk/licht, mqtt (topic <mqtt/topic>/notification), webhook (event notification) or email (digest)
+type = enum
+check/enum = #6
+check/enum/#0 = talk
+check/enum/#1 = talk/default
+check/enum/#2 = talk/ping
+check/enum/#3 = talk/licht
+check/enum/#4 = mqtt
+check/enum/#5 = webhook
+check/enum/#6 = email
+default = talk
+
+[notify/route/#/quiet]
+description = local time in which the route takes no notifications, e.g. 22:00-07:00, empty for none
+type = string
+default =
+
 [nextcloud/format/time]
 description=Format to be used for formatting time within Nextcloud messages, e.g. when entry gets prohibited because of time. By default ISO 8601 (Hour-minute-second format). Example is locales time.
 see/#0 = nextcloud/format/date
@@ -81,6 +477,31 @@ see/#1 = https://docs.rs/chrono/latest/chrono/format/strftime/index.html#specifi
 default = %+
 example = %c
 
+[supervisor/restart/max]
+description = number of consecutive failures after which a module is disabled (until restart of opensesame)
+type = unsigned_long
+default = 5
+
+[supervisor/backoff/initial]
+description = seconds to wait before the first restart of a failed module, doubled on every further failure
+type = unsigned_long
+default = 10
+
+[supervisor/backoff/max]
+description = maximum seconds to wait before restarting a failed module
+type = unsigned_long
+default = 3600
+
+[supervisor/shutdown/timeout]
+description = seconds modules get to clean up on SIGTERM/SIGINT before they get aborted
+type = unsigned_long
+default = 5
+
+[buttons/enable]
+description = enables/disables the buttons module (front door, light, bell, PIN pad)
+type = boolean
+default = 0
+
 [garage/enable]
 description = enable garage HW (Endposition, further GPIO buttons)
 type = boolean
@@ -137,16 +558,39 @@ default = 10
 description=Device path of Watchdog
 default = "/dev/watchdog"
 
+[environment/enable]
+description = enables/disables the environment sensor
+type = boolean
+default = 0
+
 [environment/device]
 description=Which device to use for the environment sensor. /dev/null means that no environment sensor is connected.
 example = "/dev/i2c"
 default = "/dev/null"
 
+[environment/name]
+description = Name of the location of the environment sensor, used in messages.
+
 [environment/data/interval]
 description=How often to get new data (default: every 60 seconds, which is the highest interval)
 default = 60
 type = unsigned_short
 
+[environment/baseline/interval]
+description = how many days between automatic saves of the CCS811 baseline to the state (environment/baseline)
+type = unsigned_short
+default = 7
+
+[environment/baseline/burnin]
+description = minutes after start until the CCS811 delivers a valid baseline. Only then the stored baseline gets restored and the baseline gets saved.
+type = unsigned_short
+default = 20
+
+[environment/baseline/validity]
+description = how many days a stored baseline may be old to still get restored. Must be at least environment/baseline/interval.
+type = unsigned_short
+default = 30
+
 [sensors/enable]
 description = If the sensors module is enabled
 type = boolean
@@ -205,6 +649,7 @@ description = Access-Token for Opensensemap senseBoxes, see doc/Opensensemap.md
 
 [ir/enable]
 description=enables/disables MOD-IR-TEMP sensor
+type = boolean
 example=1
 default=0
 
@@ -217,3 +662,13 @@ default = "/dev/null"
 description=How often to get new data, depends on how often handle() is called
 default=60
 type = unsigned_short
+
+[bat/enable]
+description = enables/disables reading the battery capacity
+type = boolean
+default = 0
+
+[ping/enable]
+description = enables/disables the ping module
+type = boolean
+default = 0
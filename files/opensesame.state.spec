@@ -23,4 +23,17 @@ infos/plugins = quickdump shell execute/set=reload-opensesame
 description = Which room currently has a present alarm, if any. Can be triggered from both environment or sensors.
 
 [environment/baseline]
-description = internal state from CCS811 to be used in initalization. Is automatically saved every seven days or on shutdown.
+description = internal state from CCS811 to be restored after the burn-in. Is automatically saved every environment/baseline/interval days (default seven) or on shutdown, written with the next state flush.
+
+[environment/baseline/saved]
+description = when environment/baseline was saved, as unix time. Older baselines are not restored, see environment/baseline/validity.
+type = unsigned_long
+
+[ping/counter]
+description = number of pings sent so far. Is saved with the periodic state flush (state/flush/interval) and on shutdown.
+type = unsigned_long
+default = 0
+
+[nextcloud/commands/last]
+description = ID of the last message seen in the commands room (nextcloud/chat/commands). Polling continues after it on restart, so commands are neither lost nor executed twice.
+type = unsigned_long
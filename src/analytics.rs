@@ -0,0 +1,347 @@
+//! Publishes readings to the reports of Nextcloud Analytics, see doc/Analytics.md.
+//!
+//! Every `analytics/interval` the latest value of each reading of a source
+//! (e.g. `environment`) becomes a row in the report configured for the source.
+//! Rows stay buffered until the server accepted them, retries back off
+//! exponentially up to the interval.
+
+use std::{
+	collections::{BTreeMap, VecDeque},
+	time::Duration,
+};
+
+use chrono::Local;
+use futures::{future::BoxFuture, never::Never, FutureExt};
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{
+	select,
+	time::{interval, sleep_until, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+	bus::{Event, EventBus},
+	config::{
+		validate::{in_range, Validate},
+		Config, ConfigError,
+	},
+	log,
+	log::Level,
+	module::{Context, Module},
+	nextcloud::{NextcloudClient, NextcloudConfig},
+	readings::Reading,
+	types::ModuleError,
+};
+
+/// rows kept per report while the server is unreachable, the oldest get dropped
+const MAX_ROWS: usize = 10_000;
+/// rows per request
+const BATCH: usize = 500;
+const INITIAL_RETRY: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize)]
+pub struct AnalyticsConfig {
+	/// seconds between rows
+	pub interval: u64,
+	/// report ID by source, e.g. `analytics/report/environment`, 0 to not publish the source
+	pub report: BTreeMap<String, u32>,
+}
+
+impl Validate for AnalyticsConfig {
+	fn validate(&self) -> Vec<ConfigError> {
+		in_range("analytics/interval", self.interval, 60..=24 * 60 * 60)
+			.into_iter()
+			.collect()
+	}
+}
+
+/// as expected by the data-add API
+#[derive(Clone, Debug, PartialEq, Serialize)]
+struct Row {
+	/// name of the reading, e.g. `co2`
+	dimension1: String,
+	/// local time of the sample
+	dimension2: String,
+	value: f64,
+}
+
+pub struct Analytics {
+	client: NextcloudClient,
+	/// report ID by source
+	reports: BTreeMap<String, u32>,
+	/// source → name → value, received since the last sample
+	latest: BTreeMap<String, BTreeMap<String, f64>>,
+	/// rows not yet accepted by the server, by report ID
+	pending: BTreeMap<u32, VecDeque<Row>>,
+	/// log instead of sending, see `--dry-run`
+	dry_run: bool,
+}
+
+impl Analytics {
+	pub fn new(nextcloud: &NextcloudConfig, config: AnalyticsConfig, dry_run: bool) -> Self {
+		Self {
+			client: NextcloudClient::new(nextcloud),
+			reports: config
+				.report
+				.into_iter()
+				.filter(|(_, report)| *report != 0)
+				.collect(),
+			latest: BTreeMap::new(),
+			pending: BTreeMap::new(),
+			dry_run,
+		}
+	}
+
+	fn record(&mut self, reading: &Reading) {
+		if self.reports.contains_key(reading.source) {
+			self.latest
+				.entry(reading.source.to_string())
+				.or_default()
+				.insert(reading.name.clone(), reading.value);
+		}
+	}
+
+	/// Turns the latest values into rows, readings not received since the last sample are skipped.
+	fn sample(&mut self, time: &str) {
+		for (source, values) in std::mem::take(&mut self.latest) {
+			let report = self.reports[&source];
+			let rows = self.pending.entry(report).or_default();
+			rows.extend(values.into_iter().map(|(name, value)| Row {
+				dimension1: name,
+				dimension2: time.to_string(),
+				value,
+			}));
+			if rows.len() > MAX_ROWS {
+				log!(
+					Level::Warning,
+					"Analytics report {} unreachable, dropped {} old rows",
+					report,
+					rows.len() - MAX_ROWS
+				);
+				rows.drain(..rows.len() - MAX_ROWS);
+			}
+		}
+	}
+
+	/// Sends the pending rows of all reports, keeps the ones which failed.
+	async fn push(&mut self) -> Result<(), String> {
+		let mut errors = Vec::new();
+		for (report, rows) in &mut self.pending {
+			while !rows.is_empty() {
+				let batch = rows.iter().take(BATCH).cloned().collect::<Vec<Row>>();
+				if self.dry_run {
+					log!(
+						Level::Info,
+						"Dry run, not sent to Analytics report {}: {:?}",
+						report,
+						batch
+					);
+				} else {
+					let result = self
+						.client
+						.request(
+							Method::POST,
+							&format!("/apps/analytics/api/2.0/adddata/{}", report),
+						)
+						.json(&json!({ "data": batch }))
+						.send()
+						.await
+						.and_then(|response| response.error_for_status());
+					if let Err(error) = result {
+						errors.push(format!("report {}: {}", report, error));
+						break;
+					}
+				}
+				rows.drain(..batch.len());
+			}
+		}
+		self.pending.retain(|_, rows| !rows.is_empty());
+		if errors.is_empty() {
+			Ok(())
+		} else {
+			Err(errors.join(", "))
+		}
+	}
+
+	pub async fn get_background_task(
+		mut self,
+		bus: EventBus,
+		period: Duration,
+		shutdown: CancellationToken,
+	) -> Result<Never, ModuleError> {
+		let mut events = bus.subscribe_readings();
+		let mut samples = interval(period);
+		samples.tick().await;
+		let initial_retry = INITIAL_RETRY.min(period);
+		let mut backoff = initial_retry;
+		let mut retry: Option<Instant> = None;
+		loop {
+			select! {
+				event = events.recv() => match event {
+					Some(Event::Reading(reading)) => self.record(&reading),
+					Some(_) => {}
+					None => break,
+				},
+				_ = samples.tick() => {
+					self.sample(&Local::now().format("%Y-%m-%d %H:%M:%S").to_string());
+				}
+				_ = sleep_until(retry.unwrap_or_else(Instant::now)), if retry.is_some() => {}
+				_ = shutdown.cancelled() => {
+					return Err(ModuleError::new(String::from("Analytics shut down")));
+				}
+			}
+			let due = !matches!(retry, Some(retry) if retry > Instant::now());
+			if self.pending.is_empty() || !due {
+				continue;
+			}
+			match self.push().await {
+				Ok(()) => {
+					backoff = initial_retry;
+					retry = None;
+				}
+				Err(error) => {
+					log!(
+						Level::Warning,
+						"Couldn't publish to Analytics, retrying in {:?}: {}",
+						backoff,
+						error
+					);
+					retry = Some(Instant::now() + backoff);
+					backoff = (backoff * 2).min(period);
+				}
+			}
+		}
+		Err(ModuleError::new(String::from(
+			"analytics background task exited",
+		)))
+	}
+}
+
+pub struct AnalyticsModule;
+
+impl Module for AnalyticsModule {
+	fn name(&self) -> &'static str {
+		"Analytics"
+	}
+
+	fn enabled(&self, config: &mut Config) -> Result<bool, ConfigError> {
+		config.value::<bool>("analytics/enable")
+	}
+
+	fn config_keys(&self) -> &'static [&'static str] {
+		&["analytics", "nextcloud"]
+	}
+
+	fn check_config(&self, config: &mut Config) -> Result<(), Vec<ConfigError>> {
+		config.check::<AnalyticsConfig>("analytics")
+	}
+
+	fn start(&self, context: Context) -> BoxFuture<'static, Result<Never, ModuleError>> {
+		async move {
+			let nextcloud: NextcloudConfig = context.config("nextcloud").await?;
+			let analytics: AnalyticsConfig = context.config("analytics").await?;
+			let period = Duration::from_secs(analytics.interval);
+			Analytics::new(&nextcloud, analytics, context.options.dry_run)
+				.get_background_task(context.bus, period, context.shutdown)
+				.await
+		}
+		.boxed()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::Value;
+	use tokio::spawn;
+
+	use super::*;
+	use crate::stub::Stub;
+
+	fn analytics(url: &str) -> Analytics {
+		Analytics::new(
+			&NextcloudConfig {
+				url: url.to_string(),
+				user: String::from("opensesame"),
+				pass: String::from("secret"),
+				chat: String::new(),
+				chat_ping: String::new(),
+				chat_licht: String::new(),
+				chat_commands: String::new(),
+				outbox: String::new(),
+				outbox_size: 10,
+				format_datetime: String::from("%+"),
+			},
+			AnalyticsConfig {
+				interval: 60,
+				report: BTreeMap::from([
+					(String::from("environment"), 7),
+					(String::from("battery"), 9),
+					(String::from("weatherstation"), 0),
+				]),
+			},
+			false,
+		)
+	}
+
+	#[tokio::test]
+	async fn test_push() {
+		let stub = Stub::start().await;
+		let mut analytics = analytics(&stub.url);
+		analytics.record(&Reading::new("environment", "co2", 400.0, "ppm"));
+		analytics.record(&Reading::new("environment", "co2", 420.0, "ppm"));
+		analytics.record(&Reading::new("environment", "voc", 10.0, "ppb"));
+		analytics.record(&Reading::new("battery", "capacity", 80.0, "%"));
+		analytics.record(&Reading::new(
+			"weatherstation",
+			"mean_wind_speed",
+			3.0,
+			"m/s",
+		));
+		analytics.sample("2026-10-19 12:00:00");
+		// nothing new for the next sample
+		analytics.sample("2026-10-19 13:00:00");
+
+		stub.respond(503, "{}");
+		assert!(analytics.push().await.is_err());
+		assert_eq!(analytics.pending.keys().collect::<Vec<&u32>>(), vec![&7]);
+		assert!(analytics.push().await.is_ok());
+		assert!(analytics.pending.is_empty());
+
+		let requests = stub.requests();
+		assert_eq!(requests.len(), 3);
+		assert_eq!(requests[0].path, "/apps/analytics/api/2.0/adddata/7");
+		assert_eq!(requests[1].path, "/apps/analytics/api/2.0/adddata/9");
+		assert!(requests[2].headers["authorization"]
+			.to_str()
+			.unwrap()
+			.starts_with("Basic "));
+		let body: Value = serde_json::from_str(&requests[2].body).unwrap();
+		assert_eq!(
+			body,
+			json!({ "data": [
+				{ "dimension1": "co2", "dimension2": "2026-10-19 12:00:00", "value": 420.0 },
+				{ "dimension1": "voc", "dimension2": "2026-10-19 12:00:00", "value": 10.0 },
+			]})
+		);
+	}
+
+	#[tokio::test]
+	async fn test_retry() {
+		let stub = Stub::start().await;
+		stub.respond(500, "{}");
+		let bus = EventBus::new();
+		spawn(analytics(&stub.url).get_background_task(
+			bus.clone(),
+			Duration::from_millis(100),
+			CancellationToken::new(),
+		));
+		// let the task subscribe
+		sleep_until(Instant::now() + Duration::from_millis(20)).await;
+		bus.send(Reading::new("battery", "capacity", 80.0, "%"));
+		let requests = stub.wait_for(2).await;
+		assert_eq!(requests[0].body, requests[1].body);
+		assert!(requests[1].body.contains("\"capacity\""));
+	}
+}
@@ -0,0 +1,398 @@
+//! Local HTTP API with JSON, e.g. for dashboards and scripts, see doc/API.md.
+//!
+//! GET requests, including `/metrics` for Prometheus and the CSV history, are
+//! open to everyone who can reach `api/listen`,
+//! POST requests need `Authorization: Bearer <api/token>`.
+
+use std::net::TcpListener;
+
+use futures::{future::BoxFuture, never::Never, FutureExt};
+use hyper::{
+	body,
+	header::{AUTHORIZATION, CONTENT_TYPE},
+	service::{make_service_fn, service_fn},
+	Body, Method, Request, Response, Server, StatusCode,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+	bus::EventBus,
+	command::{Command, DEFAULT_HISTORY_RANGE},
+	config::{
+		validate::{min_length, Validate},
+		Config, ConfigError,
+	},
+	history::{parse_range, History},
+	instance::Instance,
+	log,
+	log::Level,
+	metrics,
+	module::{Context, Module},
+	readings::{unix_time, Readings},
+	state::StateStore,
+	supervisor::Health,
+	types::ModuleError,
+};
+
+const MIN_TOKEN_LENGTH: usize = 16;
+
+#[derive(Deserialize)]
+pub struct ApiConfig {
+	/// e.g. 127.0.0.1:8080
+	pub listen: String,
+	/// for POST requests
+	pub token: String,
+}
+
+impl Validate for ApiConfig {
+	fn validate(&self) -> Vec<ConfigError> {
+		min_length("api/token", &self.token, MIN_TOKEN_LENGTH)
+			.into_iter()
+			.collect()
+	}
+}
+
+#[derive(Deserialize)]
+struct Lights {
+	inside: bool,
+	outside: bool,
+}
+
+/// compares in constant time, so that the token cannot be guessed by timing
+fn same(a: &[u8], b: &[u8]) -> bool {
+	a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn json_response(status: StatusCode, value: Value) -> Response<Body> {
+	Response::builder()
+		.status(status)
+		.header(CONTENT_TYPE, "application/json")
+		.body(Body::from(value.to_string()))
+		.unwrap()
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Body> {
+	json_response(status, json!({ "error": message }))
+}
+
+#[derive(Clone)]
+pub struct Api {
+	token: String,
+	bus: EventBus,
+	state: StateStore,
+	health: Health,
+	history: History,
+	readings: Readings,
+	instance: Instance,
+	startup_time: String,
+}
+
+impl Api {
+	pub fn new(config: ApiConfig, context: &Context) -> Self {
+		Self {
+			token: config.token,
+			bus: context.bus.clone(),
+			state: context.state.clone(),
+			health: context.health.clone(),
+			history: context.history.clone(),
+			readings: context.readings.clone(),
+			instance: context.instance.clone(),
+			startup_time: context.startup_time.clone(),
+		}
+	}
+
+	fn authorized(&self, request: &Request<Body>) -> bool {
+		request
+			.headers()
+			.get(AUTHORIZATION)
+			.and_then(|value| value.to_str().ok())
+			.and_then(|value| value.strip_prefix("Bearer "))
+			.is_some_and(|token| same(token.as_bytes(), self.token.as_bytes()))
+	}
+
+	async fn status(&self) -> Result<Value, String> {
+		let alarm = self
+			.state
+			.get_option::<String>("alarm/fire")
+			.await
+			.map_err(|error| error.to_string())?;
+		let modules = self
+			.health
+			.lock()
+			.unwrap()
+			.iter()
+			.map(|(name, health)| (name.to_string(), json!(health)))
+			.collect::<serde_json::Map<String, Value>>();
+		let latest = self.readings.latest();
+		Ok(json!({
+			"version": env!("CARGO_PKG_VERSION"),
+			"instance": self.instance.to_string(),
+			"startup_time": self.startup_time,
+			"alarm": alarm,
+			"last_alarm": latest.last_alarm,
+			"door_opened": latest.door_opened,
+			"garage_closed": latest.garage_closed,
+			"lights": { "inside": latest.lights_inside, "outside": latest.lights_outside },
+			"air_quality": latest.air_quality,
+			"modules": modules,
+		}))
+	}
+
+	/// CSV of a reading, e.g. `/api/history/environment/co2?range=7d`
+	fn history(&self, reading: &str, query: Option<&str>) -> Response<Body> {
+		let range = match query
+			.unwrap_or_default()
+			.split('&')
+			.find_map(|parameter| parameter.strip_prefix("range="))
+		{
+			Some(range) => match parse_range(range) {
+				Some(range) => range,
+				None => {
+					return error(
+						StatusCode::BAD_REQUEST,
+						"range like 30m, 24h, 7d or 1y expected",
+					)
+				}
+			},
+			None => DEFAULT_HISTORY_RANGE,
+		};
+		match self.history.csv(reading, range, unix_time()) {
+			Ok(csv) => Response::builder()
+				.header(CONTENT_TYPE, "text/csv")
+				.body(Body::from(csv))
+				.unwrap(),
+			Err(message) => error(StatusCode::NOT_FOUND, &message),
+		}
+	}
+
+	async fn execute(&self, command: Command) -> Response<Body> {
+		match command.execute(&self.bus, &self.state, &self.history).await {
+			Ok(_output) => json_response(StatusCode::OK, json!({ "ok": true })),
+			Err(message) => error(StatusCode::INTERNAL_SERVER_ERROR, &message),
+		}
+	}
+
+	async fn handle(&self, request: Request<Body>) -> Response<Body> {
+		let method = request.method().clone();
+		let path = request.uri().path().to_string();
+		if method == Method::POST && !self.authorized(&request) {
+			log!(
+				Level::Warning,
+				"Unauthorized API request {} {}",
+				method,
+				path
+			);
+			return error(StatusCode::UNAUTHORIZED, "missing or wrong token");
+		}
+		match (method, path.as_str()) {
+			(Method::GET, "/api/status") => match self.status().await {
+				Ok(status) => json_response(StatusCode::OK, status),
+				Err(message) => error(StatusCode::INTERNAL_SERVER_ERROR, &message),
+			},
+			(Method::GET, "/metrics") => Response::builder()
+				.header(CONTENT_TYPE, metrics::CONTENT_TYPE)
+				.body(Body::from(metrics::render(
+					&self.readings.latest(),
+					&self.health,
+				)))
+				.unwrap(),
+			(Method::GET, path) if path.starts_with("/api/history/") => {
+				self.history(&path["/api/history/".len()..], request.uri().query())
+			}
+			(Method::GET, "/api/readings") => {
+				json_response(StatusCode::OK, json!(self.readings.latest().readings))
+			}
+			(Method::POST, "/api/door/open") => self.execute(Command::OpenDoor).await,
+			(Method::POST, "/api/bell") => self.execute(Command::Ring).await,
+			(Method::POST, "/api/lights") => {
+				let lights = body::to_bytes(request.into_body())
+					.await
+					.ok()
+					.and_then(|bytes| serde_json::from_slice::<Lights>(&bytes).ok());
+				match lights {
+					Some(Lights { inside, outside }) => {
+						self.execute(Command::SwitchLights { inside, outside })
+							.await
+					}
+					None => error(
+						StatusCode::BAD_REQUEST,
+						"expected {\"inside\": bool, \"outside\": bool}",
+					),
+				}
+			}
+			(
+				_,
+				"/api/status" | "/api/readings" | "/api/door/open" | "/api/bell" | "/api/lights"
+				| "/metrics",
+			) => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+			_ => error(StatusCode::NOT_FOUND, "not found"),
+		}
+	}
+
+	pub async fn get_background_task(
+		self,
+		listener: TcpListener,
+		shutdown: CancellationToken,
+	) -> Result<Never, ModuleError> {
+		let make_service = make_service_fn(move |_connection| {
+			let api = self.clone();
+			async move {
+				Ok::<_, hyper::Error>(service_fn(move |request| {
+					let api = api.clone();
+					async move { Ok::<_, hyper::Error>(api.handle(request).await) }
+				}))
+			}
+		});
+		Server::from_tcp(listener)
+			.map_err(|error| ModuleError::new(format!("API: {}", error)))?
+			.serve(make_service)
+			.with_graceful_shutdown(async move { shutdown.cancelled().await })
+			.await
+			.map_err(|error| ModuleError::new(format!("API: {}", error)))?;
+		Err(ModuleError::new(String::from("API shut down")))
+	}
+}
+
+pub struct ApiModule;
+
+impl Module for ApiModule {
+	fn name(&self) -> &'static str {
+		"API"
+	}
+
+	fn enabled(&self, config: &mut Config) -> Result<bool, ConfigError> {
+		config.value::<bool>("api/enable")
+	}
+
+	fn config_keys(&self) -> &'static [&'static str] {
+		&["api"]
+	}
+
+	fn check_config(&self, config: &mut Config) -> Result<(), Vec<ConfigError>> {
+		config.check::<ApiConfig>("api")
+	}
+
+	fn start(&self, context: Context) -> BoxFuture<'static, Result<Never, ModuleError>> {
+		async move {
+			let config: ApiConfig = context.config("api").await?;
+			let listener = TcpListener::bind(&config.listen)?;
+			Api::new(config, &context)
+				.get_background_task(listener, context.shutdown.clone())
+				.await
+		}
+		.boxed()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::spawn;
+
+	use super::*;
+	use crate::{
+		audio::AudioEvent,
+		bus::Event,
+		cli::Options,
+		readings::Reading,
+		supervisor::{ModuleHealth, ModuleState},
+	};
+
+	const TOKEN: &str = "0123456789abcdef";
+
+	#[tokio::test]
+	async fn test_api() {
+		let context = Context::new(
+			Instance::default(),
+			String::from("today"),
+			Default::default(),
+			Options::default(),
+		)
+		.await
+		.unwrap();
+		context.health.lock().unwrap().insert(
+			"Audio",
+			ModuleHealth {
+				state: ModuleState::Running,
+				failures: 0,
+				restarts: 1,
+				last_error: None,
+			},
+		);
+		context.readings.update(&Event::Reading(Reading::new(
+			"environment",
+			"co2",
+			420.0,
+			"ppm",
+		)));
+		let mut events = context.bus.subscribe();
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("http://{}/api", listener.local_addr().unwrap());
+		let api = Api::new(
+			ApiConfig {
+				listen: String::new(),
+				token: String::from(TOKEN),
+			},
+			&context,
+		);
+		spawn(api.get_background_task(listener, CancellationToken::new()));
+		let client = reqwest::Client::new();
+
+		let status: Value = client
+			.get(format!("{}/status", url))
+			.send()
+			.await
+			.unwrap()
+			.json()
+			.await
+			.unwrap();
+		assert_eq!(status["instance"], "#0/current");
+		assert_eq!(status["modules"]["Audio"]["state"], "running");
+		assert_eq!(status["modules"]["Audio"]["restarts"], 1);
+
+		let readings: Value = client
+			.get(format!("{}/readings", url))
+			.send()
+			.await
+			.unwrap()
+			.json()
+			.await
+			.unwrap();
+		assert_eq!(readings["environment/co2"]["value"], 420.0);
+		assert_eq!(readings["environment/co2"]["unit"], "ppm");
+
+		let metrics = client
+			.get(url.replace("/api", "/metrics"))
+			.send()
+			.await
+			.unwrap()
+			.text()
+			.await
+			.unwrap();
+		assert!(metrics.contains("opensesame_module_restarts_total{module=\"Audio\"} 1\n"));
+
+		let response = client.post(format!("{}/bell", url)).send().await.unwrap();
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+		let response = client
+			.post(format!("{}/bell", url))
+			.bearer_auth(TOKEN)
+			.send()
+			.await
+			.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+		assert!(matches!(
+			events.recv().await,
+			Some(Event::Audio(AudioEvent::Bell))
+		));
+
+		let response = client
+			.post(format!("{}/lights", url))
+			.bearer_auth(TOKEN)
+			.body("{\"inside\": true}")
+			.send()
+			.await
+			.unwrap();
+		assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+	}
+}
@@ -1,20 +1,43 @@
-use futures::never::Never;
+use futures::{future::BoxFuture, never::Never, FutureExt};
 
 use gettextrs::gettext;
-use tokio::{
-	io,
-	process::Command,
-	spawn,
-	sync::mpsc::{Receiver, Sender},
-};
+use serde::Deserialize;
+use tokio::{io, process::Command, select, spawn};
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-	nextcloud::{NextcloudChat, NextcloudEvent},
-	ssh::exec_ssh_command,
+	bus::{Event, EventBus},
+	config::{
+		validate::{exists, Validate},
+		Config, ConfigError,
+	},
+	module::{Context, Module},
+	nextcloud::NextcloudChat,
+	notify::Notification,
+	peer::PeerMessage,
 	types::ModuleError,
 };
 
+#[derive(Deserialize)]
+pub struct AudioConfig {
+	/// played when the bell is pressed, /dev/null to not play anything
+	pub bell: String,
+	/// played on alarm, /dev/null to not play anything
+	pub alarm: String,
+}
+
+impl Validate for AudioConfig {
+	fn validate(&self) -> Vec<ConfigError> {
+		[
+			exists("audio/bell", &self.bell),
+			exists("audio/alarm", &self.alarm),
+		]
+		.into_iter()
+		.flatten()
+		.collect()
+	}
+}
+
 // play audio file with argument. If you do not have an argument, simply pass --quiet again
 async fn play_audio_file(
 	file: String,
@@ -37,6 +60,7 @@ async fn play_audio_file(
 	Ok(())
 }
 
+#[derive(Clone, Debug)]
 pub enum AudioEvent {
 	Bell,
 	FireAlarm,
@@ -57,24 +81,39 @@ impl Audio {
 
 	pub async fn get_background_task(
 		self,
-		mut audio_receiver: Receiver<AudioEvent>,
-		nextcloud_sender: Sender<NextcloudEvent>,
+		bus: EventBus,
+		shutdown: CancellationToken,
 	) -> Result<Never, ModuleError> {
+		let mut events = bus.subscribe();
 		let mut maybe_cancellation_token: Option<CancellationToken> = Option::None;
 
-		while let Some(event) = audio_receiver.recv().await {
+		loop {
+			let event = select! {
+				event = events.recv() => event,
+				_ = shutdown.cancelled() => {
+					// stop playing
+					if let Some(cancellation_token) = maybe_cancellation_token {
+						cancellation_token.cancel();
+					}
+					return Err(ModuleError::new(String::from("audio shut down")));
+				}
+			};
+			let event = match event {
+				Some(Event::Audio(event)) => event,
+				Some(_) => continue,
+				None => break,
+			};
 			if maybe_cancellation_token.is_some() {
 				maybe_cancellation_token.unwrap().cancel();
 			};
 			maybe_cancellation_token = Option::Some(CancellationToken::new());
 			match event {
 				AudioEvent::Bell => {
-					nextcloud_sender
-						.send(NextcloudEvent::Chat(
-							NextcloudChat::Default,
-							gettext("🔔 Ringing the Audio Bell"),
-						))
-						.await?;
+					bus.send(Notification::info(
+						"Audio",
+						NextcloudChat::Default,
+						gettext("🔔 Ringing the Audio Bell"),
+					));
 					spawn(play_audio_file(
 						self.bell_path.clone(),
 						"",
@@ -82,33 +121,17 @@ impl Audio {
 					));
 				}
 				AudioEvent::FireAlarm => {
-					nextcloud_sender
-						.send(NextcloudEvent::Chat(
-							NextcloudChat::Default,
-							gettext("🚨 Audio Fire Alarm!"),
-						))
-						.await?;
+					bus.send(Notification::alarm(
+						"Audio",
+						gettext("🚨 Audio Fire Alarm!"),
+					));
 					spawn(play_audio_file(
 						self.fire_alarm_path.clone(),
 						"--repeat",
 						maybe_cancellation_token.clone().unwrap(),
 					));
-					let nextcloud_sender_clone = nextcloud_sender.clone();
-					spawn(async move {
-						let ssh_result =
-							exec_ssh_command(String::from("killall -SIGUSR2 opensesame")).await;
-						if let Err(err) = ssh_result {
-							let _ = nextcloud_sender_clone
-								.send(NextcloudEvent::Chat(
-									NextcloudChat::Ping,
-									gettext!(
-										"Couldn't send SIGUSR2 to other opensesame instance: {}",
-										err
-									),
-								))
-								.await;
-						}
-					});
+					// the other opensesame instances also ring
+					bus.send(PeerMessage::Bell);
 				}
 			}
 		}
@@ -117,3 +140,36 @@ impl Audio {
 		)))
 	}
 }
+
+pub struct AudioModule;
+
+impl Module for AudioModule {
+	fn name(&self) -> &'static str {
+		"Audio"
+	}
+
+	fn enabled(&self, _config: &mut Config) -> Result<bool, ConfigError> {
+		Ok(true)
+	}
+
+	fn config_keys(&self) -> &'static [&'static str] {
+		&["audio"]
+	}
+
+	fn check_config(&self, config: &mut Config) -> Result<(), Vec<ConfigError>> {
+		config.check::<AudioConfig>("audio")
+	}
+
+	fn start(&self, context: Context) -> BoxFuture<'static, Result<Never, ModuleError>> {
+		async move {
+			let audio: AudioConfig = context.config("audio").await?;
+			Audio::get_background_task(
+				Audio::new(audio.bell, audio.alarm),
+				context.bus,
+				context.shutdown,
+			)
+			.await
+		}
+		.boxed()
+	}
+}
@@ -2,18 +2,21 @@
 
 const CAPACITY_FILE: &str = "/sys/class/power_supply/axp20x-battery/capacity";
 
-use futures::never::Never;
+use futures::{future::BoxFuture, never::Never, FutureExt};
 use gettextrs::gettext;
 use std::fmt;
 use std::fs;
 use systemstat::Duration;
-use tokio::sync::mpsc::Sender;
 use tokio::time::interval;
 
+use crate::bus::{Event, EventBus};
+use crate::config::{Config, ConfigError};
+use crate::module::{Context, Module};
 use crate::nextcloud::NextcloudChat;
 use crate::nextcloud::NextcloudEvent;
 use crate::nextcloud::NextcloudStatus;
-use crate::ping::PingEvent;
+use crate::notify::Notification;
+use crate::readings::Reading;
 use crate::types::ModuleError;
 
 const START_CAPACITY_THRESHOLD: u8 = 80;
@@ -38,38 +41,36 @@ impl Bat {
 		}
 	}
 
-	pub async fn get_background_task(
-		mut self,
-		nextcloud_sender: Sender<NextcloudEvent>,
-		ping_sender: Sender<PingEvent>,
-	) -> Result<Never, ModuleError> {
+	pub async fn get_background_task(mut self, bus: EventBus) -> Result<Never, ModuleError> {
 		let mut interval = interval(Duration::from_secs(600));
 		loop {
 			interval.tick().await;
 			let new_capacity = self.capacity();
-			ping_sender
-				.send(PingEvent::UpdateBatCapacity(new_capacity))
-				.await?;
+			bus.send(Event::BatteryLevel(new_capacity));
+			bus.send(Reading::new(
+				"battery",
+				"capacity",
+				new_capacity.into(),
+				"%",
+			));
 
 			if new_capacity != self.capacity {
 				self.capacity = new_capacity;
 				if self.capacity < self.capacity_threshold {
-					nextcloud_sender
-						.send(NextcloudEvent::Status(
-							NextcloudStatus::Online,
-							gettext!("🪫 ON {}%", self.capacity),
-						))
-						.await?;
-					nextcloud_sender
-						.send(NextcloudEvent::Chat(
-							NextcloudChat::Default,
-							gettext!(
-								"🪫 Battery Capacity is below {}% at {}%",
-								self.capacity_threshold,
-								self.capacity
-							),
-						))
-						.await?;
+					bus.send(Event::BatteryLow(self.capacity));
+					bus.send(NextcloudEvent::Status(
+						NextcloudStatus::Online,
+						gettext!("🪫 ON {}%", self.capacity),
+					));
+					bus.send(Notification::warning(
+						"Battery",
+						NextcloudChat::Default,
+						gettext!(
+							"🪫 Battery Capacity is below {}% at {}%",
+							self.capacity_threshold,
+							self.capacity
+						),
+					));
 					if self.capacity_threshold - 10 > 0 {
 						self.capacity_threshold -= 10;
 					} else {
@@ -77,18 +78,15 @@ impl Bat {
 					}
 				} else if self.capacity == 100 {
 					self.capacity_threshold = START_CAPACITY_THRESHOLD;
-					nextcloud_sender
-						.send(NextcloudEvent::Status(
-							NextcloudStatus::Online,
-							gettext!("🔋 ON {}%", self.capacity),
-						))
-						.await?;
-					nextcloud_sender
-						.send(NextcloudEvent::Chat(
-							NextcloudChat::Default,
-							gettext!("🔋 Battery Capacity is back to {}%", self.capacity),
-						))
-						.await?;
+					bus.send(NextcloudEvent::Status(
+						NextcloudStatus::Online,
+						gettext!("🔋 ON {}%", self.capacity),
+					));
+					bus.send(Notification::info(
+						"Battery",
+						NextcloudChat::Default,
+						gettext!("🔋 Battery Capacity is back to {}%", self.capacity),
+					));
 				}
 			}
 		}
@@ -100,3 +98,27 @@ impl fmt::Display for Bat {
 		write!(f, "{}%", self.capacity())
 	}
 }
+
+pub struct BatModule;
+
+impl Module for BatModule {
+	fn name(&self) -> &'static str {
+		"Battery"
+	}
+
+	fn enabled(&self, config: &mut Config) -> Result<bool, ConfigError> {
+		config.value::<bool>("bat/enable")
+	}
+
+	fn hardware(&self) -> bool {
+		true
+	}
+
+	fn config_keys(&self) -> &'static [&'static str] {
+		&["bat"]
+	}
+
+	fn start(&self, context: Context) -> BoxFuture<'static, Result<Never, ModuleError>> {
+		Bat::get_background_task(Bat::new(), context.bus).boxed()
+	}
+}
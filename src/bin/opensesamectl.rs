@@ -0,0 +1,82 @@
+//! Controls a running opensesame via its control socket, e.g.
+//! `opensesamectl --profile garage lights on off`, see doc/Control.md.
+
+use std::{collections::BTreeMap, env, process::ExitCode};
+
+use opensesame::{command::USAGE, config::Config, control::request, instance::Instance};
+
+// exit code for wrong usage, as in sysexits.h
+const EXIT_USAGE: u8 = 64;
+
+fn usage() -> String {
+	format!(
+		"Usage: opensesamectl [-i|--instance <number>] [-p|--profile <profile>] <command> [<arguments>]\n\n{}",
+		USAGE
+	)
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+	let mut arguments = BTreeMap::new();
+	let mut args = env::args().skip(1).peekable();
+	while let Some(arg) = args.next_if(|arg| arg.starts_with('-')) {
+		let name = match arg.as_str() {
+			"-i" | "--instance" => "cli/instance",
+			"-p" | "--profile" => "cli/profile",
+			"-h" | "--help" => {
+				println!("{}", usage());
+				return ExitCode::SUCCESS;
+			}
+			_ => {
+				eprintln!("Unknown option {}\n\n{}", arg, usage());
+				return ExitCode::from(EXIT_USAGE);
+			}
+		};
+		match args.next() {
+			Some(value) => arguments.insert(name.to_string(), value),
+			None => {
+				eprintln!("{} needs a value\n\n{}", arg, usage());
+				return ExitCode::from(EXIT_USAGE);
+			}
+		};
+	}
+	let command = args.collect::<Vec<String>>().join(" ");
+	if command.is_empty() {
+		eprintln!("{}", usage());
+		return ExitCode::from(EXIT_USAGE);
+	}
+	let instance = match Instance::from_arguments(&arguments) {
+		Ok(instance) => instance,
+		Err(error) => {
+			eprintln!("{}\n\n{}", error, usage());
+			return ExitCode::from(EXIT_USAGE);
+		}
+	};
+	let runtime = match Config::open(&instance.config_parent()) {
+		Ok(mut config) => config.get::<String>("instance/runtime"),
+		Err(error) => {
+			eprintln!("Could not read configuration {}", error);
+			return ExitCode::FAILURE;
+		}
+	};
+	let socket = instance.control_socket(&runtime);
+	match request(&socket, &command).await {
+		Ok((ok, output)) => {
+			print!("{}", output);
+			if ok {
+				ExitCode::SUCCESS
+			} else {
+				ExitCode::FAILURE
+			}
+		}
+		Err(error) => {
+			eprintln!(
+				"Could not reach instance {} via {}: {}",
+				instance,
+				socket.display(),
+				error
+			);
+			ExitCode::FAILURE
+		}
+	}
+}
@@ -0,0 +1,250 @@
+use tokio::{
+	select,
+	sync::broadcast::{
+		self,
+		error::{RecvError, TryRecvError},
+	},
+};
+
+use crate::{
+	audio::AudioEvent, buttons::CommandToButtons, environment::AirQualityChange,
+	environment::EnvEvent, log, log::Level, nextcloud::NextcloudEvent, peer::PeerMessage,
+	ping::PingEvent, readings::Reading,
+};
+
+// large enough that the 10 ms loops of Buttons and Garage never lag behind,
+// readings have their own channel so that they cannot push out commands
+const CAPACITY: usize = 256;
+
+#[derive(Clone, Debug)]
+pub enum Event {
+	// requests to a specific module
+	Nextcloud(NextcloudEvent),
+	Command(CommandToButtons),
+	Audio(AudioEvent),
+	Ping(PingEvent),
+	Env(EnvEvent),
+	/// to all peers
+	Peer(PeerMessage),
+	/// to the supervisor, e.g. from `module restart`
+	RestartModule(&'static str),
+
+	// what happened, for everyone interested
+	DoorOpened(String),
+	/// PIN sequence timed out or got too long
+	PinFailed,
+	BellPressed,
+	LightsOn {
+		inside: bool,
+		outside: bool,
+	},
+	LightsOff,
+	GarageDoorClosed(bool),
+	AirQualityChanged(AirQualityChange),
+	AlarmRaised(String),
+	BatteryLevel(u8),
+	/// capacity in % fell below the warning threshold
+	BatteryLow(u8),
+	/// measured value of a sensor, only for `subscribe_readings`
+	Reading(Reading),
+	/// configuration keys changed on reload (SIGHUP)
+	ConfigChanged(Vec<String>),
+}
+
+impl From<NextcloudEvent> for Event {
+	fn from(event: NextcloudEvent) -> Self {
+		Event::Nextcloud(event)
+	}
+}
+
+impl From<CommandToButtons> for Event {
+	fn from(command: CommandToButtons) -> Self {
+		Event::Command(command)
+	}
+}
+
+impl From<AudioEvent> for Event {
+	fn from(event: AudioEvent) -> Self {
+		Event::Audio(event)
+	}
+}
+
+impl From<PingEvent> for Event {
+	fn from(event: PingEvent) -> Self {
+		Event::Ping(event)
+	}
+}
+
+impl From<EnvEvent> for Event {
+	fn from(event: EnvEvent) -> Self {
+		Event::Env(event)
+	}
+}
+
+impl From<Reading> for Event {
+	fn from(reading: Reading) -> Self {
+		Event::Reading(reading)
+	}
+}
+
+impl From<PeerMessage> for Event {
+	fn from(message: PeerMessage) -> Self {
+		Event::Peer(message)
+	}
+}
+
+/// Typed broadcast bus: every module can publish, every module can subscribe.
+#[derive(Clone)]
+pub struct EventBus {
+	sender: broadcast::Sender<Event>,
+	/// `Event::Reading`s, which come at a high rate, e.g. 24 per line of Sensors
+	readings: broadcast::Sender<Event>,
+}
+
+impl Default for EventBus {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl EventBus {
+	pub fn new() -> Self {
+		let (sender, _receiver) = broadcast::channel(CAPACITY);
+		let (readings, _receiver) = broadcast::channel(CAPACITY);
+		Self { sender, readings }
+	}
+
+	/// Events without subscriber (e.g. module disabled) are silently dropped.
+	pub fn send(&self, event: impl Into<Event>) {
+		let event = event.into();
+		log!(Level::Debug, "Event: {:?}", event);
+		let _ = match event {
+			Event::Reading(_) => self.readings.send(event),
+			_ => self.sender.send(event),
+		};
+	}
+
+	/// Only events sent after subscribing are received, without readings.
+	pub fn subscribe(&self) -> Subscription {
+		Subscription {
+			receiver: self.sender.subscribe(),
+			readings: None,
+		}
+	}
+
+	/// Like `subscribe`, but also receives `Event::Reading`s.
+	pub fn subscribe_readings(&self) -> Subscription {
+		Subscription {
+			receiver: self.sender.subscribe(),
+			readings: Some(self.readings.subscribe()),
+		}
+	}
+}
+
+pub struct Subscription {
+	receiver: broadcast::Receiver<Event>,
+	readings: Option<broadcast::Receiver<Event>>,
+}
+
+impl Subscription {
+	/// Waits for the next event, None if the bus is gone.
+	pub async fn recv(&mut self) -> Option<Event> {
+		match &mut self.readings {
+			None => recv(&mut self.receiver, "events").await,
+			Some(readings) => select! {
+				biased;
+				event = recv(&mut self.receiver, "events") => event,
+				reading = recv(readings, "readings") => reading,
+			},
+		}
+	}
+
+	/// Next event if one is already available.
+	pub fn try_recv(&mut self) -> Option<Event> {
+		try_recv(&mut self.receiver, "events")
+			.or_else(|| self.readings.as_mut().and_then(|r| try_recv(r, "readings")))
+	}
+}
+
+async fn recv(receiver: &mut broadcast::Receiver<Event>, what: &str) -> Option<Event> {
+	loop {
+		match receiver.recv().await {
+			Ok(event) => return Some(event),
+			Err(RecvError::Lagged(missed)) => {
+				log!(
+					Level::Warning,
+					"Event bus subscriber missed {} {}",
+					missed,
+					what
+				);
+			}
+			Err(RecvError::Closed) => return None,
+		}
+	}
+}
+
+fn try_recv(receiver: &mut broadcast::Receiver<Event>, what: &str) -> Option<Event> {
+	loop {
+		match receiver.try_recv() {
+			Ok(event) => return Some(event),
+			Err(TryRecvError::Lagged(missed)) => {
+				log!(
+					Level::Warning,
+					"Event bus subscriber missed {} {}",
+					missed,
+					what
+				);
+			}
+			Err(TryRecvError::Empty) | Err(TryRecvError::Closed) => return None,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_every_subscriber_receives() {
+		let bus = EventBus::new();
+		let mut first = bus.subscribe();
+		let mut second = bus.subscribe();
+		bus.send(Event::BatteryLevel(42));
+		assert!(matches!(first.recv().await, Some(Event::BatteryLevel(42))));
+		assert!(matches!(second.try_recv(), Some(Event::BatteryLevel(42))));
+		assert!(second.try_recv().is_none());
+	}
+
+	#[tokio::test]
+	async fn test_lagging_subscriber_continues() {
+		let bus = EventBus::new();
+		let mut subscription = bus.subscribe();
+		for level in 0..CAPACITY + 10 {
+			bus.send(Event::BatteryLevel(level as u8));
+		}
+		assert!(matches!(
+			subscription.try_recv(),
+			Some(Event::BatteryLevel(10))
+		));
+	}
+
+	#[tokio::test]
+	async fn test_readings_do_not_push_out_events() {
+		let bus = EventBus::new();
+		let mut events = bus.subscribe();
+		let mut readings = bus.subscribe_readings();
+		bus.send(Event::BellPressed);
+		for value in 0..CAPACITY + 10 {
+			bus.send(Reading::new("sensors", "test", value as f64, "°C"));
+		}
+		assert!(matches!(events.try_recv(), Some(Event::BellPressed)));
+		assert!(events.try_recv().is_none());
+		assert!(matches!(readings.recv().await, Some(Event::BellPressed)));
+		assert!(matches!(readings.recv().await, Some(Event::Reading(_))));
+	}
+
+	#[test]
+	fn test_send_without_subscriber() {
+		EventBus::new().send(Event::BellPressed);
+	}
+}
@@ -1,31 +1,88 @@
 use std::cmp::Ordering;
+use std::collections::BTreeMap;
 
 use chrono::Datelike;
 use chrono::Local;
 use chrono::Timelike;
+use futures::future::BoxFuture;
 use futures::never::Never;
+use futures::FutureExt;
 use gettextrs::gettext;
 use i2cdev::core::*;
 use i2cdev::linux::LinuxI2CDevice;
 use i2cdev::linux::LinuxI2CError;
+use serde::Deserialize;
 use sunrise::sunrise_sunset;
 use systemstat::Duration;
 use systemstat::{Platform, System};
 
-use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::time::interval;
 use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
 
 use crate::audio::AudioEvent;
-use crate::config::Config;
+use crate::bus::{Event, EventBus};
+use crate::config::{
+	validate::{in_range, Validate},
+	Config, ConfigError,
+};
+use crate::module::{Context, Module};
 use crate::nextcloud::NextcloudChat;
-use crate::nextcloud::NextcloudEvent;
+use crate::notify::{Category, Notification};
 use crate::pwr::Pwr;
 
 use crate::types::ModuleError;
-use crate::validator::{Validation, Validator};
+use crate::validator::{Sequence, Validation, Validator, MAX_SEQUENCE_LENGTH};
 use crate::watchdog;
 
+/// Deserialized from the top of the configuration, as the keys are spread over several sections.
+#[derive(Deserialize)]
+pub struct ButtonsConfig {
+	#[serde(rename = "light/timeout")]
+	pub light_timeout: u32,
+	#[serde(rename = "bell/enable")]
+	pub bell_enable: bool,
+	#[serde(rename = "pwr/enable")]
+	pub pwr_enable: bool,
+	/// user name → PIN sequence
+	pub validator: BTreeMap<String, Sequence>,
+	pub location: Location,
+	#[serde(rename = "nextcloud/format/time")]
+	pub time_format: String,
+}
+
+/// used for sunrise/sunset
+#[derive(Deserialize)]
+pub struct Location {
+	pub latitude: f64,
+	pub longitude: f64,
+}
+
+impl Validate for ButtonsConfig {
+	fn validate(&self) -> Vec<ConfigError> {
+		let mut errors = [
+			in_range("light/timeout", self.light_timeout, 1..=86400),
+			in_range("location/latitude", self.location.latitude, -90.0..=90.0),
+			in_range(
+				"location/longitude",
+				self.location.longitude,
+				-180.0..=180.0,
+			),
+		]
+		.into_iter()
+		.flatten()
+		.collect::<Vec<ConfigError>>();
+		errors.extend(self.validator.iter().filter_map(|(user, sequence)| {
+			in_range(
+				&format!("validator/{}", user),
+				sequence.len(),
+				1..=MAX_SEQUENCE_LENGTH,
+			)
+		}));
+		errors
+	}
+}
+
 pub struct Buttons {
 	pub sequence: Vec<u8>,
 	pub pins1: u8,
@@ -71,6 +128,7 @@ pub enum StateChange {
 	LightsOff,
 }
 
+#[derive(Clone, Debug)]
 pub enum CommandToButtons {
 	OpenDoor,
 	RingBell(u32, u32), // maybe implement it with interval
@@ -131,7 +189,7 @@ const RELAY_LICHT_INNEN: u8 = 0x01 << 1;
 const PINS2_INIT: u8 = 0b01100000;
 
 impl Buttons {
-	pub fn new(config: &mut Config) -> Self {
+	pub fn new(config: &ButtonsConfig) -> Self {
 		let mut s = Self {
 			sequence: vec![],
 
@@ -147,11 +205,11 @@ impl Buttons {
 
 			door_timeout: 0,
 
-			init_light_timeout: config.get::<u32>("light/timeout") * 100,
+			init_light_timeout: config.light_timeout * 100,
 			light_timeout: 0,
 			light_permanent: false,
 
-			bell_enable: config.get_bool("bell/enable"),
+			bell_enable: config.bell_enable,
 			bell_timeout: 0,
 			bell_timeout_init: 0,
 			bell_counter: 0,
@@ -484,61 +542,72 @@ impl Buttons {
 		ret
 	}
 
-	async fn do_reset(
-		nextcloud_sender: Sender<NextcloudEvent>,
-		pwr: &mut Pwr,
-	) -> Result<(), ModuleError> {
+	/// switch_lights and tell everyone on the bus that the lights are on
+	fn switch_lights_on_bus(&mut self, bus: &EventBus, inside: bool, outside: bool) -> String {
+		let ret = self.switch_lights(inside, outside);
+		bus.send(Event::LightsOn { inside, outside });
+		ret
+	}
+
+	async fn do_reset(bus: &EventBus, pwr: &mut Pwr) -> Result<(), ModuleError> {
 		if pwr.enabled() {
 			pwr.switch(false);
-			nextcloud_sender
-				.send(NextcloudEvent::Chat(
-					NextcloudChat::Ping,
-					gettext("👋 Turned PWR_SWITCH off"),
-				))
-				.await?;
+			bus.send(Notification::debug(
+				"Buttons",
+				NextcloudChat::Ping,
+				gettext("👋 Turned PWR_SWITCH off"),
+			));
 			sleep(Duration::from_millis(watchdog::SAFE_TIMEOUT)).await;
 
 			pwr.switch(true);
-			nextcloud_sender
-				.send(NextcloudEvent::Chat(
-					NextcloudChat::Ping,
-					gettext("👋 Turned PWR_SWITCH on"),
-				))
-				.await?;
+			bus.send(Notification::debug(
+				"Buttons",
+				NextcloudChat::Ping,
+				gettext("👋 Turned PWR_SWITCH on"),
+			));
 			sleep(Duration::from_millis(watchdog::SAFE_TIMEOUT)).await;
 		}
 		Ok(())
 	}
 
+	#[allow(clippy::too_many_arguments)]
 	pub async fn get_background_task(
 		mut self,
 		mut validator: Validator,
 		mut pwr: Pwr,
 		time_format: String,
-		mut command_receiver: Receiver<CommandToButtons>,
-		nextcloud_sender: Sender<NextcloudEvent>,
-		audio_sender: Sender<AudioEvent>,
+		bus: EventBus,
 		location_latitude: f64,
 		location_longitude: f64,
+		shutdown: CancellationToken,
 	) -> Result<Never, ModuleError> {
+		let mut events = bus.subscribe();
 		let mut interval = interval(Duration::from_millis(10));
 		loop {
 			interval.tick().await;
-			if let Ok(command) = command_receiver.try_recv() {
+			if shutdown.is_cancelled() {
+				self.turn_everything_off()?;
+				return Err(ModuleError::new(String::from("Buttons shut down")));
+			}
+			while let Some(event) = events.try_recv() {
+				let command = match event {
+					Event::Command(command) => command,
+					_ => continue,
+				};
 				match command {
 					CommandToButtons::OpenDoor => {
 						self.open_door();
+						bus.send(Event::DoorOpened(String::from("command")));
 					}
 					CommandToButtons::RingBell(period, counter) => {
 						self.ring_bell(period, counter);
 					}
 					CommandToButtons::SwitchLights(inside, outside, _text) => {
-						nextcloud_sender
-							.send(NextcloudEvent::Chat(
-								NextcloudChat::Licht,
-								gettext!("{}", self.switch_lights(inside, outside)),
-							))
-							.await?;
+						bus.send(Notification::info(
+							"Buttons",
+							NextcloudChat::Licht,
+							gettext!("{}", self.switch_lights_on_bus(&bus, inside, outside)),
+						));
 					}
 					CommandToButtons::RingBellAlarm(period) => {
 						self.ring_bell_alarm(period);
@@ -549,94 +618,88 @@ impl Buttons {
 			match self.handle() {
 				Ok(StateChange::Pressed(button)) => match button {
 					BUTTON_BELL => {
+						bus.send(Event::BellPressed);
 						let now = Local::now();
 						if now.hour() >= 7 && now.hour() <= 21 {
 							self.ring_bell(2, 5);
-							audio_sender.send(AudioEvent::Bell).await?;
-							nextcloud_sender
-								.send(NextcloudEvent::Chat(
-									NextcloudChat::Default,
-									gettext("🔔 Pressed button bell."),
-								))
-								.await?;
+							bus.send(AudioEvent::Bell);
+							bus.send(Notification::info(
+								"Buttons",
+								NextcloudChat::Default,
+								gettext("🔔 Pressed button bell."),
+							));
 						} else {
 							self.show_wrong_input();
-							nextcloud_sender
-								.send(NextcloudEvent::Chat(
-									NextcloudChat::Default,
-									gettext!(
-									"🔕 Did not ring bell (button was pressed) because the time 🌜 is {}, {}",
-									now.format(&time_format)
-								),
-								))
-								.await?;
+							bus.send(Notification::info(
+								"Buttons",
+								NextcloudChat::Default,
+								gettext!(
+								"🔕 Did not ring bell (button was pressed) because the time 🌜 is {}, {}",
+								now.format(&time_format)
+							),
+							));
 						}
 					}
 					TASTER_INNEN => {
-						nextcloud_sender
-							.send(NextcloudEvent::Chat(
-								NextcloudChat::Licht,
-								gettext!(
-									"💡 Pressed switch inside. {}.",
-									self.switch_lights(true, true)
-								),
-							))
-							.await?;
+						bus.send(Notification::info(
+							"Buttons",
+							NextcloudChat::Licht,
+							gettext!(
+								"💡 Pressed switch inside. {}.",
+								self.switch_lights_on_bus(&bus, true, true)
+							),
+						));
 					}
 					TASTER_AUSSEN => {
-						nextcloud_sender
-							.send(NextcloudEvent::Chat(
-								NextcloudChat::Licht,
-								gettext!(
-									"💡 Pressed switch outside or light button. {}.",
-									self.switch_lights(false, true),
-								),
-							))
-							.await?;
+						bus.send(Notification::info(
+							"Buttons",
+							NextcloudChat::Licht,
+							gettext!(
+								"💡 Pressed switch outside or light button. {}.",
+								self.switch_lights_on_bus(&bus, false, true),
+							),
+						));
 					}
 					TASTER_GLOCKE => {
+						bus.send(Event::BellPressed);
 						let now = Local::now();
 						if now.hour() >= 7 && now.hour() <= 21 {
 							self.ring_bell(5, 5);
-							nextcloud_sender
-								.send(NextcloudEvent::Chat(
-									NextcloudChat::Default,
-									gettext("🔔 Pressed switch bell."),
-								))
-								.await?;
+							bus.send(Notification::info(
+								"Buttons",
+								NextcloudChat::Default,
+								gettext("🔔 Pressed switch bell."),
+							));
 						} else {
 							self.show_wrong_input();
-							nextcloud_sender
-								.send(NextcloudEvent::Chat(
-									NextcloudChat::Default,
-									gettext!(
-									"🔕 Did not ring bell (taster outside) because the time 🌜 is {}, {}",
-									now.format(&time_format)
-								),
-								))
-								.await?;
+							bus.send(Notification::info(
+								"Buttons",
+								NextcloudChat::Default,
+								gettext!(
+								"🔕 Did not ring bell (taster outside) because the time 🌜 is {}, {}",
+								now.format(&time_format)
+							),
+							));
 						}
 					}
 					_ => panic!("🔘 Pressed {}", button),
 				},
 				Ok(StateChange::Released(_button)) => (),
 				Ok(StateChange::LightsOff) => {
-					nextcloud_sender
-						.send(NextcloudEvent::Chat(
-							NextcloudChat::Licht,
-							gettext("🕶️ Light was turned off."),
-						))
-						.await?;
+					bus.send(Event::LightsOff);
+					bus.send(Notification::info(
+						"Buttons",
+						NextcloudChat::Licht,
+						gettext("🕶️ Light was turned off."),
+					));
 				}
 				Ok(StateChange::None) => (),
 				Err(board) => {
 					let sys = System::new();
 					let loadavg = sys.load_average().unwrap();
 					//TODO implementierung von Ping Senden
-					nextcloud_sender
-						.send(NextcloudEvent::Chat(NextcloudChat::Ping, gettext!("⚠️ Error reading buttons of board {}. Load average: {} {} {}, Memory usage: {}, Swap: {}, CPU temp: {}", board, loadavg.one, loadavg.five, loadavg.fifteen, sys.memory().unwrap().total, sys.swap().unwrap().total, sys.cpu_temp().unwrap())))
-						.await?;
-					Buttons::do_reset(nextcloud_sender.clone(), &mut pwr).await?;
+					bus.send(Notification::warning("Buttons", NextcloudChat::Ping, gettext!("⚠️ Error reading buttons of board {}. Load average: {} {} {}, Memory usage: {}, Swap: {}, CPU temp: {}", board, loadavg.one, loadavg.five, loadavg.fifteen, sys.memory().unwrap().total, sys.swap().unwrap().total, sys.cpu_temp().unwrap())).category(Category::Hardware));
+					Buttons::do_reset(&bus, &mut pwr).await?;
 				}
 			}
 			// Validation start
@@ -644,12 +707,12 @@ impl Buttons {
 			match validator.validate(&mut self.sequence) {
 				Validation::Validated(user) => {
 					self.open_door();
-					nextcloud_sender
-						.send(NextcloudEvent::Chat(
-							NextcloudChat::Default,
-							gettext!("🤗 Opened for {}", user),
-						))
-						.await?;
+					bus.send(Event::DoorOpened(user.clone()));
+					bus.send(Notification::info(
+						"Buttons",
+						NextcloudChat::Default,
+						gettext!("🤗 Opened for {}", user),
+					));
 					let now = Local::now();
 					let (sunrise, sunset) = sunrise_sunset(
 						location_latitude,
@@ -659,50 +722,48 @@ impl Buttons {
 						now.day(),
 					);
 					if now.timestamp() < sunrise || now.timestamp() > sunset {
-						nextcloud_sender
-							.send(NextcloudEvent::Chat(
-								NextcloudChat::Licht,
-								gettext!(
-									"💡 Switch lights in and out. {}",
-									self.switch_lights(true, true)
-								),
-							))
-							.await?;
+						bus.send(Notification::info(
+							"Buttons",
+							NextcloudChat::Licht,
+							gettext!(
+								"💡 Switch lights in and out. {}",
+								self.switch_lights_on_bus(&bus, true, true)
+							),
+						));
 					} else {
-						nextcloud_sender
-							.send(NextcloudEvent::Chat(
-								NextcloudChat::Licht,
-								gettext!(
+						bus.send(Notification::info(
+							"Buttons",
+							NextcloudChat::Licht,
+							gettext!(
 								"🕶️ Don't switch lights as its day. Now: {} Sunrise: {} Sunset: {}",
 								now.timestamp(),
 								sunrise,
 								sunset
 							),
-							))
-							.await?;
+						));
 					}
 				}
 				Validation::Timeout => {
 					if sequence != vec![0, 15] {
+						bus.send(Event::PinFailed);
 						self.show_wrong_input();
 						self.ring_bell(20, 0);
-						nextcloud_sender
-							.send(NextcloudEvent::Chat(
-								NextcloudChat::Default,
-								gettext!("⌛ Timeout with sequence {}", format!("{:?}", sequence)),
-							))
-							.await?;
+						bus.send(Notification::warning(
+							"Buttons",
+							NextcloudChat::Default,
+							gettext!("⌛ Timeout with sequence {}", format!("{:?}", sequence)),
+						));
 					}
 				}
 				Validation::SequenceTooLong => {
+					bus.send(Event::PinFailed);
 					self.show_wrong_input();
 					self.ring_bell(20, 0);
-					nextcloud_sender
-						.send(NextcloudEvent::Chat(
-							NextcloudChat::Default,
-							gettext!("⌛ Sequence {} too long", format!("{:?}", sequence)),
-						))
-						.await?;
+					bus.send(Notification::warning(
+						"Buttons",
+						NextcloudChat::Default,
+						gettext!("⌛ Sequence {} too long", format!("{:?}", sequence)),
+					));
 				}
 				Validation::None => (),
 			}
@@ -710,6 +771,56 @@ impl Buttons {
 	}
 }
 
+pub struct ButtonsModule;
+
+impl Module for ButtonsModule {
+	fn name(&self) -> &'static str {
+		"Buttons"
+	}
+
+	fn enabled(&self, config: &mut Config) -> Result<bool, ConfigError> {
+		config.value::<bool>("buttons/enable")
+	}
+
+	fn hardware(&self) -> bool {
+		true
+	}
+
+	fn config_keys(&self) -> &'static [&'static str] {
+		&[
+			"buttons",
+			"bell",
+			"light",
+			"validator",
+			"pwr",
+			"location",
+			"nextcloud/format/time",
+		]
+	}
+
+	fn check_config(&self, config: &mut Config) -> Result<(), Vec<ConfigError>> {
+		config.check::<ButtonsConfig>("")
+	}
+
+	fn start(&self, context: Context) -> BoxFuture<'static, Result<Never, ModuleError>> {
+		async move {
+			let config: ButtonsConfig = context.config("").await?;
+			Buttons::get_background_task(
+				Buttons::new(&config),
+				Validator::new(&config.validator),
+				Pwr::new(config.pwr_enable),
+				config.time_format,
+				context.bus,
+				config.location.latitude,
+				config.location.longitude,
+				context.shutdown,
+			)
+			.await
+		}
+		.boxed()
+	}
+}
+
 impl Drop for Buttons {
 	fn drop(&mut self) {
 		let _ = self.turn_everything_off();
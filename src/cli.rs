@@ -0,0 +1,270 @@
+//! Command line arguments and environment variables, as specified with
+//! `opt`, `opt/long` and `env` in opensesame.spec.
+//! They are resolved to configuration keys, which take precedence over
+//! the configuration files, see `Config::apply_arguments`.
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+use crate::instance::InstanceSignal;
+
+pub struct CliOption {
+	/// configuration key the value is stored in
+	pub key: &'static str,
+	pub short: Option<char>,
+	pub long: &'static str,
+	pub env: &'static str,
+	/// takes no value, sets the key to 1
+	pub flag: bool,
+	pub help: &'static str,
+}
+
+/// Must match the keys with `opt/long` in opensesame.spec.
+pub const OPTIONS: &[CliOption] = &[
+	CliOption {
+		key: "cli/profile",
+		short: Some('p'),
+		long: "profile",
+		env: "OPENSESAME_PROFILE",
+		flag: false,
+		help: "<name> use the configuration and state of this profile (default: current)",
+	},
+	CliOption {
+		key: "cli/instance",
+		short: Some('i'),
+		long: "instance",
+		env: "OPENSESAME_INSTANCE",
+		flag: false,
+		help: "<number> use the configuration and state of this instance (default: 0)",
+	},
+	CliOption {
+		key: "cli/signal",
+		short: None,
+		long: "signal",
+		env: "",
+		flag: false,
+		help: "<hup|usr1|usr2|alrm|term|int> signal the running instance and exit",
+	},
+	CliOption {
+		key: "cli/only",
+		short: None,
+		long: "only",
+		env: "OPENSESAME_ONLY",
+		flag: false,
+		help: "<module,…> only start these modules",
+	},
+	CliOption {
+		key: "cli/dry-run",
+		short: Some('n'),
+		long: "dry-run",
+		env: "OPENSESAME_DRY_RUN",
+		flag: true,
+		help: "log Nextcloud messages instead of sending them",
+	},
+	CliOption {
+		key: "cli/check-config",
+		short: None,
+		long: "check-config",
+		env: "OPENSESAME_CHECK_CONFIG",
+		flag: true,
+		help: "check the configuration and exit",
+	},
+	CliOption {
+		key: "cli/simulate",
+		short: None,
+		long: "simulate",
+		env: "OPENSESAME_SIMULATE",
+		flag: true,
+		help: "do not start modules which need the hardware",
+	},
+	CliOption {
+		key: "log/level",
+		short: Some('l'),
+		long: "log-level",
+		env: "OPENSESAME_LOG_LEVEL",
+		flag: false,
+		help: "<error|warning|info|debug> what gets logged",
+	},
+	CliOption {
+		key: "cli/help",
+		short: Some('h'),
+		long: "help",
+		env: "",
+		flag: true,
+		help: "print this help and exit",
+	},
+];
+
+/// The options as deserialized from `cli`, after `Config::apply_arguments`.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct Options {
+	pub profile: String,
+	pub instance: u16,
+	pub signal: Option<InstanceSignal>,
+	/// comma-separated module names
+	pub only: Option<String>,
+	#[serde(rename = "dry-run")]
+	pub dry_run: bool,
+	#[serde(rename = "check-config")]
+	pub check_config: bool,
+	pub simulate: bool,
+	pub help: bool,
+}
+
+impl Options {
+	/// module names given with `--only`, lowercase
+	pub fn only(&self) -> Option<Vec<String>> {
+		self.only.as_ref().map(|only| {
+			only.split(',')
+				.map(|name| name.trim().to_lowercase())
+				.filter(|name| !name.is_empty())
+				.collect()
+		})
+	}
+}
+
+/// Resolves arguments (without the program name) and environment variables
+/// to configuration keys. Arguments take precedence over environment variables.
+pub fn parse(
+	args: impl IntoIterator<Item = String>,
+	env: impl Fn(&str) -> Option<String>,
+) -> Result<BTreeMap<String, String>, String> {
+	let mut values = BTreeMap::new();
+	for option in OPTIONS.iter().filter(|option| !option.env.is_empty()) {
+		if let Some(value) = env(option.env) {
+			values.insert(option.key.to_string(), value);
+		}
+	}
+	let mut args = args.into_iter();
+	while let Some(arg) = args.next() {
+		let (name, inline_value) = match arg.split_once('=') {
+			Some((name, value)) => (name, Some(value.to_string())),
+			None => (arg.as_str(), None),
+		};
+		let option = OPTIONS
+			.iter()
+			.find(|option| {
+				name.strip_prefix("--") == Some(option.long)
+					|| (option.short.is_some()
+						&& name.strip_prefix('-').and_then(|short| short.parse().ok())
+							== option.short)
+			})
+			.ok_or_else(|| format!("unknown argument '{}'", arg))?;
+		let value = match (option.flag, inline_value) {
+			(true, None) => String::from("1"),
+			(true, Some(_)) => return Err(format!("'{}' takes no value", name)),
+			(false, Some(value)) => value,
+			(false, None) => args
+				.next()
+				.ok_or_else(|| format!("'{}' needs a value", name))?,
+		};
+		values.insert(option.key.to_string(), value);
+	}
+	Ok(values)
+}
+
+pub fn usage() -> String {
+	let mut usage = String::from("Usage: opensesame [OPTIONS]\n\nOptions:\n");
+	for option in OPTIONS {
+		let short = option
+			.short
+			.map(|short| format!("-{}, ", short))
+			.unwrap_or_default();
+		usage += &format!("  {}--{} {}\n", short, option.long, option.help);
+		if !option.env.is_empty() {
+			usage += &format!("      (env: {})\n", option.env);
+		}
+	}
+	usage
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn args(args: &[&str]) -> Vec<String> {
+		args.iter().map(|arg| arg.to_string()).collect()
+	}
+
+	#[test]
+	fn test_parse() {
+		let values = parse(
+			args(&["-p", "garage", "--only=buttons,garage", "--dry-run"]),
+			|name| {
+				(name == "OPENSESAME_PROFILE" || name == "OPENSESAME_LOG_LEVEL")
+					.then(|| String::from("env"))
+			},
+		)
+		.unwrap();
+		assert_eq!(
+			values,
+			BTreeMap::from([
+				(String::from("cli/dry-run"), String::from("1")),
+				(String::from("cli/only"), String::from("buttons,garage")),
+				(String::from("cli/profile"), String::from("garage")),
+				(String::from("log/level"), String::from("env")),
+			])
+		);
+		assert!(parse(args(&["--unknown"]), |_| None).is_err());
+		assert!(parse(args(&["--profile"]), |_| None).is_err());
+		assert!(parse(args(&["--simulate=0"]), |_| None).is_err());
+	}
+
+	#[test]
+	fn test_only() {
+		let options = Options {
+			only: Some(String::from("Buttons, garage,")),
+			..Options::default()
+		};
+		assert_eq!(
+			options.only(),
+			Some(vec![String::from("buttons"), String::from("garage")])
+		);
+	}
+
+	/// every option must be specified in opensesame.spec with the same `opt`, `opt/long` and `env`
+	#[test]
+	fn test_options_match_spec() {
+		let spec = include_str!("../files/opensesame.spec");
+		let mut sections = BTreeMap::<&str, BTreeMap<&str, &str>>::new();
+		let mut section = "";
+		for line in spec.lines() {
+			if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+				section = name;
+			} else if let Some((meta, value)) = line.split_once('=') {
+				sections
+					.entry(section)
+					.or_default()
+					.insert(meta.trim(), value.trim());
+			}
+		}
+		for option in OPTIONS {
+			let meta = &sections[option.key];
+			assert_eq!(meta.get("opt/long"), Some(&option.long), "{}", option.key);
+			assert_eq!(
+				meta.get("opt").copied(),
+				option.short.map(|short| short.to_string()).as_deref(),
+				"{}",
+				option.key
+			);
+			assert_eq!(
+				meta.get("env").copied().unwrap_or_default(),
+				option.env,
+				"{}",
+				option.key
+			);
+			assert_eq!(
+				meta.get("opt/arg") == Some(&"none"),
+				option.flag,
+				"{}",
+				option.key
+			);
+		}
+		let specified = sections
+			.values()
+			.filter(|meta| meta.contains_key("opt/long"))
+			.count();
+		assert_eq!(specified, OPTIONS.len());
+	}
+}
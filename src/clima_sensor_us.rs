@@ -1,17 +1,20 @@
 extern crate libmodbus;
 
-use crate::config::Config;
-use crate::nextcloud::{NextcloudChat, NextcloudEvent};
+use crate::bus::EventBus;
+use crate::config::{validate::Validate, Config, ConfigError};
+use crate::module::{Context, Module};
+use crate::nextcloud::NextcloudChat;
+use crate::notify::{Category, Notification};
+use crate::readings::Reading;
 use crate::types::ModuleError;
-use futures::never::Never;
+use futures::{future::BoxFuture, never::Never, FutureExt};
 use gettextrs::gettext;
 use libmodbus::{Modbus, ModbusClient, ModbusRTU, RequestToSendMode, SerialMode};
 use reqwest::header::HeaderMap;
 use reqwest::Client;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::io;
 use systemstat::Duration;
-use tokio::sync::mpsc::Sender;
 use tokio::time::interval;
 
 ///Constants
@@ -63,88 +66,295 @@ const REG_GLOBAL_RADIATION: u16 = 0x8913;
 const REG_PITCH_MAGNETIC_COMPASS_NS: u16 = 0x8915;
 const REG_ROLL_MAGNETIC_COMPASS_EW: u16 = 0x8917;
 
-//Elements of tuple (opensensemap-id, reg-address, factor, datatype(signed or unsigned))
-const OPENSENSE_CLIMA_DATA: [(&str, u16, f32, char); 36] = [
-	("64cb602193c69500072a5813", REG_MEAN_WIND_SPEED, 10.0, 'u'),
-	("64cb7c21d588b90007d69a5f", REG_MEAN_WIND_DIREC, 10.0, 'u'),
-	("64cb7c21d588b90007d69a60", REG_AIR_TEMP, 10.0, 's'),
-	("64cb7c21d588b90007d69a61", REG_FRAME_TEMP, 10.0, 's'),
-	("64cb7c21d588b90007d69a62", REG_ACOUSTIC_TEMP, 10.0, 's'),
+//Elements of tuple (opensensemap-id, reg-address, factor, datatype(signed or unsigned), reading name, unit)
+const OPENSENSE_CLIMA_DATA: [(&str, u16, f32, char, &str, &str); 36] = [
+	(
+		"64cb602193c69500072a5813",
+		REG_MEAN_WIND_SPEED,
+		10.0,
+		'u',
+		"mean_wind_speed",
+		"m/s",
+	),
+	(
+		"64cb7c21d588b90007d69a5f",
+		REG_MEAN_WIND_DIREC,
+		10.0,
+		'u',
+		"mean_wind_direc",
+		"°",
+	),
+	(
+		"64cb7c21d588b90007d69a60",
+		REG_AIR_TEMP,
+		10.0,
+		's',
+		"air_temp",
+		"°C",
+	),
+	(
+		"64cb7c21d588b90007d69a61",
+		REG_FRAME_TEMP,
+		10.0,
+		's',
+		"frame_temp",
+		"°C",
+	),
+	(
+		"64cb7c21d588b90007d69a62",
+		REG_ACOUSTIC_TEMP,
+		10.0,
+		's',
+		"acoustic_temp",
+		"°C",
+	),
 	(
 		"64cb7c21d588b90007d69a63",
 		REG_AIR_TEMP_UNCORRECTED,
 		10.0,
 		's',
+		"air_temp_uncorrected",
+		"°C",
+	),
+	(
+		"64cb7c21d588b90007d69a64",
+		REG_REL_HUMIDITY,
+		10.0,
+		'u',
+		"rel_humidity",
+		"%",
+	),
+	(
+		"64cb7c21d588b90007d69a65",
+		REG_DEW_POINT_TEMP,
+		10.0,
+		's',
+		"dew_point_temp",
+		"°C",
+	),
+	(
+		"64cb7c21d588b90007d69a66",
+		REG_ABS_AIR_PRESSURE,
+		100.0,
+		'u',
+		"abs_air_pressure",
+		"hPa",
+	),
+	(
+		"64cb7c21d588b90007d69a67",
+		REG_REL_AIR_PRESSURE,
+		100.0,
+		'u',
+		"rel_air_pressure",
+		"hPa",
+	),
+	(
+		"64cb7c21d588b90007d69a68",
+		REG_BRIGHTNESS_N,
+		10.0,
+		'u',
+		"brightness_n",
+		"kLux",
+	),
+	(
+		"64cb7c21d588b90007d69a69",
+		REG_BRIGHTNESS_E,
+		10.0,
+		'u',
+		"brightness_e",
+		"kLux",
+	),
+	(
+		"64cb7c21d588b90007d69a6a",
+		REG_BRIGHTNESS_S,
+		10.0,
+		'u',
+		"brightness_s",
+		"kLux",
+	),
+	(
+		"64cb7c21d588b90007d69a6b",
+		REG_BRIGHTNESS_W,
+		10.0,
+		'u',
+		"brightness_w",
+		"kLux",
+	),
+	(
+		"64cb7cfdd588b90007d702d6",
+		REG_DIREC_BRIGHTNESS,
+		1.0,
+		'u',
+		"direc_brightness",
+		"°",
+	),
+	(
+		"64cb7cfdd588b90007d702d7",
+		REG_BRIGHTNESS_MAX,
+		10.0,
+		'u',
+		"brightness_max",
+		"kLux",
 	),
-	("64cb7c21d588b90007d69a64", REG_REL_HUMIDITY, 10.0, 'u'),
-	("64cb7c21d588b90007d69a65", REG_DEW_POINT_TEMP, 10.0, 's'),
-	("64cb7c21d588b90007d69a66", REG_ABS_AIR_PRESSURE, 100.0, 'u'),
-	("64cb7c21d588b90007d69a67", REG_REL_AIR_PRESSURE, 100.0, 'u'),
-	("64cb7c21d588b90007d69a68", REG_BRIGHTNESS_N, 10.0, 'u'),
-	("64cb7c21d588b90007d69a69", REG_BRIGHTNESS_E, 10.0, 'u'),
-	("64cb7c21d588b90007d69a6a", REG_BRIGHTNESS_S, 10.0, 'u'),
-	("64cb7c21d588b90007d69a6b", REG_BRIGHTNESS_W, 10.0, 'u'),
-	("64cb7cfdd588b90007d702d6", REG_DIREC_BRIGHTNESS, 1.0, 'u'),
-	("64cb7cfdd588b90007d702d7", REG_BRIGHTNESS_MAX, 10.0, 'u'),
 	(
 		"64cb7cfdd588b90007d702d8",
 		REG_PRECIPITATION_EVENT,
 		1.0,
 		'u',
+		"precipitation_event",
+		"",
 	),
 	(
 		"64cb7cfdd588b90007d702d9",
 		REG_PRECIPITATION_INTENSITY,
 		1000.0,
 		'u',
+		"precipitation_intensity",
+		"mm/h",
 	),
 	(
 		"64cb7cfdd588b90007d702da",
 		REG_PRECIPITATION_AMOUNT,
 		1000.0,
 		'u',
+		"precipitation_amount",
+		"mm",
+	),
+	(
+		"64cb7cfdd588b90007d702db",
+		REG_PRECIPITATION_TYPE,
+		1.0,
+		'u',
+		"precipitation_type",
+		"",
+	),
+	(
+		"64cb7d79d588b90007d7402e",
+		REG_SUN_ELEVATION,
+		10.0,
+		's',
+		"sun_elevation",
+		"°",
+	),
+	(
+		"64cb7d79d588b90007d7402f",
+		REG_SUN_AZIMUTH,
+		10.0,
+		's',
+		"sun_azimuth",
+		"°",
+	),
+	(
+		"64cb7d79d588b90007d74030",
+		REG_HEIGHT_ABOVE_SEA,
+		1.0,
+		's',
+		"height_above_sea",
+		"m",
+	),
+	(
+		"64cb7d79d588b90007d74031",
+		REG_SENSOR_SUPPLY,
+		10.0,
+		'u',
+		"sensor_supply",
+		"V",
+	),
+	(
+		"64cb7dfbd588b90007d782fc",
+		REG_MAX_WIND_SPEED,
+		10.0,
+		'u',
+		"max_wind_speed",
+		"m/s",
+	),
+	(
+		"64cb7dfbd588b90007d782fd",
+		REG_WIND_DIREC,
+		10.0,
+		'u',
+		"wind_direc",
+		"°",
+	),
+	(
+		"64cb7dfbd588b90007d782fe",
+		REG_ABS_HUMIDITY,
+		100.0,
+		'u',
+		"abs_humidity",
+		"g/m³",
 	),
-	("64cb7cfdd588b90007d702db", REG_PRECIPITATION_TYPE, 1.0, 'u'),
-	("64cb7d79d588b90007d7402e", REG_SUN_ELEVATION, 10.0, 's'),
-	("64cb7d79d588b90007d7402f", REG_SUN_AZIMUTH, 10.0, 's'),
-	("64cb7d79d588b90007d74030", REG_HEIGHT_ABOVE_SEA, 1.0, 's'),
-	("64cb7d79d588b90007d74031", REG_SENSOR_SUPPLY, 10.0, 'u'),
-	("64cb7dfbd588b90007d782fc", REG_MAX_WIND_SPEED, 10.0, 'u'),
-	("64cb7dfbd588b90007d782fd", REG_WIND_DIREC, 10.0, 'u'),
-	("64cb7dfbd588b90007d782fe", REG_ABS_HUMIDITY, 100.0, 'u'),
 	(
 		"64cb7dfbd588b90007d782ff",
 		REG_REL_HUMIDITY_UNCORRECTED,
 		10.0,
 		'u',
+		"rel_humidity_uncorrected",
+		"%",
 	),
 	(
 		"64cb7eb2d588b90007d7dd96",
 		REG_MAGNETIC_COMPASS_DIFF_ANGLE,
 		10.0,
 		'u',
+		"magnetic_compass_diff_angle",
+		"°",
+	),
+	(
+		"64cb7eb2d588b90007d7dd97",
+		REG_BRIGHTNESS_VEC_SUM,
+		1.0,
+		'u',
+		"brightness_vec_sum",
+		"kLux",
+	),
+	(
+		"64cb7eb2d588b90007d7dd98",
+		REG_WINDCHILL_TEMP,
+		10.0,
+		's',
+		"windchill_temp",
+		"°C",
+	),
+	(
+		"64cb7eb2d588b90007d7dd99",
+		REG_HEAT_INDEX_TEMP,
+		10.0,
+		's',
+		"heat_index_temp",
+		"°C",
 	),
-	("64cb7eb2d588b90007d7dd97", REG_BRIGHTNESS_VEC_SUM, 1.0, 'u'),
-	("64cb7eb2d588b90007d7dd98", REG_WINDCHILL_TEMP, 10.0, 's'),
-	("64cb7eb2d588b90007d7dd99", REG_HEAT_INDEX_TEMP, 10.0, 's'),
 	(
 		"64cb7eb2d588b90007d7dd9a",
 		REG_ABS_PRECIPITATION_AMOUNT,
 		1000.0,
 		'u',
+		"abs_precipitation_amount",
+		"mm",
+	),
+	(
+		"64cb7eb2d588b90007d7dd9b",
+		REG_GLOBAL_RADIATION,
+		10.0,
+		's',
+		"global_radiation",
+		"W/m²",
 	),
-	("64cb7eb2d588b90007d7dd9b", REG_GLOBAL_RADIATION, 10.0, 's'),
 	(
 		"64cb7eb2d588b90007d7dd9c",
 		REG_PITCH_MAGNETIC_COMPASS_NS,
 		10.0,
 		's',
+		"pitch_magnetic_compass_ns",
+		"°",
 	),
 	(
 		"64cb7eb2d588b90007d7dd9d",
 		REG_ROLL_MAGNETIC_COMPASS_EW,
 		10.0,
 		's',
+		"roll_magnetic_compass_ew",
+		"°",
 	),
 ];
 
@@ -193,10 +403,32 @@ pub struct ClimaSensorUS {
 struct SensorValue {
 	sensor: &'static str,
 	value: f32,
+	#[serde(skip)]
+	name: &'static str,
+	#[serde(skip)]
+	unit: &'static str,
 }
 
 unsafe impl Send for ClimaSensorUS {}
 
+#[derive(Deserialize)]
+pub struct WeatherstationConfig {
+	pub opensensemap: OpensensemapConfig,
+}
+
+impl Validate for WeatherstationConfig {
+	fn validate(&self) -> Vec<ConfigError> {
+		Vec::new()
+	}
+}
+
+/// see doc/Opensensemap.md
+#[derive(Deserialize)]
+pub struct OpensensemapConfig {
+	pub id: String,
+	pub token: String,
+}
+
 impl ClimaSensorUS {
 	// Temperature
 	pub const LOW_CANCEL_TEMP: f32 = 5.0;
@@ -209,9 +441,9 @@ impl ClimaSensorUS {
 	pub const STRONG_WIND_SPEED: f32 = 10.8;
 	pub const OK_WIND_SPEED: f32 = 8.0;
 
-	pub fn new(config: &mut Config) -> Result<Self, libmodbus::Error> {
-		let opensensebox_id = config.get::<String>("weatherstation/opensensemap/id");
-		let opensense_access_token = config.get::<String>("weatherstation/opensensemap/token");
+	pub fn new(config: WeatherstationConfig) -> Result<Self, libmodbus::Error> {
+		let opensensebox_id = config.opensensemap.id;
+		let opensense_access_token = config.opensensemap.token;
 		let warning_active = Warning::None;
 
 		let client = Client::new();
@@ -243,7 +475,7 @@ impl ClimaSensorUS {
 	/// (Input Register - 0x04) wind-reg address 0x7533; typ U32; real_result = response_wind/10
 	/// The return value is bool on success, true if alarm is active and false is alarm is not active
 	/// If no ctx is configured the this function returns always false, so no warning is triggered
-	async fn handle(&mut self) -> Result<Option<String>, libmodbus::Error> {
+	async fn handle(&mut self, bus: &EventBus) -> Result<Option<String>, libmodbus::Error> {
 		let mut response_temp = vec![0u16; 2];
 		let mut response_wind = vec![0u16; 2];
 		let temp: f32;
@@ -272,8 +504,18 @@ impl ClimaSensorUS {
 			}
 		}
 
+		let sensor_values = self.collect_sensor_values().await;
+		for sensor_value in &sensor_values {
+			bus.send(Reading::new(
+				"weatherstation",
+				sensor_value.name,
+				sensor_value.value.into(),
+				sensor_value.unit,
+			));
+		}
+
 		//check if new data should be published to opensensemap.org
-		match self.publish_to_opensensemap().await {
+		match self.publish_to_opensensemap(&sensor_values).await {
 			Ok(_) => {}
 			// TODO
 			Err(error) => return Err(libmodbus::Error::IoError(error)),
@@ -404,6 +646,8 @@ impl ClimaSensorUS {
 					sensor_values.push(SensorValue {
 						sensor: tuple_data.0,
 						value,
+						name: tuple_data.4,
+						unit: tuple_data.5,
 					});
 				}
 			}
@@ -416,8 +660,11 @@ impl ClimaSensorUS {
 	/// All information needed are stored in a const array of tuples. The tuples contain the opensensemap-sensor-id, register-address, factor and datatype.
 	/// The return value indicates if the api request was successfully or not.
 	/// Information about the reading of registers can be accessed through the json_payload  
-	async fn publish_to_opensensemap(&mut self) -> Result<(), io::Error> {
-		let json = serde_json::to_string(&self.collect_sensor_values().await).unwrap();
+	async fn publish_to_opensensemap(
+		&self,
+		sensor_values: &[SensorValue],
+	) -> Result<(), io::Error> {
+		let json = serde_json::to_string(sensor_values).unwrap();
 
 		//Send JSON to https://api.opensensemap.org
 		let result = self
@@ -445,26 +692,26 @@ impl ClimaSensorUS {
 		}
 	}
 
-	pub async fn get_background_task(
-		mut self,
-		nextcloud_sender: Sender<NextcloudEvent>,
-	) -> Result<Never, ModuleError> {
+	pub async fn get_background_task(mut self, bus: EventBus) -> Result<Never, ModuleError> {
 		let mut interval = interval(Duration::from_secs(60));
 		loop {
-			match self.handle().await {
+			match self.handle(&bus).await {
 				Ok(Some(message)) => {
-					nextcloud_sender
-						.send(NextcloudEvent::Chat(NextcloudChat::Default, message))
-						.await?;
+					bus.send(
+						Notification::info("Weatherstation", NextcloudChat::Default, message)
+							.category(Category::Weather),
+					);
 				}
 				Ok(None) => (),
 				Err(error) => {
-					nextcloud_sender
-						.send(NextcloudEvent::Chat(
+					bus.send(
+						Notification::warning(
+							"Weatherstation",
 							NextcloudChat::Ping,
 							gettext!("⚠️ Error from weather station: {}", error),
-						))
-						.await?;
+						)
+						.category(Category::Weather),
+					);
 				}
 			};
 			interval.tick().await;
@@ -472,6 +719,44 @@ impl ClimaSensorUS {
 	}
 }
 
+pub struct ClimaSensorUSModule;
+
+impl Module for ClimaSensorUSModule {
+	fn name(&self) -> &'static str {
+		"Weatherstation"
+	}
+
+	fn enabled(&self, config: &mut Config) -> Result<bool, ConfigError> {
+		config.value::<bool>("weatherstation/enable")
+	}
+
+	fn hardware(&self) -> bool {
+		true
+	}
+
+	fn config_keys(&self) -> &'static [&'static str] {
+		&["weatherstation"]
+	}
+
+	fn check_config(&self, config: &mut Config) -> Result<(), Vec<ConfigError>> {
+		config.check::<WeatherstationConfig>("weatherstation")
+	}
+
+	fn start(&self, context: Context) -> BoxFuture<'static, Result<Never, ModuleError>> {
+		async move {
+			let config: WeatherstationConfig = context.config("weatherstation").await?;
+			let clima_sensor = ClimaSensorUS::new(config).map_err(|error| {
+				ModuleError::new(gettext!(
+					"⚠️ Failed to init libmodbus connection: {}",
+					error
+				))
+			})?;
+			ClimaSensorUS::get_background_task(clima_sensor, context.bus).await
+		}
+		.boxed()
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
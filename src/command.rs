@@ -0,0 +1,243 @@
+//! Commands of the Nextcloud chat (`\<command>`) and of `opensesamectl`,
+//! both map to the same events on the bus.
+
+use nix::sys::signal::{raise, Signal};
+
+use crate::{
+	audio::AudioEvent,
+	bus::{Event, EventBus},
+	buttons::CommandToButtons,
+	environment::{BaselineCommand, EnvEvent},
+	history::{parse_range, History},
+	module::Registry,
+	nextcloud::{NextcloudChat, NextcloudEvent},
+	notify::Notification,
+	readings::unix_time,
+	state::StateStore,
+};
+
+pub const USAGE: &str = "Commands:
+status
+opensesame (or open-door)
+switchlights <in> <out> (or lights <in> <out>), with on|off
+ring_bell (or ring)
+fire_alarm
+alarm raise [reason]
+alarm clear
+reload
+module restart <module>
+baseline [save|restore]
+history <reading> [<range>] [csv], e.g. history co2 24h";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+	Status,
+	OpenDoor,
+	SwitchLights {
+		inside: bool,
+		outside: bool,
+	},
+	Ring,
+	FireAlarm,
+	/// sets `alarm/fire`, as the fire alarm sensors do
+	RaiseAlarm(String),
+	/// removes `alarm/fire`
+	ClearAlarm,
+	/// like `reload-opensesame`
+	Reload,
+	RestartModule(&'static str),
+	Baseline(BaselineCommand),
+	/// summary or CSV of the readings of the last `range` seconds
+	History {
+		reading: String,
+		range: u64,
+		csv: bool,
+	},
+}
+
+pub const DEFAULT_HISTORY_RANGE: u64 = 24 * 60 * 60;
+
+/// on/off for `switchlights`, true/false as the chat always accepted
+pub fn switch(word: &str) -> Option<bool> {
+	match word.to_lowercase().as_str() {
+		"on" | "true" | "1" => Some(true),
+		"off" | "false" | "0" => Some(false),
+		_ => None,
+	}
+}
+
+impl Command {
+	/// The words of a chat message without the leading `\`, or of the `opensesamectl` arguments.
+	pub fn parse(words: &[&str]) -> Result<Self, String> {
+		let (command, args) = match words.split_first() {
+			Some((command, args)) => (*command, args),
+			None => return Err(String::from(USAGE)),
+		};
+		let usage = |usage: &str| Err(format!("Usage: {}", usage));
+		match command {
+			"status" => Ok(Command::Status),
+			"opensesame" | "open-door" => Ok(Command::OpenDoor),
+			"switchlights" | "lights" => match args {
+				[inside, outside] => match (switch(inside), switch(outside)) {
+					(Some(inside), Some(outside)) => Ok(Command::SwitchLights { inside, outside }),
+					_ => usage("switchlights on|off on|off"),
+				},
+				_ => usage("switchlights on|off on|off"),
+			},
+			"ring_bell" | "ring" => Ok(Command::Ring),
+			"fire_alarm" => Ok(Command::FireAlarm),
+			"alarm" => match args {
+				["raise"] => Ok(Command::RaiseAlarm(String::from("command"))),
+				["raise", reason @ ..] => Ok(Command::RaiseAlarm(reason.join(" "))),
+				["clear"] => Ok(Command::ClearAlarm),
+				_ => usage("alarm raise [reason] | alarm clear"),
+			},
+			"reload" => Ok(Command::Reload),
+			"module" => match args {
+				["restart", name] => Registry::new().find(name).map(Command::RestartModule),
+				_ => usage("module restart <module>"),
+			},
+			"baseline" => match args {
+				[] => Ok(Command::Baseline(BaselineCommand::Show)),
+				["save"] => Ok(Command::Baseline(BaselineCommand::Save)),
+				["restore"] => Ok(Command::Baseline(BaselineCommand::Restore)),
+				_ => usage("baseline [save|restore]"),
+			},
+			"history" => {
+				let usage = || {
+					usage("history <reading> [<range>] [csv], with a range like 30m, 24h, 7d or 1y")
+				};
+				let (reading, args) = match args.split_first() {
+					Some((reading, args)) => (reading.to_string(), args),
+					None => return usage(),
+				};
+				let (csv, args) = match args.split_last() {
+					Some((&"csv", args)) => (true, args),
+					_ => (false, args),
+				};
+				let range = match args {
+					[] => DEFAULT_HISTORY_RANGE,
+					[range] => match parse_range(range) {
+						Some(range) => range,
+						None => return usage(),
+					},
+					_ => return usage(),
+				};
+				Ok(Command::History {
+					reading,
+					range,
+					csv,
+				})
+			}
+			_ => Err(format!("Unknown command {}!\n{}", command, USAGE)),
+		}
+	}
+
+	/// Publishes the command on the bus, answers to `status` go to the chat.
+	/// Returns the output of commands answered directly, e.g. `history`.
+	pub async fn execute(
+		self,
+		bus: &EventBus,
+		state: &StateStore,
+		history: &History,
+	) -> Result<String, String> {
+		match self {
+			Command::Status => bus.send(NextcloudEvent::SendStatus),
+			Command::OpenDoor => {
+				bus.send(Notification::info(
+					"Buttons",
+					NextcloudChat::Default,
+					String::from("Opening door"),
+				));
+				bus.send(CommandToButtons::OpenDoor);
+			}
+			Command::SwitchLights { inside, outside } => {
+				bus.send(CommandToButtons::SwitchLights(
+					inside,
+					outside,
+					String::from("Switch lights {} {}"),
+				));
+			}
+			Command::Ring => bus.send(AudioEvent::Bell),
+			Command::FireAlarm => bus.send(AudioEvent::FireAlarm),
+			// Signals picks up the change via reload-opensesame
+			Command::RaiseAlarm(reason) => state
+				.set_now("alarm/fire", &reason)
+				.await
+				.map_err(|error| error.to_string())?,
+			Command::ClearAlarm => state
+				.remove_now("alarm/fire")
+				.await
+				.map_err(|error| error.to_string())?,
+			Command::Reload => raise(Signal::SIGHUP).map_err(|error| error.to_string())?,
+			Command::RestartModule(name) => bus.send(Event::RestartModule(name)),
+			Command::Baseline(command) => bus.send(EnvEvent::Baseline(command)),
+			Command::History {
+				reading,
+				range,
+				csv: false,
+			} => return history.summary(&reading, range, unix_time()),
+			Command::History {
+				reading,
+				range,
+				csv: true,
+			} => return history.csv(&reading, range, unix_time()),
+		}
+		Ok(String::new())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse() {
+		assert_eq!(Command::parse(&["opensesame"]), Ok(Command::OpenDoor));
+		assert_eq!(Command::parse(&["open-door"]), Ok(Command::OpenDoor));
+		assert_eq!(
+			Command::parse(&["switchlights", "true", "false"]),
+			Ok(Command::SwitchLights {
+				inside: true,
+				outside: false
+			})
+		);
+		assert_eq!(
+			Command::parse(&["lights", "off", "on"]),
+			Ok(Command::SwitchLights {
+				inside: false,
+				outside: true
+			})
+		);
+		assert!(Command::parse(&["lights", "on"]).is_err());
+		assert_eq!(
+			Command::parse(&["alarm", "raise", "smoke", "kitchen"]),
+			Ok(Command::RaiseAlarm(String::from("smoke kitchen")))
+		);
+		assert_eq!(Command::parse(&["alarm", "clear"]), Ok(Command::ClearAlarm));
+		assert_eq!(
+			Command::parse(&["module", "restart", "env"]),
+			Ok(Command::RestartModule("Environment"))
+		);
+		assert!(Command::parse(&["module", "restart", "unknown"]).is_err());
+		assert_eq!(
+			Command::parse(&["history", "co2"]),
+			Ok(Command::History {
+				reading: String::from("co2"),
+				range: 24 * 60 * 60,
+				csv: false
+			})
+		);
+		assert_eq!(
+			Command::parse(&["history", "environment/co2", "7d", "csv"]),
+			Ok(Command::History {
+				reading: String::from("environment/co2"),
+				range: 7 * 24 * 60 * 60,
+				csv: true
+			})
+		);
+		assert!(Command::parse(&["history", "co2", "soon"]).is_err());
+		assert!(Command::parse(&["setpin"]).is_err());
+		assert!(Command::parse(&[]).is_err());
+	}
+}
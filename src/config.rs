@@ -1,39 +1,77 @@
 extern crate elektra;
 
+mod de;
+mod handle;
+pub mod validate;
+
 use elektra::{CopyOption, KeySet, LookupOption, ReadableKey, StringKey, WriteableKey, KDB};
-use std::collections::HashMap;
-use std::str::FromStr;
+use serde::de::DeserializeOwned;
+use std::collections::{BTreeMap, BTreeSet};
+
+pub use de::ConfigError;
+use de::{from_node, Node};
+pub use handle::ConfigHandle;
+use validate::Validate;
 
 pub struct Config<'a> {
 	kdb: KDB,
 	parent_key: StringKey<'a>,
 	ks: KeySet,
+	/// applied again on `reopen`
+	arguments: BTreeMap<String, String>,
 }
 
 unsafe impl<'a> Send for Config<'a> {}
 
 impl Config<'_> {
+	/// Panics if the configuration cannot be read, see `open`.
 	pub fn new(parent: &str) -> Self {
+		Self::open(parent).unwrap_or_else(|error| panic!("Could not read configuration {}", error))
+	}
+
+	pub fn open(parent: &str) -> Result<Self, ConfigError> {
 		let mut s = Self {
-			kdb: KDB::open(KeySet::with_capacity(0)).unwrap(),
+			kdb: KDB::open(KeySet::with_capacity(0)).map_err(|error| ConfigError {
+				key: parent.to_string(),
+				message: error.to_error_message(),
+			})?,
 			parent_key: StringKey::new(parent).unwrap(),
 			ks: KeySet::with_capacity(100),
+			arguments: BTreeMap::new(),
 		};
-		s.sync();
-		s
+		s.sync()?;
+		Ok(s)
+	}
+
+	/// Reads everything again into a fresh instance, so that removed keys are gone, too.
+	/// Blocks while Elektra reads, from async code use `ConfigHandle::reopen`.
+	pub fn reopen(&mut self) -> Result<(), ConfigError> {
+		let mut fresh = Self::open(&self.parent())?;
+		fresh.apply_arguments(&self.arguments);
+		*self = fresh;
+		Ok(())
+	}
+
+	/// Blocks while Elektra reads, from async code use `ConfigHandle::sync`.
+	pub fn sync(&mut self) -> Result<(), ConfigError> {
+		self.kdb
+			.get(&mut self.ks, &mut self.parent_key)
+			.map(|_| ())
+			.map_err(|kdb_error| self.kdb_error(kdb_error.to_error_message()))
 	}
 
-	pub fn sync(&mut self) {
-		let res = self.kdb.get(&mut self.ks, &mut self.parent_key);
-		match res {
-			Ok(_success) => (),
-			Err(kdb_error) => {
-				panic!("Set config failed: {}", kdb_error.to_error_message());
-			}
+	pub fn parent(&self) -> String {
+		self.parent_key.name().to_string()
+	}
+
+	fn kdb_error(&self, message: String) -> ConfigError {
+		ConfigError {
+			key: self.parent(),
+			message,
 		}
 	}
 
-	#[cfg(test)]
+	/// Removes the key and everything below it, without writing.
 	pub fn cut(&mut self, name: &str) {
 		let cut_key =
 			StringKey::new(&format!("user:/{}/{}", self.parent_key.name(), name)).unwrap();
@@ -47,78 +85,125 @@ impl Config<'_> {
 		self.ks.append_key(new_key);
 	}
 
-	pub fn set(&mut self, name: &str, value: &str) {
-		self.add(name, value);
-		let res = self.kdb.set(&mut self.ks, &mut self.parent_key);
-		match res {
-			Ok(_success) => (),
-			Err(kdb_error) => {
-				panic!("Set config failed: {}", kdb_error.to_error_message());
-			}
+	/// Values from the command line and environment (see `cli::parse`),
+	/// in the proc namespace so that they take precedence and never get written.
+	pub fn apply_arguments(&mut self, arguments: &BTreeMap<String, String>) {
+		for (name, value) in arguments {
+			let mut new_key =
+				StringKey::new(&format!("proc:/{}/{}", self.parent_key.name(), name)).unwrap();
+			new_key.set_value(value);
+			self.ks.append_key(new_key);
 		}
+		self.arguments.extend(arguments.clone());
 	}
 
-	pub fn get_hash_map_vec_u8(&mut self, name: &str) -> HashMap<Vec<u8>, String> {
-		let mut lookup_key = self.parent_key.duplicate(CopyOption::KEY_CP_NAME);
-		lookup_key.add_name(name).unwrap_or_else(|_| {
-			panic!("Could not add '{}' to '{}'!", name, self.parent_key.name())
-		});
-		let mut ret = HashMap::new();
-		for (_i, key) in self.ks.iter_mut().enumerate() {
-			if key.is_directly_below(&lookup_key) {
-				ret.insert(
-					key.value()
-						.replace(&['(', ')', ',', '[', ']'][..], "")
-						.split_whitespace()
-						.map(|s| s.parse().unwrap())
-						.collect(),
-					key.basename().to_string(),
-				);
-			}
-		}
-		ret
+	/// Blocks while Elektra writes, from async code use `ConfigHandle::set`.
+	pub fn set(&mut self, name: &str, value: &str) -> Result<(), ConfigError> {
+		self.add(name, value);
+		self.write()
 	}
 
-	pub fn get_bool(&mut self, name: &str) -> bool {
-		let mut lookup_key = self.parent_key.duplicate(CopyOption::KEY_CP_NAME);
-		lookup_key.add_name(name).unwrap_or_else(|_| {
-			panic!("Could not add '{}' to '{}'!", name, self.parent_key.name())
-		});
-		if let Some(found_key) = self.ks.lookup(lookup_key, LookupOption::KDB_O_NONE) {
-			return found_key.value() == "1";
+	/// Like `set`, but writes all keys at once.
+	pub fn set_all(&mut self, values: &BTreeMap<String, String>) -> Result<(), ConfigError> {
+		for (name, value) in values {
+			self.add(name, value);
 		}
-		false
+		self.write()
 	}
 
-	pub fn get_option<T: FromStr>(&mut self, name: &str) -> Option<T> {
-		let mut lookup_key = self.parent_key.duplicate(CopyOption::KEY_CP_NAME);
-		lookup_key.add_name(name).unwrap_or_else(|_| {
-			panic!("Could not add '{}' to '{}'!", name, self.parent_key.name())
-		});
-		if let Some(found_key) = self.ks.lookup(lookup_key, LookupOption::KDB_O_NONE) {
-			if let Ok(ret) = found_key.value().parse::<T>() {
-				return Some(ret);
-			}
+	/// Blocks while Elektra writes, from async code use `ConfigHandle::remove`.
+	pub fn remove(&mut self, name: &str) -> Result<(), ConfigError> {
+		self.cut(name);
+		self.write()
+	}
+
+	fn write(&mut self) -> Result<(), ConfigError> {
+		self.kdb
+			.set(&mut self.ks, &mut self.parent_key)
+			.map(|_| ())
+			.map_err(|kdb_error| self.kdb_error(kdb_error.to_error_message()))
+	}
+
+	/// All keys below the parent (relative to it) with their values, e.g. to find out what changed.
+	pub fn snapshot(&mut self) -> BTreeMap<String, String> {
+		let parent = self.parent_key.name().to_string();
+		let parent_key = &self.parent_key;
+		let names: BTreeSet<String> = self
+			.ks
+			.iter_mut()
+			.filter(|key| key.is_below(parent_key))
+			.filter_map(|key| {
+				let name = key.name();
+				let without_namespace = match name.find(":/") {
+					Some(colon) => &name[colon + 1..],
+					None => &name,
+				};
+				without_namespace
+					.strip_prefix(&parent)
+					.and_then(|relative| relative.strip_prefix('/'))
+					.map(String::from)
+			})
+			.collect();
+		names
+			.into_iter()
+			.filter_map(|name| self.get_option::<String>(&name).map(|value| (name, value)))
+			.collect()
+	}
+
+	/// Deserializes the keys below `name` ("" for all keys) into `T`,
+	/// e.g. the `#[derive(Deserialize)]` configuration struct of a module.
+	/// Returns all problems found, not only the first one.
+	pub fn deserialize<T: DeserializeOwned>(&mut self, name: &str) -> Result<T, Vec<ConfigError>> {
+		from_node(&Node::from_keys(self.snapshot()), name)
+	}
+
+	/// Deserializes and validates `T`, e.g. before the module using it gets started.
+	pub fn check<T: DeserializeOwned + Validate>(
+		&mut self,
+		name: &str,
+	) -> Result<(), Vec<ConfigError>> {
+		let errors = self.deserialize::<T>(name)?.validate();
+		if errors.is_empty() {
+			Ok(())
+		} else {
+			Err(errors)
 		}
-		None
 	}
 
-	pub fn get<T: FromStr>(&mut self, name: &str) -> T {
+	fn lookup(&mut self, name: &str) -> Option<String> {
 		let mut lookup_key = self.parent_key.duplicate(CopyOption::KEY_CP_NAME);
 		lookup_key.add_name(name).unwrap_or_else(|_| {
 			panic!("Could not add '{}' to '{}'!", name, self.parent_key.name())
 		});
-		if let Some(found_key) = self.ks.lookup(lookup_key, LookupOption::KDB_O_NONE) {
-			if let Ok(ret) = found_key.value().parse::<T>() {
-				ret
-			} else {
-				panic!(
-					"Could not convert '{}' to type '{}' from key '{}'!",
-					found_key.value(),
-					std::any::type_name::<T>(),
-					found_key.name()
-				);
-			}
+		self.ks
+			.lookup(lookup_key, LookupOption::KDB_O_NONE)
+			.map(|found_key| found_key.value().to_string())
+	}
+
+	pub fn get_option<T: DeserializeOwned>(&mut self, name: &str) -> Option<T> {
+		convert(name, self.lookup(name)?)
+	}
+
+	/// The value of the key `name` as `T`, an error if it is missing or cannot be converted.
+	pub fn value<T: DeserializeOwned>(&mut self, name: &str) -> Result<T, ConfigError> {
+		let keys = self.lookup(name).map(|value| (name.to_string(), value));
+		from_node(&Node::from_keys(keys), name).map_err(|mut errors| errors.remove(0))
+	}
+
+	/// Panics if the key is missing or cannot be converted, see `value`.
+	pub fn get<T: DeserializeOwned>(&mut self, name: &str) -> T {
+		if let Some(value) = self.lookup(name) {
+			from_node(&Node::from_keys([(name.to_string(), value.clone())]), name).unwrap_or_else(
+				|_| {
+					panic!(
+						"Could not convert '{}' to type '{}' from key '{}/{}'!",
+						value,
+						std::any::type_name::<T>(),
+						self.parent_key.name(),
+						name
+					)
+				},
+			)
 		} else {
 			panic!(
 				"Did not find the key '{}/{}'!",
@@ -128,3 +213,128 @@ impl Config<'_> {
 		}
 	}
 }
+
+/// The value of the key `name` as `T`, None if it cannot be converted.
+pub fn convert<T: DeserializeOwned>(name: &str, value: String) -> Option<T> {
+	from_node(&Node::from_keys([(name.to_string(), value)]), name).ok()
+}
+
+/// Keys which were added, removed or got a different value.
+pub fn changed_keys(old: &BTreeMap<String, String>, new: &BTreeMap<String, String>) -> Vec<String> {
+	old.keys()
+		.chain(new.keys())
+		.collect::<BTreeSet<&String>>()
+		.into_iter()
+		.filter(|name| old.get(*name) != new.get(*name))
+		.cloned()
+		.collect()
+}
+
+/// The errors of several `Config::check`s together, e.g. of a module and of `notify`.
+pub fn all_errors(
+	results: impl IntoIterator<Item = Result<(), Vec<ConfigError>>>,
+) -> Result<(), Vec<ConfigError>> {
+	let errors = results
+		.into_iter()
+		.filter_map(Result::err)
+		.flatten()
+		.collect::<Vec<ConfigError>>();
+	if errors.is_empty() {
+		Ok(())
+	} else {
+		Err(errors)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_changed_keys() {
+		let old = BTreeMap::from([
+			(String::from("light/timeout"), String::from("60")),
+			(String::from("garage/enable"), String::from("0")),
+			(String::from("audio/bell"), String::from("bell.ogg")),
+		]);
+		let new = BTreeMap::from([
+			(String::from("light/timeout"), String::from("60")),
+			(String::from("garage/enable"), String::from("1")),
+			(String::from("ping/enable"), String::from("1")),
+		]);
+		assert_eq!(
+			changed_keys(&old, &new),
+			vec!["audio/bell", "garage/enable", "ping/enable"]
+		);
+		assert!(changed_keys(&new, &new).is_empty());
+	}
+
+	#[test]
+	fn test_all_errors() {
+		let error = |key: &str| ConfigError {
+			key: key.to_string(),
+			message: String::from("missing"),
+		};
+		assert_eq!(all_errors([Ok(()), Ok(())]), Ok(()));
+		assert_eq!(
+			all_errors([
+				Err(vec![error("nextcloud/url")]),
+				Ok(()),
+				Err(vec![error("notify/dedup"), error("notify/air/limit")]),
+			]),
+			Err(vec![
+				error("nextcloud/url"),
+				error("notify/dedup"),
+				error("notify/air/limit")
+			])
+		);
+	}
+
+	#[test]
+	fn test_snapshot() {
+		let mut config = Config::new("/sw/libelektra/opensesame/#0/current");
+		config.add("test/snapshot", "42");
+		let snapshot = config.snapshot();
+		assert_eq!(snapshot["test/snapshot"], "42");
+		assert!(snapshot.contains_key("garage/enable"));
+	}
+
+	#[test]
+	fn test_deserialize() {
+		let mut config = Config::new("/sw/libelektra/opensesame/#0/current");
+		config.add("test/deserialize/enable", "1");
+		config.add("test/deserialize/#0", "first");
+		config.add("test/deserialize/#1", "second");
+		assert!(config.get::<bool>("test/deserialize/enable"));
+		config.add("test/deserialize/invalid", "yes");
+		assert_eq!(
+			config.value::<bool>("test/deserialize/invalid"),
+			Err(ConfigError {
+				key: String::from("test/deserialize/invalid"),
+				message: String::from("'yes' is not a boolean (1 or 0)"),
+			})
+		);
+		assert_eq!(
+			config.value::<bool>("test/deserialize/missing"),
+			Err(ConfigError {
+				key: String::from("test/deserialize/missing"),
+				message: String::from("missing"),
+			})
+		);
+		assert_eq!(
+			config.deserialize::<Vec<String>>("test/deserialize"),
+			Ok(vec![String::from("first"), String::from("second")])
+		);
+	}
+
+	#[test]
+	fn test_apply_arguments() {
+		let mut config = Config::new("/sw/libelektra/opensesame/#0/current");
+		assert!(!config.get::<bool>("cli/dry-run"));
+		config.apply_arguments(&BTreeMap::from([(
+			String::from("cli/dry-run"),
+			String::from("1"),
+		)]));
+		assert!(config.get::<bool>("cli/dry-run"));
+	}
+}
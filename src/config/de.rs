@@ -0,0 +1,507 @@
+//! serde `Deserializer` over a subtree of the configuration.
+//!
+//! Nested sections become structs (field names may contain `/`),
+//! arrays (`#0`, `#1`, …, `#_10`) become sequences and all other
+//! children of a key become maps.
+//! Problems are collected instead of aborting on the first one:
+//! missing keys are errors unless the field is an `Option`.
+
+use std::{cell::RefCell, collections::BTreeMap, error::Error, fmt};
+
+use serde::de::{
+	self, value::StrDeserializer, DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess,
+	SeqAccess, Visitor,
+};
+
+/// A problem with the configuration, together with the key it is about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+	pub key: String,
+	pub message: String,
+}
+
+impl ConfigError {
+	fn at(mut self, key: &str) -> Self {
+		if self.key.is_empty() {
+			self.key = key.to_string();
+		}
+		self
+	}
+}
+
+impl fmt::Display for ConfigError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}: {}", self.key, self.message)
+	}
+}
+
+impl Error for ConfigError {}
+
+impl de::Error for ConfigError {
+	fn custom<T: fmt::Display>(message: T) -> Self {
+		Self {
+			key: String::new(),
+			message: message.to_string(),
+		}
+	}
+}
+
+/// Keys (relative to some parent) arranged as tree.
+#[derive(Debug, Default)]
+pub struct Node {
+	value: Option<String>,
+	children: BTreeMap<String, Node>,
+}
+
+impl Node {
+	pub fn from_keys(keys: impl IntoIterator<Item = (String, String)>) -> Self {
+		let mut root = Node::default();
+		for (name, value) in keys {
+			let node = name.split('/').fold(&mut root, |node, part| {
+				node.children.entry(part.to_string()).or_default()
+			});
+			node.value = Some(value);
+		}
+		root
+	}
+
+	fn get(&self, path: &str) -> Option<&Node> {
+		if path.is_empty() {
+			return Some(self);
+		}
+		path.split('/')
+			.try_fold(self, |node, part| node.children.get(part))
+	}
+
+	/// Array elements in the order of their index, other children are ignored.
+	fn elements(&self) -> Vec<(&String, &Node)> {
+		let mut elements = self
+			.children
+			.iter()
+			.filter_map(|(name, node)| array_index(name).map(|index| (index, (name, node))))
+			.collect::<Vec<_>>();
+		elements.sort_by_key(|(index, _)| *index);
+		elements.into_iter().map(|(_, element)| element).collect()
+	}
+}
+
+/// `#0` → 0, `#_10` → 10, `#` (the spec placeholder) → None
+fn array_index(name: &str) -> Option<usize> {
+	name.strip_prefix('#')?.trim_start_matches('_').parse().ok()
+}
+
+fn join(parent: &str, name: &str) -> String {
+	if parent.is_empty() {
+		name.to_string()
+	} else {
+		format!("{}/{}", parent, name)
+	}
+}
+
+/// Deserializes the subtree at `path` (relative to `root`, "" for `root` itself).
+pub fn from_node<T: DeserializeOwned>(root: &Node, path: &str) -> Result<T, Vec<ConfigError>> {
+	let errors = RefCell::new(Vec::new());
+	let result = T::deserialize(Deserializer {
+		node: root.get(path),
+		key: path.to_string(),
+		errors: &errors,
+	});
+	let mut errors = errors.into_inner();
+	match result {
+		Ok(value) if errors.is_empty() => Ok(value),
+		Ok(_) => Err(errors),
+		Err(error) => {
+			errors.push(error.at(path));
+			Err(errors)
+		}
+	}
+}
+
+struct Deserializer<'a> {
+	node: Option<&'a Node>,
+	key: String,
+	errors: &'a RefCell<Vec<ConfigError>>,
+}
+
+impl<'a> Deserializer<'a> {
+	fn child(&self, name: &str) -> Self {
+		Self {
+			node: self.node.and_then(|node| node.get(name)),
+			key: join(&self.key, name),
+			errors: self.errors,
+		}
+	}
+
+	fn value(&self) -> Option<&'a str> {
+		self.node.and_then(|node| node.value.as_deref())
+	}
+
+	fn error(&self, message: String) -> ConfigError {
+		ConfigError {
+			key: self.key.clone(),
+			message,
+		}
+	}
+
+	/// Records the problem, the caller continues with a placeholder value,
+	/// so that further problems get found, too.
+	fn report(&self, message: String) {
+		self.errors.borrow_mut().push(self.error(message));
+	}
+
+	fn parse<T: std::str::FromStr + Default>(&self, type_name: &str) -> T {
+		match self.value() {
+			Some(value) => value.trim().parse().unwrap_or_else(|_| {
+				self.report(format!("'{}' is not a valid {}", value, type_name));
+				T::default()
+			}),
+			None => {
+				self.report(String::from("missing"));
+				T::default()
+			}
+		}
+	}
+}
+
+macro_rules! deserialize_parse {
+	($($method:ident => $visit:ident: $type:ty, $type_name:expr;)*) => {$(
+		fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+			let value: $type = self.parse($type_name);
+			visitor.$visit(value)
+		}
+	)*};
+}
+
+impl<'de, 'a> de::Deserializer<'de> for Deserializer<'a> {
+	type Error = ConfigError;
+
+	deserialize_parse! {
+		deserialize_i8 => visit_i8: i8, "number";
+		deserialize_i16 => visit_i16: i16, "number";
+		deserialize_i32 => visit_i32: i32, "number";
+		deserialize_i64 => visit_i64: i64, "number";
+		deserialize_u8 => visit_u8: u8, "unsigned number (0-255)";
+		deserialize_u16 => visit_u16: u16, "unsigned number (0-65535)";
+		deserialize_u32 => visit_u32: u32, "unsigned number";
+		deserialize_u64 => visit_u64: u64, "unsigned number";
+		deserialize_f32 => visit_f32: f32, "floating point number";
+		deserialize_f64 => visit_f64: f64, "floating point number";
+		deserialize_char => visit_char: char, "character";
+	}
+
+	fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+		match self.node {
+			Some(node) if !node.children.is_empty() => self.deserialize_map(visitor),
+			Some(node) if node.value.is_some() => self.deserialize_string(visitor),
+			_ => visitor.visit_unit(),
+		}
+	}
+
+	fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+		let value = match self.value() {
+			Some("1") | Some("true") => true,
+			Some("0") | Some("false") => false,
+			Some(value) => {
+				self.report(format!("'{}' is not a boolean (1 or 0)", value));
+				false
+			}
+			None => {
+				self.report(String::from("missing"));
+				false
+			}
+		};
+		visitor.visit_bool(value)
+	}
+
+	fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+		self.deserialize_string(visitor)
+	}
+
+	fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+		let value = self.value().unwrap_or_else(|| {
+			self.report(String::from("missing"));
+			""
+		});
+		visitor.visit_string(value.to_string())
+	}
+
+	fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+		self.deserialize_string(visitor)
+	}
+
+	fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+		self.deserialize_string(visitor)
+	}
+
+	fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+		match self.node {
+			Some(node)
+				if !node.children.is_empty()
+					|| node.value.as_deref().is_some_and(|value| !value.is_empty()) =>
+			{
+				visitor.visit_some(self)
+			}
+			_ => visitor.visit_none(),
+		}
+	}
+
+	fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+		visitor.visit_unit()
+	}
+
+	fn deserialize_unit_struct<V: Visitor<'de>>(
+		self,
+		_name: &'static str,
+		visitor: V,
+	) -> Result<V::Value, ConfigError> {
+		visitor.visit_unit()
+	}
+
+	fn deserialize_newtype_struct<V: Visitor<'de>>(
+		self,
+		_name: &'static str,
+		visitor: V,
+	) -> Result<V::Value, ConfigError> {
+		visitor.visit_newtype_struct(self)
+	}
+
+	fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+		let elements = self.node.map(Node::elements).unwrap_or_default();
+		visitor.visit_seq(Elements {
+			elements: elements.into_iter(),
+			parent: self,
+		})
+	}
+
+	fn deserialize_tuple<V: Visitor<'de>>(
+		self,
+		_len: usize,
+		visitor: V,
+	) -> Result<V::Value, ConfigError> {
+		self.deserialize_seq(visitor)
+	}
+
+	fn deserialize_tuple_struct<V: Visitor<'de>>(
+		self,
+		_name: &'static str,
+		_len: usize,
+		visitor: V,
+	) -> Result<V::Value, ConfigError> {
+		self.deserialize_seq(visitor)
+	}
+
+	fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+		let names = self
+			.node
+			.map(|node| {
+				node.children
+					.keys()
+					.map(String::as_str)
+					.collect::<Vec<&str>>()
+			})
+			.unwrap_or_default();
+		visitor.visit_map(Children {
+			names: names.into_iter(),
+			current: None,
+			parent: self,
+		})
+	}
+
+	/// Every field gets visited, also the missing ones, so that all of them get reported.
+	fn deserialize_struct<V: Visitor<'de>>(
+		self,
+		_name: &'static str,
+		fields: &'static [&'static str],
+		visitor: V,
+	) -> Result<V::Value, ConfigError> {
+		visitor.visit_map(Children {
+			names: fields.iter().copied(),
+			current: None,
+			parent: self,
+		})
+	}
+
+	fn deserialize_enum<V: Visitor<'de>>(
+		self,
+		_name: &'static str,
+		_variants: &'static [&'static str],
+		visitor: V,
+	) -> Result<V::Value, ConfigError> {
+		match self.value() {
+			Some(value) => visitor
+				.visit_enum(value.into_deserializer())
+				.map_err(|error: ConfigError| error.at(&self.key)),
+			None => Err(self.error(String::from("missing"))),
+		}
+	}
+
+	fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+		self.deserialize_string(visitor)
+	}
+
+	fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+		visitor.visit_unit()
+	}
+}
+
+struct Elements<'a> {
+	elements: std::vec::IntoIter<(&'a String, &'a Node)>,
+	parent: Deserializer<'a>,
+}
+
+impl<'de, 'a> SeqAccess<'de> for Elements<'a> {
+	type Error = ConfigError;
+
+	fn next_element_seed<T: DeserializeSeed<'de>>(
+		&mut self,
+		seed: T,
+	) -> Result<Option<T::Value>, ConfigError> {
+		match self.elements.next() {
+			Some((name, node)) => {
+				let key = join(&self.parent.key, name);
+				seed.deserialize(Deserializer {
+					node: Some(node),
+					key: key.clone(),
+					errors: self.parent.errors,
+				})
+				.map(Some)
+				.map_err(|error| error.at(&key))
+			}
+			None => Ok(None),
+		}
+	}
+}
+
+struct Children<'a, I> {
+	names: I,
+	current: Option<&'a str>,
+	parent: Deserializer<'a>,
+}
+
+impl<'de, 'a, I: Iterator<Item = &'a str>> MapAccess<'de> for Children<'a, I> {
+	type Error = ConfigError;
+
+	fn next_key_seed<K: DeserializeSeed<'de>>(
+		&mut self,
+		seed: K,
+	) -> Result<Option<K::Value>, ConfigError> {
+		self.current = self.names.next();
+		match self.current {
+			Some(name) => {
+				let name: StrDeserializer<ConfigError> = name.into_deserializer();
+				seed.deserialize(name).map(Some)
+			}
+			None => Ok(None),
+		}
+	}
+
+	fn next_value_seed<V: DeserializeSeed<'de>>(
+		&mut self,
+		seed: V,
+	) -> Result<V::Value, ConfigError> {
+		let name = self
+			.current
+			.take()
+			.expect("next_value_seed called before next_key_seed");
+		let child = self.parent.child(name);
+		let key = child.key.clone();
+		seed.deserialize(child).map_err(|error| error.at(&key))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde::Deserialize;
+
+	#[derive(Deserialize, Debug, PartialEq)]
+	struct Sensor {
+		loc: String,
+		alarm: Option<u16>,
+	}
+
+	#[derive(Deserialize, Debug, PartialEq)]
+	struct Section {
+		enable: bool,
+		#[serde(rename = "data/interval")]
+		interval: u64,
+		sensors: Vec<Sensor>,
+		users: BTreeMap<String, String>,
+	}
+
+	fn node(keys: &[(&str, &str)]) -> Node {
+		Node::from_keys(
+			keys.iter()
+				.map(|(name, value)| (name.to_string(), value.to_string())),
+		)
+	}
+
+	#[test]
+	fn test_nested_sections_and_arrays() {
+		let root = node(&[
+			("section/enable", "1"),
+			("section/data/interval", "60"),
+			("section/sensors", "#_10"),
+			("section/sensors/#_10/loc", "ten"),
+			("section/sensors/#2/loc", "two"),
+			("section/sensors/#2/alarm", "300"),
+			("section/sensors/#/loc", "spec placeholder"),
+			("section/users/anna", "[1, 2]"),
+		]);
+		assert_eq!(
+			from_node::<Section>(&root, "section"),
+			Ok(Section {
+				enable: true,
+				interval: 60,
+				sensors: vec![
+					Sensor {
+						loc: String::from("two"),
+						alarm: Some(300),
+					},
+					Sensor {
+						loc: String::from("ten"),
+						alarm: None,
+					},
+				],
+				users: BTreeMap::from([(String::from("anna"), String::from("[1, 2]"))]),
+			})
+		);
+	}
+
+	#[test]
+	fn test_all_errors_collected() {
+		let root = node(&[
+			("section/enable", "yes"),
+			("section/sensors/#0/alarm", "-1"),
+		]);
+		let errors = from_node::<Section>(&root, "section").unwrap_err();
+		let keys = errors
+			.iter()
+			.map(|error| error.key.as_str())
+			.collect::<Vec<&str>>();
+		assert_eq!(
+			keys,
+			vec![
+				"section/enable",
+				"section/data/interval",
+				"section/sensors/#0/loc",
+				"section/sensors/#0/alarm",
+			]
+		);
+	}
+
+	#[test]
+	fn test_single_value() {
+		let root = node(&[("garage/enable", "0"), ("light/timeout", "60")]);
+		assert_eq!(from_node::<bool>(&root, "garage/enable"), Ok(false));
+		assert_eq!(from_node::<u32>(&root, "light/timeout"), Ok(60));
+		assert_eq!(from_node::<Option<u32>>(&root, "light/missing"), Ok(None));
+		assert_eq!(
+			from_node::<u32>(&root, "light/missing"),
+			Err(vec![ConfigError {
+				key: String::from("light/missing"),
+				message: String::from("missing"),
+			}])
+		);
+	}
+}
@@ -0,0 +1,239 @@
+//! Async access to a `Config`, which lives on its own thread, as Elektra
+//! blocks while reading or writing (and sometimes hangs, see
+//! https://github.com/ElektraInitiative/libelektra/issues/4981).
+
+use std::{
+	collections::BTreeMap,
+	sync::mpsc::{channel, Sender},
+	thread,
+	time::Duration,
+};
+
+use serde::de::DeserializeOwned;
+use tokio::{sync::oneshot, time};
+
+use super::{Config, ConfigError};
+
+type Job = Box<dyn FnOnce(&mut Config<'static>) + Send>;
+
+/// Cheap to clone, all clones talk to the same worker thread,
+/// which stops when the last clone is dropped.
+#[derive(Clone)]
+pub struct ConfigHandle {
+	parent: String,
+	jobs: Sender<Job>,
+	/// how long to wait for Elektra, see `elektra/timeout`
+	timeout: Duration,
+}
+
+impl ConfigHandle {
+	pub fn new(config: Config<'static>, timeout: Duration) -> Self {
+		let parent = config.parent();
+		Self::spawn(parent, timeout, move || Ok(config)).0
+	}
+
+	/// Like `new`, but Elektra opens the configuration on the worker, too.
+	pub async fn open(
+		parent: &str,
+		arguments: BTreeMap<String, String>,
+		timeout: Duration,
+	) -> Result<Self, ConfigError> {
+		let parent = parent.to_string();
+		let open_parent = parent.clone();
+		let (handle, opened) = Self::spawn(parent, timeout, move || {
+			let mut config = Config::open(&open_parent)?;
+			config.apply_arguments(&arguments);
+			Ok(config)
+		});
+		match time::timeout(timeout, opened).await {
+			Ok(Ok(result)) => result.map(|()| handle),
+			Ok(Err(_)) => Err(handle.error(String::from("Elektra worker stopped"))),
+			Err(_) => Err(handle.error(format!("Elektra did not answer within {:?}", timeout))),
+		}
+	}
+
+	/// Starts the worker thread, which runs jobs once `open` returned the configuration.
+	fn spawn(
+		parent: String,
+		timeout: Duration,
+		open: impl FnOnce() -> Result<Config<'static>, ConfigError> + Send + 'static,
+	) -> (Self, oneshot::Receiver<Result<(), ConfigError>>) {
+		let (jobs, receiver) = channel::<Job>();
+		let (opened_sender, opened) = oneshot::channel();
+		thread::Builder::new()
+			.name(format!("elektra {}", parent))
+			.spawn(move || {
+				let mut config = match open() {
+					Ok(config) => {
+						let _ = opened_sender.send(Ok(()));
+						config
+					}
+					Err(error) => {
+						let _ = opened_sender.send(Err(error));
+						return;
+					}
+				};
+				while let Ok(job) = receiver.recv() {
+					job(&mut config);
+				}
+			})
+			.expect("Could not spawn Elektra worker");
+		(
+			Self {
+				parent,
+				jobs,
+				timeout,
+			},
+			opened,
+		)
+	}
+
+	/// Same worker, but waits `timeout` for it.
+	pub fn with_timeout(&self, timeout: Duration) -> Self {
+		Self {
+			timeout,
+			..self.clone()
+		}
+	}
+
+	fn error(&self, message: String) -> ConfigError {
+		ConfigError {
+			key: self.parent.clone(),
+			message,
+		}
+	}
+
+	/// Runs `job` on the worker, jobs are run in the order they got sent.
+	/// Fails if the worker does not answer within the timeout.
+	pub async fn run<R: Send + 'static>(
+		&self,
+		job: impl FnOnce(&mut Config<'static>) -> R + Send + 'static,
+	) -> Result<R, ConfigError> {
+		let (sender, receiver) = oneshot::channel();
+		self.jobs
+			.send(Box::new(move |config| {
+				let _ = sender.send(job(config));
+			}))
+			.map_err(|_| self.error(String::from("Elektra worker stopped")))?;
+		match time::timeout(self.timeout, receiver).await {
+			Ok(Ok(result)) => Ok(result),
+			Ok(Err(_)) => Err(self.error(String::from("Elektra worker stopped"))),
+			Err(_) => Err(self.error(format!("Elektra did not answer within {:?}", self.timeout))),
+		}
+	}
+
+	pub async fn sync(&self) -> Result<(), ConfigError> {
+		self.run(|config| config.sync()).await?
+	}
+
+	pub async fn reopen(&self) -> Result<(), ConfigError> {
+		self.run(|config| config.reopen()).await?
+	}
+
+	pub async fn set(&self, name: &str, value: &str) -> Result<(), ConfigError> {
+		let (name, value) = (name.to_string(), value.to_string());
+		self.run(move |config| config.set(&name, &value)).await?
+	}
+
+	pub async fn remove(&self, name: &str) -> Result<(), ConfigError> {
+		let name = name.to_string();
+		self.run(move |config| config.remove(&name)).await?
+	}
+
+	/// Writes the values that changed with one Elektra call, true if anything was written.
+	pub async fn set_changed(&self, values: BTreeMap<String, String>) -> Result<bool, ConfigError> {
+		self.run(move |config| {
+			let changed: BTreeMap<String, String> = values
+				.into_iter()
+				.filter(|(name, value)| config.get_option::<String>(name).as_ref() != Some(value))
+				.collect();
+			if changed.is_empty() {
+				Ok(false)
+			} else {
+				config.set_all(&changed).map(|()| true)
+			}
+		})
+		.await?
+	}
+
+	pub async fn get_option<T: DeserializeOwned + Send + 'static>(
+		&self,
+		name: &str,
+	) -> Result<Option<T>, ConfigError> {
+		let name = name.to_string();
+		self.run(move |config| config.get_option(&name)).await
+	}
+
+	pub async fn value<T: DeserializeOwned + Send + 'static>(
+		&self,
+		name: &str,
+	) -> Result<T, ConfigError> {
+		let name = name.to_string();
+		self.run(move |config| config.value(&name)).await?
+	}
+
+	pub async fn deserialize<T: DeserializeOwned + Send + 'static>(
+		&self,
+		name: &str,
+	) -> Result<T, Vec<ConfigError>> {
+		let name = name.to_string();
+		self.run(move |config| config.deserialize(&name))
+			.await
+			.map_err(|error| vec![error])?
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// not the state of opensesame, whose writes call `reload-opensesame`
+	const TEST_PARENT: &str = "/tests/opensesame/state";
+
+	#[tokio::test]
+	async fn test_handle() {
+		let handle = ConfigHandle::new(Config::new(TEST_PARENT), Duration::from_secs(5));
+		handle
+			.run(|config| config.add("test/handle", "1"))
+			.await
+			.unwrap();
+		assert_eq!(
+			handle.get_option::<bool>("test/handle").await,
+			Ok(Some(true))
+		);
+		assert_eq!(handle.get_option::<bool>("test/missing").await, Ok(None));
+	}
+
+	#[tokio::test]
+	async fn test_open() {
+		let arguments = BTreeMap::from([(String::from("test/open"), String::from("1"))]);
+		let handle = ConfigHandle::open(TEST_PARENT, arguments, Duration::from_secs(5))
+			.await
+			.unwrap();
+		assert_eq!(handle.value::<bool>("test/open").await, Ok(true));
+		handle.reopen().await.unwrap();
+		assert_eq!(handle.value::<bool>("test/open").await, Ok(true));
+		assert_eq!(
+			handle.value::<bool>("test/missing").await,
+			Err(ConfigError {
+				key: String::from("test/missing"),
+				message: String::from("missing"),
+			})
+		);
+	}
+
+	#[tokio::test]
+	async fn test_timeout() {
+		let handle = ConfigHandle::new(Config::new(TEST_PARENT), Duration::from_millis(10));
+		let result = handle
+			.run(|_| thread::sleep(Duration::from_millis(100)))
+			.await;
+		assert_eq!(
+			result,
+			Err(ConfigError {
+				key: String::from(TEST_PARENT),
+				message: String::from("Elektra did not answer within 10ms"),
+			})
+		);
+	}
+}
@@ -0,0 +1,119 @@
+//! Checks beyond what deserializing finds, e.g. whether a device exists.
+
+use std::{fmt::Display, ops::RangeInclusive, path::Path};
+
+use super::ConfigError;
+
+/// as specified for the `nextcloud/chat` keys in opensesame.spec
+pub const CHAT_TOKEN_LENGTH: usize = 8;
+
+/// Implemented by the configuration structs of the modules.
+pub trait Validate {
+	/// All problems found, empty if the configuration is fine.
+	fn validate(&self) -> Vec<ConfigError>;
+}
+
+fn error(key: &str, message: String) -> Option<ConfigError> {
+	Some(ConfigError {
+		key: key.to_string(),
+		message,
+	})
+}
+
+/// The file or device at `path` must exist, /dev/null is fine, too.
+pub fn exists(key: &str, path: &str) -> Option<ConfigError> {
+	if Path::new(path).exists() {
+		None
+	} else {
+		error(key, format!("'{}' does not exist", path))
+	}
+}
+
+pub fn max_length(key: &str, value: &str, max: usize) -> Option<ConfigError> {
+	if value.chars().count() <= max {
+		None
+	} else {
+		error(
+			key,
+			format!("'{}' is longer than {} characters", value, max),
+		)
+	}
+}
+
+/// Does not show the value, which might be secret.
+pub fn min_length(key: &str, value: &str, min: usize) -> Option<ConfigError> {
+	if value.chars().count() >= min {
+		None
+	} else {
+		error(key, format!("is shorter than {} characters", min))
+	}
+}
+
+pub fn in_range<T: PartialOrd + Display>(
+	key: &str,
+	value: T,
+	range: RangeInclusive<T>,
+) -> Option<ConfigError> {
+	if range.contains(&value) {
+		None
+	} else {
+		error(
+			key,
+			format!(
+				"{} is not between {} and {}",
+				value,
+				range.start(),
+				range.end()
+			),
+		)
+	}
+}
+
+/// MQTT topic to publish to, without wildcards and not empty.
+pub fn topic(key: &str, value: &str) -> Option<ConfigError> {
+	if value.is_empty() {
+		error(key, String::from("is empty"))
+	} else if value.contains(['+', '#']) {
+		error(key, format!("'{}' contains a wildcard (+ or #)", value))
+	} else {
+		None
+	}
+}
+
+/// HTTP(S) URL, e.g. of a webhook.
+pub fn url(key: &str, value: &str) -> Option<ConfigError> {
+	match value.parse::<reqwest::Url>() {
+		Ok(url) if ["http", "https"].contains(&url.scheme()) => None,
+		_ => error(key, format!("'{}' is not an http(s) URL", value)),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_checks() {
+		assert_eq!(exists("audio/bell", "/dev/null"), None);
+		assert_eq!(
+			exists("ir/device", "/dev/does-not-exist"),
+			error(
+				"ir/device",
+				String::from("'/dev/does-not-exist' does not exist")
+			)
+		);
+		assert_eq!(max_length("nextcloud/chat", "abcd1234", 8), None);
+		assert!(max_length("nextcloud/chat", "abcd12345", 8).is_some());
+		assert_eq!(in_range("light/timeout", 60, 1..=3600), None);
+		assert_eq!(
+			in_range("light/timeout", 0, 1..=3600),
+			error("light/timeout", String::from("0 is not between 1 and 3600"))
+		);
+		assert_eq!(topic("mqtt/topic", "opensesame/garage"), None);
+		assert!(topic("mqtt/topic", "opensesame/#").is_some());
+		assert!(topic("mqtt/topic", "").is_some());
+		assert_eq!(url("webhook/hook/#0/url", "https://example.org/hook"), None);
+		assert!(url("webhook/hook/#0/url", "example.org/hook").is_some());
+		assert!(url("webhook/hook/#0/url", "ftp://example.org").is_some());
+	}
+}
@@ -0,0 +1,235 @@
+//! Local control via a UNIX socket, see `opensesamectl`.
+//!
+//! The client sends one line with the command and its arguments, the same
+//! as in the Nextcloud chat (see `Command`). The answer starts with a line
+//! `ok` or `error`, followed by the output, e.g. of `status`.
+
+use std::{fs, io, os::unix::fs::PermissionsExt, path::Path, time::Duration};
+
+use futures::{future::BoxFuture, never::Never, FutureExt};
+use tokio::{
+	io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+	net::{UnixListener, UnixStream},
+	select, spawn,
+	time::timeout,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+	bus::EventBus,
+	command::Command,
+	config::{Config, ConfigError},
+	history::History,
+	instance::Instance,
+	log,
+	log::Level,
+	module::{Context, Module},
+	state::StateStore,
+	supervisor::{health_report, Health},
+	types::ModuleError,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_LINE: u64 = 4096;
+/// owner and group, e.g. for `sudo -g opensesame opensesamectl`
+const MODE: u32 = 0o660;
+
+/// Sends `command` to the socket and returns whether it succeeded and the output.
+pub async fn request(socket: &Path, command: &str) -> io::Result<(bool, String)> {
+	let stream = timeout(TIMEOUT, UnixStream::connect(socket)).await??;
+	let (reader, mut writer) = stream.into_split();
+	timeout(
+		TIMEOUT,
+		writer.write_all(format!("{}\n", command).as_bytes()),
+	)
+	.await??;
+	let mut answer = String::new();
+	timeout(TIMEOUT, BufReader::new(reader).read_to_string(&mut answer)).await??;
+	match answer.split_once('\n') {
+		Some(("ok", output)) => Ok((true, output.to_string())),
+		Some(("error", output)) => Ok((false, output.to_string())),
+		_ => Err(io::Error::new(
+			io::ErrorKind::InvalidData,
+			format!("Unexpected answer '{}'", answer),
+		)),
+	}
+}
+
+#[derive(Clone)]
+pub struct Control {
+	bus: EventBus,
+	state: StateStore,
+	health: Health,
+	history: History,
+	instance: Instance,
+	startup_time: String,
+}
+
+impl Control {
+	pub fn new(context: &Context) -> Self {
+		Self {
+			bus: context.bus.clone(),
+			state: context.state.clone(),
+			health: context.health.clone(),
+			history: context.history.clone(),
+			instance: context.instance.clone(),
+			startup_time: context.startup_time.clone(),
+		}
+	}
+
+	async fn status(&self) -> Result<String, String> {
+		let alarm = self
+			.state
+			.get_option::<String>("alarm/fire")
+			.await
+			.map_err(|error| error.to_string())?;
+		Ok(format!(
+			"opensesame {} {}, running since {}\nalarm: {}\n{}\n",
+			env!("CARGO_PKG_VERSION"),
+			self.instance,
+			self.startup_time,
+			alarm.as_deref().unwrap_or("none"),
+			health_report(&self.health)
+		))
+	}
+
+	async fn answer(&self, line: &str) -> Result<String, String> {
+		let words = line.split_whitespace().collect::<Vec<&str>>();
+		match Command::parse(&words)? {
+			Command::Status => self.status().await,
+			command => command
+				.execute(&self.bus, &self.state, &self.history)
+				.await
+				.map(|mut output| {
+					if !output.is_empty() && !output.ends_with('\n') {
+						output.push('\n');
+					}
+					output
+				}),
+		}
+	}
+
+	async fn handle(&self, stream: UnixStream) -> io::Result<()> {
+		let (reader, mut writer) = stream.into_split();
+		let mut line = String::new();
+		timeout(
+			TIMEOUT,
+			BufReader::new(reader.take(MAX_LINE)).read_line(&mut line),
+		)
+		.await??;
+		log!(Level::Info, "Control command: {}", line.trim_end());
+		let answer = match self.answer(&line).await {
+			Ok(output) => format!("ok\n{}", output),
+			Err(error) => format!("error\n{}\n", error),
+		};
+		timeout(TIMEOUT, writer.write_all(answer.as_bytes())).await??;
+		Ok(())
+	}
+
+	pub async fn get_background_task(
+		self,
+		listener: UnixListener,
+		shutdown: CancellationToken,
+	) -> Result<Never, ModuleError> {
+		loop {
+			select! {
+				connection = listener.accept() => {
+					let (stream, _address) = connection?;
+					let control = self.clone();
+					spawn(async move {
+						if let Err(error) = control.handle(stream).await {
+							log!(Level::Warning, "Control connection failed: {}", error);
+						}
+					});
+				}
+				_ = shutdown.cancelled() => {
+					return Err(ModuleError::new(String::from("Control shut down")));
+				}
+			}
+		}
+	}
+}
+
+/// Binds the socket, one left over from a crash gets replaced.
+/// Only one instance can run at a time, see `Instance::write_pid_file`.
+pub fn bind(socket: &Path) -> io::Result<UnixListener> {
+	match fs::remove_file(socket) {
+		Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+		_ => {}
+	}
+	let listener = UnixListener::bind(socket)?;
+	fs::set_permissions(socket, fs::Permissions::from_mode(MODE))?;
+	Ok(listener)
+}
+
+pub struct ControlModule;
+
+impl Module for ControlModule {
+	fn name(&self) -> &'static str {
+		"Control"
+	}
+
+	fn enabled(&self, config: &mut Config) -> Result<bool, ConfigError> {
+		config.value::<bool>("control/enable")
+	}
+
+	fn config_keys(&self) -> &'static [&'static str] {
+		&["control"]
+	}
+
+	fn start(&self, context: Context) -> BoxFuture<'static, Result<Never, ModuleError>> {
+		async move {
+			let listener = bind(&context.instance.control_socket(&context.runtime))?;
+			Control::new(&context)
+				.get_background_task(listener, context.shutdown)
+				.await
+		}
+		.boxed()
+	}
+
+	fn shutdown(&self, context: &Context) {
+		let _ = fs::remove_file(context.instance.control_socket(&context.runtime));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::process;
+
+	use super::*;
+	use crate::{bus::Event, cli::Options};
+
+	#[tokio::test]
+	async fn test_request() {
+		let runtime = std::env::temp_dir().join(format!("opensesame-control-{}", process::id()));
+		fs::create_dir_all(&runtime).unwrap();
+		let context = Context::new(
+			Instance::default(),
+			String::from("today"),
+			Default::default(),
+			Options::default(),
+		)
+		.await
+		.unwrap();
+		let socket = context.instance.control_socket(runtime.to_str().unwrap());
+		let listener = bind(&socket).unwrap();
+		let mut events = context.bus.subscribe();
+		spawn(Control::new(&context).get_background_task(listener, CancellationToken::new()));
+
+		let (ok, output) = request(&socket, "status").await.unwrap();
+		assert!(ok);
+		assert!(output.contains("#0/current, running since today"));
+
+		assert_eq!(
+			request(&socket, "ring").await.unwrap(),
+			(true, String::new())
+		);
+		assert!(matches!(events.recv().await, Some(Event::Audio(_))));
+
+		let (ok, output) = request(&socket, "lights on").await.unwrap();
+		assert!(!ok);
+		assert!(output.starts_with("Usage: "));
+
+		fs::remove_dir_all(runtime).unwrap();
+	}
+}
@@ -0,0 +1,544 @@
+//! Mails alarms right away and other notifications as digest via SMTP, see doc/Email.md.
+//!
+//! Alarms arriving within a few seconds, e.g. the alarm of a sensor and the
+//! fire alarm in the chat, are combined into one mail. Mails stay queued until
+//! the server accepted them, retries back off exponentially.
+
+use std::{collections::VecDeque, time::Duration};
+
+use chrono::Local;
+use futures::{future::BoxFuture, never::Never, FutureExt};
+use gettextrs::gettext;
+use lettre::{
+	message::{header::ContentType, Mailbox},
+	transport::smtp::authentication::Credentials,
+	AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use serde::Deserialize;
+use tokio::{
+	select,
+	time::{sleep_until, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+	bus::{Event, EventBus},
+	config::{
+		all_errors,
+		validate::{in_range, Validate},
+		Config, ConfigError,
+	},
+	log,
+	log::Level,
+	module::{Context, Module},
+	nextcloud::NextcloudEvent,
+	notify::{Notification, NotifyConfig, Severity},
+	routing::{Destination, Routing},
+	types::ModuleError,
+};
+
+/// collect further alarms for this long before mailing
+const ALERT_DELAY: Duration = Duration::from_secs(5);
+const INITIAL_RETRY: Duration = Duration::from_secs(10);
+const MAX_RETRY: Duration = Duration::from_secs(10 * 60);
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+/// mails kept while the server is unreachable, the oldest digests get dropped first
+const MAX_MAILS: usize = 100;
+const WEEK: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Security {
+	/// plain text, only for servers on localhost
+	None,
+	/// upgrade to TLS, usually port 587
+	Starttls,
+	/// TLS from the start, usually port 465
+	Tls,
+}
+
+#[derive(Deserialize)]
+pub struct EmailConfig {
+	pub host: String,
+	pub port: u16,
+	pub security: Security,
+	/// empty for servers which don't need authentication
+	pub user: String,
+	pub password: String,
+	pub from: String,
+	pub to: Vec<String>,
+	/// seconds between digests, 0 to mail routed notifications right away
+	pub digest: u64,
+}
+
+fn mailbox(key: &str, address: &str) -> Option<ConfigError> {
+	address.parse::<Mailbox>().err().map(|error| ConfigError {
+		key: key.to_string(),
+		message: format!("'{}' is not a mail address: {}", address, error),
+	})
+}
+
+impl Validate for EmailConfig {
+	fn validate(&self) -> Vec<ConfigError> {
+		let mut errors = Vec::from_iter(mailbox("email/from", &self.from));
+		if self.to.is_empty() {
+			errors.push(ConfigError {
+				key: String::from("email/to"),
+				message: String::from("is empty"),
+			});
+		}
+		errors.extend(
+			self.to
+				.iter()
+				.enumerate()
+				.filter_map(|(index, to)| mailbox(&format!("email/to/#{}", index), to)),
+		);
+		if self.digest != 0 {
+			errors.extend(in_range("email/digest", self.digest, 60..=WEEK));
+		}
+		errors
+	}
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Mail {
+	subject: String,
+	body: String,
+	/// ahead of the digests
+	alarm: bool,
+}
+
+pub struct Email {
+	transport: AsyncSmtpTransport<Tokio1Executor>,
+	from: Mailbox,
+	to: Vec<Mailbox>,
+	routing: Routing,
+	instance: String,
+	/// alarms for the next mail, each once
+	alerts: Vec<String>,
+	alerts_due: Option<Instant>,
+	/// lines of the next digest
+	digest: Vec<String>,
+	digest_period: Option<Duration>,
+	digest_due: Option<Instant>,
+	/// not yet accepted by the server
+	outbox: VecDeque<Mail>,
+	backoff: Duration,
+	retry: Option<Instant>,
+	/// log instead of sending, see `--dry-run`
+	dry_run: bool,
+}
+
+impl Email {
+	pub fn new(config: EmailConfig, routing: Routing, context: &Context) -> Result<Self, String> {
+		let builder = match config.security {
+			Security::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host),
+			Security::Starttls => {
+				AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)
+					.map_err(|error| error.to_string())?
+			}
+			Security::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)
+				.map_err(|error| error.to_string())?,
+		};
+		let mut builder = builder.port(config.port).timeout(Some(SEND_TIMEOUT));
+		if !config.user.is_empty() {
+			builder = builder.credentials(Credentials::new(config.user, config.password));
+		}
+		Ok(Self {
+			transport: builder.build(),
+			from: config.from.parse().map_err(|error| format!("{}", error))?,
+			to: config
+				.to
+				.iter()
+				.map(|to| to.parse())
+				.collect::<Result<Vec<Mailbox>, _>>()
+				.map_err(|error| format!("{}", error))?,
+			routing,
+			instance: context.instance.to_string(),
+			alerts: Vec::new(),
+			alerts_due: None,
+			digest: Vec::new(),
+			digest_period: (config.digest != 0).then(|| Duration::from_secs(config.digest)),
+			digest_due: None,
+			outbox: VecDeque::new(),
+			backoff: INITIAL_RETRY,
+			retry: None,
+			dry_run: context.options.dry_run,
+		})
+	}
+
+	fn alert(&mut self, text: String, now: Instant) {
+		if !self.alerts.contains(&text) {
+			self.alerts.push(text);
+		}
+		self.alerts_due.get_or_insert(now + ALERT_DELAY);
+	}
+
+	fn line(notification: &Notification) -> String {
+		let severity = serde_json::to_value(notification.severity).unwrap();
+		format!(
+			"{} {} {}: {}",
+			Local::now().format("%Y-%m-%d %H:%M"),
+			severity.as_str().unwrap_or_default(),
+			notification.module,
+			notification.text
+		)
+	}
+
+	/// Collects alarms and the notifications routed to `email`.
+	fn handle(&mut self, event: &Event, now: Instant) {
+		match event {
+			Event::AlarmRaised(reason) => self.alert(gettext!("🚨 Alarm: {}", reason), now),
+			Event::Nextcloud(NextcloudEvent::Chat(notification))
+				if notification.severity == Severity::Alarm =>
+			{
+				self.alert(notification.text.clone(), now)
+			}
+			Event::Nextcloud(NextcloudEvent::Chat(notification))
+				if self.routing.routes_to(
+					notification,
+					Destination::Email,
+					Local::now().time(),
+				) =>
+			{
+				match self.digest_period {
+					Some(period) => {
+						self.digest.push(Email::line(notification));
+						self.digest_due.get_or_insert(now + period);
+					}
+					None => self.push(Mail {
+						subject: gettext!("Opensesame {}: {}", self.instance, notification.text),
+						body: Email::line(notification),
+						alarm: false,
+					}),
+				}
+			}
+			_ => {}
+		}
+	}
+
+	/// Alarms go behind the queued alarms, digests to the end.
+	fn push(&mut self, mail: Mail) {
+		let position = if mail.alarm {
+			self.outbox
+				.iter()
+				.position(|queued| !queued.alarm)
+				.unwrap_or(self.outbox.len())
+		} else {
+			self.outbox.len()
+		};
+		self.outbox.insert(position, mail);
+		if self.outbox.len() > MAX_MAILS {
+			let oldest = self
+				.outbox
+				.iter()
+				.position(|queued| !queued.alarm)
+				.unwrap_or(0);
+			if let Some(dropped) = self.outbox.remove(oldest) {
+				log!(
+					Level::Warning,
+					"Too many unsent mails, dropped {}",
+					dropped.subject
+				);
+			}
+		}
+	}
+
+	fn flush_alerts(&mut self) {
+		self.alerts_due = None;
+		if self.alerts.is_empty() {
+			return;
+		}
+		let alerts = std::mem::take(&mut self.alerts);
+		self.push(Mail {
+			subject: gettext!("🚨 Opensesame {}: {}", self.instance, alerts[0]),
+			body: alerts.join("\n"),
+			alarm: true,
+		});
+	}
+
+	fn flush_digest(&mut self) {
+		self.digest_due = None;
+		if self.digest.is_empty() {
+			return;
+		}
+		let digest = std::mem::take(&mut self.digest);
+		self.push(Mail {
+			subject: gettext!(
+				"Opensesame {}: {} notifications",
+				self.instance,
+				digest.len()
+			),
+			body: digest.join("\n"),
+			alarm: false,
+		});
+	}
+
+	fn message(&self, mail: &Mail) -> Result<Message, String> {
+		let mut builder = Message::builder()
+			.from(self.from.clone())
+			.subject(mail.subject.lines().next().unwrap_or_default())
+			.header(ContentType::TEXT_PLAIN);
+		for to in &self.to {
+			builder = builder.to(to.clone());
+		}
+		builder
+			.body(mail.body.clone())
+			.map_err(|error| error.to_string())
+	}
+
+	/// Sends the queued mails in order, until the first failure.
+	async fn deliver(&mut self) {
+		if matches!(self.retry, Some(retry) if retry > Instant::now()) {
+			return;
+		}
+		while let Some(mail) = self.outbox.front() {
+			if self.dry_run {
+				log!(Level::Info, "Dry run, not mailed: {:?}", mail);
+			} else {
+				let result = match self.message(mail) {
+					Ok(message) => self
+						.transport
+						.send(message)
+						.await
+						.map(|_| ())
+						.map_err(|error| error.to_string()),
+					Err(error) => {
+						log!(Level::Warning, "Dropped broken mail {:?}: {}", mail, error);
+						Ok(())
+					}
+				};
+				if let Err(error) = result {
+					log!(
+						Level::Warning,
+						"Couldn't send mail, retrying in {:?}: {}",
+						self.backoff,
+						error
+					);
+					self.retry = Some(Instant::now() + self.backoff);
+					self.backoff = (self.backoff * 2).min(MAX_RETRY);
+					return;
+				}
+			}
+			self.outbox.pop_front();
+			self.backoff = INITIAL_RETRY;
+			self.retry = None;
+		}
+	}
+
+	pub async fn get_background_task(
+		mut self,
+		bus: EventBus,
+		shutdown: CancellationToken,
+	) -> Result<Never, ModuleError> {
+		let mut events = bus.subscribe();
+		loop {
+			let retry = self.retry.filter(|_| !self.outbox.is_empty());
+			select! {
+				event = events.recv() => match event {
+					Some(event) => self.handle(&event, Instant::now()),
+					None => break,
+				},
+				_ = sleep_until(self.alerts_due.unwrap_or_else(Instant::now)), if self.alerts_due.is_some() => {
+					self.flush_alerts();
+				}
+				_ = sleep_until(self.digest_due.unwrap_or_else(Instant::now)), if self.digest_due.is_some() => {
+					self.flush_digest();
+				}
+				_ = sleep_until(retry.unwrap_or_else(Instant::now)), if retry.is_some() => {}
+				_ = shutdown.cancelled() => {
+					self.flush_alerts();
+					self.flush_digest();
+					self.retry = None;
+					self.deliver().await;
+					return Err(ModuleError::new(String::from("Email shut down")));
+				}
+			}
+			self.deliver().await;
+		}
+		Err(ModuleError::new(String::from(
+			"email background task exited",
+		)))
+	}
+}
+
+pub struct EmailModule;
+
+impl Module for EmailModule {
+	fn name(&self) -> &'static str {
+		"Email"
+	}
+
+	fn enabled(&self, config: &mut Config) -> Result<bool, ConfigError> {
+		config.value::<bool>("email/enable")
+	}
+
+	fn config_keys(&self) -> &'static [&'static str] {
+		&["email", "notify"]
+	}
+
+	fn check_config(&self, config: &mut Config) -> Result<(), Vec<ConfigError>> {
+		all_errors([
+			config.check::<EmailConfig>("email"),
+			config.check::<NotifyConfig>("notify"),
+		])
+	}
+
+	fn start(&self, context: Context) -> BoxFuture<'static, Result<Never, ModuleError>> {
+		async move {
+			let notify: NotifyConfig = context.config("notify").await?;
+			let email: EmailConfig = context.config("email").await?;
+			Email::new(email, Routing::new(&notify.route), &context)
+				.map_err(ModuleError::new)?
+				.get_background_task(context.bus, context.shutdown)
+				.await
+		}
+		.boxed()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeMap;
+
+	use tokio::{
+		io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+		net::TcpListener,
+		spawn,
+		sync::mpsc::{unbounded_channel, UnboundedSender},
+	};
+
+	use super::*;
+	use crate::{cli::Options, instance::Instance, nextcloud::NextcloudChat, routing::Route};
+
+	/// Local SMTP server: answers what the client needs and forwards the transcript of each connection.
+	async fn server(listener: TcpListener, transcripts: UnboundedSender<String>) {
+		loop {
+			let (stream, _address) = listener.accept().await.unwrap();
+			let (reader, mut writer) = stream.into_split();
+			let mut lines = BufReader::new(reader).lines();
+			writer.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+			let mut transcript = String::new();
+			let mut data = false;
+			while let Some(line) = lines.next_line().await.unwrap() {
+				transcript.push_str(&line);
+				transcript.push('\n');
+				let answer = if data {
+					if line != "." {
+						continue;
+					}
+					data = false;
+					"250 Queued\r\n"
+				} else if line.starts_with("EHLO") {
+					"250-localhost\r\n250 AUTH PLAIN LOGIN\r\n"
+				} else if line.starts_with("AUTH") {
+					"235 Authenticated\r\n"
+				} else if line == "DATA" {
+					data = true;
+					"354 Go ahead\r\n"
+				} else if line == "QUIT" {
+					"221 Bye\r\n"
+				} else {
+					"250 OK\r\n"
+				};
+				writer.write_all(answer.as_bytes()).await.unwrap();
+			}
+			transcripts.send(transcript).unwrap();
+		}
+	}
+
+	async fn email(port: u16, digest: u64) -> Email {
+		let context = Context::new(
+			Instance::default(),
+			String::from("today"),
+			Default::default(),
+			Options::default(),
+		)
+		.await
+		.unwrap();
+		let routing = Routing::new(&BTreeMap::from([(
+			String::from("#0"),
+			Route {
+				severity: Severity::Warning,
+				module: None,
+				to: Destination::Email,
+				quiet: None,
+			},
+		)]));
+		Email::new(
+			EmailConfig {
+				host: String::from("127.0.0.1"),
+				port,
+				security: Security::None,
+				user: String::from("opensesame"),
+				password: String::from("secret"),
+				from: String::from("opensesame@example.org"),
+				to: vec![
+					String::from("anna@example.org"),
+					String::from("bernd@example.org"),
+				],
+				digest,
+			},
+			routing,
+			&context,
+		)
+		.unwrap()
+	}
+
+	#[tokio::test]
+	async fn test_send() {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let port = listener.local_addr().unwrap().port();
+		let (sender, mut transcripts) = unbounded_channel();
+		spawn(server(listener, sender));
+		let mut email = email(port, 3600).await;
+		let now = Instant::now();
+
+		let battery = Notification::warning("Battery", NextcloudChat::Default, "Battery low");
+		email.handle(&battery.into(), now);
+		email.handle(
+			&Notification::info("Buttons", NextcloudChat::Default, "Bell").into(),
+			now,
+		);
+		email.handle(&Event::AlarmRaised(String::from("kitchen")), now);
+		email.handle(
+			&Notification::alarm("Sensors", "Fire Alarm kitchen").into(),
+			now,
+		);
+		assert_eq!(email.alerts.len(), 2);
+		assert_eq!(email.alerts_due, Some(now + ALERT_DELAY));
+		assert_eq!(email.digest.len(), 1);
+
+		email.flush_digest();
+		email.flush_alerts();
+		assert!(email.outbox[0].alarm);
+		email.deliver().await;
+		assert!(email.outbox.is_empty());
+
+		let alarm = transcripts.recv().await.unwrap();
+		assert!(alarm.contains("AUTH PLAIN"));
+		assert!(alarm.contains("MAIL FROM:<opensesame@example.org>"));
+		assert!(alarm.contains("RCPT TO:<anna@example.org>"));
+		assert!(alarm.contains("RCPT TO:<bernd@example.org>"));
+		assert!(alarm.contains("Fire Alarm kitchen"));
+		let digest = transcripts.recv().await.unwrap();
+		assert!(digest.contains("Subject: Opensesame #0/current: 1 notifications"));
+		assert!(digest.contains("warning Battery: Battery low"));
+		assert!(!digest.contains("Bell"));
+	}
+
+	#[tokio::test]
+	async fn test_retry() {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let port = listener.local_addr().unwrap().port();
+		drop(listener);
+		let mut email = email(port, 0).await;
+		let battery = Notification::warning("Battery", NextcloudChat::Default, "Battery low");
+		email.handle(&battery.into(), Instant::now());
+		assert_eq!(email.outbox.len(), 1);
+		email.deliver().await;
+		assert_eq!(email.outbox.len(), 1);
+		assert!(email.retry.is_some());
+		assert_eq!(email.backoff, INITIAL_RETRY * 2);
+	}
+}
@@ -1,28 +1,87 @@
 use bme280::i2c::BME280;
-use futures::never::Never;
+use futures::{future::BoxFuture, never::Never, FutureExt};
 use gettextrs::gettext;
 use i2cdev::core::*;
 use i2cdev::linux::LinuxI2CDevice;
 use linux_embedded_hal::{Delay, I2cdev};
-use std::{fmt, sync::Arc};
+use serde::Deserialize;
+use std::{fmt, time::Instant};
 use systemstat::Duration;
 use tokio::{
-	sync::{
-		mpsc::{Receiver, Sender},
-		Mutex,
-	},
-	time::{sleep, Interval},
+	select,
+	time::{interval, sleep, Interval},
 };
+use tokio_util::sync::CancellationToken;
 
 use crate::{
 	audio::AudioEvent,
+	bus::{Event, EventBus},
 	buttons::CommandToButtons,
-	config::Config,
+	config::{
+		validate::{exists, in_range, Validate},
+		Config, ConfigError,
+	},
+	module::{Context, Module},
 	nextcloud::{NextcloudChat, NextcloudEvent, NextcloudStatus},
+	notify::{Category, Notification},
+	readings::{unix_time, Reading},
+	state::StateStore,
 	types::ModuleError,
 };
 
-pub struct Environment<'a> {
+/// Deserialized from the top of the configuration, as it also needs `garage/enable`.
+#[derive(Deserialize)]
+pub struct EnvironmentConfig {
+	/// /dev/null if no environment sensor is connected
+	#[serde(rename = "environment/device")]
+	pub device: String,
+	#[serde(rename = "environment/name")]
+	pub name: String,
+	/// in seconds
+	#[serde(rename = "environment/data/interval")]
+	pub data_interval: u16,
+	/// in days
+	#[serde(rename = "environment/baseline/interval")]
+	pub baseline_interval: u16,
+	/// in minutes
+	#[serde(rename = "environment/baseline/burnin")]
+	pub baseline_burnin: u16,
+	/// in days
+	#[serde(rename = "environment/baseline/validity")]
+	pub baseline_validity: u16,
+	#[serde(rename = "garage/enable")]
+	pub garage_enable: bool,
+}
+
+impl Validate for EnvironmentConfig {
+	fn validate(&self) -> Vec<ConfigError> {
+		[
+			exists("environment/device", &self.device),
+			// the CCS811 measures at most every 60 seconds
+			in_range("environment/data/interval", self.data_interval, 1..=60),
+			in_range(
+				"environment/baseline/interval",
+				self.baseline_interval,
+				1..=30,
+			),
+			in_range(
+				"environment/baseline/burnin",
+				self.baseline_burnin,
+				1..=1440,
+			),
+			in_range(
+				"environment/baseline/validity",
+				self.baseline_validity,
+				self.baseline_interval..=365,
+			),
+		]
+		.into_iter()
+		.flatten()
+		.collect()
+	}
+}
+
+pub struct Environment {
 	pub co2: u16,
 	pub voc: u16,
 	pub temperature: f32,
@@ -42,7 +101,13 @@ pub struct Environment<'a> {
 	pub data_interval: u16,
 	pub baseline: u16,
 	pub name: String,
-	state_mutex: Arc<Mutex<Config<'a>>>,
+	state: StateStore,
+	baseline_interval: u16,
+	baseline_burnin: Duration,
+	baseline_validity: u16,
+	started: Instant,
+	/// restored after the burn-in
+	baseline_restored: bool,
 }
 
 const LOW_CO2_OK_QUALITY: u16 = 3000;
@@ -104,9 +169,55 @@ pub enum AirQualityChange {
 	FireAlarm,
 }
 
+#[derive(Clone, Debug)]
 pub enum EnvEvent {
 	RememberBaseline,
 	RestoreBaseline,
+	/// from the `\baseline` chat command, answered in the commands chat
+	Baseline(BaselineCommand),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BaselineCommand {
+	Show,
+	Save,
+	Restore,
+}
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// CCS811 baseline as stored in the state
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StoredBaseline {
+	pub value: u16,
+	/// unix time, None if saved by a version which did not track it
+	pub saved: Option<u64>,
+}
+
+impl StoredBaseline {
+	/// in days
+	pub fn age(&self, now: u64) -> Option<u64> {
+		self.saved
+			.map(|saved| now.saturating_sub(saved) / SECONDS_PER_DAY)
+	}
+
+	/// A baseline of unknown age is restored, as before it was tracked.
+	pub fn valid(&self, now: u64, validity_days: u16) -> bool {
+		match self.age(now) {
+			Some(age) => age <= u64::from(validity_days),
+			None => true,
+		}
+	}
+
+	fn describe(&self, now: u64, validity_days: u16) -> String {
+		match self.age(now) {
+			None => gettext!("{} (age unknown)", self.value),
+			Some(age) if self.valid(now, validity_days) => {
+				gettext!("{} ({} days old)", self.value, age)
+			}
+			Some(age) => gettext!("{} ({} days old, too old to be restored)", self.value, age),
+		}
+	}
 }
 
 fn set_env_data_ccs811(board5a: &mut LinuxI2CDevice, temperature: f32, humidity: f32) {
@@ -125,10 +236,9 @@ fn set_env_data_ccs811(board5a: &mut LinuxI2CDevice, temperature: f32, humidity:
 		.unwrap();
 }
 
-impl<'a> Environment<'a> {
-	pub fn new(config: &mut Config, state_mutex: Arc<Mutex<Config<'a>>>) -> Self {
-		let dev_name = config.get::<String>("environment/device");
-		if dev_name == "/dev/null" {
+impl Environment {
+	pub fn new(config: &EnvironmentConfig, state: StateStore) -> Self {
+		if config.device == "/dev/null" {
 			Self {
 				co2: 0,
 				voc: 0,
@@ -146,11 +256,16 @@ impl<'a> Environment<'a> {
 				first_time: true,
 				data_interval: 0,
 				baseline: 0,
-				name: config.get::<String>("environment/name"),
-				state_mutex,
+				name: config.name.clone(),
+				state,
+				baseline_interval: config.baseline_interval,
+				baseline_burnin: Duration::from_secs(u64::from(config.baseline_burnin) * 60),
+				baseline_validity: config.baseline_validity,
+				started: Instant::now(),
+				baseline_restored: false,
 			}
 		} else {
-			let i2c_bus = I2cdev::new(dev_name).unwrap();
+			let i2c_bus = I2cdev::new(&config.device).unwrap();
 			let mut s = Self {
 				co2: 0,
 				voc: 0,
@@ -163,16 +278,18 @@ impl<'a> Environment<'a> {
 				data: Vec::new(),
 				app_version: 0,
 				boot_version: 0,
-				board5a: Some(
-					LinuxI2CDevice::new(config.get::<String>("environment/device"), BOARD5A)
-						.unwrap(),
-				),
+				board5a: Some(LinuxI2CDevice::new(&config.device, BOARD5A).unwrap()),
 				bme280: Some(BME280::new_secondary(i2c_bus, Delay)),
 				first_time: true,
-				data_interval: config.get::<u16>("environment/data/interval"),
+				data_interval: config.data_interval,
 				baseline: 0,
-				name: config.get::<String>("environment/name"),
-				state_mutex,
+				name: config.name.clone(),
+				state,
+				baseline_interval: config.baseline_interval,
+				baseline_burnin: Duration::from_secs(u64::from(config.baseline_burnin) * 60),
+				baseline_validity: config.baseline_validity,
+				started: Instant::now(),
+				baseline_restored: false,
 			};
 			//if sending SW_RESET fails it disables ccs811
 			match s
@@ -255,23 +372,120 @@ impl<'a> Environment<'a> {
 		is_changed
 	}
 
-	/// go back to remembered state
-	async fn restore_baseline(&mut self) {
-		let mut state = self.state_mutex.lock().await;
-		match self.board5a.as_mut() {
-			None => (),
-			Some(board5a) => {
-				if let Some(baseline) = state.get_option::<u16>("environment/baseline") {
-					board5a.smbus_write_word_data(BASELINE, baseline).unwrap();
-				}
+	/// after the burn-in the CCS811 delivers a baseline worth saving
+	fn baseline_valid(&self) -> bool {
+		self.board5a.is_some()
+			&& self.baseline != 0
+			&& self.started.elapsed() >= self.baseline_burnin
+	}
+
+	async fn stored_baseline(&self) -> Result<Option<StoredBaseline>, ConfigError> {
+		let value = self.state.get_option::<u16>("environment/baseline").await?;
+		let saved = self
+			.state
+			.get_option::<u64>("environment/baseline/saved")
+			.await?;
+		Ok(value.map(|value| StoredBaseline { value, saved }))
+	}
+
+	/// go back to remembered state, unless it is too old
+	async fn restore_baseline(&mut self) -> Result<String, ConfigError> {
+		let now = unix_time();
+		let stored = self.stored_baseline().await?;
+		self.baseline_restored = true;
+		Ok(match (self.board5a.as_mut(), stored) {
+			(None, _) => gettext("No CCS811 connected, baseline not restored"),
+			(_, None) => gettext("No baseline stored yet"),
+			(Some(_), Some(stored)) if !stored.valid(now, self.baseline_validity) => {
+				gettext!(
+					"Baseline {} not restored",
+					stored.describe(now, self.baseline_validity)
+				)
+			}
+			(Some(board5a), Some(stored)) => {
+				board5a
+					.smbus_write_word_data(BASELINE, stored.value)
+					.unwrap();
+				gettext!(
+					"Restored baseline {}",
+					stored.describe(now, self.baseline_validity)
+				)
+			}
+		})
+	}
+
+	/// remember for later, written with the next flush of the state
+	fn remember_baseline(&mut self) -> String {
+		if self.board5a.is_none() {
+			return gettext("No CCS811 connected, baseline not saved");
+		}
+		if !self.baseline_valid() {
+			return gettext("Baseline not saved, the CCS811 is still burning in");
+		}
+		self.state
+			.set("environment/baseline", &self.baseline.to_string());
+		self.state
+			.set("environment/baseline/saved", &unix_time().to_string());
+		gettext!("Saved baseline {}", self.baseline)
+	}
+
+	/// every `environment/baseline/interval` days
+	async fn baseline_due(&self) -> Result<bool, ConfigError> {
+		if !self.baseline_valid() {
+			return Ok(false);
+		}
+		let saved = self
+			.state
+			.get_option::<u64>("environment/baseline/saved")
+			.await?;
+		Ok(match saved {
+			Some(saved) => {
+				unix_time().saturating_sub(saved)
+					>= u64::from(self.baseline_interval) * SECONDS_PER_DAY
 			}
+			None => true,
+		})
+	}
+
+	async fn show_baseline(&self) -> Result<String, ConfigError> {
+		let stored = match self.stored_baseline().await? {
+			Some(stored) => stored.describe(unix_time(), self.baseline_validity),
+			None => gettext("none"),
+		};
+		Ok(if self.baseline_valid() {
+			gettext!("Baseline: current {}, stored {}", self.baseline, stored)
+		} else {
+			gettext!(
+				"Baseline: current {} (burning in), stored {}",
+				self.baseline,
+				stored
+			)
+		})
+	}
+
+	async fn baseline_command(&mut self, command: BaselineCommand) -> Result<String, ConfigError> {
+		match command {
+			BaselineCommand::Show => self.show_baseline().await,
+			BaselineCommand::Save => Ok(self.remember_baseline()),
+			BaselineCommand::Restore => self.restore_baseline().await,
 		}
 	}
 
-	/// remember for later
-	async fn remember_baseline(&mut self) {
-		let mut state = self.state_mutex.lock().await;
-		state.set("environment/baseline", &self.baseline.to_string());
+	/// CO₂, VOC and baseline only with the CCS811
+	fn readings(&self) -> Vec<Reading> {
+		let mut readings = vec![
+			Reading::new("environment", "temperature", self.temperature.into(), "°C"),
+			Reading::new("environment", "humidity", self.humidity.into(), "%"),
+			Reading::new("environment", "pressure", self.pressure.into(), "Pa"),
+		];
+		if self.board5a.is_some() {
+			readings.extend([
+				Reading::new("environment", "co2", self.co2.into(), "ppm"),
+				Reading::new("environment", "voc", self.voc.into(), "ppb"),
+				Reading::new("environment", "baseline", self.baseline.into(), ""),
+			]);
+		}
+		readings
 	}
 
 	fn print_values(&self) -> String {
@@ -351,15 +565,40 @@ impl<'a> Environment<'a> {
 		}
 	}
 
+	/// Requests from the bus, e.g. `\baseline` from the chat.
+	async fn command(&mut self, env: EnvEvent, bus: &EventBus) {
+		match env {
+			EnvEvent::RememberBaseline => {
+				bus.send(Notification::debug(
+					"Environment",
+					NextcloudChat::Ping,
+					self.remember_baseline(),
+				));
+			}
+			EnvEvent::RestoreBaseline => {
+				// otherwise restored after the burn-in
+				if self.baseline_restored {
+					report_baseline(bus, NextcloudChat::Ping, self.restore_baseline().await);
+				}
+			}
+			EnvEvent::Baseline(command) => {
+				report_baseline(
+					bus,
+					NextcloudChat::Commands,
+					self.baseline_command(command).await,
+				);
+			}
+		}
+	}
+
 	pub async fn get_background_task(
 		mut self,
 		mut interval: Interval,
-		nextcloud_sender: Sender<NextcloudEvent>,
-		command_sender: Sender<CommandToButtons>,
-		audio_sender: Sender<AudioEvent>,
-		mut environment_receiver: Receiver<EnvEvent>,
+		bus: EventBus,
 		garage_enabled: bool,
+		shutdown: CancellationToken,
 	) -> Result<Never, ModuleError> {
+		let mut events = bus.subscribe();
 		let mut old_airquality = AirQualityChange::Error;
 		if self.board5a.is_some() {
 			sleep(Duration::from_millis(RESET_INTERVAL.into())).await;
@@ -367,26 +606,52 @@ impl<'a> Environment<'a> {
 		}
 
 		loop {
-			interval.tick().await;
-			if let Ok(env) = environment_receiver.try_recv() {
-				match env {
-					EnvEvent::RememberBaseline => {
-						self.remember_baseline().await;
+			select! {
+				_ = interval.tick() => (),
+				_ = shutdown.cancelled() => {
+					if self.baseline_valid() {
+						self.remember_baseline();
+					}
+					return Err(ModuleError::new(String::from("Environment shut down")));
+				}
+				// right away, not to fall behind on the bus until the next tick
+				Some(event) = events.recv() => {
+					if let Event::Env(env) = event {
+						self.command(env, &bus).await;
 					}
-					EnvEvent::RestoreBaseline => {
-						self.restore_baseline().await;
+					continue;
+				}
+			}
+
+			if !self.baseline_restored && self.started.elapsed() >= self.baseline_burnin {
+				report_baseline(&bus, NextcloudChat::Ping, self.restore_baseline().await);
+			} else {
+				match self.baseline_due().await {
+					Ok(true) => {
+						bus.send(Notification::debug(
+							"Environment",
+							NextcloudChat::Ping,
+							self.remember_baseline(),
+						));
 					}
+					Ok(false) => (),
+					Err(error) => report_state_error(&bus, Err(error)),
 				}
 			}
 
-			if self.handle() && self.air_quality != old_airquality {
+			let changed = self.handle();
+			if self.bme280.is_some() && self.air_quality != AirQualityChange::Error {
+				for reading in self.readings() {
+					bus.send(reading);
+				}
+			}
+			if changed && self.air_quality != old_airquality {
 				old_airquality = self.air_quality;
-				nextcloud_sender
-					.send(NextcloudEvent::Status(
-						NextcloudStatus::Env,
-						format!("💨 {:?}", self.air_quality),
-					))
-					.await?;
+				bus.send(NextcloudEvent::Status(
+					NextcloudStatus::Env,
+					format!("💨 {:?}", self.air_quality),
+				));
+				bus.send(Event::AirQualityChanged(self.air_quality));
 
 				match self.air_quality {
 					AirQualityChange::Error => {
@@ -396,62 +661,70 @@ impl<'a> Environment<'a> {
 							self.status,
 							self
 						);
-						nextcloud_sender
-							.send(NextcloudEvent::Chat(NextcloudChat::Default, error.clone()))
-							.await?;
+						bus.send(
+							Notification::warning(
+								"Environment",
+								NextcloudChat::Default,
+								error.clone(),
+							)
+							.category(Category::Hardware),
+						);
 						return Err(ModuleError::new(error));
 					}
 					AirQualityChange::Ok => {
-						nextcloud_sender
-							.send(NextcloudEvent::Chat(
+						bus.send(
+							Notification::info(
+								"Environment",
 								NextcloudChat::Default,
 								gettext!("💨 Airquality is ok. {}", self),
-							))
-							.await?;
+							)
+							.category(Category::Air),
+						);
 					}
 					AirQualityChange::Moderate => {
-						nextcloud_sender
-							.send(NextcloudEvent::Chat(
+						bus.send(
+							Notification::info(
+								"Environment",
 								NextcloudChat::Default,
 								gettext!("💩 Airquality is moderate. {}", self),
-							))
-							.await?;
+							)
+							.category(Category::Air),
+						);
 					}
 					AirQualityChange::Bad => {
-						nextcloud_sender
-							.send(NextcloudEvent::Chat(
+						bus.send(
+							Notification::warning(
+								"Environment",
 								NextcloudChat::Default,
 								gettext!("💩 Airquality is bad! {}", self),
-							))
-							.await?;
+							)
+							.category(Category::Air),
+						);
 					}
 
 					AirQualityChange::FireAlarm => {
-						let mut state = self.state_mutex.lock().await;
-						state.set("alarm/fire", &self.name);
+						report_state_error(
+							&bus,
+							self.state.set_now("alarm/fire", &self.name).await,
+						);
+						bus.send(Event::AlarmRaised(self.name.clone()));
 					}
 					AirQualityChange::FireBell => {
-						nextcloud_sender
-							.send(NextcloudEvent::Chat(
-								NextcloudChat::Default,
-								gettext!("🚨 Possible fire alarm! Ring bell once! ⏰. {}", self),
-							))
-							.await?;
+						bus.send(Notification::alarm(
+							"Environment",
+							gettext!("🚨 Possible fire alarm! Ring bell once! ⏰. {}", self),
+						));
 
-						command_sender
-							.send(CommandToButtons::RingBell(20, 0))
-							.await?;
+						bus.send(CommandToButtons::RingBell(20, 0));
 						if garage_enabled {
-							audio_sender.send(AudioEvent::FireAlarm).await?;
+							bus.send(AudioEvent::FireAlarm);
 						}
 					}
 					AirQualityChange::FireChat => {
-						nextcloud_sender
-							.send(NextcloudEvent::Chat(
-								NextcloudChat::Default,
-								gettext!("🚨 Possible fire alarm! (don't ring yet). {}", self),
-							))
-							.await?;
+						bus.send(Notification::alarm(
+							"Environment",
+							gettext!("🚨 Possible fire alarm! (don't ring yet). {}", self),
+						));
 					}
 				};
 			}
@@ -459,12 +732,73 @@ impl<'a> Environment<'a> {
 	}
 }
 
-impl fmt::Display for Environment<'_> {
+fn report_baseline(bus: &EventBus, chat: NextcloudChat, result: Result<String, ConfigError>) {
+	match result {
+		Ok(message) if chat == NextcloudChat::Ping => {
+			bus.send(Notification::debug("Environment", chat, message))
+		}
+		Ok(message) => bus.send(Notification::info("Environment", chat, message)),
+		Err(error) => report_state_error(bus, Err(error)),
+	}
+}
+
+/// the state could not be read or written, e.g. Elektra did not answer in time
+fn report_state_error(bus: &EventBus, result: Result<(), ConfigError>) {
+	if let Err(error) = result {
+		bus.send(Notification::warning(
+			"Environment",
+			NextcloudChat::Ping,
+			gettext!("Couldn't access state {}", error),
+		));
+	}
+}
+
+impl fmt::Display for Environment {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		write!(f, "{}. {}", self.name, self.print_values())
 	}
 }
 
+pub struct EnvironmentModule;
+
+impl Module for EnvironmentModule {
+	fn name(&self) -> &'static str {
+		"Environment"
+	}
+
+	fn enabled(&self, config: &mut Config) -> Result<bool, ConfigError> {
+		config.value::<bool>("environment/enable")
+	}
+
+	fn hardware(&self) -> bool {
+		true
+	}
+
+	fn config_keys(&self) -> &'static [&'static str] {
+		&["environment", "garage/enable"]
+	}
+
+	fn check_config(&self, config: &mut Config) -> Result<(), Vec<ConfigError>> {
+		config.check::<EnvironmentConfig>("")
+	}
+
+	fn start(&self, context: Context) -> BoxFuture<'static, Result<Never, ModuleError>> {
+		async move {
+			let config: EnvironmentConfig = context.config("").await?;
+			let interval = interval(Duration::from_secs(config.data_interval.into()));
+			Environment::get_background_task(
+				Environment::new(&config, context.state),
+				interval,
+				context.bus,
+				config.garage_enable,
+				context.shutdown,
+			)
+			.await
+		}
+		.boxed()
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	// Note this useful idiom: importing names from outer (for mod tests) scope.
@@ -489,4 +823,23 @@ mod tests {
 			Environment::convert_env_data(23.5f32, 48.5f32)
 		);
 	}
+
+	#[test]
+	fn test_stored_baseline() {
+		let now = 100 * SECONDS_PER_DAY;
+		let stored = StoredBaseline {
+			value: 0x8453,
+			saved: Some(now - 10 * SECONDS_PER_DAY - 1),
+		};
+		assert_eq!(stored.age(now), Some(10));
+		assert!(stored.valid(now, 30));
+		assert!(!stored.valid(now, 7));
+		assert_eq!(stored.describe(now, 30), "33875 (10 days old)");
+		let unknown = StoredBaseline {
+			value: 0x8453,
+			saved: None,
+		};
+		assert_eq!(unknown.age(now), None);
+		assert!(unknown.valid(now, 7));
+	}
 }
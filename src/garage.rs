@@ -1,12 +1,16 @@
-use futures::never::Never;
+use futures::{future::BoxFuture, never::Never, FutureExt};
 use gpio_cdev::{Chip, LineHandle, LineRequestFlags};
+use serde::Deserialize;
 use systemstat::Duration;
-use tokio::{sync::mpsc::Sender, time::interval};
+use tokio::time::interval;
 
 use crate::{
+	bus::{Event, EventBus},
 	buttons::CommandToButtons,
-	config::Config,
+	config::{validate::Validate, Config, ConfigError},
+	module::{Context, Module},
 	nextcloud::{NextcloudChat, NextcloudEvent, NextcloudStatus},
+	notify::Notification,
 	types::ModuleError,
 };
 
@@ -48,10 +52,32 @@ pub struct Garage {
 	schalter_tor_endposition: bool,
 }
 
+/// Deserialized from the top of the configuration, as it also needs `buttons/enable`.
+#[derive(Deserialize)]
+pub struct GarageConfig {
+	#[serde(rename = "garage/enable")]
+	pub enable: bool,
+	#[serde(rename = "buttons/enable")]
+	pub buttons_enable: bool,
+}
+
+impl Validate for GarageConfig {
+	fn validate(&self) -> Vec<ConfigError> {
+		if self.enable && !self.buttons_enable {
+			vec![ConfigError {
+				key: String::from("garage/enable"),
+				message: String::from("Garage depends on buttons, needs buttons/enable = 1"),
+			}]
+		} else {
+			Vec::new()
+		}
+	}
+}
+
 impl Garage {
-	pub fn new(config: &mut Config) -> Self {
+	pub fn new(config: &GarageConfig) -> Self {
 		Self {
-			line_handles: if config.get_bool("garage/enable") {
+			line_handles: if config.enable {
 				let mut chip = Chip::new("/dev/gpiochip0").unwrap();
 				Some(LineHandles {
 					taster_eingang_oben_line: chip
@@ -153,64 +179,54 @@ impl Garage {
 	/// So we dont need to run it all few seconds.
 	pub async fn get_background_task(
 		mut garage: Garage,
-		command_sender: Sender<CommandToButtons>,
-		nextcloud_sender: Sender<NextcloudEvent>,
+		bus: EventBus,
 	) -> Result<Never, ModuleError> {
 		let mut interval = interval(Duration::from_millis(10));
 		loop {
 			match garage.handle() {
 				GarageChange::None => (),
 				GarageChange::PressedTasterEingangOben => {
-					command_sender
-						.send(CommandToButtons::SwitchLights(
-							true,
-							false,
-							"💡 Pressed at entrance top switch. Switch lights in garage"
-								.to_string(),
-						))
-						.await?;
+					bus.send(CommandToButtons::SwitchLights(
+						true,
+						false,
+						"💡 Pressed at entrance top switch. Switch lights in garage".to_string(),
+					));
 				}
 				GarageChange::PressedTasterTorOben => {
-					command_sender
-						.send(CommandToButtons::SwitchLights(
-							true,
-							true,
-							"💡 Pressed top switch at garage door. Switch lights in and out garage"
-								.to_string(),
-						))
-						.await?;
+					bus.send(CommandToButtons::SwitchLights(
+						true,
+						true,
+						"💡 Pressed top switch at garage door. Switch lights in and out garage"
+							.to_string(),
+					));
 				}
 				GarageChange::PressedTasterEingangUnten | GarageChange::PressedTasterTorUnten => {
-					command_sender.send(CommandToButtons::OpenDoor).await?;
+					bus.send(CommandToButtons::OpenDoor);
 				}
 
 				GarageChange::ReachedTorEndposition => {
-					nextcloud_sender
-						.send(NextcloudEvent::Status(
-							NextcloudStatus::Door,
-							String::from("🔒 Closed"),
-						))
-						.await?;
-					nextcloud_sender
-						.send(NextcloudEvent::Chat(
-							NextcloudChat::Default,
-							String::from("🔒 Garage door closed."),
-						))
-						.await?;
+					bus.send(Event::GarageDoorClosed(true));
+					bus.send(NextcloudEvent::Status(
+						NextcloudStatus::Door,
+						String::from("🔒 Closed"),
+					));
+					bus.send(Notification::info(
+						"Garage",
+						NextcloudChat::Default,
+						String::from("🔒 Garage door closed."),
+					));
 				}
 				GarageChange::LeftTorEndposition => {
-					nextcloud_sender
-						.send(NextcloudEvent::Status(
-							NextcloudStatus::Door,
-							String::from("🔓 Open"),
-						))
-						.await?;
-					nextcloud_sender
-						.send(NextcloudEvent::Chat(
-							NextcloudChat::Default,
-							String::from("🔓 Garage door open"),
-						))
-						.await?;
+					bus.send(Event::GarageDoorClosed(false));
+					bus.send(NextcloudEvent::Status(
+						NextcloudStatus::Door,
+						String::from("🔓 Open"),
+					));
+					bus.send(Notification::info(
+						"Garage",
+						NextcloudChat::Default,
+						String::from("🔓 Garage door open"),
+					));
 				}
 			}
 			interval.tick().await;
@@ -218,6 +234,38 @@ impl Garage {
 	}
 }
 
+pub struct GarageModule;
+
+impl Module for GarageModule {
+	fn name(&self) -> &'static str {
+		"Garage"
+	}
+
+	fn enabled(&self, config: &mut Config) -> Result<bool, ConfigError> {
+		config.value::<bool>("garage/enable")
+	}
+
+	fn hardware(&self) -> bool {
+		true
+	}
+
+	fn config_keys(&self) -> &'static [&'static str] {
+		&["garage", "buttons/enable"]
+	}
+
+	fn check_config(&self, config: &mut Config) -> Result<(), Vec<ConfigError>> {
+		config.check::<GarageConfig>("")
+	}
+
+	fn start(&self, context: Context) -> BoxFuture<'static, Result<Never, ModuleError>> {
+		async move {
+			let config: GarageConfig = context.config("").await?;
+			Garage::get_background_task(Garage::new(&config), context.bus).await
+		}
+		.boxed()
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -232,7 +280,7 @@ mod tests {
 
 		env::set_var("RUST_BACKTRACE", config.get::<String>("debug/backtrace"));
 
-		let mut garage = Garage::new(&mut config);
+		let mut garage = Garage::new(&config.deserialize("").unwrap());
 
 		loop {
 			match garage.handle() {
@@ -0,0 +1,590 @@
+//! History of the readings in ring buffer files, with three resolutions:
+//! `history/raw` seconds for a day, minutes for a week and hours for a year,
+//! see doc/History.md.
+//!
+//! Every series (e.g. `environment/co2`) and resolution has its own file of
+//! fixed size with one slot per time bucket, the mean of the readings in it.
+//! Slots are only written when they changed, together every
+//! `history/flush/interval` and on shutdown, to spare the SD card.
+
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	fs::{self, OpenOptions},
+	io,
+	os::unix::fs::FileExt,
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use chrono::{Local, TimeZone};
+use futures::{future::BoxFuture, never::Never, FutureExt};
+use serde::Deserialize;
+use tokio::{select, task::spawn_blocking, time::interval};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+	bus::{Event, EventBus},
+	config::{Config, ConfigError},
+	log,
+	log::Level,
+	module::{Context, Module},
+	readings::{unix_time, Reading},
+	types::ModuleError,
+};
+
+const DAY: u64 = 24 * 60 * 60;
+/// as in the spec of `history/raw`, all divide a day
+const RAW_RESOLUTIONS: [u64; 6] = [1, 5, 10, 15, 30, 60];
+/// bytes per slot: start of the bucket (u32), count (u32), mean (f64)
+const SLOT_SIZE: usize = 16;
+/// characters of the sparkline in the chat
+const SPARKLINE_WIDTH: usize = 24;
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// name (file extension), seconds per slot, covered time
+fn tiers(raw: u64) -> [(&'static str, u64, u64); 3] {
+	[
+		("raw", raw, DAY),
+		("minute", 60, 7 * DAY),
+		("hour", 60 * 60, 365 * DAY),
+	]
+}
+
+/// e.g. `30m`, `24h`, `7d`, `1y` in seconds
+pub fn parse_range(range: &str) -> Option<u64> {
+	let unit = match range.chars().last()? {
+		'm' => 60,
+		'h' => 60 * 60,
+		'd' => DAY,
+		'w' => 7 * DAY,
+		'y' => 365 * DAY,
+		_ => return None,
+	};
+	let count = range[..range.len() - 1].parse::<u64>().ok()?;
+	count.checked_mul(unit).filter(|seconds| *seconds > 0)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Slot {
+	/// start of the bucket, 0 if empty
+	time: u32,
+	count: u32,
+	mean: f64,
+}
+
+impl Slot {
+	fn to_bytes(self) -> [u8; SLOT_SIZE] {
+		let mut bytes = [0; SLOT_SIZE];
+		bytes[0..4].copy_from_slice(&self.time.to_le_bytes());
+		bytes[4..8].copy_from_slice(&self.count.to_le_bytes());
+		bytes[8..16].copy_from_slice(&self.mean.to_le_bytes());
+		bytes
+	}
+
+	fn from_bytes(bytes: &[u8]) -> Self {
+		Self {
+			time: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+			count: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+			mean: f64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+		}
+	}
+}
+
+#[derive(Debug)]
+struct Ring {
+	resolution: u64,
+	slots: Vec<Slot>,
+	/// slots to write with the next flush
+	dirty: BTreeSet<usize>,
+}
+
+impl Ring {
+	fn new(resolution: u64, span: u64) -> Self {
+		Self {
+			resolution,
+			slots: vec![Slot::default(); (span / resolution) as usize],
+			dirty: BTreeSet::new(),
+		}
+	}
+
+	fn add(&mut self, time: u64, value: f64) {
+		let bucket = time - time % self.resolution;
+		let index = (bucket / self.resolution) as usize % self.slots.len();
+		let slot = &mut self.slots[index];
+		if u64::from(slot.time) != bucket {
+			*slot = Slot {
+				time: bucket as u32,
+				count: 0,
+				mean: 0.0,
+			};
+		}
+		slot.count += 1;
+		slot.mean += (value - slot.mean) / f64::from(slot.count);
+		self.dirty.insert(index);
+	}
+
+	/// (time, mean) since `from`, oldest first
+	fn values(&self, from: u64) -> Vec<(u64, f64)> {
+		let mut values = self
+			.slots
+			.iter()
+			.filter(|slot| slot.time != 0 && u64::from(slot.time) >= from)
+			.map(|slot| (u64::from(slot.time), slot.mean))
+			.collect::<Vec<(u64, f64)>>();
+		values.sort_by_key(|(time, _)| *time);
+		values
+	}
+
+	/// A file of another size (e.g. `history/raw` changed) is ignored.
+	fn load(&mut self, path: &Path) -> io::Result<()> {
+		let bytes = fs::read(path)?;
+		if bytes.len() != self.slots.len() * SLOT_SIZE {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				format!("{} has an unexpected size", path.display()),
+			));
+		}
+		for (slot, bytes) in self.slots.iter_mut().zip(bytes.chunks(SLOT_SIZE)) {
+			*slot = Slot::from_bytes(bytes);
+		}
+		Ok(())
+	}
+
+	fn flush(&mut self, path: &Path) -> io::Result<()> {
+		if self.dirty.is_empty() {
+			return Ok(());
+		}
+		let file = OpenOptions::new()
+			.create(true)
+			.write(true)
+			.truncate(false)
+			.open(path)?;
+		let size = (self.slots.len() * SLOT_SIZE) as u64;
+		if file.metadata()?.len() != size {
+			file.set_len(0)?;
+			file.set_len(size)?;
+			self.dirty = (0..self.slots.len()).collect();
+		}
+		for index in &self.dirty {
+			file.write_all_at(&self.slots[*index].to_bytes(), (index * SLOT_SIZE) as u64)?;
+		}
+		self.dirty.clear();
+		Ok(())
+	}
+}
+
+#[derive(Debug)]
+struct Series {
+	/// empty until the first reading since the start
+	unit: String,
+	rings: Vec<(&'static str, Ring)>,
+}
+
+#[derive(Default)]
+struct Store {
+	/// None while the History module does not run
+	dir: Option<PathBuf>,
+	raw: u64,
+	series: BTreeMap<String, Series>,
+}
+
+impl Store {
+	fn series(&mut self, key: &str) -> &mut Series {
+		let raw = self.raw;
+		self.series
+			.entry(key.to_string())
+			.or_insert_with(|| Series {
+				unit: String::new(),
+				rings: tiers(raw)
+					.into_iter()
+					.map(|(name, resolution, span)| (name, Ring::new(resolution, span)))
+					.collect(),
+			})
+	}
+}
+
+/// Shared by the History module, which records, and the commands, which query.
+#[derive(Clone, Default)]
+pub struct History {
+	store: Arc<Mutex<Store>>,
+}
+
+impl History {
+	/// Loads the files in `dir`, e.g. `dir/environment/co2.raw`.
+	/// What was not written before, e.g. before a restart of the module, is written first.
+	pub fn open(&self, dir: &Path, raw: u64) -> io::Result<()> {
+		if let Err(error) = self.flush() {
+			log!(Level::Error, "Could not write history: {}", error);
+		}
+		fs::create_dir_all(dir)?;
+		let mut store = self.store.lock().unwrap();
+		store.dir = Some(dir.to_path_buf());
+		store.raw = raw;
+		store.series.clear();
+		for source in fs::read_dir(dir)? {
+			let source = source?.path();
+			if !source.is_dir() {
+				continue;
+			}
+			for file in fs::read_dir(&source)? {
+				let path = file?.path();
+				let (Some(source), Some(name), Some(tier)) = (
+					source.file_name().and_then(|name| name.to_str()),
+					path.file_stem().and_then(|name| name.to_str()),
+					path.extension().and_then(|tier| tier.to_str()),
+				) else {
+					continue;
+				};
+				let key = format!("{}/{}", source, name);
+				let series = store.series(&key);
+				if let Some((_, ring)) = series.rings.iter_mut().find(|(name, _)| *name == tier) {
+					if let Err(error) = ring.load(&path) {
+						log!(Level::Warning, "Ignored history {}", error);
+					}
+				}
+			}
+		}
+		Ok(())
+	}
+
+	pub fn add(&self, reading: &Reading, time: u64) {
+		let mut store = self.store.lock().unwrap();
+		if store.dir.is_none() {
+			return;
+		}
+		let series = store.series(&reading.key());
+		if series.unit != reading.unit {
+			series.unit = reading.unit.to_string();
+		}
+		for (_, ring) in &mut series.rings {
+			ring.add(time, reading.value);
+		}
+	}
+
+	/// Writes the changed slots, blocking.
+	pub fn flush(&self) -> io::Result<()> {
+		let mut store = self.store.lock().unwrap();
+		let Some(dir) = store.dir.clone() else {
+			return Ok(());
+		};
+		for (key, series) in &mut store.series {
+			let path = dir.join(key);
+			fs::create_dir_all(path.parent().unwrap())?;
+			for (tier, ring) in &mut series.rings {
+				ring.flush(&path.with_extension(tier))?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Key of the series, the full key or an unambiguous name, e.g. `co2` for `environment/co2`.
+	pub fn find(&self, name: &str) -> Result<String, String> {
+		let store = self.store.lock().unwrap();
+		if store.dir.is_none() {
+			return Err(String::from("History is not enabled"));
+		}
+		if store.series.contains_key(name) {
+			return Ok(name.to_string());
+		}
+		let found = store
+			.series
+			.keys()
+			.filter(|key| key.rsplit('/').next() == Some(name))
+			.collect::<Vec<&String>>();
+		match found.as_slice() {
+			[key] => Ok(key.to_string()),
+			[] => Err(format!(
+				"No history for {}, known are: {}",
+				name,
+				store
+					.series
+					.keys()
+					.cloned()
+					.collect::<Vec<String>>()
+					.join(", ")
+			)),
+			keys => Err(format!(
+				"{} is ambiguous: {}",
+				name,
+				keys.iter()
+					.map(|key| key.as_str())
+					.collect::<Vec<&str>>()
+					.join(", ")
+			)),
+		}
+	}
+
+	/// (time, mean) of the last `range` seconds before `now`, from the finest
+	/// resolution covering the range, and the unit.
+	fn values(&self, key: &str, range: u64, now: u64) -> (Vec<(u64, f64)>, String) {
+		let store = self.store.lock().unwrap();
+		let Some(series) = store.series.get(key) else {
+			return (Vec::new(), String::new());
+		};
+		let tiers = tiers(store.raw);
+		let index = tiers
+			.iter()
+			.position(|(_, _, span)| range <= *span)
+			.unwrap_or(tiers.len() - 1);
+		let values = series.rings[index].1.values(now.saturating_sub(range));
+		(values, series.unit.clone())
+	}
+
+	/// min, avg, max and latest value, with a sparkline
+	pub fn summary(&self, name: &str, range: u64, now: u64) -> Result<String, String> {
+		let key = self.find(name)?;
+		let (values, unit) = self.values(&key, range, now);
+		let Some((_, latest)) = values.last() else {
+			return Ok(format!("No values for {} in this time", key));
+		};
+		let means = values.iter().map(|(_, mean)| *mean).collect::<Vec<f64>>();
+		let min = means.iter().cloned().fold(f64::INFINITY, f64::min);
+		let max = means.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+		let avg = means.iter().sum::<f64>() / means.len() as f64;
+		Ok(format!(
+			"{}: min {:.1} avg {:.1} max {:.1} latest {:.1} {}\n{}",
+			key,
+			min,
+			avg,
+			max,
+			latest,
+			unit,
+			sparkline(&means, min, max)
+		)
+		.replace(" \n", "\n"))
+	}
+
+	/// `time,value` with the local time in RFC 3339
+	pub fn csv(&self, name: &str, range: u64, now: u64) -> Result<String, String> {
+		let key = self.find(name)?;
+		let (values, _unit) = self.values(&key, range, now);
+		let mut csv = String::from("time,value\n");
+		for (time, mean) in values {
+			if let Some(time) = Local.timestamp_opt(time as i64, 0).single() {
+				csv += &format!("{},{}\n", time.to_rfc3339(), mean);
+			}
+		}
+		Ok(csv)
+	}
+
+	pub async fn get_background_task(
+		self,
+		bus: EventBus,
+		flush_interval: Duration,
+		shutdown: CancellationToken,
+	) -> Result<Never, ModuleError> {
+		let mut events = bus.subscribe_readings();
+		let mut flush = interval(flush_interval);
+		flush.tick().await;
+		loop {
+			select! {
+				event = events.recv() => match event {
+					Some(Event::Reading(reading)) => self.add(&reading, unix_time()),
+					Some(_) => {}
+					None => break,
+				},
+				_ = flush.tick() => {
+					let history = self.clone();
+					spawn_blocking(move || history.flush())
+						.await
+						.map_err(|error| ModuleError::new(error.to_string()))??;
+				}
+				_ = shutdown.cancelled() => {
+					// blocking like `Module::shutdown`, also on restarts of the module
+					if let Err(error) = self.flush() {
+						log!(Level::Error, "Could not write history: {}", error);
+					}
+					return Err(ModuleError::new(String::from("History shut down")));
+				}
+			}
+		}
+		Err(ModuleError::new(String::from(
+			"history background task exited",
+		)))
+	}
+}
+
+/// one character per part of the values, e.g. `▁▂▅█▅`
+fn sparkline(values: &[f64], min: f64, max: f64) -> String {
+	let parts = values.len().min(SPARKLINE_WIDTH);
+	(0..parts)
+		.map(|part| {
+			let chunk = &values[part * values.len() / parts..(part + 1) * values.len() / parts];
+			let mean = chunk.iter().sum::<f64>() / chunk.len() as f64;
+			let level = if max > min {
+				((mean - min) / (max - min) * (SPARKS.len() - 1) as f64).round() as usize
+			} else {
+				0
+			};
+			SPARKS[level.min(SPARKS.len() - 1)]
+		})
+		.collect()
+}
+
+#[derive(Deserialize)]
+struct HistoryConfig {
+	dir: String,
+	raw: u64,
+	#[serde(rename = "flush/interval")]
+	flush_interval: u64,
+}
+
+pub struct HistoryModule;
+
+impl Module for HistoryModule {
+	fn name(&self) -> &'static str {
+		"History"
+	}
+
+	fn enabled(&self, config: &mut Config) -> Result<bool, ConfigError> {
+		config.value::<bool>("history/enable")
+	}
+
+	fn config_keys(&self) -> &'static [&'static str] {
+		&["history"]
+	}
+
+	fn start(&self, context: Context) -> BoxFuture<'static, Result<Never, ModuleError>> {
+		async move {
+			let config: HistoryConfig = context.config("history").await?;
+			let dir = context.instance.history_dir(&config.dir);
+			let raw = config.raw;
+			if !RAW_RESOLUTIONS.contains(&raw) {
+				return Err(ModuleError::new(format!(
+					"history/raw {} is not one of {:?}",
+					raw, RAW_RESOLUTIONS
+				)));
+			}
+			let flush_interval = Duration::from_secs(config.flush_interval);
+			let history = context.history.clone();
+			let open = history.clone();
+			spawn_blocking(move || open.open(&dir, raw))
+				.await
+				.map_err(|error| ModuleError::new(error.to_string()))??;
+			history
+				.get_background_task(context.bus, flush_interval, context.shutdown)
+				.await
+		}
+		.boxed()
+	}
+
+	fn shutdown(&self, context: &Context) {
+		if let Err(error) = context.history.flush() {
+			log!(Level::Error, "Could not write history: {}", error);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::process;
+
+	use super::*;
+
+	const NOW: u64 = 1_700_000_000;
+
+	fn dir(name: &str) -> PathBuf {
+		std::env::temp_dir().join(format!("opensesame-history-{}-{}", name, process::id()))
+	}
+
+	#[test]
+	fn test_parse_range() {
+		assert_eq!(parse_range("24h"), Some(DAY));
+		assert_eq!(parse_range("7d"), Some(7 * DAY));
+		assert_eq!(parse_range("30m"), Some(30 * 60));
+		assert_eq!(parse_range("0h"), None);
+		assert_eq!(parse_range("h"), None);
+		assert_eq!(parse_range("24"), None);
+		assert_eq!(parse_range("99999999999999y"), None);
+		assert_eq!(parse_range("1000y"), Some(1000 * 365 * DAY));
+	}
+
+	/// as in doc/History.md
+	#[test]
+	fn test_size() {
+		let size = |raw| {
+			tiers(raw)
+				.iter()
+				.map(|(_, resolution, span)| span / resolution * SLOT_SIZE as u64)
+				.sum::<u64>()
+		};
+		assert_eq!(size(10), 439_680);
+		assert_eq!(size(1), 1_683_840);
+	}
+
+	#[test]
+	fn test_ring() {
+		let mut ring = Ring::new(60, 60 * 60);
+		ring.add(NOW, 1.0);
+		ring.add(NOW + 1, 3.0);
+		ring.add(NOW + 60, 5.0);
+		// an hour later the slot gets reused
+		ring.add(NOW + 60 * 60, 7.0);
+		let bucket = NOW - NOW % 60;
+		assert_eq!(
+			ring.values(0),
+			vec![(bucket + 60, 5.0), (bucket + 60 * 60, 7.0)]
+		);
+		assert_eq!(ring.values(bucket + 61), vec![(bucket + 60 * 60, 7.0)]);
+	}
+
+	#[test]
+	fn test_history() {
+		let dir = dir("test");
+		let history = History::default();
+		history.open(&dir, 10).unwrap();
+		let co2 = |value| Reading::new("environment", "co2", value, "ppm");
+		for minute in 0..120 {
+			history.add(&co2(400.0 + minute as f64), NOW - 120 * 60 + minute * 60);
+		}
+		history.add(&Reading::new("modir", "object", 21.0, "°C"), NOW);
+		history.flush().unwrap();
+		assert!(dir.join("environment/co2.raw").exists());
+
+		let reopened = History::default();
+		reopened.open(&dir, 10).unwrap();
+		assert_eq!(reopened.find("co2"), Ok(String::from("environment/co2")));
+		assert!(reopened.find("humidity").is_err());
+		let summary = reopened.summary("co2", 60 * 60, NOW).unwrap();
+		assert!(summary.starts_with("environment/co2: min 460.0 avg 489.5 max 519.0 latest 519.0"));
+		let csv = reopened.csv("environment/co2", 30 * 60, NOW).unwrap();
+		assert_eq!(csv.lines().count(), 1 + 30);
+		assert!(csv.lines().last().unwrap().ends_with(",519"));
+		// a week falls back to minutes, with the same values
+		assert_eq!(
+			reopened.csv("co2", 7 * DAY, NOW).unwrap().lines().count(),
+			1 + 120
+		);
+
+		fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[test]
+	fn test_reopen() {
+		let dir = dir("reopen");
+		let history = History::default();
+		history.open(&dir, 10).unwrap();
+		history.add(&Reading::new("environment", "co2", 400.0, "ppm"), NOW);
+		// e.g. `module restart History`, without a flush in between
+		history.open(&dir, 10).unwrap();
+		assert!(history
+			.summary("co2", 60 * 60, NOW)
+			.unwrap()
+			.contains("latest 400.0"));
+		fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[test]
+	fn test_disabled() {
+		let history = History::default();
+		history.add(&Reading::new("environment", "co2", 400.0, "ppm"), NOW);
+		assert!(history.find("co2").is_err());
+	}
+
+	#[test]
+	fn test_sparkline() {
+		assert_eq!(sparkline(&[0.0, 1.0, 2.0, 7.0], 0.0, 7.0), "▁▂▃█");
+		assert_eq!(sparkline(&[5.0; 48], 5.0, 5.0).chars().count(), 24);
+	}
+}
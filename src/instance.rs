@@ -0,0 +1,229 @@
+//! Several opensesame instances on one host, e.g. the garage and the house door
+//! side by side for testing. An instance is named by its number and profile,
+//! which select its configuration and state, see `--instance` and `--profile`.
+
+use std::{
+	collections::BTreeMap,
+	fmt, fs, io,
+	path::{Path, PathBuf},
+	process,
+};
+
+use nix::{
+	sys::signal::{kill, Signal},
+	unistd::Pid,
+};
+use serde::Deserialize;
+
+const PROCESS_NAME: &str = "opensesame";
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Instance {
+	#[serde(rename = "instance")]
+	pub number: u16,
+	pub profile: String,
+}
+
+/// signals which may be sent with `--signal`
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum InstanceSignal {
+	Hup,
+	Usr1,
+	Usr2,
+	Alrm,
+	Term,
+	Int,
+}
+
+impl From<InstanceSignal> for Signal {
+	fn from(signal: InstanceSignal) -> Self {
+		match signal {
+			InstanceSignal::Hup => Signal::SIGHUP,
+			InstanceSignal::Usr1 => Signal::SIGUSR1,
+			InstanceSignal::Usr2 => Signal::SIGUSR2,
+			InstanceSignal::Alrm => Signal::SIGALRM,
+			InstanceSignal::Term => Signal::SIGTERM,
+			InstanceSignal::Int => Signal::SIGINT,
+		}
+	}
+}
+
+impl Default for Instance {
+	fn default() -> Self {
+		Self::new(0, "current")
+	}
+}
+
+impl fmt::Display for Instance {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "#{}/{}", self.number, self.profile)
+	}
+}
+
+impl Instance {
+	pub fn new(number: u16, profile: &str) -> Self {
+		Self {
+			number,
+			profile: profile.to_string(),
+		}
+	}
+
+	/// From `cli/instance` and `cli/profile`, which are needed before the configuration can be read.
+	pub fn from_arguments(arguments: &BTreeMap<String, String>) -> Result<Self, String> {
+		let number = match arguments.get("cli/instance") {
+			Some(number) => number
+				.parse()
+				.map_err(|_| format!("'{}' is not a valid instance number", number))?,
+			None => 0,
+		};
+		let profile = arguments
+			.get("cli/profile")
+			.map_or("current", String::as_str);
+		if profile.is_empty() || profile.contains('/') {
+			return Err(format!("'{}' is not a valid profile", profile));
+		}
+		Ok(Self::new(number, profile))
+	}
+
+	pub fn config_parent(&self) -> String {
+		format!(
+			"/sw/libelektra/opensesame/#{}/{}",
+			self.number, self.profile
+		)
+	}
+
+	pub fn state_parent(&self) -> String {
+		format!(
+			"/state/libelektra/opensesame/#{}/{}",
+			self.number, self.profile
+		)
+	}
+
+	pub fn pid_file(&self, runtime: &str) -> PathBuf {
+		Path::new(runtime).join(format!("{}-{}.pid", self.number, self.profile))
+	}
+
+	/// see `opensesamectl`
+	pub fn control_socket(&self, runtime: &str) -> PathBuf {
+		Path::new(runtime).join(format!("{}-{}.sock", self.number, self.profile))
+	}
+
+	/// state not yet written to Elektra, see `StateStore`
+	pub fn state_file(&self, runtime: &str) -> PathBuf {
+		Path::new(runtime).join(format!("{}-{}.state.json", self.number, self.profile))
+	}
+
+	/// messages not yet delivered to Nextcloud, see `Outbox`
+	pub fn outbox_file(&self, dir: &str) -> PathBuf {
+		Path::new(dir).join(format!("{}-{}.outbox.json", self.number, self.profile))
+	}
+
+	/// ring buffer files of the readings, see `History`
+	pub fn history_dir(&self, dir: &str) -> PathBuf {
+		Path::new(dir).join(format!("{}-{}", self.number, self.profile))
+	}
+
+	/// PID of the running instance, None if it is not running (anymore)
+	pub fn running(&self, runtime: &str) -> Option<Pid> {
+		let pid = fs::read_to_string(self.pid_file(runtime))
+			.ok()?
+			.trim()
+			.parse()
+			.ok()?;
+		// the PID might have been reused after a crash
+		let name = fs::read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+		(name.trim() == PROCESS_NAME).then(|| Pid::from_raw(pid))
+	}
+
+	/// Fails if the same instance is already running.
+	pub fn write_pid_file(&self, runtime: &str) -> Result<(), String> {
+		if let Some(pid) = self.running(runtime) {
+			if pid != Pid::this() {
+				return Err(format!("Instance {} is already running as {}", self, pid));
+			}
+		}
+		fs::create_dir_all(runtime)
+			.and_then(|()| fs::write(self.pid_file(runtime), process::id().to_string()))
+			.map_err(|error| {
+				format!(
+					"Could not write {}: {}",
+					self.pid_file(runtime).display(),
+					error
+				)
+			})
+	}
+
+	pub fn remove_pid_file(&self, runtime: &str) -> io::Result<()> {
+		fs::remove_file(self.pid_file(runtime))
+	}
+
+	/// instead of `killall`, which would signal all instances
+	pub fn signal(&self, runtime: &str, signal: InstanceSignal) -> Result<(), String> {
+		let pid = self
+			.running(runtime)
+			.ok_or_else(|| format!("Instance {} is not running", self))?;
+		kill(pid, Signal::from(signal))
+			.map_err(|error| format!("Could not signal instance {}: {}", self, error))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_from_arguments() {
+		assert_eq!(
+			Instance::from_arguments(&BTreeMap::new()),
+			Ok(Instance::default())
+		);
+		let instance = Instance::from_arguments(&BTreeMap::from([
+			(String::from("cli/instance"), String::from("1")),
+			(String::from("cli/profile"), String::from("garage")),
+		]))
+		.unwrap();
+		assert_eq!(instance.to_string(), "#1/garage");
+		assert_eq!(
+			instance.config_parent(),
+			"/sw/libelektra/opensesame/#1/garage"
+		);
+		assert_eq!(
+			instance.state_parent(),
+			"/state/libelektra/opensesame/#1/garage"
+		);
+		assert_eq!(
+			instance.outbox_file("/var/lib/opensesame"),
+			PathBuf::from("/var/lib/opensesame/1-garage.outbox.json")
+		);
+		assert_eq!(
+			instance.history_dir("/var/lib/opensesame/history"),
+			PathBuf::from("/var/lib/opensesame/history/1-garage")
+		);
+		assert!(Instance::from_arguments(&BTreeMap::from([(
+			String::from("cli/instance"),
+			String::from("-1")
+		)]))
+		.is_err());
+		assert!(Instance::from_arguments(&BTreeMap::from([(
+			String::from("cli/profile"),
+			String::from("a/b")
+		)]))
+		.is_err());
+	}
+
+	#[test]
+	fn test_pid_file() {
+		let runtime = std::env::temp_dir().join(format!("opensesame-test-{}", process::id()));
+		let runtime = runtime.to_str().unwrap();
+		let garage = Instance::new(0, "garage");
+		let door = Instance::new(0, "door");
+		assert_eq!(garage.running(runtime), None);
+		garage.write_pid_file(runtime).unwrap();
+		// the test binary is not called opensesame
+		assert_eq!(garage.running(runtime), None);
+		assert!(door.signal(runtime, InstanceSignal::Usr2).is_err());
+		garage.remove_pid_file(runtime).unwrap();
+		fs::remove_dir(runtime).unwrap();
+	}
+}
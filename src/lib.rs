@@ -1,17 +1,39 @@
+pub mod analytics;
+pub mod api;
 pub mod audio;
 pub mod bat;
+pub mod bus;
 pub mod buttons;
+pub mod cli;
 pub mod clima_sensor_us;
+pub mod command;
 pub mod config;
+pub mod control;
+pub mod email;
 pub mod environment;
 pub mod garage;
+pub mod history;
+pub mod instance;
+pub mod log;
+pub mod metrics;
 pub mod mod_ir_temp;
+pub mod module;
+pub mod mqtt;
 pub mod nextcloud;
+pub mod notify;
+pub mod outbox;
+pub mod peer;
 pub mod ping;
 pub mod pwr;
+pub mod readings;
+pub mod routing;
 pub mod sensors;
 pub mod signals;
-pub mod ssh;
+pub mod state;
+#[cfg(test)]
+pub mod stub;
+pub mod supervisor;
 pub mod types;
 pub mod validator;
 pub mod watchdog;
+pub mod webhook;
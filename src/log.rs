@@ -0,0 +1,48 @@
+//! Minimal leveled logging to stderr, the level is set with `log/level` or `--log-level`.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use serde::Deserialize;
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+	Error,
+	Warning,
+	Info,
+	Debug,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+pub fn set_level(level: Level) {
+	LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+	level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// `log!(Level::Warning, "Couldn't ping {}", message)`
+#[macro_export]
+macro_rules! log {
+	($level:expr, $($arg:tt)*) => {
+		if $crate::log::enabled($level) {
+			eprintln!($($arg)*);
+		}
+	};
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_enabled() {
+		set_level(Level::Warning);
+		assert!(enabled(Level::Error));
+		assert!(enabled(Level::Warning));
+		assert!(!enabled(Level::Info));
+		set_level(Level::Info);
+	}
+}
@@ -1,243 +1,242 @@
+use std::{env, process::ExitCode, time::Duration};
+
 use chrono::Local;
-use futures::future::join_all;
 use gettextrs::*;
-use mlx9061x::Error as MlxError;
-use std::panic;
-use std::sync::Arc;
-use systemstat::Duration;
-use tokio::spawn;
-use tokio::sync::{mpsc, Mutex};
-use tokio::time::interval;
-
-use opensesame::audio::{Audio, AudioEvent};
-use opensesame::bat::Bat;
-use opensesame::buttons::{Buttons, CommandToButtons};
-use opensesame::clima_sensor_us::ClimaSensorUS;
-use opensesame::config::Config;
-use opensesame::environment::{EnvEvent, Environment};
-use opensesame::garage::Garage;
-use opensesame::mod_ir_temp::ModIR;
-use opensesame::nextcloud::{Nextcloud, NextcloudChat, NextcloudEvent};
-use opensesame::ping::{Ping, PingEvent};
-use opensesame::pwr::Pwr;
-use opensesame::sensors::Sensors;
-use opensesame::signals::Signals;
+use serde::Deserialize;
+use tokio::{
+	select,
+	signal::unix::{signal, SignalKind},
+	time::interval,
+};
+
+use opensesame::bus::Event;
+use opensesame::cli::{self, Options};
+use opensesame::config::{changed_keys, Config, ConfigError};
+use opensesame::instance::Instance;
+use opensesame::module::{Context, Registry};
+use opensesame::nextcloud::NextcloudChat;
+use opensesame::notify::Notification;
+use opensesame::supervisor::Supervisor;
 use opensesame::types::ModuleError;
-use opensesame::validator::Validator;
-use opensesame::watchdog::Watchdog;
+use opensesame::{log, log::Level};
+
+// exit code for wrong usage, as in sysexits.h
+const EXIT_USAGE: u8 = 64;
+
+/// What `main` itself reads from the configuration
+#[derive(Deserialize)]
+struct Settings {
+	#[serde(rename = "instance/runtime")]
+	runtime: String,
+	#[serde(rename = "log/level")]
+	log_level: Level,
+	#[serde(rename = "nextcloud/format/datetime")]
+	date_time_format: String,
+	#[serde(rename = "state/flush/interval")]
+	state_flush_interval: u64,
+}
 
-const CONFIG_PARENT: &str = "/sw/libelektra/opensesame/#0/current";
-const STATE_PARENT: &str = "/state/libelektra/opensesame/#0/current";
+fn print_errors(errors: Vec<ConfigError>) {
+	for error in errors {
+		eprintln!("{}", error);
+	}
+}
 
 #[tokio::main]
-async fn main() -> Result<(), ModuleError> {
+async fn main() -> Result<ExitCode, ModuleError> {
 	TextDomain::new("opensesame").init().unwrap();
 
-	let mut config = Config::new(CONFIG_PARENT);
-	let config_mutex = Arc::new(Mutex::new(Config::new(CONFIG_PARENT)));
-	let state_mutex = Arc::new(Mutex::new(Config::new(STATE_PARENT)));
-
-	let date_time_format = config.get::<String>("nextcloud/format/datetime");
-	let startup_time = Local::now().format(&date_time_format);
-
-	// Sender and receiver to open doors/lights etc via Nextcloud
-	let (command_sender, command_receiver) = mpsc::channel::<CommandToButtons>(32);
-	// Info to send to next cloud
-	let (nextcloud_sender, nextcloud_receiver) = mpsc::channel::<NextcloudEvent>(32);
-	// Sender and receiver to set status of System and Send it to Nextcloud
-	let (ping_sender, ping_receiver) = mpsc::channel::<PingEvent>(32);
-	// Sender and receiver to play audio
-	let (audio_sender, audio_receiver) = mpsc::channel::<AudioEvent>(32);
-
-	let (environment_sender, environment_receiver) = mpsc::channel::<EnvEvent>(32);
-
-	let buttons_enabled = config.get_bool("buttons/enable");
-	let garage_enabled = config.get_bool("garage/enable");
-	let sensors_enabled = config.get_bool("sensors/enable");
-	let modir_enabled = config.get_bool("ir/enable");
-	let env_enabled = config.get_bool("environment/enable");
-	let weatherstation_enabled = config.get_bool("weatherstation/enable");
-	let bat_enabled = config.get_bool("bat/enable");
-	let watchdog_enabled = config.get_bool("watchdog/enable");
-	let ping_enabled = config.get_bool("ping/enable");
-
-	let mut tasks = vec![];
-
-	tasks.push(spawn(Nextcloud::get_background_task(
-		Nextcloud::new(&mut config),
-		nextcloud_receiver,
-		nextcloud_sender.clone(),
-		command_sender.clone(),
-		audio_sender.clone(),
-		startup_time.to_string(),
-	)));
-
-	if garage_enabled {
-		if !buttons_enabled {
-			panic!("Garage depends on buttons!");
+	let arguments = match cli::parse(env::args().skip(1), |name| env::var(name).ok()) {
+		Ok(arguments) => arguments,
+		Err(error) => {
+			eprintln!("{}\n\n{}", error, cli::usage());
+			return Ok(ExitCode::from(EXIT_USAGE));
+		}
+	};
+	// needed before the configuration can be read
+	let instance = match Instance::from_arguments(&arguments) {
+		Ok(instance) => instance,
+		Err(error) => {
+			eprintln!("{}\n\n{}", error, cli::usage());
+			return Ok(ExitCode::from(EXIT_USAGE));
+		}
+	};
+	let config_parent = instance.config_parent();
+	let open_config = || {
+		Config::open(&config_parent).map(|mut config| {
+			config.apply_arguments(&arguments);
+			config
+		})
+	};
+
+	let mut config = match open_config() {
+		Ok(config) => config,
+		Err(error) => {
+			eprintln!("Could not read configuration {}", error);
+			return Ok(ExitCode::FAILURE);
+		}
+	};
+	let options: Options = match config.deserialize("cli") {
+		Ok(options) => options,
+		Err(errors) => {
+			print_errors(errors);
+			return Ok(ExitCode::from(EXIT_USAGE));
 		}
-		tasks.push(spawn(Garage::get_background_task(
-			Garage::new(&mut config),
-			command_sender.clone(),
-			nextcloud_sender.clone(),
-		)));
+	};
+	if options.help {
+		print!("{}", cli::usage());
+		return Ok(ExitCode::SUCCESS);
 	}
-
-	if buttons_enabled {
-		let time_format = config.get::<String>("nextcloud/format/time");
-		let location_latitude = config.get::<f64>("location/latitude");
-		let location_longitude = config.get::<f64>("location/longitude");
-		tasks.push(spawn(Buttons::get_background_task(
-			Buttons::new(&mut config),
-			Validator::new(&mut config),
-			Pwr::new(&mut config),
-			time_format.to_string(),
-			command_receiver,
-			nextcloud_sender.clone(),
-			audio_sender.clone(),
-			location_latitude,
-			location_longitude,
-		)));
+	let settings: Settings = match config.deserialize("") {
+		Ok(settings) => settings,
+		Err(errors) => {
+			print_errors(errors);
+			return Ok(ExitCode::FAILURE);
+		}
+	};
+	let runtime = settings.runtime;
+	if let Some(signal) = options.signal {
+		return Ok(match instance.signal(&runtime, signal) {
+			Ok(()) => ExitCode::SUCCESS,
+			Err(error) => {
+				eprintln!("{}", error);
+				ExitCode::FAILURE
+			}
+		});
 	}
+	log::set_level(settings.log_level);
 
-	if sensors_enabled {
-		let device_path = config.get::<String>("sensors/device");
-		tasks.push(spawn(Sensors::get_background_task(
-			Sensors::new(&mut config),
-			device_path.to_string(),
-			nextcloud_sender.clone(),
-			/*state_mutex.clone(),
-			id(),*/
-		)));
+	let mut registry = Registry::new();
+	if let Err(error) = registry.restrict(&options) {
+		eprintln!("{}", error);
+		return Ok(ExitCode::from(EXIT_USAGE));
 	}
 
-	if modir_enabled {
-		let mod_ir_result = ModIR::new(&mut config);
-		match mod_ir_result {
-			Ok(mod_ir) => {
-				let interval = interval(Duration::from_secs(config.get::<u64>("ir/data/interval")));
-				tasks.push(spawn(ModIR::get_background_task(
-					mod_ir,
-					interval,
-					nextcloud_sender.clone(),
-				)));
-			}
-			// TODO: Streamline consistent error handling!
-			Err(error_typ) => {
-				let reason = match error_typ {
-					MlxError::I2C(error) => error.to_string(),
-					MlxError::ChecksumMismatch | MlxError::InvalidInputData => {
-						format!("{:?}", error_typ)
-					}
-				};
-				nextcloud_sender
-					.send(NextcloudEvent::Chat(
-						NextcloudChat::Ping,
-						gettext!("⚠️ Failed to init ModIR: {}", reason),
-					))
-					.await?;
-			}
-		}
+	if options.check_config {
+		let report = registry.check_config(&mut config);
+		print!("{}", report);
+		return Ok(if report.is_ok() {
+			ExitCode::SUCCESS
+		} else {
+			ExitCode::FAILURE
+		});
 	}
 
-	if env_enabled {
-		let interval = interval(Duration::from_secs(
-			config.get::<u64>("environment/data/interval"),
-		));
-		let garage_enabled = config.get_bool("garage/enable");
-		tasks.push(spawn(Environment::get_background_task(
-			Environment::new(&mut config, state_mutex.clone()),
-			interval,
-			nextcloud_sender.clone(),
-			command_sender.clone(),
-			audio_sender.clone(),
-			environment_receiver,
-			garage_enabled,
-		)));
+	if let Err(error) = instance.write_pid_file(&runtime) {
+		log!(Level::Error, "{}", error);
+		return Ok(ExitCode::FAILURE);
 	}
 
-	// if env_enabled || buttons_enabled {
-	let audio_bell = config.get::<String>("audio/bell");
-	let audio_alarm = config.get::<String>("audio/alarm");
-	tasks.push(spawn(Audio::get_background_task(
-		Audio::new(audio_bell, audio_alarm),
-		audio_receiver,
-		nextcloud_sender.clone(),
-	)));
-	// }
-
-	if weatherstation_enabled {
-		let clima_sensor_result = ClimaSensorUS::new(&mut config);
-		match clima_sensor_result {
-			Ok(clima_sensor) => {
-				tasks.push(spawn(ClimaSensorUS::get_background_task(
-					clima_sensor,
-					nextcloud_sender.clone(),
-				)));
+	let startup_time = Local::now().format(&settings.date_time_format);
+
+	// register early, so that the default handlers don't kill us without cleanup
+	let mut sig_term = signal(SignalKind::terminate())?;
+	let mut sig_int = signal(SignalKind::interrupt())?;
+	let mut sig_hup = signal(SignalKind::hangup())?;
+
+	let context = match Context::new(
+		instance.clone(),
+		startup_time.to_string(),
+		arguments.clone(),
+		options,
+	)
+	.await
+	{
+		Ok(context) => context,
+		Err(error) => {
+			log!(Level::Error, "Could not read configuration {}", error);
+			let _ = instance.remove_pid_file(&runtime);
+			return Ok(ExitCode::FAILURE);
+		}
+	};
+	let mut supervisor = match Supervisor::new(&mut config, &context) {
+		Ok(supervisor) => supervisor,
+		Err(errors) => {
+			print_errors(errors);
+			let _ = instance.remove_pid_file(&runtime);
+			return Ok(ExitCode::FAILURE);
+		}
+	};
+	let enabled_modules = registry.start(&mut config, &context, &mut supervisor);
+
+	context.bus.send(Notification::debug(
+		"Opensesame",
+		NextcloudChat::Ping,
+		gettext!("Enabled Modules: \n{}", enabled_modules),
+	));
+
+	// the first tick writes what was left over from the last run
+	let mut state_flush = interval(Duration::from_secs(settings.state_flush_interval.max(1)));
+	let mut config_snapshot = config.snapshot();
+	let mut events = context.bus.subscribe_readings();
+	let signal = loop {
+		select! {
+			Some(event) = events.recv() => {
+				context.readings.update(&event);
+				if let Event::RestartModule(name) = event {
+					let message = if supervisor.is_running(name) {
+						supervisor.restart(name);
+						gettext!("🔁 Restarting {}", name)
+					} else {
+						gettext!("{} is not running", name)
+					};
+					context.bus.send(Notification::info("Opensesame", NextcloudChat::Ping, message));
+				}
 			}
-			Err(error) => {
-				nextcloud_sender
-					.send(NextcloudEvent::Chat(
-						NextcloudChat::Ping,
-						gettext!("⚠️ Failed to init libmodbus connection: {}", error),
-					))
-					.await?;
+			_ = state_flush.tick() => {
+				if let Err(error) = context.state.flush().await {
+					context.bus.send(Notification::warning("Opensesame", NextcloudChat::Ping, gettext!("Couldn't write state {}", error)));
+				}
+			}
+			_ = sig_hup.recv() => {
+				// fresh instance, so that removed keys are gone, too
+				config = match open_config() {
+					Ok(config) => config,
+					Err(error) => {
+						context.bus.send(Notification::warning("Opensesame", NextcloudChat::Ping, gettext!("⚙️ Could not reload configuration {}", error)));
+						continue;
+					}
+				};
+				// what the modules read when they get (re)started
+				if let Err(error) = context.config_handle.reopen().await {
+					context.bus.send(Notification::warning("Opensesame", NextcloudChat::Ping, gettext!("⚙️ Could not reload configuration {}", error)));
+					continue;
+				}
+				match config.value::<Level>("log/level") {
+					Ok(level) => log::set_level(level),
+					Err(error) => context.bus.send(Notification::warning("Opensesame", NextcloudChat::Ping, gettext!("⚠️ Configuration errors:\n{}\n", error))),
+				}
+				let new_snapshot = config.snapshot();
+				let changed = changed_keys(&config_snapshot, &new_snapshot);
+				config_snapshot = new_snapshot;
+				let message = if changed.is_empty() {
+					gettext("⚙️ Configuration unchanged")
+				} else {
+					let report = registry.reload(&changed, &mut config, &context, &mut supervisor);
+					gettext!("⚙️ Changed configuration: {}\n{}", changed.join(", "), report)
+				};
+				context.bus.send(Notification::debug("Opensesame", NextcloudChat::Ping, message));
+				context.bus.send(Event::ConfigChanged(changed));
+			}
+			_ = sig_term.recv() => break "SIGTERM",
+			_ = sig_int.recv() => break "SIGINT",
+			_ = supervisor.wait() => {
+				log!(Level::Error, "All modules disabled, exiting");
+				let _ = context.state.flush().await;
+				let _ = instance.remove_pid_file(&runtime);
+				return Ok(ExitCode::FAILURE);
 			}
 		}
-	}
-
-	if bat_enabled {
-		tasks.push(spawn(Bat::get_background_task(
-			Bat::new(),
-			nextcloud_sender.clone(),
-			ping_sender.clone(),
-		)));
-	}
+	};
 
-	if watchdog_enabled {
-		let interval = interval(Duration::from_secs(config.get::<u64>("watchdog/interval")));
-		let path = config.get::<String>("watchdog/path");
-		tasks.push(spawn(Watchdog::get_background_task(path, interval)));
+	log!(Level::Info, "Received {}, shutting down", signal);
+	supervisor.shutdown().await;
+	registry.shutdown(&context);
+	if let Err(error) = context.state.flush().await {
+		log!(Level::Error, "Could not write state: {}", error);
 	}
-
-	if ping_enabled {
-		tasks.push(spawn(Ping::get_background_task(
-			Ping::new(startup_time.to_string()),
-			ping_receiver,
-			nextcloud_sender.clone(),
-		)))
+	if let Err(error) = instance.remove_pid_file(&runtime) {
+		log!(Level::Warning, "Could not remove PID file: {}", error);
 	}
-
-	let signals = Signals::new(
-		config_mutex.clone(),
-		state_mutex.clone(),
-		ping_enabled,
-		buttons_enabled,
-		env_enabled,
-		startup_time.to_string(),
-		ping_sender.clone(),
-		command_sender.clone(),
-		nextcloud_sender.clone(),
-		environment_sender.clone(),
-		audio_sender.clone(),
-	);
-
-	tasks.push(spawn(signals.get_background_task()));
-
-	nextcloud_sender.send(
-		NextcloudEvent::Chat(NextcloudChat::Ping,
-			gettext!("Enabled Modules: \nButtons: {},\n Garage: {},\n Sensors: {},\n ModIR: {},\n Environment: {},\n Weatherstation: {},\n Battery: {},\n Watchdog: {},\n Ping: {}\n",
-	buttons_enabled,
-	garage_enabled,
-	sensors_enabled,
-	modir_enabled,
-	env_enabled,
-	weatherstation_enabled,
-	bat_enabled,
-	watchdog_enabled,
-	ping_enabled,
-))).await?;
-
-	join_all(tasks).await;
-	Ok(())
+	Ok(ExitCode::SUCCESS)
 }
@@ -0,0 +1,165 @@
+//! Prometheus text format of the latest readings and the counters,
+//! served by the API on `/metrics`, see doc/Metrics.md.
+
+use std::fmt::Write;
+
+use crate::{readings::Latest, supervisor::Health};
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// as required for label values
+fn escape(value: &str) -> String {
+	value
+		.replace('\\', "\\\\")
+		.replace('"', "\\\"")
+		.replace('\n', "\\n")
+}
+
+fn number(value: f64) -> String {
+	if value.is_nan() {
+		String::from("NaN")
+	} else if value.is_infinite() {
+		String::from(if value > 0.0 { "+Inf" } else { "-Inf" })
+	} else {
+		value.to_string()
+	}
+}
+
+fn family(output: &mut String, name: &str, kind: &str, help: &str) {
+	let _ = writeln!(output, "# HELP {} {}", name, help);
+	let _ = writeln!(output, "# TYPE {} {}", name, kind);
+}
+
+pub fn render(latest: &Latest, health: &Health) -> String {
+	let mut output = String::new();
+
+	family(
+		&mut output,
+		"opensesame_reading",
+		"gauge",
+		"Latest measured value by source, name and unit.",
+	);
+	for (key, reading) in &latest.readings {
+		let (source, name) = key.split_once('/').unwrap_or(("", key));
+		let _ = writeln!(
+			output,
+			"opensesame_reading{{source=\"{}\",name=\"{}\",unit=\"{}\"}} {}",
+			escape(source),
+			escape(name),
+			escape(reading.unit),
+			number(reading.value)
+		);
+	}
+	family(
+		&mut output,
+		"opensesame_reading_time_seconds",
+		"gauge",
+		"When the reading was measured, to find stale ones.",
+	);
+	for (key, reading) in &latest.readings {
+		let (source, name) = key.split_once('/').unwrap_or(("", key));
+		let _ = writeln!(
+			output,
+			"opensesame_reading_time_seconds{{source=\"{}\",name=\"{}\"}} {}",
+			escape(source),
+			escape(name),
+			reading.time
+		);
+	}
+
+	let counters = [
+		(
+			"opensesame_door_openings_total",
+			"Door openings by PIN or command.",
+			latest.counters.door_openings,
+		),
+		(
+			"opensesame_failed_pins_total",
+			"PIN sequences which timed out or got too long.",
+			latest.counters.failed_pins,
+		),
+		(
+			"opensesame_nextcloud_failures_total",
+			"Failed attempts to deliver chat messages and status updates to Nextcloud.",
+			latest.counters.nextcloud_failures,
+		),
+	];
+	for (name, help, value) in counters {
+		family(&mut output, name, "counter", help);
+		let _ = writeln!(output, "{} {}", name, value);
+	}
+
+	family(
+		&mut output,
+		"opensesame_module_restarts_total",
+		"counter",
+		"Restarts of the module by the supervisor.",
+	);
+	for (module, health) in health.lock().unwrap().iter() {
+		let _ = writeln!(
+			output,
+			"opensesame_module_restarts_total{{module=\"{}\"}} {}",
+			escape(module),
+			health.restarts
+		);
+	}
+	output
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		bus::Event,
+		readings::{Reading, Readings},
+		supervisor::{ModuleHealth, ModuleState},
+	};
+
+	#[test]
+	fn test_render() {
+		let readings = Readings::default();
+		readings.update(&Event::Reading(Reading::new(
+			"environment",
+			"co2",
+			420.0,
+			"ppm",
+		)));
+		readings.update(&Event::Reading(Reading::new(
+			"sensors",
+			"mq135_3_ema",
+			12.5,
+			"",
+		)));
+		readings.update(&Event::DoorOpened(String::from("alice")));
+		readings.update(&Event::DoorOpened(String::from("command")));
+		let health = Health::default();
+		health.lock().unwrap().insert(
+			"Environment",
+			ModuleHealth {
+				state: ModuleState::Running,
+				failures: 0,
+				restarts: 3,
+				last_error: None,
+			},
+		);
+		let output = render(&readings.latest(), &health);
+		assert!(output.contains(
+			"opensesame_reading{source=\"environment\",name=\"co2\",unit=\"ppm\"} 420\n"
+		));
+		assert!(output.contains(
+			"opensesame_reading{source=\"sensors\",name=\"mq135_3_ema\",unit=\"\"} 12.5\n"
+		));
+		assert!(output.contains("# TYPE opensesame_door_openings_total counter\n"));
+		assert!(output.contains("opensesame_door_openings_total 2\n"));
+		assert!(output.contains("opensesame_failed_pins_total 0\n"));
+		assert!(output.contains("opensesame_module_restarts_total{module=\"Environment\"} 3\n"));
+	}
+
+	#[test]
+	fn test_format() {
+		assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+		assert_eq!(number(f64::NAN), "NaN");
+		assert_eq!(number(f64::NEG_INFINITY), "-Inf");
+		assert_eq!(number(21.25), "21.25");
+	}
+}
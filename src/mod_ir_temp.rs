@@ -1,15 +1,23 @@
-use crate::config::Config;
-use crate::nextcloud::{NextcloudChat, NextcloudEvent};
+use crate::bus::EventBus;
+use crate::config::{
+	validate::{exists, in_range, Validate},
+	Config, ConfigError,
+};
+use crate::module::{Context, Module};
+use crate::nextcloud::NextcloudChat;
+use crate::notify::{Category, Notification};
+use crate::readings::Reading;
 use crate::types::ModuleError;
-use futures::never::Never;
+use futures::{future::BoxFuture, never::Never, FutureExt};
 use gettextrs::gettext;
 use i2cdev::linux::LinuxI2CError;
 use linux_embedded_hal::{Delay, I2cdev};
 use mlx9061x::ic::Mlx90614;
 use mlx9061x::Error as MlxError;
 use mlx9061x::{Error, Mlx9061x, SlaveAddr};
-use tokio::sync::mpsc::Sender;
-use tokio::time::Interval;
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::time::{interval, Interval};
 
 // You can modify the 'THRESHOLD_AMBIENT' and 'THRESHOLD_OBJECT' values. These two thresholds trigger the IrTempStateChange.
 // For instance, if 'THRESHOLD_AMBIENT' < 'ambient_temp', then 'ChangedToAmbientTooHot' is triggered.
@@ -31,6 +39,27 @@ pub enum IrTempState {
 	TooHot,
 }
 
+#[derive(Deserialize)]
+pub struct ModIRConfig {
+	/// /dev/null if no MOD-IR-TEMP sensor is connected
+	pub device: String,
+	/// in seconds
+	#[serde(rename = "data/interval")]
+	pub data_interval: u16,
+}
+
+impl Validate for ModIRConfig {
+	fn validate(&self) -> Vec<ConfigError> {
+		[
+			exists("ir/device", &self.device),
+			in_range("ir/data/interval", self.data_interval, 1..=u16::MAX),
+		]
+		.into_iter()
+		.flatten()
+		.collect()
+	}
+}
+
 pub struct ModIR {
 	mlx: Option<Mlx9061x<I2cdev, Mlx90614>>,
 	device: String,
@@ -45,10 +74,10 @@ pub struct ModIR {
 impl ModIR {
 	/// This function initializes the MOD-IR-TEMP and returns an instance of ModIR upon success.
 	/// In case of an error, the error code is returned.
-	pub fn new(config: &mut Config) -> Result<Self, Error<LinuxI2CError>> {
+	pub fn new(config: &ModIRConfig) -> Result<Self, Error<LinuxI2CError>> {
 		let mut s = Self {
 			mlx: None,
-			device: config.get::<String>("ir/device"),
+			device: config.device.clone(),
 			addr: SlaveAddr::Default,
 			ambient_temp: 0.0,
 			object_temp: 0.0,
@@ -184,84 +213,101 @@ impl ModIR {
 	pub async fn get_background_task(
 		mut self,
 		mut interval: Interval,
-		nextcloud_sender: Sender<NextcloudEvent>,
+		bus: EventBus,
 	) -> Result<Never, ModuleError> {
 		loop {
 			interval.tick().await;
-			match self.handle() {
+			let result = self.handle();
+			if result.is_ok() && self.mlx.is_some() {
+				bus.send(Reading::new(
+					"modir",
+					"ambient",
+					self.ambient_temp.into(),
+					"°C",
+				));
+				bus.send(Reading::new(
+					"modir",
+					"object",
+					self.object_temp.into(),
+					"°C",
+				));
+			}
+			match result {
 				Ok(state) => match state {
 					IrTempStateChange::None => (),
 					IrTempStateChange::ChanedToBothToHot => {
-						nextcloud_sender
-							.send(NextcloudEvent::Chat(
-								NextcloudChat::Default,
-								gettext!(
-									"🌡️🌡️ ModIR both sensors too hot! Ambient: {} °C, Object: {} °C",
-									self.ambient_temp,
-									self.object_temp
-								),
-							))
-							.await?;
+						bus.send(Notification::warning(
+							"ModIR",
+							NextcloudChat::Default,
+							gettext!(
+								"🌡️🌡️ ModIR both sensors too hot! Ambient: {} °C, Object: {} °C",
+								self.ambient_temp,
+								self.object_temp
+							),
+						));
 					}
 					IrTempStateChange::ChangedToAmbientToHot => {
-						nextcloud_sender
-							.send(NextcloudEvent::Chat(
-								NextcloudChat::Default,
-								gettext!(
-									"🌡️ ModIR ambient sensors too hot! Ambient: {} °C",
-									self.ambient_temp
-								),
-							))
-							.await?;
+						bus.send(Notification::warning(
+							"ModIR",
+							NextcloudChat::Default,
+							gettext!(
+								"🌡️ ModIR ambient sensors too hot! Ambient: {} °C",
+								self.ambient_temp
+							),
+						));
 					}
 					IrTempStateChange::ChangedToObjectToHot => {
-						nextcloud_sender
-							.send(NextcloudEvent::Chat(
-								NextcloudChat::Default,
-								gettext!(
-									"🌡️ ModIR object sensors too hot! Object: {} °C",
-									self.object_temp
-								),
-							))
-							.await?;
+						bus.send(Notification::warning(
+							"ModIR",
+							NextcloudChat::Default,
+							gettext!(
+								"🌡️ ModIR object sensors too hot! Object: {} °C",
+								self.object_temp
+							),
+						));
 					}
 					IrTempStateChange::ChangedToCancelled => {
-						nextcloud_sender
-							.send(NextcloudEvent::Chat(
-								NextcloudChat::Default,
-								gettext!(
-									"🌡 ModIR cancelled warning! Ambient: {} °C, Object: {} °C",
-									self.ambient_temp,
-									self.object_temp
-								),
-							))
-							.await?;
+						bus.send(Notification::info(
+							"ModIR",
+							NextcloudChat::Default,
+							gettext!(
+								"🌡 ModIR cancelled warning! Ambient: {} °C, Object: {} °C",
+								self.ambient_temp,
+								self.object_temp
+							),
+						));
 					}
 				},
 				Err(error_typ) => match error_typ {
 					MlxError::I2C(error) => {
-						nextcloud_sender
-							.send(NextcloudEvent::Chat(
+						bus.send(
+							Notification::warning(
+								"ModIR",
 								NextcloudChat::Ping,
 								gettext!("⚠️ Error while handling ModIR: {}", error),
-							))
-							.await?;
+							)
+							.category(Category::Hardware),
+						);
 					}
 					MlxError::ChecksumMismatch => {
-						nextcloud_sender
-							.send(NextcloudEvent::Chat(
+						bus.send(
+							Notification::warning(
+								"ModIR",
 								NextcloudChat::Ping,
 								gettext!("⚠️ Error while handling ModIR: {}", "ChecksumMismatch"),
-							))
-							.await?;
+							)
+							.category(Category::Hardware),
+						);
 					}
 					MlxError::InvalidInputData => {
-						nextcloud_sender
-							.send(NextcloudEvent::Chat(
+						bus.send(
+							Notification::warning(
+								"ModIR",
 								NextcloudChat::Ping,
 								gettext!("⚠️ Error while handling ModIR: {}", "InvalidInputData"),
-							))
-							.await?;
+							)
+							.category(Category::Hardware),
+						);
 					}
 				},
 			}
@@ -269,6 +315,49 @@ impl ModIR {
 	}
 }
 
+pub struct ModIRModule;
+
+impl Module for ModIRModule {
+	fn name(&self) -> &'static str {
+		"ModIR"
+	}
+
+	fn enabled(&self, config: &mut Config) -> Result<bool, ConfigError> {
+		config.value::<bool>("ir/enable")
+	}
+
+	fn hardware(&self) -> bool {
+		true
+	}
+
+	fn config_keys(&self) -> &'static [&'static str] {
+		&["ir"]
+	}
+
+	fn check_config(&self, config: &mut Config) -> Result<(), Vec<ConfigError>> {
+		config.check::<ModIRConfig>("ir")
+	}
+
+	fn start(&self, context: Context) -> BoxFuture<'static, Result<Never, ModuleError>> {
+		async move {
+			let config: ModIRConfig = context.config("ir").await?;
+			// TODO: Streamline consistent error handling!
+			let mod_ir = ModIR::new(&config).map_err(|error_typ| {
+				let reason = match error_typ {
+					MlxError::I2C(error) => error.to_string(),
+					MlxError::ChecksumMismatch | MlxError::InvalidInputData => {
+						format!("{:?}", error_typ)
+					}
+				};
+				ModuleError::new(gettext!("⚠️ Failed to init ModIR: {}", reason))
+			})?;
+			let interval = interval(Duration::from_secs(config.data_interval.into()));
+			ModIR::get_background_task(mod_ir, interval, context.bus).await
+		}
+		.boxed()
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -0,0 +1,526 @@
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	fmt,
+	sync::Arc,
+	time::Duration,
+};
+
+use futures::{future::BoxFuture, never::Never};
+use gettextrs::gettext;
+use serde::de::DeserializeOwned;
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+	analytics::AnalyticsModule,
+	api::ApiModule,
+	audio::AudioModule,
+	bat::BatModule,
+	bus::{EventBus, Subscription},
+	buttons::ButtonsModule,
+	cli::Options,
+	clima_sensor_us::ClimaSensorUSModule,
+	config::{Config, ConfigError, ConfigHandle},
+	control::ControlModule,
+	email::EmailModule,
+	environment::EnvironmentModule,
+	garage::GarageModule,
+	history::{History, HistoryModule},
+	instance::Instance,
+	log,
+	log::Level,
+	mod_ir_temp::ModIRModule,
+	mqtt::MqttModule,
+	nextcloud::NextcloudModule,
+	peer::PeerModule,
+	ping::PingModule,
+	readings::Readings,
+	sensors::SensorsModule,
+	signals::SignalsModule,
+	state::StateStore,
+	supervisor::{Health, Supervisor},
+	types::ModuleError,
+	watchdog::WatchdogModule,
+	webhook::WebhookModule,
+};
+
+/// until `elektra/timeout` is read, its default
+const ELEKTRA_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Everything a module might need to talk to the rest of opensesame.
+#[derive(Clone)]
+pub struct Context {
+	/// selects the configuration and state, see `--instance` and `--profile`
+	pub instance: Instance,
+	pub startup_time: String,
+	/// for async access, e.g. to reread the configuration
+	pub config_handle: ConfigHandle,
+	/// directory for volatile files like sockets, `instance/runtime`
+	pub runtime: String,
+	pub state: StateStore,
+	pub health: Health,
+	/// latest sensor values, door, garage and lights
+	pub readings: Readings,
+	/// of the sensor values, recorded by the History module
+	pub history: History,
+	/// from the command line and environment, applied to the configuration
+	pub arguments: BTreeMap<String, String>,
+	pub options: Options,
+
+	pub bus: EventBus,
+	/// subscribed from the start, so that no chat message gets lost
+	/// during startup or while Nextcloud restarts
+	pub nextcloud_events: Arc<Mutex<Subscription>>,
+	/// cancelled on SIGTERM/SIGINT or when the module gets restarted/stopped,
+	/// modules should clean up and return
+	pub shutdown: CancellationToken,
+}
+
+impl Context {
+	/// Opens configuration and state on their Elektra workers,
+	/// errors if Elektra fails or does not answer within `ELEKTRA_TIMEOUT`.
+	pub async fn new(
+		instance: Instance,
+		startup_time: String,
+		arguments: BTreeMap<String, String>,
+		options: Options,
+	) -> Result<Self, ConfigError> {
+		let bus = EventBus::new();
+		let config_handle = ConfigHandle::open(
+			&instance.config_parent(),
+			arguments.clone(),
+			ELEKTRA_TIMEOUT,
+		)
+		.await?;
+		let elektra_timeout =
+			Duration::from_secs(config_handle.value::<u64>("elektra/timeout").await?);
+		let config_handle = config_handle.with_timeout(elektra_timeout);
+		let runtime = config_handle.value::<String>("instance/runtime").await?;
+		let state_handle =
+			ConfigHandle::open(&instance.state_parent(), BTreeMap::new(), elektra_timeout).await?;
+		Ok(Self {
+			startup_time,
+			config_handle,
+			state: StateStore::new(state_handle, instance.state_file(&runtime)),
+			runtime,
+			instance,
+			health: Health::default(),
+			readings: Readings::default(),
+			history: History::default(),
+			arguments,
+			options,
+			nextcloud_events: Arc::new(Mutex::new(bus.subscribe())),
+			bus,
+			shutdown: CancellationToken::new(),
+		})
+	}
+
+	/// The configuration below `name`, e.g. to construct a module.
+	/// Read on the Elektra worker, `main` reopens it on reload.
+	pub async fn config<T: DeserializeOwned + Send + 'static>(
+		&self,
+		name: &str,
+	) -> Result<T, Vec<ConfigError>> {
+		self.config_handle.deserialize(name).await
+	}
+}
+
+pub trait Module: Send + Sync {
+	/// used in messages and for the health report
+	fn name(&self) -> &'static str;
+
+	/// Errors if the enable key is missing or invalid, then the module does not get started.
+	fn enabled(&self, config: &mut Config) -> Result<bool, ConfigError>;
+
+	/// Needs the hardware of the board, not started with `--simulate`.
+	fn hardware(&self) -> bool {
+		false
+	}
+
+	/// Configuration keys (or parents of keys) the module reads.
+	/// If one of them changes on reload, the module gets restarted.
+	fn config_keys(&self) -> &'static [&'static str];
+
+	/// Deserializes the module's configuration struct, so that all
+	/// problems get reported before the module gets started.
+	fn check_config(&self, _config: &mut Config) -> Result<(), Vec<ConfigError>> {
+		Ok(())
+	}
+
+	/// Creates the module and returns its background task.
+	/// Gets called again by the supervisor if the task exits.
+	fn start(&self, context: Context) -> BoxFuture<'static, Result<Never, ModuleError>>;
+
+	/// Called once before opensesame exits, after the background task stopped.
+	fn shutdown(&self, _context: &Context) {}
+}
+
+/// Whether `key` is one of `config_keys` or below one of them.
+fn affects(config_keys: &[&str], key: &str) -> bool {
+	config_keys.iter().any(|config_key| {
+		key == *config_key
+			|| key
+				.strip_prefix(config_key)
+				.is_some_and(|rest| rest.starts_with('/'))
+	})
+}
+
+/// Whether the module is enabled, all configuration errors if it is but cannot be started.
+fn check(module: &dyn Module, config: &mut Config) -> Result<bool, Vec<ConfigError>> {
+	if module.enabled(config).map_err(|error| vec![error])? {
+		module.check_config(config)?;
+		Ok(true)
+	} else {
+		Ok(false)
+	}
+}
+
+/// Result of checking the configuration of all modules, before any of them gets started.
+pub struct ConfigReport {
+	/// all modules in start order, with whether they are enabled
+	pub enabled: Vec<(&'static str, bool)>,
+	/// enabled modules with invalid configuration, they don't get started
+	pub invalid: BTreeSet<&'static str>,
+	/// all problems found, each key only reported once
+	pub errors: Vec<ConfigError>,
+}
+
+impl ConfigReport {
+	pub fn is_ok(&self) -> bool {
+		self.errors.is_empty()
+	}
+}
+
+impl fmt::Display for ConfigReport {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		for (name, enabled) in &self.enabled {
+			if self.invalid.contains(name) {
+				write!(f, "{}", gettext!("{}: invalid configuration\n", name))?;
+			} else {
+				writeln!(f, "{}: {}", name, enabled)?;
+			}
+		}
+		write!(f, "{}", config_errors_report(&self.errors))
+	}
+}
+
+/// One line per error, to be sent together with the list of enabled modules.
+fn config_errors_report(errors: &[ConfigError]) -> String {
+	if errors.is_empty() {
+		return String::new();
+	}
+	gettext!(
+		"⚠️ Configuration errors:\n{}\n",
+		errors
+			.iter()
+			.map(ConfigError::to_string)
+			.collect::<Vec<String>>()
+			.join("\n")
+	)
+}
+
+pub struct Registry {
+	modules: Vec<Arc<dyn Module>>,
+}
+
+impl Default for Registry {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Registry {
+	/// All modules known to opensesame, in the order they get started.
+	pub fn new() -> Self {
+		Self {
+			modules: vec![
+				Arc::new(NextcloudModule),
+				Arc::new(GarageModule),
+				Arc::new(ButtonsModule),
+				Arc::new(SensorsModule),
+				Arc::new(ModIRModule),
+				Arc::new(EnvironmentModule),
+				Arc::new(AudioModule),
+				Arc::new(ClimaSensorUSModule),
+				Arc::new(BatModule),
+				Arc::new(WatchdogModule),
+				Arc::new(PingModule),
+				Arc::new(PeerModule),
+				Arc::new(ControlModule),
+				Arc::new(ApiModule),
+				Arc::new(MqttModule),
+				Arc::new(HistoryModule),
+				Arc::new(AnalyticsModule),
+				Arc::new(WebhookModule),
+				Arc::new(EmailModule),
+				Arc::new(SignalsModule),
+			],
+		}
+	}
+
+	/// Removes the modules not selected with `--only` or needing hardware with `--simulate`.
+	pub fn restrict(&mut self, options: &Options) -> Result<(), String> {
+		if let Some(only) = options.only() {
+			if let Some(unknown) = only.iter().find(|name| {
+				!self
+					.modules
+					.iter()
+					.any(|module| module.name().to_lowercase() == **name)
+			}) {
+				return Err(format!("Unknown module '{}'", unknown));
+			}
+			self.modules
+				.retain(|module| only.contains(&module.name().to_lowercase()));
+		}
+		if options.simulate {
+			self.modules.retain(|module| !module.hardware());
+		}
+		Ok(())
+	}
+
+	/// Module by its name or an unambiguous prefix, ignoring case, e.g. `env` for Environment.
+	pub fn find(&self, name: &str) -> Result<&'static str, String> {
+		let name = name.to_lowercase();
+		let names = self
+			.modules
+			.iter()
+			.map(|module| module.name())
+			.filter(|module| module.to_lowercase().starts_with(&name))
+			.collect::<Vec<&'static str>>();
+		match names[..] {
+			[module] => Ok(module),
+			_ => match names.iter().find(|module| module.to_lowercase() == name) {
+				Some(module) => Ok(module),
+				None if names.is_empty() => Err(format!("Unknown module '{}'", name)),
+				None => Err(format!("'{}' could be {}", name, names.join(", "))),
+			},
+		}
+	}
+
+	/// Checks the configuration of all enabled modules, without starting anything.
+	pub fn check_config(&self, config: &mut Config) -> ConfigReport {
+		let mut report = ConfigReport {
+			enabled: Vec::new(),
+			invalid: BTreeSet::new(),
+			errors: Vec::new(),
+		};
+		for module in &self.modules {
+			let checked = check(module.as_ref(), config);
+			report.enabled.push((
+				module.name(),
+				checked.as_ref().is_ok_and(|enabled| *enabled),
+			));
+			if let Err(errors) = checked {
+				report.invalid.insert(module.name());
+				for error in errors {
+					// keys shared by several modules
+					if !report.errors.contains(&error) {
+						report.errors.push(error);
+					}
+				}
+			}
+		}
+		report
+	}
+
+	/// Checks the configuration of all modules first, then starts the enabled
+	/// modules with valid configuration.
+	/// Returns which modules are enabled, followed by all configuration errors.
+	pub fn start(
+		&self,
+		config: &mut Config,
+		context: &Context,
+		supervisor: &mut Supervisor,
+	) -> String {
+		let report = self.check_config(config);
+		for error in &report.errors {
+			log!(Level::Error, "Configuration error: {}", error);
+		}
+		for (module, (name, enabled)) in self.modules.iter().zip(&report.enabled) {
+			if *enabled && !report.invalid.contains(name) {
+				Registry::spawn(module, context, supervisor);
+			}
+		}
+		report.to_string()
+	}
+
+	fn spawn(module: &Arc<dyn Module>, context: &Context, supervisor: &mut Supervisor) {
+		let module = module.clone();
+		let context = context.clone();
+		supervisor.spawn(module.name(), move |stop| {
+			let mut context = context.clone();
+			context.shutdown = stop;
+			module.start(context)
+		});
+	}
+
+	/// Restarts, starts or stops the modules affected by the changed keys.
+	/// Returns a report of what happened.
+	pub fn reload(
+		&self,
+		changed_keys: &[String],
+		config: &mut Config,
+		context: &Context,
+		supervisor: &mut Supervisor,
+	) -> String {
+		let mut report = String::new();
+		for module in &self.modules {
+			if !changed_keys
+				.iter()
+				.any(|key| affects(module.config_keys(), key))
+			{
+				continue;
+			}
+			let running = supervisor.is_running(module.name());
+			let enabled = match check(module.as_ref(), config) {
+				Ok(enabled) => enabled,
+				Err(errors) => {
+					if running {
+						supervisor.stop_module(module.name());
+					}
+					for error in &errors {
+						log!(Level::Error, "Configuration error: {}", error);
+					}
+					report += &gettext!("{}: invalid configuration\n", module.name());
+					report += &config_errors_report(&errors);
+					continue;
+				}
+			};
+			if enabled && running {
+				supervisor.restart(module.name());
+				report += &gettext!("{}: restarted\n", module.name());
+			} else if enabled {
+				Registry::spawn(module, context, supervisor);
+				report += &gettext!("{}: started\n", module.name());
+			} else if running {
+				supervisor.stop_module(module.name());
+				report += &gettext!("{}: stopped\n", module.name());
+			}
+		}
+		let unapplied = changed_keys
+			.iter()
+			.filter(|key| {
+				!self
+					.modules
+					.iter()
+					.any(|module| affects(module.config_keys(), key))
+			})
+			.cloned()
+			.collect::<Vec<String>>();
+		if !unapplied.is_empty() {
+			report += &gettext!(
+				"Only applied after restarting opensesame: {}\n",
+				unapplied.join(", ")
+			);
+		}
+		report
+	}
+
+	/// To be called after the supervisor stopped all modules.
+	pub fn shutdown(&self, context: &Context) {
+		for module in &self.modules {
+			module.shutdown(context);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_affects() {
+		let config_keys = ["buttons", "nextcloud/format/time"];
+		assert!(affects(&config_keys, "buttons"));
+		assert!(affects(&config_keys, "buttons/enable"));
+		assert!(affects(&config_keys, "nextcloud/format/time"));
+		assert!(!affects(&config_keys, "buttonsx/enable"));
+		assert!(!affects(&config_keys, "nextcloud/format/datetime"));
+		assert!(!affects(&config_keys, "nextcloud"));
+	}
+
+	#[test]
+	fn test_unique_names() {
+		let registry = Registry::new();
+		let mut names = registry
+			.modules
+			.iter()
+			.map(|module| module.name())
+			.collect::<Vec<&str>>();
+		names.sort_unstable();
+		names.dedup();
+		assert_eq!(names.len(), registry.modules.len());
+	}
+
+	#[test]
+	fn test_config_report() {
+		let report = ConfigReport {
+			enabled: vec![("Nextcloud", true), ("Garage", false)],
+			invalid: BTreeSet::from(["Nextcloud"]),
+			errors: vec![ConfigError {
+				key: String::from("nextcloud/chat"),
+				message: String::from("missing"),
+			}],
+		};
+		assert!(!report.is_ok());
+		assert_eq!(
+			report.to_string(),
+			"Nextcloud: invalid configuration\nGarage: false\n⚠️ Configuration errors:\nnextcloud/chat: missing\n"
+		);
+	}
+
+	#[test]
+	fn test_restrict() {
+		let mut registry = Registry::new();
+		let options = Options {
+			only: Some(String::from("nextcloud,buttons,audio")),
+			simulate: true,
+			..Options::default()
+		};
+		registry.restrict(&options).unwrap();
+		let names = registry
+			.modules
+			.iter()
+			.map(|module| module.name())
+			.collect::<Vec<&str>>();
+		assert_eq!(names, vec!["Nextcloud", "Audio"]);
+
+		let options = Options {
+			only: Some(String::from("doorbell")),
+			..Options::default()
+		};
+		assert!(Registry::new().restrict(&options).is_err());
+	}
+
+	#[test]
+	fn test_check_invalid_enable() {
+		let mut registry = Registry::new();
+		let options = Options {
+			only: Some(String::from("api,signals")),
+			..Options::default()
+		};
+		registry.restrict(&options).unwrap();
+		let mut config = Config::new("/sw/libelektra/opensesame/#0/current");
+		config.add("api/enable", "maybe");
+		let report = registry.check_config(&mut config);
+		assert_eq!(report.enabled, vec![("API", false), ("Signals", true)]);
+		assert_eq!(report.invalid, BTreeSet::from(["API"]));
+		assert_eq!(
+			report.errors,
+			vec![ConfigError {
+				key: String::from("api/enable"),
+				message: String::from("'maybe' is not a boolean (1 or 0)"),
+			}]
+		);
+	}
+
+	#[test]
+	fn test_find() {
+		let registry = Registry::new();
+		assert_eq!(registry.find("env"), Ok("Environment"));
+		assert_eq!(registry.find("PING"), Ok("Ping"));
+		assert!(registry.find("doorbell").is_err());
+		// Bat and Buttons
+		assert!(registry.find("b").is_err());
+	}
+}
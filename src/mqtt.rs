@@ -0,0 +1,756 @@
+//! Publishes readings and what happened to an MQTT broker, with discovery
+//! for Home Assistant, and executes commands received on `<topic>/command/…`,
+//! see doc/MQTT.md.
+
+use std::{collections::HashSet, time::Duration};
+
+use chrono::Local;
+use futures::{future::BoxFuture, never::Never, FutureExt};
+use rumqttc::{AsyncClient, Event as MqttEvent, LastWill, MqttOptions, Packet, QoS};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::{
+	select,
+	time::{sleep_until, timeout, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+	bus::{Event, EventBus},
+	command::{switch, Command},
+	config::{
+		all_errors,
+		validate::{topic, Validate},
+		Config, ConfigError,
+	},
+	history::History,
+	instance::Instance,
+	log,
+	log::Level,
+	module::{Context, Module},
+	nextcloud::NextcloudEvent,
+	notify::NotifyConfig,
+	readings::Readings,
+	routing::{Destination, Routing},
+	state::StateStore,
+	types::ModuleError,
+};
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+/// after the connection to the broker failed
+const RECONNECT: Duration = Duration::from_secs(5);
+/// to deliver the offline status on shutdown
+const TIMEOUT: Duration = Duration::from_secs(2);
+/// messages waiting to be sent, e.g. while the broker is unreachable
+const CAPACITY: usize = 256;
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+#[derive(Deserialize)]
+pub struct MqttConfig {
+	pub host: String,
+	pub port: u16,
+	/// empty for brokers allowing anonymous clients
+	pub user: String,
+	pub password: String,
+	/// prefix of all topics, e.g. `opensesame/garage`
+	pub topic: String,
+	/// prefix of the Home Assistant discovery topics, empty to disable discovery
+	pub discovery: String,
+}
+
+impl Validate for MqttConfig {
+	fn validate(&self) -> Vec<ConfigError> {
+		[
+			topic("mqtt/topic", &self.topic),
+			if self.discovery.is_empty() {
+				None
+			} else {
+				topic("mqtt/discovery", &self.discovery)
+			},
+		]
+		.into_iter()
+		.flatten()
+		.collect()
+	}
+}
+
+/// e.g. `opensesame_0_current`, as client id and Home Assistant node id
+fn node_id(instance: &Instance) -> String {
+	format!("opensesame_{}_{}", instance.number, instance.profile)
+		.chars()
+		.map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+		.collect()
+}
+
+#[derive(Debug, PartialEq)]
+struct Message {
+	topic: String,
+	payload: String,
+	retain: bool,
+}
+
+pub struct Mqtt {
+	topic: String,
+	discovery: String,
+	node_id: String,
+	instance: Instance,
+	bus: EventBus,
+	state: StateStore,
+	history: History,
+	readings: Readings,
+	/// which notifications get published
+	routing: Routing,
+	/// readings announced to Home Assistant since connecting
+	discovered: HashSet<String>,
+}
+
+impl Mqtt {
+	pub fn new(config: &MqttConfig, routing: Routing, context: &Context) -> Self {
+		Self {
+			topic: config.topic.clone(),
+			discovery: config.discovery.clone(),
+			node_id: node_id(&context.instance),
+			instance: context.instance.clone(),
+			bus: context.bus.clone(),
+			state: context.state.clone(),
+			history: context.history.clone(),
+			readings: context.readings.clone(),
+			routing,
+			discovered: HashSet::new(),
+		}
+	}
+
+	/// with the last will, which marks all entities unavailable
+	pub fn options(&self, config: &MqttConfig) -> MqttOptions {
+		let mut options = MqttOptions::new(&self.node_id, &config.host, config.port);
+		options.set_keep_alive(KEEP_ALIVE);
+		// the broker must not keep commands for later while opensesame is away
+		options.set_clean_session(true);
+		options.set_last_will(LastWill::new(
+			self.availability(),
+			OFFLINE,
+			QoS::AtLeastOnce,
+			true,
+		));
+		if !config.user.is_empty() {
+			options.set_credentials(&config.user, &config.password);
+		}
+		options
+	}
+
+	fn availability(&self) -> String {
+		format!("{}/status", self.topic)
+	}
+
+	fn message(&self, subtopic: &str, payload: impl ToString, retain: bool) -> Message {
+		Message {
+			topic: format!("{}/{}", self.topic, subtopic),
+			payload: payload.to_string(),
+			retain,
+		}
+	}
+
+	/// Home Assistant discovery of one entity, `config` gets the common fields added.
+	fn entity(&self, component: &str, object_id: &str, mut config: Value) -> Message {
+		config["unique_id"] = json!(format!("{}_{}", self.node_id, object_id));
+		config["availability_topic"] = json!(self.availability());
+		config["device"] = json!({
+			"identifiers": [self.node_id],
+			"name": format!("opensesame {}", self.instance),
+			"sw_version": env!("CARGO_PKG_VERSION"),
+		});
+		Message {
+			topic: format!(
+				"{}/{}/{}/{}/config",
+				self.discovery, component, self.node_id, object_id
+			),
+			payload: config.to_string(),
+			retain: true,
+		}
+	}
+
+	fn discovery(&self) -> Vec<Message> {
+		let topic = |subtopic: &str| format!("{}/{}", self.topic, subtopic);
+		let event = |name: &str, subtopic: &str, event_type: &str| {
+			json!({
+				"name": name,
+				"state_topic": topic(subtopic),
+				"event_types": [event_type],
+			})
+		};
+		let light = |name: &str, subtopic: &str| {
+			json!({
+				"name": name,
+				"state_topic": topic(subtopic),
+				"command_topic": topic(&format!("command/{}", subtopic)),
+			})
+		};
+		vec![
+			self.entity(
+				"binary_sensor",
+				"garage",
+				json!({
+					"name": "Garage door",
+					"state_topic": topic("garage"),
+					"payload_on": "open",
+					"payload_off": "closed",
+					"device_class": "garage_door",
+				}),
+			),
+			self.entity(
+				"sensor",
+				"air_quality",
+				json!({ "name": "Air quality", "state_topic": topic("air_quality") }),
+			),
+			self.entity(
+				"switch",
+				"lights_inside",
+				light("Lights inside", "lights/inside"),
+			),
+			self.entity(
+				"switch",
+				"lights_outside",
+				light("Lights outside", "lights/outside"),
+			),
+			self.entity(
+				"button",
+				"open_door",
+				json!({ "name": "Open door", "command_topic": topic("command/door") }),
+			),
+			self.entity(
+				"button",
+				"ring_bell",
+				json!({ "name": "Ring bell", "command_topic": topic("command/bell") }),
+			),
+			self.entity("event", "door", event("Door", "door", "opened")),
+			self.entity("event", "bell", event("Bell", "bell", "pressed")),
+			self.entity("event", "alarm", event("Alarm", "alarm", "raised")),
+		]
+	}
+
+	/// Discovery of a reading, once per connection.
+	fn discover_reading(&mut self, key: &str, unit: &str) -> Option<Message> {
+		if self.discovery.is_empty() || !self.discovered.insert(key.to_string()) {
+			return None;
+		}
+		let mut config = json!({
+			"name": key,
+			"state_topic": format!("{}/{}", self.topic, key),
+			"state_class": "measurement",
+		});
+		if !unit.is_empty() {
+			config["unit_of_measurement"] = json!(unit);
+		}
+		Some(self.entity("sensor", &key.replace('/', "_"), config))
+	}
+
+	/// Availability, discovery and the latest readings and states, after (re)connecting.
+	fn connected(&mut self) -> Vec<Message> {
+		self.discovered.clear();
+		let mut messages = vec![Message {
+			topic: self.availability(),
+			payload: String::from(ONLINE),
+			retain: true,
+		}];
+		if !self.discovery.is_empty() {
+			messages.extend(self.discovery());
+		}
+		let latest = self.readings.latest();
+		for (key, reading) in &latest.readings {
+			messages.extend(self.discover_reading(key, reading.unit));
+			messages.push(self.message(key, reading.value, true));
+		}
+		messages.push(self.message("lights/inside", on_off(latest.lights_inside), true));
+		messages.push(self.message("lights/outside", on_off(latest.lights_outside), true));
+		if let Some(closed) = latest.garage_closed {
+			messages.push(self.message("garage", garage(closed), true));
+		}
+		if let Some(air_quality) = latest.air_quality {
+			messages.push(self.message("air_quality", air_quality, true));
+		}
+		messages
+	}
+
+	/// What gets published for an event on the bus.
+	fn messages(&mut self, event: &Event) -> Vec<Message> {
+		match event {
+			Event::Reading(reading) => {
+				let key = reading.key();
+				let mut messages = Vec::from_iter(self.discover_reading(&key, reading.unit));
+				messages.push(self.message(&key, reading.value, true));
+				messages
+			}
+			Event::DoorOpened(by) => {
+				vec![self.message("door", json!({ "event_type": "opened", "by": by }), false)]
+			}
+			Event::BellPressed => {
+				vec![self.message("bell", json!({ "event_type": "pressed" }), false)]
+			}
+			Event::AlarmRaised(reason) => vec![self.message(
+				"alarm",
+				json!({ "event_type": "raised", "reason": reason }),
+				false,
+			)],
+			Event::LightsOn { inside, outside } => vec![
+				self.message("lights/inside", on_off(*inside), true),
+				self.message("lights/outside", on_off(*outside), true),
+			],
+			Event::LightsOff => vec![
+				self.message("lights/inside", on_off(false), true),
+				self.message("lights/outside", on_off(false), true),
+			],
+			Event::GarageDoorClosed(closed) => {
+				vec![self.message("garage", garage(*closed), true)]
+			}
+			Event::AirQualityChanged(air_quality) => {
+				vec![self.message("air_quality", format!("{:?}", air_quality), true)]
+			}
+			Event::Nextcloud(NextcloudEvent::Chat(notification))
+				if self
+					.routing
+					.routes_to(notification, Destination::Mqtt, Local::now().time()) =>
+			{
+				vec![self.message(
+					"notification",
+					json!({
+						"module": notification.module,
+						"severity": notification.severity,
+						"text": notification.text,
+					}),
+					false,
+				)]
+			}
+			_ => vec![],
+		}
+	}
+
+	/// Command for a message on `<topic>/command/…`, None if unknown.
+	fn command(&self, topic: &str, payload: &[u8]) -> Option<Command> {
+		let command = topic
+			.strip_prefix(self.topic.as_str())?
+			.strip_prefix("/command/")?;
+		let payload = String::from_utf8_lossy(payload);
+		let latest = self.readings.latest();
+		match command {
+			"door" => Some(Command::OpenDoor),
+			"bell" => Some(Command::Ring),
+			"lights/inside" => Some(Command::SwitchLights {
+				inside: switch(payload.trim())?,
+				outside: latest.lights_outside,
+			}),
+			"lights/outside" => Some(Command::SwitchLights {
+				inside: latest.lights_inside,
+				outside: switch(payload.trim())?,
+			}),
+			_ => None,
+		}
+	}
+
+	fn publish(client: &AsyncClient, message: Message) {
+		if let Err(error) = client.try_publish(
+			&message.topic,
+			QoS::AtLeastOnce,
+			message.retain,
+			message.payload,
+		) {
+			log!(
+				Level::Debug,
+				"Dropped MQTT message to {}: {}",
+				message.topic,
+				error
+			);
+		}
+	}
+
+	pub async fn get_background_task(
+		mut self,
+		options: MqttOptions,
+		shutdown: CancellationToken,
+	) -> Result<Never, ModuleError> {
+		let (client, mut eventloop) = AsyncClient::new(options, CAPACITY);
+		let mut events = self.bus.subscribe_readings();
+		let mut failing = false;
+		// while the broker is unreachable, not to block the event bus and shutdown
+		let mut reconnect: Option<Instant> = None;
+		loop {
+			select! {
+				_ = sleep_until(reconnect.unwrap_or_else(Instant::now)), if reconnect.is_some() => {
+					reconnect = None;
+				}
+				notification = eventloop.poll(), if reconnect.is_none() => match notification {
+					Ok(MqttEvent::Incoming(Packet::ConnAck(_))) => {
+						log!(Level::Info, "Connected to MQTT broker");
+						failing = false;
+						if let Err(error) = client.try_subscribe(
+							format!("{}/command/#", self.topic),
+							QoS::AtLeastOnce,
+						) {
+							log!(Level::Warning, "Could not subscribe to MQTT commands: {}", error);
+						}
+						for message in self.connected() {
+							Self::publish(&client, message);
+						}
+					}
+					Ok(MqttEvent::Incoming(Packet::Publish(publish))) if publish.retain => {
+						// the broker sends retained messages on every (re)connect,
+						// they must not open the door each time
+						log!(Level::Warning, "Ignored retained MQTT command {}", publish.topic);
+					}
+					Ok(MqttEvent::Incoming(Packet::Publish(publish))) => {
+						match self.command(&publish.topic, &publish.payload) {
+							Some(command) => {
+								log!(Level::Info, "MQTT command: {:?}", command);
+								if let Err(error) = command.execute(&self.bus, &self.state, &self.history).await {
+									log!(Level::Warning, "MQTT command failed: {}", error);
+								}
+							}
+							None => log!(
+								Level::Warning,
+								"Unknown MQTT command {} {}",
+								publish.topic,
+								String::from_utf8_lossy(&publish.payload)
+							),
+						}
+					}
+					Ok(_) => {}
+					Err(error) => {
+						if !failing {
+							log!(Level::Warning, "MQTT broker not reachable, retrying: {}", error);
+						}
+						failing = true;
+						reconnect = Some(Instant::now() + RECONNECT);
+					}
+				},
+				event = events.recv() => match event {
+					Some(event) => {
+						for message in self.messages(&event) {
+							Self::publish(&client, message);
+						}
+					}
+					None => break,
+				},
+				_ = shutdown.cancelled() => {
+					Self::publish(&client, Message {
+						topic: self.availability(),
+						payload: String::from(OFFLINE),
+						retain: true,
+					});
+					let _ = client.try_disconnect();
+					let _ = timeout(TIMEOUT, async {
+						while eventloop.poll().await.is_ok() {}
+					})
+					.await;
+					return Err(ModuleError::new(String::from("MQTT shut down")));
+				}
+			}
+		}
+		Err(ModuleError::new(String::from(
+			"MQTT background task exited",
+		)))
+	}
+}
+
+fn on_off(on: bool) -> &'static str {
+	if on {
+		"ON"
+	} else {
+		"OFF"
+	}
+}
+
+fn garage(closed: bool) -> &'static str {
+	if closed {
+		"closed"
+	} else {
+		"open"
+	}
+}
+
+pub struct MqttModule;
+
+impl Module for MqttModule {
+	fn name(&self) -> &'static str {
+		"MQTT"
+	}
+
+	fn enabled(&self, config: &mut Config) -> Result<bool, ConfigError> {
+		config.value::<bool>("mqtt/enable")
+	}
+
+	fn config_keys(&self) -> &'static [&'static str] {
+		&["mqtt", "notify"]
+	}
+
+	fn check_config(&self, config: &mut Config) -> Result<(), Vec<ConfigError>> {
+		all_errors([
+			config.check::<MqttConfig>("mqtt"),
+			config.check::<NotifyConfig>("notify"),
+		])
+	}
+
+	fn start(&self, context: Context) -> BoxFuture<'static, Result<Never, ModuleError>> {
+		async move {
+			let notify: NotifyConfig = context.config("notify").await?;
+			let config: MqttConfig = context.config("mqtt").await?;
+			let mqtt = Mqtt::new(&config, Routing::new(&notify.route), &context);
+			let options = mqtt.options(&config);
+			mqtt.get_background_task(options, context.shutdown).await
+		}
+		.boxed()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeMap;
+
+	use bytes::BytesMut;
+	use rumqttc::{
+		mqttbytes::{v4::read, Error},
+		ConnAck, ConnectReturnCode, PingResp, PubAck, Publish, SubAck, SubscribeReasonCode,
+	};
+	use tokio::{
+		io::{AsyncReadExt, AsyncWriteExt},
+		net::TcpListener,
+		spawn,
+		sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+	};
+
+	use super::*;
+	use crate::{
+		audio::AudioEvent,
+		buttons::CommandToButtons,
+		cli::Options,
+		nextcloud::NextcloudChat,
+		notify::{Notification, Severity},
+		readings::Reading,
+		routing::Route,
+	};
+
+	async fn context() -> Context {
+		Context::new(
+			Instance::default(),
+			String::from("today"),
+			Default::default(),
+			Options::default(),
+		)
+		.await
+		.unwrap()
+	}
+
+	fn config(port: u16) -> MqttConfig {
+		MqttConfig {
+			host: String::from("127.0.0.1"),
+			port,
+			user: String::new(),
+			password: String::new(),
+			topic: String::from("opensesame/test"),
+			discovery: String::from("homeassistant"),
+		}
+	}
+
+	/// Local broker for one client: acknowledges what the client needs,
+	/// sends `commands` once the client subscribed and forwards everything published.
+	async fn broker(
+		listener: TcpListener,
+		commands: Vec<Publish>,
+		published: UnboundedSender<Publish>,
+	) {
+		let (mut stream, _address) = listener.accept().await.unwrap();
+		let mut buffer = BytesMut::new();
+		loop {
+			let packet = match read(&mut buffer, 1 << 20) {
+				Ok(packet) => packet,
+				Err(Error::InsufficientBytes(_)) => {
+					if stream.read_buf(&mut buffer).await.unwrap() == 0 {
+						return;
+					}
+					continue;
+				}
+				Err(error) => panic!("{:?}", error),
+			};
+			let mut answer = BytesMut::new();
+			match packet {
+				Packet::Connect(_) => {
+					ConnAck::new(ConnectReturnCode::Success, false)
+						.write(&mut answer)
+						.unwrap();
+				}
+				Packet::Subscribe(subscribe) => {
+					SubAck::new(
+						subscribe.pkid,
+						vec![SubscribeReasonCode::Success(QoS::AtLeastOnce)],
+					)
+					.write(&mut answer)
+					.unwrap();
+					for command in &commands {
+						command.write(&mut answer).unwrap();
+					}
+				}
+				Packet::Publish(publish) => {
+					PubAck::new(publish.pkid).write(&mut answer).unwrap();
+					published.send(publish).unwrap();
+				}
+				Packet::PingReq => {
+					PingResp.write(&mut answer).unwrap();
+				}
+				Packet::Disconnect => return,
+				_ => {}
+			}
+			stream.write_all(&answer).await.unwrap();
+		}
+	}
+
+	async fn next(published: &mut UnboundedReceiver<Publish>, topic: &str) -> Publish {
+		loop {
+			let publish = published.recv().await.unwrap();
+			if publish.topic == topic {
+				return publish;
+			}
+		}
+	}
+
+	#[tokio::test]
+	async fn test_command() {
+		let context = context().await;
+		let mqtt = Mqtt::new(&config(1883), Routing::default(), &context);
+		context.readings.update(&Event::LightsOn {
+			inside: false,
+			outside: true,
+		});
+		assert_eq!(
+			mqtt.command("opensesame/test/command/door", b"PRESS"),
+			Some(Command::OpenDoor)
+		);
+		assert_eq!(
+			mqtt.command("opensesame/test/command/lights/inside", b"ON"),
+			Some(Command::SwitchLights {
+				inside: true,
+				outside: true
+			})
+		);
+		assert_eq!(
+			mqtt.command("opensesame/test/command/lights/outside", b"off"),
+			Some(Command::SwitchLights {
+				inside: false,
+				outside: false
+			})
+		);
+		assert_eq!(
+			mqtt.command("opensesame/test/command/lights/inside", b"dim"),
+			None
+		);
+		assert_eq!(mqtt.command("opensesame/other/command/door", b""), None);
+	}
+
+	#[tokio::test]
+	async fn test_messages() {
+		let mut mqtt = Mqtt::new(&config(1883), Routing::default(), &context().await);
+		let reading = Event::Reading(Reading::new("environment", "co2", 420.0, "ppm"));
+		let messages = mqtt.messages(&reading);
+		assert_eq!(messages.len(), 2);
+		assert_eq!(
+			messages[0].topic,
+			"homeassistant/sensor/opensesame_0_current/environment_co2/config"
+		);
+		assert!(messages[0]
+			.payload
+			.contains("\"unit_of_measurement\":\"ppm\""));
+		assert_eq!(
+			messages[1],
+			Message {
+				topic: String::from("opensesame/test/environment/co2"),
+				payload: String::from("420"),
+				retain: true,
+			}
+		);
+		// discovered only once
+		assert_eq!(mqtt.messages(&reading).len(), 1);
+		assert_eq!(
+			mqtt.messages(&Event::GarageDoorClosed(false)),
+			vec![Message {
+				topic: String::from("opensesame/test/garage"),
+				payload: String::from("open"),
+				retain: true,
+			}]
+		);
+		assert!(mqtt.messages(&Event::LightsOff).len() == 2);
+	}
+
+	#[tokio::test]
+	async fn test_notification() {
+		let routing = Routing::new(&BTreeMap::from([(
+			String::from("#0"),
+			Route {
+				severity: Severity::Warning,
+				module: None,
+				to: Destination::Mqtt,
+				quiet: None,
+			},
+		)]));
+		let mut mqtt = Mqtt::new(&config(1883), routing, &context().await);
+		let bell = Notification::info("Buttons", NextcloudChat::Default, "🔔");
+		assert!(mqtt.messages(&bell.into()).is_empty());
+		let battery = Notification::warning("Battery", NextcloudChat::Default, "🪫");
+		assert_eq!(
+			mqtt.messages(&battery.into()),
+			vec![Message {
+				topic: String::from("opensesame/test/notification"),
+				payload: String::from(r#"{"module":"Battery","severity":"warning","text":"🪫"}"#),
+				retain: false,
+			}]
+		);
+	}
+
+	#[tokio::test]
+	async fn test_broker() {
+		let context = context().await;
+		let mut events = context.bus.subscribe();
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let config = config(listener.local_addr().unwrap().port());
+		let (sender, mut published) = unbounded_channel();
+		// retained, e.g. published with -r by mistake
+		let mut door = Publish::new("opensesame/test/command/door", QoS::AtLeastOnce, "PRESS");
+		door.pkid = 1;
+		door.retain = true;
+		let mut bell = Publish::new("opensesame/test/command/bell", QoS::AtLeastOnce, "PRESS");
+		bell.pkid = 2;
+		spawn(broker(listener, vec![door, bell], sender));
+		let mqtt = Mqtt::new(&config, Routing::default(), &context);
+		let options = mqtt.options(&config);
+		let shutdown = CancellationToken::new();
+		spawn(mqtt.get_background_task(options, shutdown.clone()));
+
+		let status = next(&mut published, "opensesame/test/status").await;
+		assert_eq!(&status.payload[..], b"online");
+		assert!(status.retain);
+		let garage = next(
+			&mut published,
+			"homeassistant/binary_sensor/opensesame_0_current/garage/config",
+		)
+		.await;
+		assert!(garage.retain);
+
+		loop {
+			match events.recv().await {
+				Some(Event::Audio(AudioEvent::Bell)) => break,
+				Some(Event::Command(CommandToButtons::OpenDoor)) => {
+					panic!("retained command executed")
+				}
+				_ => {}
+			}
+		}
+
+		context.bus.send(Event::DoorOpened(String::from("alice")));
+		let door = next(&mut published, "opensesame/test/door").await;
+		assert_eq!(
+			&door.payload[..],
+			br#"{"by":"alice","event_type":"opened"}"#
+		);
+
+		shutdown.cancel();
+		let status = next(&mut published, "opensesame/test/status").await;
+		assert_eq!(&status.payload[..], b"offline");
+	}
+}
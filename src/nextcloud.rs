@@ -1,73 +1,228 @@
-use crate::{audio::AudioEvent, buttons::CommandToButtons, config::Config, types::ModuleError};
-use futures::{never::Never, try_join};
+use crate::{
+	bus::{Event, EventBus, Subscription},
+	command::Command,
+	config::{
+		all_errors,
+		validate::{in_range, max_length, Validate, CHAT_TOKEN_LENGTH},
+		Config, ConfigError,
+	},
+	history::History,
+	instance::Instance,
+	log,
+	log::Level,
+	module::{Context, Module},
+	notify::{Notification, Notifier, NotifyConfig, Severity},
+	outbox::{Message, Outbox},
+	readings::{unix_time, Readings},
+	routing::{Destination, Routing},
+	state::StateStore,
+	supervisor::{health_report, Health},
+	types::ModuleError,
+};
+use chrono::{DateTime, Local, Utc};
+use futures::{
+	future::{pending, BoxFuture},
+	never::Never,
+	try_join, FutureExt,
+};
 use gettextrs::gettext;
 use reqwest::{
-	header::{HeaderMap, ACCEPT, CONTENT_TYPE},
-	Client,
+	header::{HeaderMap, ACCEPT, CONTENT_TYPE, RETRY_AFTER},
+	Client, Error, Method, RequestBuilder, StatusCode,
 };
-use std::collections::HashMap;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{collections::HashMap, sync::Arc};
 use tokio::{
-	sync::mpsc::{Receiver, Sender},
-	time::{self, interval},
+	select,
+	sync::{Mutex, Notify},
+	time::{self, sleep_until, timeout, Instant},
 };
+use tokio_util::sync::CancellationToken;
 
+// on shutdown, deliver messages of other modules until it is quiet for this long
+const SHUTDOWN_QUIET: time::Duration = time::Duration::from_secs(1);
+// on shutdown, try this long to deliver the outbox, the rest is sent after the next start
+const SHUTDOWN_DELIVERY: time::Duration = time::Duration::from_secs(10);
+// first retry of undelivered messages, doubled up to MAX_RETRY
+const INITIAL_RETRY: time::Duration = time::Duration::from_secs(5);
+const MAX_RETRY: time::Duration = time::Duration::from_secs(10 * 60);
+const SEND_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+// seconds Talk keeps a poll for new commands open, at most 30
+const POLL_TIMEOUT: u64 = 30;
+// polls are at least this far apart, in case Talk answers right away
+const MIN_POLL: time::Duration = time::Duration::from_secs(1);
+// commands older than this are not executed, e.g. after a restart
+const STALE_COMMAND: u64 = 10 * 60;
+// state key of the last message seen in the commands room
+const LAST_KNOWN_MESSAGE: &str = "nextcloud/commands/last";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum NextcloudChat {
 	Default,
 	Ping,
 	Licht,
+	/// answers to chat commands
+	Commands,
 }
 
+#[derive(Clone, Debug)]
 pub enum NextcloudStatus {
 	Online,
 	Env,
 	Door,
 }
 
+#[derive(Clone, Debug)]
 pub enum NextcloudEvent {
-	Chat(NextcloudChat, String),
+	/// to the Talk rooms of the routing, see `notify::Notification`
+	Chat(Notification),
 	SendStatus,
 	Status(NextcloudStatus, String),
 }
 
+/// Credentials and HTTP client for the Nextcloud server, used for Talk and Analytics.
 #[derive(Clone)]
-pub struct Nextcloud {
+pub struct NextcloudClient {
 	base_url: String,
+	user: String,
+	pass: String,
+	client: Client,
+	headers: HeaderMap,
+}
+
+impl NextcloudClient {
+	pub fn new(config: &NextcloudConfig) -> Self {
+		let mut headers = HeaderMap::new();
+		headers.insert(CONTENT_TYPE, "application/json".parse().unwrap());
+		headers.insert(ACCEPT, "application/json".parse().unwrap());
+		headers.insert("OCS-APIRequest", "true".parse().unwrap());
+		Self {
+			base_url: config.url.clone(),
+			user: config.user.clone(),
+			pass: config.pass.clone(),
+			client: Client::new(),
+			headers,
+		}
+	}
+
+	/// Authenticated request to `path`, e.g. `/ocs/v2.php/apps/spreed/api/v1/chat/<token>`.
+	pub fn request(&self, method: Method, path: &str) -> RequestBuilder {
+		self.client
+			.request(method, format!("{}{}", self.base_url, path))
+			.basic_auth(&self.user, Some(&self.pass))
+			.headers(self.headers.clone())
+	}
+}
+
+#[derive(Clone)]
+pub struct Nextcloud {
 	chat: String,
 	chat_ping: String,
 	chat_licht: String,
 	chat_commands: String,
-	user: String,
-	pass: String,
 	info_door: String,
 	info_environment: String,
 	info_online: String,
-	client: Client,
-	headers: HeaderMap,
+	client: NextcloudClient,
+	/// which notifications go to which rooms
+	routing: Routing,
+	/// deduplication and rate limits in front of the outbox
+	notifier: Notifier,
+	/// messages not yet accepted by Nextcloud
+	outbox: Outbox,
+	/// wakes `delivery_loop` when something got queued
+	queued: Arc<Notify>,
+	/// wakes `delivery_loop` also while it waits for the next retry
+	alarm: Arc<Notify>,
+	/// for the time of late messages
+	format_datetime: String,
 	startup_time: String,
+	instance: Instance,
+	health: Health,
+	history: History,
+	readings: Readings,
+	/// for the `alarm` command
+	state: StateStore,
+	/// log instead of sending, see `--dry-run`
+	dry_run: bool,
+}
+
+#[derive(Deserialize)]
+pub struct NextcloudConfig {
+	pub url: String,
+	pub user: String,
+	pub pass: String,
+	/// tokens of the Talk rooms
+	pub chat: String,
+	#[serde(rename = "chat/ping")]
+	pub chat_ping: String,
+	#[serde(rename = "chat/licht")]
+	pub chat_licht: String,
+	#[serde(rename = "chat/commands")]
+	pub chat_commands: String,
+	/// file of the undelivered messages, empty to only keep them in memory
+	pub outbox: String,
+	#[serde(rename = "outbox/size")]
+	pub outbox_size: u32,
+	#[serde(rename = "format/datetime")]
+	pub format_datetime: String,
+}
+
+impl Validate for NextcloudConfig {
+	fn validate(&self) -> Vec<ConfigError> {
+		[
+			("nextcloud/chat", &self.chat),
+			("nextcloud/chat/ping", &self.chat_ping),
+			("nextcloud/chat/licht", &self.chat_licht),
+			("nextcloud/chat/commands", &self.chat_commands),
+		]
+		.into_iter()
+		.filter_map(|(key, token)| max_length(key, token, CHAT_TOKEN_LENGTH))
+		.chain(in_range(
+			"nextcloud/outbox/size",
+			self.outbox_size,
+			1..=100_000,
+		))
+		.collect()
+	}
 }
 
 impl Nextcloud {
-	pub fn new(config: &mut Config) -> Self {
-		let mut headers = HeaderMap::new();
-		headers.insert(CONTENT_TYPE, "application/json".parse().unwrap());
-		headers.insert(ACCEPT, "application/json".parse().unwrap());
-		headers.insert("OCS-APIRequest", "true".parse().unwrap());
-		let client = reqwest::Client::new();
+	pub async fn new(config: NextcloudConfig, notify: &NotifyConfig, context: &Context) -> Self {
+		let outbox = Some(context.instance.outbox_file(&config.outbox))
+			.filter(|_| !config.outbox.is_empty());
 		Self {
-			base_url: config.get::<String>("nextcloud/url"),
-			chat: config.get::<String>("nextcloud/chat"),
-			chat_ping: config.get::<String>("nextcloud/chat/ping"),
-			chat_licht: config.get::<String>("nextcloud/chat/licht"),
-			chat_commands: config.get::<String>("nextcloud/chat/commands"),
-			user: config.get::<String>("nextcloud/user"),
-			pass: config.get::<String>("nextcloud/pass"),
+			client: NextcloudClient::new(&config),
+			routing: Routing::new(&notify.route),
+			notifier: Notifier::new(notify),
+			outbox: Outbox::open(outbox, config.outbox_size as usize).await,
+			queued: Arc::new(Notify::new()),
+			alarm: Arc::new(Notify::new()),
+			format_datetime: config.format_datetime,
+			chat: config.chat,
+			chat_ping: config.chat_ping,
+			chat_licht: config.chat_licht,
+			chat_commands: config.chat_commands,
 			info_door: String::new(),
 			info_environment: String::new(),
 			info_online: String::new(),
-			client,
-			headers,
-			startup_time: String::new(),
+			startup_time: context.startup_time.clone(),
+			instance: context.instance.clone(),
+			health: context.health.clone(),
+			history: context.history.clone(),
+			readings: context.readings.clone(),
+			state: context.state.clone(),
+			dry_run: context.options.dry_run,
+		}
+	}
+
+	/// true if messages only get logged, see `--dry-run`
+	fn dry_run(&self, chat: &str, message: &str) -> bool {
+		if self.dry_run {
+			log!(Level::Info, "Dry run, not sent to {}: {}", chat, message);
 		}
+		self.dry_run
 	}
 
 	// sends once, Err if it does not work on network or nextcloud level
@@ -81,61 +236,56 @@ impl Nextcloud {
 		payload.insert("message", &message);
 		let response = self
 			.client
-			.post(format!(
-				"{}/ocs/v2.php/apps/spreed/api/v1/chat/{}",
-				&self.base_url, chat
-			))
-			.basic_auth(&self.user, Some(&self.pass))
-			.headers(self.headers.clone())
+			.request(
+				Method::POST,
+				&format!("/ocs/v2.php/apps/spreed/api/v1/chat/{}", chat),
+			)
 			.json(&payload)
 			.send()
 			.await?;
 		response.error_for_status()
 	}
 
-	async fn licht(&self, message: String) {
-		let result = self.send_message_once(&message, &self.chat_licht).await;
-
-		match result {
-			Ok(..) => (),
-			Err(error) => {
-				eprintln!("Couldn't post to licht {} because {}", message, error);
-			}
-		};
-	}
-
-	async fn ping(&self, message: String) {
-		let result = self.send_message_once(&message, &self.chat_ping).await;
-
-		match result {
-			Ok(..) => (),
-			Err(error) => {
-				eprintln!("Couldn't ping {} because {}", message, error);
-			}
-		};
+	/// Queues the message for `delivery_loop`, unless `--dry-run`.
+	fn queue(&self, chat: &str, text: String, alarm: bool) {
+		if self.dry_run(chat, &text) {
+			return;
+		}
+		self.outbox.push(Message {
+			chat: chat.to_string(),
+			text,
+			time: unix_time(),
+			alarm,
+		});
+		self.queued.notify_one();
+		if alarm {
+			self.alarm.notify_one();
+		}
 	}
 
-	// logs and sends message, retries once, if it fails twice it logs the error
-	async fn send_message(&self, message: String) {
-		let result = self.send_message_once(&message, &self.chat).await;
-
-		match result {
-			Ok(..) => (),
-			Err(old_error) => {
-				let result = self
-					.send_message_once(&format!("{} (sent again)", message), &self.chat)
-					.await;
-				match result {
-					Ok(..) => (),
-					Err(error) => {
-						eprintln!(
-							"Couldn't send {} first because {} and then because {}",
-							message, old_error, error
-						);
-					}
+	/// Sends the queued messages in order until one fails. Messages queued meanwhile
+	/// are sent in the same go, alarms before older messages.
+	async fn deliver(&self) -> Result<(), String> {
+		while let Some(message) = self.outbox.front() {
+			let text = message.text_at(unix_time(), &self.format_datetime);
+			match timeout(SEND_TIMEOUT, self.send_message_once(&text, &message.chat)).await {
+				Ok(Ok(..)) => {}
+				Ok(Err(error)) if rejected(&error) => {
+					self.readings.nextcloud_failed();
+					log!(
+						Level::Warning,
+						"Dropped message {} because {}",
+						message.text,
+						error
+					);
 				}
+				Ok(Err(error)) => return Err(error.to_string()),
+				Err(..) => return Err(format!("no answer within {:?}", SEND_TIMEOUT)),
 			}
+			// an alarm might have been queued in front of it meanwhile
+			self.outbox.remove(&message);
 		}
+		Ok(())
 	}
 
 	async fn set_info_online(&mut self, info: String) {
@@ -153,16 +303,15 @@ impl Nextcloud {
 		self.send_status().await;
 	}
 
-	async fn set_status_in_chat(&self) {
-		self.send_message_once(
-			&format!(
-				"Status: {} {} {}",
-				self.info_online, self.info_door, self.info_environment
-			),
-			&self.chat_commands,
-		)
-		.await
-		.unwrap();
+	fn set_status_in_chat(&self) {
+		let status = format!(
+			"Status: {} {} {}\n{}",
+			self.info_online,
+			self.info_door,
+			self.info_environment,
+			health_report(&self.health)
+		);
+		self.queue(&self.chat_commands, status, false);
 	}
 
 	async fn send_status(&self) {
@@ -170,202 +319,733 @@ impl Nextcloud {
 			"{} {} {}",
 			self.info_online, self.info_door, self.info_environment
 		);
+		if self.dry_run("status", &status) {
+			return;
+		}
 		let mut payload = HashMap::new();
 		payload.insert("message", &status);
 		let result = self
 			.client
-			.put(format!(
-				"{}/ocs/v2.php/apps/user_status/api/v1/user_status/message/custom",
-				&self.base_url
-			))
-			.basic_auth(&self.user, Some(&self.pass))
-			.headers(self.headers.clone())
+			.request(
+				Method::PUT,
+				"/ocs/v2.php/apps/user_status/api/v1/user_status/message/custom",
+			)
 			.json(&payload)
+			.timeout(SEND_TIMEOUT)
 			.send()
 			.await;
 		match result {
 			Ok(..) => (),
 			Err(error) => {
-				eprintln!("Couldn't set status message {} because {}", status, error);
+				self.readings.nextcloud_failed();
+				log!(
+					Level::Warning,
+					"Couldn't set status message {} because {}",
+					status,
+					error
+				);
 			}
 		};
 	}
 
-	async fn get_last_messages(
-		&self,
-		last_known_message_id: &str,
-	) -> Result<reqwest::Response, reqwest::Error> {
-		let endpoint = format!(
-			"{}/ocs/v2.php/apps/spreed/api/v1/chat/{}",
-			self.base_url, self.chat_commands,
-		);
-		let query_params = [
-			("lookIntoFuture", "1"),
-			("limit", "100"),
-			("lastKnownMessageId", last_known_message_id),
-		];
+	/// ID of the newest message in the commands room, to start listening from.
+	async fn latest_message_id(&self) -> Result<u64, String> {
+		let endpoint = format!("/ocs/v2.php/apps/spreed/api/v1/chat/{}", self.chat_commands);
+		let response = self
+			.client
+			.request(Method::GET, &endpoint)
+			.query(&[("lookIntoFuture", "0"), ("limit", "1")])
+			.timeout(SEND_TIMEOUT)
+			.send()
+			.await
+			.and_then(|response| response.error_for_status())
+			.map_err(|error| error.to_string())?;
+		let json = response
+			.json::<Value>()
+			.await
+			.map_err(|error| error.to_string())?;
+		Ok(parse_messages(&json)?
+			.iter()
+			.map(|message| message.id)
+			.max()
+			.unwrap_or(0))
+	}
 
+	/// Long poll: waits up to POLL_TIMEOUT seconds for messages after `last_known_message_id`,
+	/// empty if there were none.
+	async fn poll(&self, last_known_message_id: u64) -> Result<Vec<ChatMessage>, PollError> {
+		let endpoint = format!("/ocs/v2.php/apps/spreed/api/v1/chat/{}", self.chat_commands);
 		let response = self
 			.client
-			.get(&endpoint)
-			.query(&query_params)
-			.basic_auth(&self.user, Some(&self.pass))
-			.headers(self.headers.clone())
+			.request(Method::GET, &endpoint)
+			.query(&[
+				("lookIntoFuture", String::from("1")),
+				("timeout", POLL_TIMEOUT.to_string()),
+				("limit", String::from("100")),
+				("lastKnownMessageId", last_known_message_id.to_string()),
+			])
+			.timeout(time::Duration::from_secs(POLL_TIMEOUT) + SEND_TIMEOUT)
 			.send()
-			.await?;
+			.await
+			.map_err(|error| error.to_string())?;
+		if response.status() == StatusCode::NOT_MODIFIED {
+			return Ok(Vec::new());
+		}
+		let retry_after = retry_after(response.headers(), Utc::now());
+		let response = response.error_for_status().map_err(|error| PollError {
+			message: error.to_string(),
+			retry_after,
+		})?;
+		let json = response
+			.json::<Value>()
+			.await
+			.map_err(|error| error.to_string())?;
+		Ok(parse_messages(&json)?)
+	}
 
-		response.error_for_status()
+	/// One poll, the new messages are remembered before their commands get executed,
+	/// so a command which crashes opensesame is not repeated.
+	/// Returns the ID to continue from.
+	async fn poll_commands(&self, bus: &EventBus, last: Option<u64>) -> Result<u64, PollError> {
+		let Some(last) = last else {
+			let latest = self.latest_message_id().await?;
+			self.state.set(LAST_KNOWN_MESSAGE, &latest.to_string());
+			return Ok(latest);
+		};
+		let messages = self.poll(last).await?;
+		let latest = messages
+			.iter()
+			.map(|message| message.id)
+			.fold(last, u64::max);
+		if latest != last {
+			self.state.set(LAST_KNOWN_MESSAGE, &latest.to_string());
+		}
+		let now = unix_time();
+		for message in &messages {
+			let Some(command) = message.command() else {
+				continue;
+			};
+			let result = if now.saturating_sub(message.timestamp) > STALE_COMMAND {
+				Err(gettext!("⌛ Ignored old command \\{}", command))
+			} else {
+				let words = command.split_whitespace().collect::<Vec<&str>>();
+				match Command::parse(&words) {
+					Ok(command) => command.execute(bus, &self.state, &self.history).await,
+					Err(usage) => Err(usage),
+				}
+			};
+			match result {
+				Ok(output) if output.is_empty() => {}
+				Ok(output) | Err(output) => bus.send(Notification::info(
+					"Nextcloud",
+					NextcloudChat::Commands,
+					output,
+				)),
+			}
+		}
+		Ok(latest)
 	}
 
 	pub async fn get_background_task(
-		mut self,
-		nextcloud_receiver: Receiver<NextcloudEvent>,
-		nextcloud_sender: Sender<NextcloudEvent>,
-		command_sender: Sender<CommandToButtons>,
-		audio_sender: Sender<AudioEvent>,
-		startup_time: String,
+		self,
+		nextcloud_events: Arc<Mutex<Subscription>>,
+		bus: EventBus,
+		shutdown: CancellationToken,
 	) -> Result<Never, ModuleError> {
-		self.startup_time = startup_time;
 		try_join!(
-			self.clone().message_sender_loop(nextcloud_receiver),
-			self.command_loop(nextcloud_sender, command_sender, audio_sender)
+			self.clone().message_sender_loop(nextcloud_events, shutdown),
+			self.command_loop(bus)
 		)?;
 		Err(ModuleError::new(String::from(
 			"Exit get_background_task loop!",
 		)))
 	}
 
+	/// Receives the events in `event_loop` while `delivery_loop` sends the queued
+	/// messages, so that a slow or unreachable Nextcloud never holds up the bus.
+	/// On shutdown, the outbox gets one more try.
 	async fn message_sender_loop(
 		mut self,
-		mut nextcloud_receiver: Receiver<NextcloudEvent>,
+		nextcloud_events: Arc<Mutex<Subscription>>,
+		shutdown: CancellationToken,
 	) -> Result<Never, ModuleError> {
-		self.ping(gettext!(
-			"👋 Opensesame {} init {}",
-			env!("CARGO_PKG_VERSION"),
-			self.startup_time
-		))
-		.await;
-		while let Some(event) = nextcloud_receiver.recv().await {
-			match event {
-				NextcloudEvent::Chat(chat, message) => match chat {
-					NextcloudChat::Default => self.send_message(message).await,
-					NextcloudChat::Ping => self.ping(message).await,
-					NextcloudChat::Licht => self.licht(message).await,
-				},
-				NextcloudEvent::SendStatus => self.set_status_in_chat().await,
-				NextcloudEvent::Status(status, message) => match status {
-					NextcloudStatus::Online => self.set_info_online(message).await,
-					NextcloudStatus::Env => self.set_info_environment(message).await,
-					NextcloudStatus::Door => self.set_info_door(message).await,
+		let delivery = self.clone().delivery_loop();
+		let error = select! {
+			error = self.event_loop(nextcloud_events, shutdown) => error,
+			never = delivery => match never {},
+		};
+		// a message interrupted while sending is still in the outbox
+		if !matches!(timeout(SHUTDOWN_DELIVERY, self.deliver()).await, Ok(Ok(()))) {
+			log!(
+				Level::Warning,
+				"{} messages left in the outbox for the next start",
+				self.outbox.len()
+			);
+		}
+		self.outbox.save().await;
+		Err(error)
+	}
+
+	/// Queues the notifications of the bus until shutdown or the bus is gone.
+	async fn event_loop(
+		&mut self,
+		nextcloud_events: Arc<Mutex<Subscription>>,
+		shutdown: CancellationToken,
+	) -> ModuleError {
+		let mut nextcloud_events = nextcloud_events.lock().await;
+		self.notify(Notification::debug(
+			"Nextcloud",
+			NextcloudChat::Ping,
+			gettext!(
+				"👋 Opensesame {} {} init {}",
+				env!("CARGO_PKG_VERSION"),
+				self.instance,
+				self.startup_time
+			),
+		));
+		loop {
+			let summaries = self.notifier.next_due();
+			// usually nothing is left to write
+			self.outbox.save().await;
+			select! {
+				event = nextcloud_events.recv() => match event {
+					Some(event) => self.handle_event(event).await,
+					None => break,
 				},
+				_ = sleep_until(summaries.unwrap_or_else(Instant::now)), if summaries.is_some() => {
+					for notification in self.notifier.due(Instant::now()) {
+						self.queue_chat(notification);
+					}
+				}
+				_ = shutdown.cancelled() => {
+					while let Ok(Some(event)) = timeout(SHUTDOWN_QUIET, nextcloud_events.recv()).await {
+						self.handle_event(event).await;
+					}
+					self.notify(Notification::debug(
+						"Nextcloud",
+						NextcloudChat::Ping,
+						gettext!(
+							"👋 Opensesame {} {} stopped, was running since {}",
+							env!("CARGO_PKG_VERSION"),
+							self.instance,
+							self.startup_time
+						),
+					));
+					for notification in self.notifier.flush() {
+						self.queue_chat(notification);
+					}
+					return ModuleError::new(String::from("Nextcloud shut down"));
+				}
 			}
 		}
-		Err(ModuleError::new(String::from(
-			"Exit Nextcloud messagesender loop!",
-		)))
+		ModuleError::new(String::from("Exit Nextcloud messagesender loop!"))
 	}
 
-	async fn command_loop(
-		self,
-		nextcloud_sender: Sender<NextcloudEvent>,
-		command_sender: Sender<CommandToButtons>,
-		audio_sender: Sender<AudioEvent>,
-	) -> Result<Never, ModuleError> {
-		let a = self
-			.send_message_once("Started listening to commands here", &self.chat_commands)
-			.await
-			.unwrap();
-		let mut last_known_message_id =
-			a.json::<serde_json::Value>().await.unwrap()["ocs"]["data"]["id"].to_string();
+	/// Sends the outbox whenever something got queued, with a growing pause after failures.
+	async fn delivery_loop(self) -> Never {
+		let mut backoff = INITIAL_RETRY;
+		let mut retry: Option<Instant> = None;
 		loop {
-			let response = self.get_last_messages(&last_known_message_id).await;
-			match response {
-				Ok(response) => {
-					let status = response.status().as_u16();
-					if status == 200 {
-						let json = response.json::<serde_json::Value>().await.unwrap();
-
-						let messages = json["ocs"]["data"].as_array();
-						for message in messages
-							.unwrap()
-							.iter()
-							.map(|m| m["message"].as_str().unwrap())
-						{
-							if message.starts_with('\\') {
-								let command_and_args = message
-									.strip_prefix('\\')
-									.unwrap()
-									.split_whitespace()
-									.collect::<Vec<&str>>();
-								let command = command_and_args[0];
-								let args = &command_and_args[1..];
-								match command {
-									"status" => {
-										nextcloud_sender.send(NextcloudEvent::SendStatus).await?
-									}
-									"setpin" => {
-										// TODO: How do we access config?
-									}
-									"switchlights" => {
-										if args.len() != 2 {
-											nextcloud_sender
-												.send(NextcloudEvent::Chat(
-													NextcloudChat::Default,
-													String::from(
-														"Usage: switchlights <bool> <bool>",
-													),
-												))
-												.await?;
-										}
-
-										let inner_light = args[0].eq_ignore_ascii_case("true");
-										let outer_light = args[1].eq_ignore_ascii_case("true");
-
-										command_sender
-											.send(CommandToButtons::SwitchLights(
-												inner_light,
-												outer_light,
-												String::from("Switch lights {} {}"),
-											))
-											.await?;
-									}
-									"opensesame" => {
-										nextcloud_sender
-											.send(NextcloudEvent::Chat(
-												NextcloudChat::Default,
-												String::from("Opening door"),
-											))
-											.await?;
-										command_sender.send(CommandToButtons::OpenDoor).await?;
-									}
-									"ring_bell" => audio_sender.send(AudioEvent::Bell).await?,
-									"fire_alarm" => {
-										audio_sender.send(AudioEvent::FireAlarm).await?
-									}
-									_ => {
-										nextcloud_sender
-											.send(NextcloudEvent::Chat(
-												NextcloudChat::Default,
-												format!("Unknown command {}!", command),
-											))
-											.await?;
-									}
-								}
-							}
-						}
-						if let Some(last_message) = messages.unwrap().last() {
-							last_known_message_id = last_message["id"].to_string();
-						}
-					} else if status != 304 {
-						// 304 - Not modified is expected if there are no new messages
-						println!("Status code was not successful but {}", response.status());
+			if retry.is_none() {
+				match self.deliver().await {
+					Ok(()) => backoff = INITIAL_RETRY,
+					Err(error) => {
+						self.readings.nextcloud_failed();
+						log!(
+							Level::Warning,
+							"Couldn't deliver {} messages, retrying in {:?}: {}",
+							self.outbox.len(),
+							backoff,
+							error
+						);
+						retry = Some(Instant::now() + backoff);
+						backoff = (backoff * 2).min(MAX_RETRY);
 					}
 				}
-				Err(err) => {
-					eprintln!("Error: {:?}", err);
+			}
+			// once per batch
+			self.outbox.save().await;
+			select! {
+				_ = self.queued.notified(), if retry.is_none() => {}
+				// alarms don't wait for the next retry
+				_ = self.alarm.notified() => {
+					retry = None;
+				}
+				_ = sleep_until(retry.unwrap_or_else(Instant::now)), if retry.is_some() => {
+					retry = None;
+				}
+			}
+		}
+	}
+
+	/// Queues the notification for the rooms of the routing, as far as it passes the
+	/// deduplication and rate limits. Answers to commands go back directly.
+	fn notify(&mut self, notification: Notification) {
+		let rooms = if notification.chat == NextcloudChat::Commands {
+			vec![NextcloudChat::Commands]
+		} else {
+			self.routing
+				.destinations(&notification, Local::now().time())
+				.iter()
+				.filter_map(Destination::chat)
+				.collect()
+		};
+		for chat in rooms {
+			let notification = Notification {
+				chat,
+				..notification.clone()
+			};
+			for message in self.notifier.notify(notification, Instant::now()) {
+				self.queue_chat(message);
+			}
+		}
+	}
+
+	fn queue_chat(&self, notification: Notification) {
+		let token = match notification.chat {
+			NextcloudChat::Default => &self.chat,
+			NextcloudChat::Ping => &self.chat_ping,
+			NextcloudChat::Licht => &self.chat_licht,
+			NextcloudChat::Commands => &self.chat_commands,
+		};
+		let alarm = notification.severity == Severity::Alarm;
+		self.queue(token, notification.text, alarm);
+	}
+
+	async fn handle_event(&mut self, event: Event) {
+		let event = match event {
+			Event::Nextcloud(event) => event,
+			_ => return,
+		};
+		match event {
+			NextcloudEvent::Chat(notification) => self.notify(notification),
+			NextcloudEvent::SendStatus => self.set_status_in_chat(),
+			NextcloudEvent::Status(status, message) => match status {
+				NextcloudStatus::Online => self.set_info_online(message).await,
+				NextcloudStatus::Env => self.set_info_environment(message).await,
+				NextcloudStatus::Door => self.set_info_door(message).await,
+			},
+		}
+	}
+
+	/// Executes the commands of the commands room, continuing after the last message
+	/// seen before a restart.
+	async fn command_loop(self, bus: EventBus) -> Result<Never, ModuleError> {
+		if self.dry_run {
+			// listening would need a message to start from
+			return pending().await;
+		}
+		self.queue(
+			&self.chat_commands,
+			String::from("Started listening to commands here"),
+			false,
+		);
+		let mut last = self
+			.state
+			.get_option::<u64>(LAST_KNOWN_MESSAGE)
+			.await
+			.unwrap_or_else(|error| {
+				log!(
+					Level::Warning,
+					"Couldn't read {}, starting with new messages: {}",
+					LAST_KNOWN_MESSAGE,
+					error
+				);
+				None
+			});
+		let mut backoff = INITIAL_RETRY;
+		loop {
+			let next_poll = Instant::now() + MIN_POLL;
+			match self.poll_commands(&bus, last).await {
+				Ok(latest) => {
+					last = Some(latest);
+					backoff = INITIAL_RETRY;
+					sleep_until(next_poll).await;
+				}
+				Err(error) => {
+					// as long as Talk asks for, e.g. when rate limiting
+					let pause = error
+						.retry_after
+						.map_or(backoff, |after| after.max(backoff));
+					log!(
+						Level::Warning,
+						"Couldn't poll commands, retrying in {:?}: {}",
+						pause,
+						error.message
+					);
+					time::sleep(pause).await;
+					backoff = (backoff * 2).min(MAX_RETRY);
 				}
 			}
-			interval(time::Duration::from_secs(1)).tick().await;
 		}
 	}
 }
+
+/// Why polling the commands failed.
+#[derive(Debug, PartialEq)]
+struct PollError {
+	message: String,
+	/// from the `Retry-After` header, e.g. on 429
+	retry_after: Option<time::Duration>,
+}
+
+impl From<String> for PollError {
+	fn from(message: String) -> Self {
+		Self {
+			message,
+			retry_after: None,
+		}
+	}
+}
+
+/// `Retry-After` in seconds or as HTTP date, None if missing or invalid.
+fn retry_after(headers: &HeaderMap, now: DateTime<Utc>) -> Option<time::Duration> {
+	let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+	match value.parse::<u64>() {
+		Ok(seconds) => Some(time::Duration::from_secs(seconds)),
+		Err(_) => {
+			let date = DateTime::parse_from_rfc2822(value).ok()?;
+			(date.with_timezone(&Utc) - now).to_std().ok()
+		}
+	}
+}
+
+/// A message of the commands room, as far as needed.
+#[derive(Debug, PartialEq)]
+struct ChatMessage {
+	id: u64,
+	/// unix time
+	timestamp: u64,
+	/// None for system messages, deleted messages and the like
+	text: Option<String>,
+}
+
+impl ChatMessage {
+	/// The command without the backslash, e.g. `status`.
+	fn command(&self) -> Option<&str> {
+		self.text.as_deref()?.strip_prefix('\\')
+	}
+}
+
+/// The messages of a chat answer. Messages without ID are skipped, only
+/// comments have a text. Fails if the answer contains no messages at all.
+fn parse_messages(json: &Value) -> Result<Vec<ChatMessage>, String> {
+	let data = json["ocs"]["data"]
+		.as_array()
+		.ok_or_else(|| String::from("no messages in the answer"))?;
+	Ok(data
+		.iter()
+		.filter_map(|message| {
+			let comment = !matches!(message["messageType"].as_str(), Some(kind) if kind != "comment")
+				&& !matches!(message["systemMessage"].as_str(), Some(system) if !system.is_empty());
+			Some(ChatMessage {
+				id: message["id"].as_u64()?,
+				timestamp: message["timestamp"].as_u64().unwrap_or_default(),
+				text: message["message"]
+					.as_str()
+					.filter(|_| comment)
+					.map(String::from),
+			})
+		})
+		.collect())
+}
+
+/// Nextcloud will never accept the message, e.g. an unknown chat or a too long message.
+fn rejected(error: &Error) -> bool {
+	matches!(
+		error.status(),
+		Some(StatusCode::BAD_REQUEST | StatusCode::NOT_FOUND | StatusCode::PAYLOAD_TOO_LARGE)
+	)
+}
+
+pub struct NextcloudModule;
+
+impl Module for NextcloudModule {
+	fn name(&self) -> &'static str {
+		"Nextcloud"
+	}
+
+	fn enabled(&self, _config: &mut Config) -> Result<bool, ConfigError> {
+		Ok(true)
+	}
+
+	fn config_keys(&self) -> &'static [&'static str] {
+		&["nextcloud", "notify"]
+	}
+
+	fn check_config(&self, config: &mut Config) -> Result<(), Vec<ConfigError>> {
+		all_errors([
+			config.check::<NextcloudConfig>("nextcloud"),
+			config.check::<NotifyConfig>("notify"),
+		])
+	}
+
+	fn start(&self, context: Context) -> BoxFuture<'static, Result<Never, ModuleError>> {
+		async move {
+			let nextcloud: NextcloudConfig = context.config("nextcloud").await?;
+			let notify: NotifyConfig = context.config("notify").await?;
+			Nextcloud::get_background_task(
+				Nextcloud::new(nextcloud, &notify, &context).await,
+				context.nextcloud_events,
+				context.bus,
+				context.shutdown,
+			)
+			.await
+		}
+		.boxed()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{collections::BTreeMap, fs};
+
+	use super::*;
+	use crate::{cli::Options, notify::Limit, stub::Stub};
+
+	async fn nextcloud(url: &str, context: &Context) -> Nextcloud {
+		Nextcloud::new(
+			NextcloudConfig {
+				url: url.to_string(),
+				user: String::from("opensesame"),
+				pass: String::from("secret"),
+				chat: String::from("default"),
+				chat_ping: String::from("ping"),
+				chat_licht: String::from("licht"),
+				chat_commands: String::from("commands"),
+				outbox: String::new(),
+				outbox_size: 10,
+				format_datetime: String::from("%s"),
+			},
+			&NotifyConfig {
+				dedup: 0,
+				air: Limit {
+					limit: 1,
+					period: 60,
+				},
+				weather: Limit {
+					limit: 1,
+					period: 60,
+				},
+				hardware: Limit {
+					limit: 1,
+					period: 60,
+				},
+				route: BTreeMap::new(),
+			},
+			context,
+		)
+		.await
+	}
+
+	#[tokio::test]
+	async fn test_deliver() {
+		let stub = Stub::start().await;
+		let context = Context::new(
+			Instance::default(),
+			String::from("today"),
+			Default::default(),
+			Options::default(),
+		)
+		.await
+		.unwrap();
+		let mut nextcloud = nextcloud(&stub.url, &context).await;
+		let queued = unix_time() - 3600;
+		nextcloud.outbox.push(Message {
+			chat: String::from("default"),
+			text: String::from("🔔 Received bell"),
+			time: queued,
+			alarm: false,
+		});
+		nextcloud
+			.handle_event(Notification::debug("Ping", NextcloudChat::Ping, "👋").into())
+			.await;
+
+		stub.respond(503, "{}");
+		assert!(nextcloud.deliver().await.is_err());
+		assert_eq!(nextcloud.outbox.len(), 2);
+
+		nextcloud
+			.handle_event(Notification::alarm("Signals", "🚨 Fire").into())
+			.await;
+		// wakes the delivery while it waits for the retry
+		assert!(
+			timeout(time::Duration::from_secs(1), nextcloud.alarm.notified())
+				.await
+				.is_ok()
+		);
+		// the ping goes to an unknown chat
+		stub.respond(200, "{}");
+		stub.respond(200, "{}");
+		stub.respond(404, "{}");
+		assert!(nextcloud.deliver().await.is_ok());
+		assert!(nextcloud.outbox.is_empty());
+
+		let requests = stub.requests();
+		let sent = requests
+			.iter()
+			.map(|request| {
+				let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+				(
+					request.path.as_str(),
+					body["message"].as_str().unwrap().to_string(),
+				)
+			})
+			.collect::<Vec<(&str, String)>>();
+		let late = format!("[{}] 🔔 Received bell", queued);
+		assert_eq!(
+			sent,
+			vec![
+				("/ocs/v2.php/apps/spreed/api/v1/chat/default", late.clone()),
+				(
+					"/ocs/v2.php/apps/spreed/api/v1/chat/default",
+					String::from("🚨 Fire")
+				),
+				("/ocs/v2.php/apps/spreed/api/v1/chat/default", late),
+				(
+					"/ocs/v2.php/apps/spreed/api/v1/chat/ping",
+					String::from("👋")
+				),
+			]
+		);
+		assert_eq!(context.readings.latest().counters.nextcloud_failures, 1);
+	}
+
+	#[test]
+	fn test_retry_after() {
+		let now = DateTime::parse_from_rfc2822("Mon, 19 Oct 2026 10:00:00 GMT")
+			.unwrap()
+			.with_timezone(&Utc);
+		let headers = |value: &str| HeaderMap::from_iter([(RETRY_AFTER, value.parse().unwrap())]);
+		assert_eq!(
+			retry_after(&headers("30"), now),
+			Some(time::Duration::from_secs(30))
+		);
+		assert_eq!(
+			retry_after(&headers("Mon, 19 Oct 2026 10:02:00 GMT"), now),
+			Some(time::Duration::from_secs(120))
+		);
+		assert_eq!(
+			retry_after(&headers("Mon, 19 Oct 2026 09:00:00 GMT"), now),
+			None
+		);
+		assert_eq!(retry_after(&headers("soon"), now), None);
+		assert_eq!(retry_after(&HeaderMap::new(), now), None);
+	}
+
+	#[test]
+	fn test_parse_messages() {
+		let json = serde_json::json!({ "ocs": { "data": [
+			{ "id": 41, "timestamp": 1000, "message": "\\status", "messageType": "comment", "systemMessage": "" },
+			{ "id": 42, "timestamp": 1001, "message": "{actor} joined", "messageType": "system", "systemMessage": "user_added" },
+			{ "id": 43, "timestamp": 1002, "message": "{file}", "messageType": "comment", "messageParameters": { "file": { "type": "file" } } },
+			{ "id": 44, "message": "Message deleted", "messageType": "comment_deleted" },
+			{ "id": "45", "message": "\\open" },
+			{ "id": 46, "message": ["\\open"] },
+		]}});
+		let messages = parse_messages(&json).unwrap();
+		assert_eq!(
+			messages
+				.iter()
+				.map(|message| message.id)
+				.collect::<Vec<u64>>(),
+			vec![41, 42, 43, 44, 46]
+		);
+		assert_eq!(messages[0].command(), Some("status"));
+		assert_eq!(messages[0].timestamp, 1000);
+		assert_eq!(messages[1].text, None);
+		assert_eq!(messages[2].text.as_deref(), Some("{file}"));
+		assert_eq!(messages[2].command(), None);
+		assert_eq!(messages[3].text, None);
+		assert_eq!(messages[4].text, None);
+		assert!(parse_messages(&serde_json::json!({ "ocs": { "data": {} } })).is_err());
+		assert!(parse_messages(&serde_json::json!("<html>")).is_err());
+	}
+
+	#[tokio::test]
+	async fn test_poll_commands() {
+		let stub = Stub::start().await;
+		// not to touch the state of a running opensesame
+		let runtime = std::env::temp_dir().join(format!("opensesame-talk-{}", std::process::id()));
+		fs::create_dir_all(&runtime).unwrap();
+		let context = Context::new(
+			Instance::default(),
+			String::from("today"),
+			BTreeMap::from([(
+				String::from("instance/runtime"),
+				runtime.to_str().unwrap().to_string(),
+			)]),
+			Options::default(),
+		)
+		.await
+		.unwrap();
+		let nextcloud = nextcloud(&stub.url, &context).await;
+		let mut events = context.bus.subscribe();
+		let now = unix_time();
+
+		stub.respond(
+			200,
+			r#"{"ocs": {"data": [{"id": 40, "message": "hello"}]}}"#,
+		);
+		assert_eq!(nextcloud.poll_commands(&context.bus, None).await, Ok(40));
+		stub.respond(
+			200,
+			&serde_json::json!({ "ocs": { "data": [
+				{ "id": 41, "timestamp": now, "message": "\\status", "messageType": "comment" },
+				{ "id": 42, "timestamp": now - STALE_COMMAND - 1, "message": "\\open" },
+				{ "id": 43, "timestamp": now, "message": "thanks" },
+			]}})
+			.to_string(),
+		);
+		assert_eq!(
+			nextcloud.poll_commands(&context.bus, Some(40)).await,
+			Ok(43)
+		);
+		assert_eq!(
+			context.state.get_option::<u64>(LAST_KNOWN_MESSAGE).await,
+			Ok(Some(43))
+		);
+		assert!(matches!(
+			events.try_recv(),
+			Some(Event::Nextcloud(NextcloudEvent::SendStatus))
+		));
+		match events.try_recv() {
+			Some(Event::Nextcloud(NextcloudEvent::Chat(answer))) => {
+				assert_eq!(answer.chat, NextcloudChat::Commands);
+				assert!(answer.text.contains("\\open"));
+			}
+			event => panic!("no answer but {:?}", event),
+		}
+		assert!(events.try_recv().is_none());
+
+		// no new messages within the timeout
+		stub.respond(304, "");
+		assert_eq!(
+			nextcloud.poll_commands(&context.bus, Some(43)).await,
+			Ok(43)
+		);
+		stub.respond_with(429, &[("Retry-After", "120")], "{}");
+		let error = nextcloud
+			.poll_commands(&context.bus, Some(43))
+			.await
+			.unwrap_err();
+		assert!(error.message.contains("429"));
+		assert_eq!(error.retry_after, Some(time::Duration::from_secs(120)));
+		stub.respond(200, r#"{"ocs": {"meta": {"status": "failure"}}}"#);
+		assert!(nextcloud
+			.poll_commands(&context.bus, Some(43))
+			.await
+			.is_err());
+
+		let requests = stub.requests();
+		assert_eq!(
+			requests[0].path,
+			"/ocs/v2.php/apps/spreed/api/v1/chat/commands?lookIntoFuture=0&limit=1"
+		);
+		assert_eq!(
+			requests[1].path,
+			"/ocs/v2.php/apps/spreed/api/v1/chat/commands?lookIntoFuture=1&timeout=30&limit=100&lastKnownMessageId=40"
+		);
+		fs::remove_dir_all(runtime).unwrap();
+	}
+}
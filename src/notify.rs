@@ -0,0 +1,447 @@
+//! Notifications of the modules, and their deduplication and rate limits in
+//! front of the outbox of Nextcloud, see doc/Nextcloud.md#notifications.
+//!
+//! Identical messages within `notify/dedup` seconds are sent once. Messages of
+//! a category, e.g. flapping air quality, are limited to `notify/<category>/limit`
+//! per `notify/<category>/period`, the rest is summarized at the end of the period.
+
+use std::collections::BTreeMap;
+
+use gettextrs::gettext;
+use serde::{Deserialize, Serialize};
+use tokio::time::{Duration, Instant};
+
+use crate::{
+	bus::Event,
+	config::{
+		validate::{in_range, Validate},
+		ConfigError,
+	},
+	nextcloud::{NextcloudChat, NextcloudEvent},
+	routing::{self, Route},
+};
+
+/// longest period and deduplication, in seconds
+const DAY: u64 = 24 * 60 * 60;
+
+/// How important a notification is, the routing sends it to destinations from some severity on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+	/// e.g. pings and baselines
+	Debug,
+	/// e.g. bell, door and lights
+	Info,
+	/// e.g. read errors, bad air quality and low battery
+	Warning,
+	/// fire alarms, ahead of everything else in the outbox
+	Alarm,
+}
+
+/// Sent by the modules for the chat and the other destinations of the routing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Notification {
+	/// name of the sending module, e.g. `Buttons`
+	pub module: &'static str,
+	pub severity: Severity,
+	/// Talk room chosen by the module
+	pub chat: NextcloudChat,
+	/// rate limited if set
+	pub category: Option<Category>,
+	pub text: String,
+}
+
+impl Notification {
+	pub fn new(
+		module: &'static str,
+		severity: Severity,
+		chat: NextcloudChat,
+		text: impl Into<String>,
+	) -> Self {
+		Self {
+			module,
+			severity,
+			chat,
+			category: None,
+			text: text.into(),
+		}
+	}
+
+	pub fn debug(module: &'static str, chat: NextcloudChat, text: impl Into<String>) -> Self {
+		Self::new(module, Severity::Debug, chat, text)
+	}
+
+	pub fn info(module: &'static str, chat: NextcloudChat, text: impl Into<String>) -> Self {
+		Self::new(module, Severity::Info, chat, text)
+	}
+
+	pub fn warning(module: &'static str, chat: NextcloudChat, text: impl Into<String>) -> Self {
+		Self::new(module, Severity::Warning, chat, text)
+	}
+
+	/// to the default chat
+	pub fn alarm(module: &'static str, text: impl Into<String>) -> Self {
+		Self::new(module, Severity::Alarm, NextcloudChat::Default, text)
+	}
+
+	pub fn category(mut self, category: Category) -> Self {
+		self.category = Some(category);
+		self
+	}
+}
+
+impl From<Notification> for Event {
+	fn from(notification: Notification) -> Self {
+		Event::Nextcloud(NextcloudEvent::Chat(notification))
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Category {
+	/// air quality changes of Environment
+	Air,
+	/// warnings and errors of the weather station
+	Weather,
+	/// read errors of boards and sensors
+	Hardware,
+}
+
+impl Category {
+	fn description(&self) -> String {
+		match self {
+			Category::Air => gettext("air quality"),
+			Category::Weather => gettext("weather"),
+			Category::Hardware => gettext("hardware error"),
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct Limit {
+	/// messages per period, 0 to only send the summary
+	pub limit: u32,
+	/// seconds
+	pub period: u64,
+}
+
+#[derive(Deserialize)]
+pub struct NotifyConfig {
+	/// seconds in which identical messages are sent once, 0 to send all
+	pub dedup: u64,
+	pub air: Limit,
+	pub weather: Limit,
+	pub hardware: Limit,
+	/// `notify/route/#<n>`, see routing
+	pub route: BTreeMap<String, Route>,
+}
+
+impl Validate for NotifyConfig {
+	fn validate(&self) -> Vec<ConfigError> {
+		[
+			("notify/dedup", self.dedup, 0),
+			("notify/air/period", self.air.period, 1),
+			("notify/weather/period", self.weather.period, 1),
+			("notify/hardware/period", self.hardware.period, 1),
+		]
+		.into_iter()
+		.filter_map(|(key, seconds, min)| in_range(key, seconds, min..=DAY))
+		.chain(routing::validate(&self.route))
+		.collect()
+	}
+}
+
+#[derive(Clone)]
+struct Repeated {
+	since: Instant,
+	/// suppressed since
+	count: u32,
+	latest: Notification,
+}
+
+#[derive(Clone)]
+struct Window {
+	start: Instant,
+	sent: u32,
+	held: u32,
+	/// of the held messages
+	latest: Option<Notification>,
+}
+
+#[derive(Clone)]
+pub struct Notifier {
+	dedup: Duration,
+	limits: BTreeMap<Category, Limit>,
+	recent: BTreeMap<(NextcloudChat, String), Repeated>,
+	windows: BTreeMap<Category, Window>,
+}
+
+/// for the summaries, rounded up
+fn minutes(duration: Duration) -> u64 {
+	duration.as_secs().div_ceil(60).max(1)
+}
+
+impl Notifier {
+	pub fn new(config: &NotifyConfig) -> Self {
+		Self {
+			dedup: Duration::from_secs(config.dedup),
+			limits: BTreeMap::from([
+				(Category::Air, config.air),
+				(Category::Weather, config.weather),
+				(Category::Hardware, config.hardware),
+			]),
+			recent: BTreeMap::new(),
+			windows: BTreeMap::new(),
+		}
+	}
+
+	fn period(&self, category: Category) -> Duration {
+		Duration::from_secs(self.limits[&category].period)
+	}
+
+	/// The messages to send now: due summaries and the notification itself, unless it is
+	/// a duplicate or over the limit of its category. Alarms and answers to commands always pass.
+	pub fn notify(&mut self, notification: Notification, now: Instant) -> Vec<Notification> {
+		let mut messages = self.due(now);
+		if notification.severity == Severity::Alarm || notification.chat == NextcloudChat::Commands
+		{
+			messages.push(notification);
+			return messages;
+		}
+		let key = (notification.chat, notification.text.clone());
+		if let Some(repeated) = self.recent.get_mut(&key) {
+			repeated.count += 1;
+			repeated.latest = notification;
+			return messages;
+		}
+		if !self.dedup.is_zero() {
+			self.recent.insert(
+				key,
+				Repeated {
+					since: now,
+					count: 0,
+					latest: notification.clone(),
+				},
+			);
+		}
+		if let Some(category) = notification.category {
+			let limit = self.limits[&category].limit;
+			let window = self.windows.entry(category).or_insert(Window {
+				start: now,
+				sent: 0,
+				held: 0,
+				latest: None,
+			});
+			if window.sent >= limit {
+				window.held += 1;
+				window.latest = Some(notification);
+				return messages;
+			}
+			window.sent += 1;
+		}
+		messages.push(notification);
+		messages
+	}
+
+	/// Summaries of the suppressed messages whose deduplication or period ended.
+	pub fn due(&mut self, now: Instant) -> Vec<Notification> {
+		let mut messages = Vec::new();
+		let dedup = self.dedup;
+		self.recent.retain(|_, repeated| {
+			if now < repeated.since + dedup {
+				return true;
+			}
+			if repeated.count > 0 {
+				messages.push(Notification {
+					category: None,
+					text: gettext!(
+						"🔁 {} more times in the last {} min: {}",
+						repeated.count,
+						minutes(dedup),
+						repeated.latest.text
+					),
+					..repeated.latest.clone()
+				});
+			}
+			false
+		});
+		let limits = &self.limits;
+		self.windows.retain(|category, window| {
+			let period = Duration::from_secs(limits[category].period);
+			if now < window.start + period {
+				return true;
+			}
+			if let Some(latest) = window.latest.take() {
+				messages.push(Notification {
+					category: None,
+					text: gettext!(
+						"⏳ {} more {} messages in the last {} min, the latest: {}",
+						window.held,
+						category.description(),
+						minutes(period),
+						latest.text
+					),
+					..latest
+				});
+			}
+			false
+		});
+		messages
+	}
+
+	/// All pending summaries, e.g. on shutdown.
+	pub fn flush(&mut self) -> Vec<Notification> {
+		self.due(Instant::now() + Duration::from_secs(DAY))
+	}
+
+	/// When the next summary is due, None if nothing was suppressed.
+	pub fn next_due(&self) -> Option<Instant> {
+		let repeated = self
+			.recent
+			.values()
+			.filter(|repeated| repeated.count > 0)
+			.map(|repeated| repeated.since + self.dedup);
+		let held = self
+			.windows
+			.iter()
+			.filter(|(_, window)| window.held > 0)
+			.map(|(category, window)| window.start + self.period(*category));
+		repeated.chain(held).min()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn notifier() -> Notifier {
+		Notifier::new(&NotifyConfig {
+			dedup: 60,
+			air: Limit {
+				limit: 2,
+				period: 3600,
+			},
+			weather: Limit {
+				limit: 2,
+				period: 3600,
+			},
+			hardware: Limit {
+				limit: 1,
+				period: 600,
+			},
+			route: BTreeMap::new(),
+		})
+	}
+
+	fn texts(messages: Vec<Notification>) -> Vec<String> {
+		messages.into_iter().map(|message| message.text).collect()
+	}
+
+	#[test]
+	fn test_dedup() {
+		let mut notifier = notifier();
+		let start = Instant::now();
+		let bell = || String::from("🔔 Pressed button bell.");
+		let notify = |notifier: &mut Notifier, chat, seconds| {
+			texts(notifier.notify(
+				Notification::info("Buttons", chat, bell()),
+				start + Duration::from_secs(seconds),
+			))
+		};
+		assert_eq!(
+			notify(&mut notifier, NextcloudChat::Default, 0),
+			vec![bell()]
+		);
+		assert!(notify(&mut notifier, NextcloudChat::Default, 10).is_empty());
+		assert!(notify(&mut notifier, NextcloudChat::Default, 20).is_empty());
+		// other chats are independent, commands always pass
+		assert_eq!(
+			notify(&mut notifier, NextcloudChat::Licht, 20),
+			vec![bell()]
+		);
+		assert_eq!(
+			notify(&mut notifier, NextcloudChat::Commands, 20),
+			vec![bell()]
+		);
+		assert_eq!(
+			notify(&mut notifier, NextcloudChat::Commands, 20),
+			vec![bell()]
+		);
+
+		assert_eq!(notifier.next_due(), Some(start + Duration::from_secs(60)));
+		assert!(notifier.due(start + Duration::from_secs(59)).is_empty());
+		assert_eq!(
+			notifier.due(start + Duration::from_secs(60)),
+			vec![Notification::info(
+				"Buttons",
+				NextcloudChat::Default,
+				"🔁 2 more times in the last 1 min: 🔔 Pressed button bell."
+			)]
+		);
+		assert_eq!(notifier.next_due(), None);
+		assert_eq!(
+			notify(&mut notifier, NextcloudChat::Default, 61),
+			vec![bell()]
+		);
+	}
+
+	#[test]
+	fn test_limit() {
+		let mut notifier = notifier();
+		let start = Instant::now();
+		let error = |board| {
+			Notification::warning(
+				"Buttons",
+				NextcloudChat::Ping,
+				format!("⚠️ Error reading buttons of board {}", board),
+			)
+			.category(Category::Hardware)
+		};
+		for board in 0..12 {
+			let messages = notifier.notify(error(board), start + Duration::from_secs(board));
+			assert_eq!(messages.len(), if board == 0 { 1 } else { 0 });
+		}
+		// other categories have their own limit
+		let co2 = notifier.notify(
+			Notification::info(
+				"Environment",
+				NextcloudChat::Default,
+				"💩 Airquality is moderate.",
+			)
+			.category(Category::Air),
+			start + Duration::from_secs(20),
+		);
+		assert_eq!(co2.len(), 1);
+
+		assert_eq!(notifier.next_due(), Some(start + Duration::from_secs(600)));
+		let messages = notifier.notify(error(12), start + Duration::from_secs(600));
+		assert_eq!(messages[0].chat, NextcloudChat::Ping);
+		assert_eq!(messages[0].category, None);
+		assert_eq!(
+			texts(messages),
+			vec![
+				String::from("⏳ 11 more hardware error messages in the last 10 min, the latest: ⚠️ Error reading buttons of board 11"),
+				String::from("⚠️ Error reading buttons of board 12"),
+			]
+		);
+	}
+
+	#[test]
+	fn test_flush() {
+		let mut notifier = notifier();
+		let now = Instant::now();
+		let rain = || {
+			Notification::warning("Weatherstation", NextcloudChat::Default, "🌧️ Rain")
+				.category(Category::Weather)
+		};
+		for _ in 0..3 {
+			notifier.notify(rain(), now);
+		}
+		let alarm = Notification::alarm("Signals", "🌧️ Rain");
+		assert_eq!(notifier.notify(alarm.clone(), now), vec![alarm]);
+		assert_eq!(
+			texts(notifier.flush()),
+			vec![String::from("🔁 2 more times in the last 1 min: 🌧️ Rain")]
+		);
+		assert!(notifier.flush().is_empty());
+	}
+}
@@ -0,0 +1,302 @@
+//! Durable queue of the chat messages to Nextcloud, see doc/Nextcloud.md#outbox.
+//!
+//! Messages stay in the file until Nextcloud accepted them, so they survive
+//! network outages and restarts. Alarms are queued before all other messages,
+//! otherwise the order is kept. The file is only written by `save`, off the
+//! async runtime and only if the queue changed since the last write.
+
+use std::{
+	collections::VecDeque,
+	fs, io,
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex},
+};
+
+use chrono::{Local, TimeZone};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Mutex as AsyncMutex, task::spawn_blocking};
+
+use crate::{log, log::Level};
+
+/// seconds after which a message gets the time it was queued as prefix
+pub const LATE: u64 = 60;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Message {
+	/// token of the Talk room
+	pub chat: String,
+	pub text: String,
+	/// when it was queued, unix time
+	pub time: u64,
+	pub alarm: bool,
+}
+
+impl Message {
+	/// The text, prefixed with the time it was queued if it gets delivered late.
+	pub fn text_at(&self, now: u64, format: &str) -> String {
+		if now.saturating_sub(self.time) < LATE {
+			return self.text.clone();
+		}
+		match Local.timestamp_opt(self.time as i64, 0).single() {
+			Some(time) => format!("[{}] {}", time.format(format), self.text),
+			None => self.text.clone(),
+		}
+	}
+}
+
+#[derive(Default)]
+struct Queue {
+	/// None to only keep the messages in memory
+	path: Option<PathBuf>,
+	capacity: usize,
+	messages: VecDeque<Message>,
+	/// what the file contains, to skip writes if nothing changed
+	saved: Vec<u8>,
+}
+
+/// Written to a temporary file first, so a power failure leaves the old or the new queue.
+fn write(path: &Path, bytes: &[u8]) -> io::Result<()> {
+	if let Some(dir) = path.parent() {
+		fs::create_dir_all(dir)?;
+	}
+	let temporary = path.with_extension("tmp");
+	fs::write(&temporary, bytes)?;
+	fs::rename(temporary, path)
+}
+
+fn read(path: &Path) -> (VecDeque<Message>, Vec<u8>) {
+	match fs::read(path) {
+		Ok(bytes) => match serde_json::from_slice(&bytes) {
+			Ok(messages) => (messages, bytes),
+			Err(error) => {
+				log!(
+					Level::Warning,
+					"Ignored broken outbox {}: {}",
+					path.display(),
+					error
+				);
+				(VecDeque::new(), bytes)
+			}
+		},
+		Err(error) if error.kind() == io::ErrorKind::NotFound => (VecDeque::new(), Vec::new()),
+		Err(error) => {
+			log!(
+				Level::Warning,
+				"Couldn't read outbox {}: {}",
+				path.display(),
+				error
+			);
+			(VecDeque::new(), Vec::new())
+		}
+	}
+}
+
+/// Shared handle, cheap to clone.
+#[derive(Clone, Default)]
+pub struct Outbox {
+	queue: Arc<Mutex<Queue>>,
+	/// one `save` at a time, e.g. of the event and the delivery loop of Nextcloud
+	saving: Arc<AsyncMutex<()>>,
+}
+
+impl Outbox {
+	/// Loads the messages left over from the last run.
+	pub async fn open(path: Option<PathBuf>, capacity: usize) -> Self {
+		let (messages, saved) = match path.clone() {
+			Some(path) => spawn_blocking(move || read(&path))
+				.await
+				.unwrap_or_default(),
+			None => (VecDeque::new(), Vec::new()),
+		};
+		Self {
+			queue: Arc::new(Mutex::new(Queue {
+				path,
+				capacity: capacity.max(1),
+				messages,
+				saved,
+			})),
+			saving: Arc::default(),
+		}
+	}
+
+	/// Writes the queue if it changed since the last `save`, e.g. after a batch got delivered.
+	pub async fn save(&self) {
+		let _saving = self.saving.lock().await;
+		let (path, bytes) = {
+			let queue = self.queue.lock().unwrap();
+			let Some(path) = queue.path.clone() else {
+				return;
+			};
+			let bytes = match serde_json::to_vec(&queue.messages) {
+				Ok(bytes) => bytes,
+				Err(error) => {
+					log!(Level::Warning, "Couldn't serialize outbox: {}", error);
+					return;
+				}
+			};
+			if bytes == queue.saved {
+				return;
+			}
+			(path, bytes)
+		};
+		let file = path.display().to_string();
+		match spawn_blocking(move || write(&path, &bytes).map(|()| bytes)).await {
+			Ok(Ok(bytes)) => self.queue.lock().unwrap().saved = bytes,
+			Ok(Err(error)) => log!(Level::Warning, "Couldn't write outbox {}: {}", file, error),
+			Err(error) => log!(Level::Warning, "Couldn't write outbox {}: {}", file, error),
+		}
+	}
+
+	/// Alarms go behind the queued alarms, everything else to the end.
+	/// If the outbox is full, the oldest message is dropped, alarms only if there are no others.
+	pub fn push(&self, message: Message) {
+		let mut queue = self.queue.lock().unwrap();
+		let position = if message.alarm {
+			queue
+				.messages
+				.iter()
+				.position(|queued| !queued.alarm)
+				.unwrap_or(queue.messages.len())
+		} else {
+			queue.messages.len()
+		};
+		queue.messages.insert(position, message);
+		if queue.messages.len() > queue.capacity {
+			let oldest = queue
+				.messages
+				.iter()
+				.position(|queued| !queued.alarm)
+				.unwrap_or(0);
+			if let Some(dropped) = queue.messages.remove(oldest) {
+				log!(
+					Level::Warning,
+					"Outbox full, dropped message {}",
+					dropped.text
+				);
+			}
+		}
+	}
+
+	/// The next message to deliver.
+	pub fn front(&self) -> Option<Message> {
+		self.queue.lock().unwrap().messages.front().cloned()
+	}
+
+	/// Removes the next message after it was delivered.
+	pub fn pop_front(&self) {
+		self.queue.lock().unwrap().messages.pop_front();
+	}
+
+	/// Like `pop_front`, for a message which might not be the next one any more,
+	/// e.g. if an alarm got queued while it was sent.
+	pub fn remove(&self, message: &Message) {
+		let mut queue = self.queue.lock().unwrap();
+		if let Some(position) = queue.messages.iter().position(|queued| queued == message) {
+			queue.messages.remove(position);
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.queue.lock().unwrap().messages.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::process;
+
+	use super::*;
+
+	fn message(text: &str, alarm: bool) -> Message {
+		Message {
+			chat: String::from("abcdefgh"),
+			text: text.to_string(),
+			time: 1_000,
+			alarm,
+		}
+	}
+
+	fn texts(outbox: &Outbox) -> Vec<String> {
+		let mut texts = Vec::new();
+		while let Some(message) = outbox.front() {
+			texts.push(message.text);
+			outbox.pop_front();
+		}
+		texts
+	}
+
+	#[tokio::test]
+	async fn test_order() {
+		let outbox = Outbox::open(None, 4).await;
+		outbox.push(message("door", false));
+		outbox.push(message("fire", true));
+		outbox.push(message("bell", false));
+		outbox.push(message("sigalrm", true));
+		assert_eq!(texts(&outbox), vec!["fire", "sigalrm", "door", "bell"]);
+		assert!(outbox.is_empty());
+
+		// an alarm overtook the message while it was sent
+		outbox.push(message("door", false));
+		let sending = outbox.front().unwrap();
+		outbox.push(message("fire", true));
+		outbox.remove(&sending);
+		assert_eq!(texts(&outbox), vec!["fire"]);
+	}
+
+	#[tokio::test]
+	async fn test_full() {
+		let outbox = Outbox::open(None, 3).await;
+		outbox.push(message("door", false));
+		outbox.push(message("bell", false));
+		outbox.push(message("fire", true));
+		outbox.push(message("lights", false));
+		assert_eq!(texts(&outbox), vec!["fire", "bell", "lights"]);
+
+		for text in ["fire 1", "fire 2", "fire 3", "fire 4"] {
+			outbox.push(message(text, true));
+		}
+		assert_eq!(texts(&outbox), vec!["fire 2", "fire 3", "fire 4"]);
+	}
+
+	#[tokio::test]
+	async fn test_persistence() {
+		let path =
+			std::env::temp_dir().join(format!("opensesame-outbox-{}/outbox.json", process::id()));
+		let outbox = Outbox::open(Some(path.clone()), 10).await;
+		outbox.push(message("door", false));
+		outbox.push(message("fire", true));
+		outbox.pop_front();
+		outbox.save().await;
+
+		let reopened = Outbox::open(Some(path.clone()), 10).await;
+		assert_eq!(reopened.front(), Some(message("door", false)));
+		assert_eq!(reopened.len(), 1);
+
+		// unchanged, not written again
+		fs::remove_file(&path).unwrap();
+		outbox.push(message("bell", false));
+		outbox.pop_front();
+		outbox.pop_front();
+		outbox.push(message("door", false));
+		outbox.save().await;
+		assert!(!path.exists());
+
+		fs::write(&path, "[{").unwrap();
+		let broken = Outbox::open(Some(path.clone()), 10).await;
+		assert!(broken.is_empty());
+		broken.save().await;
+		assert_eq!(fs::read(&path).unwrap(), b"[]");
+		fs::remove_dir_all(path.parent().unwrap()).unwrap();
+	}
+
+	#[test]
+	fn test_late() {
+		let message = message("door", false);
+		assert_eq!(message.text_at(1_000 + LATE - 1, "%s"), "door");
+		assert_eq!(message.text_at(1_000 + LATE, "%s"), "[1000] door");
+	}
+}
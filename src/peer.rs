@@ -0,0 +1,442 @@
+//! Messages between opensesame instances, e.g. the garage forwards the fire
+//! alarm and the bell to the house door.
+//!
+//! Every message is one JSON line over its own TCP connection, signed with
+//! HMAC-SHA256 and the key shared by all peers (`peer/key`). Messages older
+//! than `MAX_AGE` or received before are rejected, so they cannot be replayed.
+//! The receiver answers with `ok` or `error: <reason>`.
+
+use std::{
+	collections::VecDeque,
+	sync::{Arc, Mutex},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::{future::BoxFuture, never::Never, FutureExt};
+use gettextrs::gettext;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::{
+	io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+	net::{TcpListener, TcpStream},
+	select, spawn,
+	time::timeout,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+	audio::AudioEvent,
+	bus::{Event, EventBus},
+	buttons::CommandToButtons,
+	config::{
+		validate::{min_length, Validate},
+		Config, ConfigError,
+	},
+	log,
+	log::Level,
+	module::{Context, Module},
+	nextcloud::NextcloudChat,
+	notify::Notification,
+	state::StateStore,
+	types::ModuleError,
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// how much the clocks of the peers may differ, plus the time in transit
+const MAX_AGE: Duration = Duration::from_secs(60);
+/// for connecting, sending and answering
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// longer lines are rejected without parsing them
+const MAX_LINE: u64 = 4096;
+const MIN_KEY_LENGTH: usize = 16;
+
+#[derive(Deserialize)]
+pub struct PeerConfig {
+	/// how this opensesame is called in messages to its peers
+	pub name: String,
+	/// address to accept messages on, empty to only send
+	pub listen: String,
+	/// shared by all peers
+	pub key: String,
+	/// `host:port` of the other opensesame instances
+	pub addresses: Vec<String>,
+}
+
+impl Validate for PeerConfig {
+	fn validate(&self) -> Vec<ConfigError> {
+		min_length("peer/key", &self.key, MIN_KEY_LENGTH)
+			.into_iter()
+			.collect()
+	}
+}
+
+/// Sent to all peers when published on the bus,
+/// received ones are handled like the corresponding signal.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum PeerMessage {
+	/// sets `alarm/fire` to the reason, like `kdb set`
+	Alarm { reason: String },
+	/// rings the bell, like SIGUSR2
+	Bell,
+}
+
+/// the signed part of a message
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct Payload {
+	from: String,
+	/// nanoseconds since the epoch, also identifies the message
+	time: u64,
+	message: PeerMessage,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+	#[serde(flatten)]
+	payload: Payload,
+	/// hex encoded HMAC-SHA256 of the JSON payload
+	mac: String,
+}
+
+fn now() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.unwrap_or_default()
+		.as_nanos() as u64
+}
+
+#[derive(Clone)]
+pub struct Peers {
+	name: String,
+	key: Vec<u8>,
+	addresses: Vec<String>,
+	bus: EventBus,
+	state: StateStore,
+	/// sender and time of the messages accepted within `MAX_AGE`
+	received: Arc<Mutex<VecDeque<(String, u64)>>>,
+}
+
+impl Peers {
+	pub fn new(config: PeerConfig, bus: EventBus, state: StateStore) -> Self {
+		Self {
+			name: config.name,
+			key: config.key.into_bytes(),
+			addresses: config.addresses,
+			bus,
+			state,
+			received: Arc::new(Mutex::new(VecDeque::new())),
+		}
+	}
+
+	fn mac(&self, payload: &Payload) -> HmacSha256 {
+		let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC takes keys of any size");
+		mac.update(serde_json::to_string(payload).unwrap().as_bytes());
+		mac
+	}
+
+	fn sign(&self, message: PeerMessage) -> String {
+		let payload = Payload {
+			from: self.name.clone(),
+			time: now(),
+			message,
+		};
+		let mac = hex::encode(self.mac(&payload).finalize().into_bytes());
+		serde_json::to_string(&Envelope { payload, mac }).unwrap()
+	}
+
+	/// Checks signature, age and whether the message was received before.
+	fn verify(&self, line: &str) -> Result<Payload, String> {
+		let envelope: Envelope =
+			serde_json::from_str(line).map_err(|error| format!("invalid message: {}", error))?;
+		let payload = envelope.payload;
+		hex::decode(&envelope.mac)
+			.ok()
+			.and_then(|mac| self.mac(&payload).verify_slice(&mac).ok())
+			.ok_or_else(|| String::from("invalid signature"))?;
+
+		let now = now();
+		let max_age = MAX_AGE.as_nanos() as u64;
+		if payload.time.abs_diff(now) > max_age {
+			return Err(String::from(
+				"message too old, are the clocks synchronized?",
+			));
+		}
+		let mut received = self.received.lock().unwrap();
+		while received
+			.front()
+			.is_some_and(|(_, time)| time.abs_diff(now) > max_age)
+		{
+			received.pop_front();
+		}
+		let id = (payload.from.clone(), payload.time);
+		if received.contains(&id) {
+			return Err(String::from("message received before"));
+		}
+		received.push_back(id);
+		Ok(payload)
+	}
+
+	async fn receive(&self, from: &str, message: PeerMessage) -> Result<(), String> {
+		log!(Level::Info, "Received {:?} from peer {}", message, from);
+		match message {
+			PeerMessage::Alarm { reason } => {
+				// triggers the alarm via reload-opensesame, see Signals
+				self.state
+					.set_now("alarm/fire", &reason)
+					.await
+					.map_err(|error| error.to_string())?;
+			}
+			PeerMessage::Bell => {
+				self.bus.send(CommandToButtons::RingBell(20, 0));
+				self.bus.send(AudioEvent::Bell);
+				self.bus.send(Notification::info(
+					"Peer",
+					NextcloudChat::Default,
+					gettext!("🔔 Received bell from {}", from),
+				));
+			}
+		}
+		Ok(())
+	}
+
+	async fn handle(&self, stream: TcpStream) -> std::io::Result<()> {
+		let (reader, mut writer) = stream.into_split();
+		let mut line = String::new();
+		timeout(
+			TIMEOUT,
+			BufReader::new(reader.take(MAX_LINE)).read_line(&mut line),
+		)
+		.await??;
+		let result = match self.verify(line.trim_end()) {
+			Ok(payload) => self.receive(&payload.from, payload.message).await,
+			Err(error) => Err(error),
+		};
+		let answer = match result {
+			Ok(()) => String::from("ok\n"),
+			Err(error) => {
+				log!(Level::Warning, "Rejected peer message: {}", error);
+				format!("error: {}\n", error)
+			}
+		};
+		timeout(TIMEOUT, writer.write_all(answer.as_bytes())).await??;
+		Ok(())
+	}
+
+	/// Sends a line and returns the answer.
+	async fn exchange(address: &str, line: &str) -> std::io::Result<String> {
+		let stream = timeout(TIMEOUT, TcpStream::connect(address)).await??;
+		let (reader, mut writer) = stream.into_split();
+		timeout(TIMEOUT, writer.write_all(format!("{}\n", line).as_bytes())).await??;
+		let mut answer = String::new();
+		timeout(
+			TIMEOUT,
+			BufReader::new(reader.take(MAX_LINE)).read_line(&mut answer),
+		)
+		.await??;
+		Ok(answer.trim_end().to_string())
+	}
+
+	pub async fn send_to(&self, address: &str, message: PeerMessage) -> Result<(), String> {
+		match Self::exchange(address, &self.sign(message)).await {
+			Ok(answer) if answer == "ok" => Ok(()),
+			Ok(answer) => Err(answer),
+			Err(error) => Err(error.to_string()),
+		}
+	}
+
+	/// To all peers, failures get reported in the Ping chat.
+	fn send(&self, message: PeerMessage) {
+		for address in &self.addresses {
+			let peers = self.clone();
+			let address = address.clone();
+			let message = message.clone();
+			spawn(async move {
+				if let Err(error) = peers.send_to(&address, message.clone()).await {
+					peers.bus.send(Notification::warning(
+						"Peer",
+						NextcloudChat::Ping,
+						gettext!(
+							"Couldn't send {} to peer {}: {}",
+							format!("{:?}", message),
+							address,
+							error
+						),
+					));
+				}
+			});
+		}
+	}
+
+	pub async fn get_background_task(
+		self,
+		listener: Option<TcpListener>,
+		shutdown: CancellationToken,
+	) -> Result<Never, ModuleError> {
+		let mut events = self.bus.subscribe();
+		loop {
+			select! {
+				event = events.recv() => match event {
+					Some(Event::Peer(message)) => self.send(message),
+					Some(_) => {}
+					None => break,
+				},
+				connection = async { listener.as_ref().unwrap().accept().await }, if listener.is_some() => {
+					let (stream, address) = connection?;
+					let peers = self.clone();
+					spawn(async move {
+						if let Err(error) = peers.handle(stream).await {
+							log!(Level::Warning, "Peer connection from {} failed: {}", address, error);
+						}
+					});
+				}
+				_ = shutdown.cancelled() => {
+					return Err(ModuleError::new(String::from("Peer shut down")));
+				}
+			}
+		}
+		Err(ModuleError::new(String::from(
+			"peer background task exited",
+		)))
+	}
+}
+
+pub struct PeerModule;
+
+impl Module for PeerModule {
+	fn name(&self) -> &'static str {
+		"Peer"
+	}
+
+	fn enabled(&self, config: &mut Config) -> Result<bool, ConfigError> {
+		config.value::<bool>("peer/enable")
+	}
+
+	fn config_keys(&self) -> &'static [&'static str] {
+		&["peer"]
+	}
+
+	fn check_config(&self, config: &mut Config) -> Result<(), Vec<ConfigError>> {
+		config.check::<PeerConfig>("peer")
+	}
+
+	fn start(&self, context: Context) -> BoxFuture<'static, Result<Never, ModuleError>> {
+		async move {
+			let peer: PeerConfig = context.config("peer").await?;
+			let listener = match peer.listen.as_str() {
+				"" => None,
+				address => Some(TcpListener::bind(address).await?),
+			};
+			Peers::new(peer, context.bus, context.state)
+				.get_background_task(listener, context.shutdown)
+				.await
+		}
+		.boxed()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{path::PathBuf, process};
+
+	use super::*;
+	use crate::config::ConfigHandle;
+
+	/// not the state of opensesame, whose writes call `reload-opensesame`
+	const TEST_PARENT: &str = "/tests/opensesame/state";
+
+	fn peers(name: &str, key: &str, addresses: Vec<String>, bus: EventBus) -> Peers {
+		let volatile = PathBuf::from(format!("/nonexistent/opensesame-{}.json", process::id()));
+		Peers::new(
+			PeerConfig {
+				name: name.to_string(),
+				listen: String::new(),
+				key: key.to_string(),
+				addresses,
+			},
+			bus,
+			StateStore::new(
+				ConfigHandle::new(Config::new(TEST_PARENT), Duration::from_secs(5)),
+				volatile,
+			),
+		)
+	}
+
+	/// the house door, listening on a free local port
+	async fn door(key: &str, bus: EventBus) -> String {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let address = listener.local_addr().unwrap().to_string();
+		let door = peers("door", key, vec![], bus);
+		spawn(door.get_background_task(Some(listener), CancellationToken::new()));
+		address
+	}
+
+	const KEY: &str = "0123456789abcdef";
+
+	#[tokio::test]
+	async fn test_bell() {
+		let door_bus = EventBus::new();
+		let mut door_events = door_bus.subscribe();
+		let address = door(KEY, door_bus).await;
+
+		let garage_bus = EventBus::new();
+		let garage = peers("garage", KEY, vec![address], garage_bus.clone());
+		spawn(garage.get_background_task(None, CancellationToken::new()));
+		// let the garage subscribe
+		tokio::task::yield_now().await;
+		garage_bus.send(PeerMessage::Bell);
+
+		let mut audio = false;
+		while !audio {
+			match timeout(TIMEOUT, door_events.recv()).await.unwrap() {
+				Some(Event::Audio(AudioEvent::Bell)) => audio = true,
+				Some(_) => {}
+				None => panic!("bus closed"),
+			}
+		}
+	}
+
+	#[tokio::test]
+	async fn test_reject() {
+		let address = door(KEY, EventBus::new()).await;
+
+		let intruder = peers("garage", "fedcba9876543210", vec![], EventBus::new());
+		assert_eq!(
+			intruder.send_to(&address, PeerMessage::Bell).await,
+			Err(String::from("error: invalid signature"))
+		);
+
+		let garage = peers("garage", KEY, vec![], EventBus::new());
+		let line = garage.sign(PeerMessage::Bell);
+		assert_eq!(Peers::exchange(&address, &line).await.unwrap(), "ok");
+		assert_eq!(
+			Peers::exchange(&address, &line).await.unwrap(),
+			"error: message received before"
+		);
+
+		let tampered = line.replace("bell", "alarm");
+		assert!(Peers::exchange(&address, &tampered)
+			.await
+			.unwrap()
+			.starts_with("error: "));
+	}
+
+	#[test]
+	fn test_too_old() {
+		let garage = peers("garage", KEY, vec![], EventBus::new());
+		let payload = Payload {
+			from: String::from("garage"),
+			time: now() - 2 * MAX_AGE.as_nanos() as u64,
+			message: PeerMessage::Bell,
+		};
+		let mac = hex::encode(garage.mac(&payload).finalize().into_bytes());
+		let line = serde_json::to_string(&Envelope { payload, mac }).unwrap();
+		assert_eq!(
+			garage.verify(&line),
+			Err(String::from(
+				"message too old, are the clocks synchronized?"
+			))
+		);
+	}
+}
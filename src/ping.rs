@@ -1,18 +1,24 @@
-use futures::never::Never;
+use futures::{future::BoxFuture, never::Never, FutureExt};
 use gettextrs::gettext;
 use systemstat::{Platform, System};
-use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::select;
+use tokio_util::sync::CancellationToken;
 
 use crate::{
-	nextcloud::{NextcloudChat, NextcloudEvent},
+	bus::{Event, EventBus},
+	config::{Config, ConfigError},
+	module::{Context, Module},
+	nextcloud::NextcloudChat,
+	notify::Notification,
+	state::StateStore,
 	types::ModuleError,
 };
 
+#[derive(Clone, Debug)]
 pub enum PingEvent {
 	UpadeEnv(String),
 	UpdateEnvStatus(u8),
 	UpdateEnvError(u8),
-	UpdateBatCapacity(u8),
 	SendPing,
 }
 
@@ -26,9 +32,9 @@ pub struct Ping {
 }
 
 impl Ping {
-	pub fn new(startup_time: String) -> Self {
+	pub fn new(startup_time: String, ping_counter: u64) -> Self {
 		Self {
-			ping_counter: 0,
+			ping_counter,
 			environment: String::from(""),
 			environment_status: 0,
 			environment_error: 0,
@@ -39,10 +45,28 @@ impl Ping {
 
 	pub async fn get_background_task(
 		mut self,
-		mut ping_receiver: Receiver<PingEvent>,
-		nextcloud_sender: Sender<NextcloudEvent>,
+		bus: EventBus,
+		state: StateStore,
+		shutdown: CancellationToken,
 	) -> Result<Never, ModuleError> {
-		while let Some(event) = ping_receiver.recv().await {
+		let mut events = bus.subscribe();
+		loop {
+			let event = select! {
+				event = events.recv() => event,
+				_ = shutdown.cancelled() => {
+					state.set("ping/counter", &self.ping_counter.to_string());
+					return Err(ModuleError::new(String::from("Ping shut down")));
+				}
+			};
+			let event = match event {
+				Some(Event::Ping(event)) => event,
+				Some(Event::BatteryLevel(value)) => {
+					self.bat_capacity = value;
+					continue;
+				}
+				Some(_) => continue,
+				None => break,
+			};
 			match event {
 				PingEvent::UpadeEnv(value) => {
 					self.environment = value;
@@ -53,14 +77,12 @@ impl Ping {
 				PingEvent::UpdateEnvError(value) => {
 					self.environment_error = value;
 				}
-				PingEvent::UpdateBatCapacity(value) => {
-					self.bat_capacity = value;
-				}
 				PingEvent::SendPing => {
 					let sys = System::new();
 					let loadavg = sys.load_average().unwrap();
+					let state_stats = state.stats();
 
-					nextcloud_sender.send(NextcloudEvent::Chat(NextcloudChat::Ping,gettext!("{} Ping! Version {}, {}, Status {}, Error {}, Load {} {} {}, Memory usage {}, Swap {}, CPU temp {}, Startup {} Bat {}", 
+					bus.send(Notification::debug("Ping", NextcloudChat::Ping, gettext!("{} Ping! Version {}, {}, Status {}, Error {}, Load {} {} {}, Memory usage {}, Swap {}, CPU temp {}, Startup {} Bat {}, State writes {} for {} updates", 
 						self.ping_counter,
 						env!("CARGO_PKG_VERSION"),
 						self.environment,
@@ -73,9 +95,13 @@ impl Ping {
 						sys.swap().unwrap().total,
 						sys.cpu_temp().unwrap(),
 						self.startup_time,
-						self.bat_capacity))).await?;
+						self.bat_capacity,
+						state_stats.writes,
+						state_stats.updates)));
 
 					self.ping_counter += 1;
+					// written with the next flush of the state
+					state.set("ping/counter", &self.ping_counter.to_string());
 				}
 			}
 		}
@@ -83,3 +109,37 @@ impl Ping {
 		Err(ModuleError::new(String::from("Exit Ping loop!")))
 	}
 }
+
+pub struct PingModule;
+
+impl Module for PingModule {
+	fn name(&self) -> &'static str {
+		"Ping"
+	}
+
+	fn enabled(&self, config: &mut Config) -> Result<bool, ConfigError> {
+		config.value::<bool>("ping/enable")
+	}
+
+	fn config_keys(&self) -> &'static [&'static str] {
+		&["ping"]
+	}
+
+	fn start(&self, context: Context) -> BoxFuture<'static, Result<Never, ModuleError>> {
+		async move {
+			let ping_counter = context
+				.state
+				.get_option::<u64>("ping/counter")
+				.await?
+				.unwrap_or(0);
+			Ping::get_background_task(
+				Ping::new(context.startup_time, ping_counter),
+				context.bus,
+				context.state,
+				context.shutdown,
+			)
+			.await
+		}
+		.boxed()
+	}
+}
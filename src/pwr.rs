@@ -1,7 +1,5 @@
 use gpio_cdev::{Chip, LineHandle, LineRequestFlags};
 
-use crate::config::Config;
-
 const GPIO_PWR_LINE: u32 = 202;
 
 pub struct Pwr {
@@ -10,8 +8,8 @@ pub struct Pwr {
 }
 
 impl Pwr {
-	pub fn new(config: &mut Config) -> Self {
-		if config.get_bool("pwr/enable") {
+	pub fn new(enable: bool) -> Self {
+		if enable {
 			let mut chip = Chip::new("/dev/gpiochip0").unwrap();
 			let line = chip
 				.get_line(GPIO_PWR_LINE)
@@ -0,0 +1,183 @@
+//! Measured values, published on the bus by the sensor modules, and the
+//! latest of them together with what happened at the door, garage and lights,
+//! e.g. for the API.
+
+use std::{
+	collections::BTreeMap,
+	sync::{Arc, Mutex},
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::bus::Event;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Reading {
+	/// e.g. `environment`
+	pub source: &'static str,
+	/// e.g. `co2`
+	pub name: String,
+	pub value: f64,
+	/// e.g. `ppm`, empty for counts and states
+	pub unit: &'static str,
+}
+
+impl Reading {
+	pub fn new(source: &'static str, name: &str, value: f64, unit: &'static str) -> Self {
+		Self {
+			source,
+			name: name.to_string(),
+			value,
+			unit,
+		}
+	}
+
+	/// e.g. `environment/co2`
+	pub fn key(&self) -> String {
+		format!("{}/{}", self.source, self.name)
+	}
+}
+
+/// seconds since the epoch
+pub fn unix_time() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map_or(0, |duration| duration.as_secs())
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LatestReading {
+	pub value: f64,
+	pub unit: &'static str,
+	pub time: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DoorOpened {
+	/// user or `command`
+	pub by: String,
+	pub time: u64,
+}
+
+/// Since the start of opensesame, e.g. for Prometheus.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Counters {
+	pub door_openings: u64,
+	/// PIN sequences that timed out or got too long
+	pub failed_pins: u64,
+	/// chat messages and status updates which could not be sent
+	pub nextcloud_failures: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Latest {
+	/// by `Reading::key`
+	pub readings: BTreeMap<String, LatestReading>,
+	pub door_opened: Option<DoorOpened>,
+	pub garage_closed: Option<bool>,
+	pub lights_inside: bool,
+	pub lights_outside: bool,
+	pub air_quality: Option<String>,
+	/// the last alarm raised since the start, see `alarm/fire` for the current one
+	pub last_alarm: Option<String>,
+	pub counters: Counters,
+}
+
+/// Cheap to clone, updated by `main` with every event on the bus.
+#[derive(Clone, Default)]
+pub struct Readings {
+	latest: Arc<Mutex<Latest>>,
+}
+
+impl Readings {
+	pub fn update(&self, event: &Event) {
+		let mut latest = self.latest.lock().unwrap();
+		match event {
+			Event::Reading(reading) => {
+				latest.readings.insert(
+					reading.key(),
+					LatestReading {
+						value: reading.value,
+						unit: reading.unit,
+						time: unix_time(),
+					},
+				);
+			}
+			Event::DoorOpened(by) => {
+				latest.counters.door_openings += 1;
+				latest.door_opened = Some(DoorOpened {
+					by: by.clone(),
+					time: unix_time(),
+				});
+			}
+			Event::GarageDoorClosed(closed) => latest.garage_closed = Some(*closed),
+			Event::LightsOn { inside, outside } => {
+				latest.lights_inside = *inside;
+				latest.lights_outside = *outside;
+			}
+			Event::LightsOff => {
+				latest.lights_inside = false;
+				latest.lights_outside = false;
+			}
+			Event::AirQualityChanged(air_quality) => {
+				latest.air_quality = Some(format!("{:?}", air_quality));
+			}
+			Event::AlarmRaised(alarm) => latest.last_alarm = Some(alarm.clone()),
+			Event::PinFailed => latest.counters.failed_pins += 1,
+			_ => {}
+		}
+	}
+
+	/// Called by Nextcloud, which cannot report its own failures via the chat.
+	pub fn nextcloud_failed(&self) {
+		self.latest.lock().unwrap().counters.nextcloud_failures += 1;
+	}
+
+	pub fn latest(&self) -> Latest {
+		self.latest.lock().unwrap().clone()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_update() {
+		let readings = Readings::default();
+		readings.update(&Event::Reading(Reading::new(
+			"environment",
+			"co2",
+			400.0,
+			"ppm",
+		)));
+		readings.update(&Event::Reading(Reading::new(
+			"environment",
+			"co2",
+			450.0,
+			"ppm",
+		)));
+		readings.update(&Event::LightsOn {
+			inside: true,
+			outside: false,
+		});
+		readings.update(&Event::BellPressed);
+		readings.update(&Event::PinFailed);
+		readings.nextcloud_failed();
+		let latest = readings.latest();
+		assert_eq!(latest.readings.len(), 1);
+		assert_eq!(latest.readings["environment/co2"].value, 450.0);
+		assert!(latest.lights_inside);
+		assert!(!latest.lights_outside);
+		assert_eq!(latest.door_opened, None);
+		assert_eq!(
+			latest.counters,
+			Counters {
+				door_openings: 0,
+				failed_pins: 1,
+				nextcloud_failures: 1,
+			}
+		);
+	}
+}
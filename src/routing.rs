@@ -0,0 +1,264 @@
+//! Routing table of the notifications: which Talk rooms and other channels get
+//! a notification, by its severity and module, see doc/Routing.md.
+//!
+//! Without routes, every notification goes to the Talk room chosen by its module.
+
+use std::collections::BTreeMap;
+
+use chrono::NaiveTime;
+use serde::Deserialize;
+
+use crate::{
+	config::ConfigError,
+	nextcloud::NextcloudChat,
+	notify::{Notification, Severity},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum Destination {
+	/// the Talk room chosen by the module
+	#[serde(rename = "talk")]
+	Room,
+	#[serde(rename = "talk/default")]
+	Default,
+	#[serde(rename = "talk/ping")]
+	Ping,
+	#[serde(rename = "talk/licht")]
+	Licht,
+	#[serde(rename = "mqtt")]
+	Mqtt,
+	#[serde(rename = "webhook")]
+	Webhook,
+	#[serde(rename = "email")]
+	Email,
+}
+
+impl From<NextcloudChat> for Destination {
+	fn from(chat: NextcloudChat) -> Self {
+		match chat {
+			NextcloudChat::Default => Destination::Default,
+			NextcloudChat::Ping => Destination::Ping,
+			NextcloudChat::Licht => Destination::Licht,
+			// answers to commands only go back to the commands room
+			NextcloudChat::Commands => Destination::Room,
+		}
+	}
+}
+
+impl Destination {
+	/// The Talk room, None for other channels.
+	pub fn chat(&self) -> Option<NextcloudChat> {
+		match self {
+			Destination::Default => Some(NextcloudChat::Default),
+			Destination::Ping => Some(NextcloudChat::Ping),
+			Destination::Licht => Some(NextcloudChat::Licht),
+			Destination::Room | Destination::Mqtt | Destination::Webhook | Destination::Email => {
+				None
+			}
+		}
+	}
+}
+
+/// `notify/route/#<n>`
+#[derive(Clone, Debug, Deserialize)]
+pub struct Route {
+	/// lowest severity
+	pub severity: Severity,
+	/// name of the module, e.g. `Environment`, all modules if not set
+	pub module: Option<String>,
+	pub to: Destination,
+	/// local time without notifications, e.g. `22:00-07:00`
+	pub quiet: Option<String>,
+}
+
+fn quiet_hours(quiet: &str) -> Option<(NaiveTime, NaiveTime)> {
+	let (start, end) = quiet.split_once('-')?;
+	Some((
+		NaiveTime::parse_from_str(start.trim(), "%H:%M").ok()?,
+		NaiveTime::parse_from_str(end.trim(), "%H:%M").ok()?,
+	))
+}
+
+pub fn validate(routes: &BTreeMap<String, Route>) -> Vec<ConfigError> {
+	routes
+		.iter()
+		.filter_map(|(name, route)| match &route.quiet {
+			Some(quiet) if quiet_hours(quiet).is_none() => Some(ConfigError {
+				key: format!("notify/route/{}/quiet", name),
+				message: format!("'{}' is not like 22:00-07:00", quiet),
+			}),
+			_ => None,
+		})
+		.collect()
+}
+
+#[derive(Clone)]
+struct Rule {
+	severity: Severity,
+	module: Option<String>,
+	to: Destination,
+	quiet: Option<(NaiveTime, NaiveTime)>,
+}
+
+impl Rule {
+	fn quiet(&self, now: NaiveTime) -> bool {
+		match self.quiet {
+			Some((start, end)) if start <= end => start <= now && now < end,
+			// over midnight
+			Some((start, end)) => start <= now || now < end,
+			None => false,
+		}
+	}
+}
+
+#[derive(Clone, Default)]
+pub struct Routing {
+	rules: Vec<Rule>,
+}
+
+impl Routing {
+	pub fn new(routes: &BTreeMap<String, Route>) -> Self {
+		Self {
+			rules: routes
+				.values()
+				.map(|route| Rule {
+					severity: route.severity,
+					module: route.module.clone(),
+					to: route.to,
+					quiet: route.quiet.as_deref().and_then(quiet_hours),
+				})
+				.collect(),
+		}
+	}
+
+	/// Where the notification goes at the local time `now`, each destination once.
+	/// `Room` is replaced by the room of the notification. Answers to commands are not routed.
+	pub fn destinations(&self, notification: &Notification, now: NaiveTime) -> Vec<Destination> {
+		let room = Destination::from(notification.chat);
+		if notification.chat == NextcloudChat::Commands {
+			return Vec::new();
+		}
+		if self.rules.is_empty() {
+			return vec![room];
+		}
+		let mut destinations = Vec::new();
+		for rule in &self.rules {
+			if notification.severity < rule.severity
+				|| rule
+					.module
+					.as_ref()
+					.is_some_and(|module| !module.eq_ignore_ascii_case(notification.module))
+				|| rule.quiet(now)
+			{
+				continue;
+			}
+			let destination = match rule.to {
+				Destination::Room => room,
+				to => to,
+			};
+			if !destinations.contains(&destination) {
+				destinations.push(destination);
+			}
+		}
+		destinations
+	}
+
+	pub fn routes_to(
+		&self,
+		notification: &Notification,
+		destination: Destination,
+		now: NaiveTime,
+	) -> bool {
+		self.destinations(notification, now).contains(&destination)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn route(severity: Severity, module: &str, to: Destination, quiet: &str) -> Route {
+		Route {
+			severity,
+			module: Some(module.to_string()).filter(|module| !module.is_empty()),
+			to,
+			quiet: Some(quiet.to_string()).filter(|quiet| !quiet.is_empty()),
+		}
+	}
+
+	fn time(time: &str) -> NaiveTime {
+		NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+	}
+
+	#[test]
+	fn test_destinations() {
+		let bell = Notification::info("Buttons", NextcloudChat::Default, "🔔");
+		let ping = Notification::debug("Ping", NextcloudChat::Ping, "Ping!");
+		let co2 = Notification::warning("Environment", NextcloudChat::Default, "💩");
+		let fire = Notification::alarm("Signals", "🚨");
+
+		let routing = Routing::default();
+		assert_eq!(
+			routing.destinations(&ping, time("12:00")),
+			vec![Destination::Ping]
+		);
+
+		let routing = Routing::new(&BTreeMap::from([
+			(
+				String::from("#0"),
+				route(Severity::Info, "", Destination::Room, "22:00-07:00"),
+			),
+			(
+				String::from("#1"),
+				route(Severity::Alarm, "", Destination::Room, ""),
+			),
+			(
+				String::from("#2"),
+				route(Severity::Warning, "environment", Destination::Mqtt, ""),
+			),
+			(
+				String::from("#3"),
+				route(Severity::Debug, "Ping", Destination::Licht, "08:00-09:00"),
+			),
+		]));
+		assert_eq!(
+			routing.destinations(&bell, time("12:00")),
+			vec![Destination::Default]
+		);
+		assert!(routing.destinations(&bell, time("23:00")).is_empty());
+		assert!(routing.destinations(&bell, time("06:59")).is_empty());
+		assert_eq!(
+			routing.destinations(&fire, time("03:00")),
+			vec![Destination::Default]
+		);
+		assert_eq!(
+			routing.destinations(&co2, time("12:00")),
+			vec![Destination::Default, Destination::Mqtt]
+		);
+		assert!(routing.routes_to(&co2, Destination::Mqtt, time("23:00")));
+		assert_eq!(
+			routing.destinations(&ping, time("12:00")),
+			vec![Destination::Licht]
+		);
+		assert!(routing.destinations(&ping, time("08:30")).is_empty());
+		let answer = Notification::info("Nextcloud", NextcloudChat::Commands, "ok");
+		assert!(routing.destinations(&answer, time("12:00")).is_empty());
+	}
+
+	#[test]
+	fn test_validate() {
+		let routes = BTreeMap::from([
+			(
+				String::from("#0"),
+				route(Severity::Info, "", Destination::Room, "22:00-07:00"),
+			),
+			(
+				String::from("#1"),
+				route(Severity::Info, "", Destination::Room, "22-7"),
+			),
+		]);
+		let errors = validate(&routes);
+		assert_eq!(errors.len(), 1);
+		assert!(errors[0].to_string().contains("notify/route/#1/quiet"));
+	}
+}
@@ -1,14 +1,79 @@
-use crate::nextcloud::NextcloudChat;
-use crate::{config::Config, nextcloud::NextcloudEvent, types::ModuleError};
-use futures::never::Never;
+use crate::{
+	bus::{Event, EventBus},
+	config::{
+		validate::{exists, in_range, Validate},
+		Config, ConfigError,
+	},
+	module::{Context, Module},
+	notify::Notification,
+	readings::Reading,
+	types::ModuleError,
+};
+use futures::{future::BoxFuture, never::Never, FutureExt};
 use gettextrs::gettext;
-use std::str::FromStr;
+use serde::Deserialize;
 use tokio::fs::File;
 use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::sync::mpsc::Sender;
 
 const ALPHA: f64 = 0.6;
 
+const SENSORS: usize = 12;
+
+/// Deserialized from the top of the configuration, as the sensors are the
+/// array elements of `sensors` itself.
+#[derive(Deserialize)]
+pub struct SensorsConfig {
+	#[serde(rename = "sensors/device")]
+	pub device: String,
+	pub sensors: Vec<SensorConfig>,
+}
+
+impl Validate for SensorsConfig {
+	fn validate(&self) -> Vec<ConfigError> {
+		[
+			exists("sensors/device", &self.device),
+			in_range("sensors", self.sensors.len(), 0..=SENSORS),
+		]
+		.into_iter()
+		.flatten()
+		.collect()
+	}
+}
+
+#[derive(Deserialize, Default)]
+pub struct SensorConfig {
+	/// empty to ignore the sensor
+	pub loc: String,
+	pub quality: Option<String>,
+	pub pin: Option<String>,
+	pub chat: Option<u16>,
+	pub alarm: Option<u16>,
+	pub min: Option<u16>,
+	pub avg: Option<u16>,
+	pub max: Option<u16>,
+}
+
+impl From<&SensorConfig> for Sensor {
+	fn from(config: &SensorConfig) -> Self {
+		Sensor {
+			loc: config.loc.clone(),
+			quality: config.quality.clone().unwrap_or_default(),
+			pin: config.pin.clone().unwrap_or_default(),
+
+			chat: config.chat,
+			alarm: config.alarm,
+
+			min: config.min.unwrap_or_default(),
+			avg: config.avg.unwrap_or_default(),
+			max: config.max.unwrap_or_default(),
+
+			value: 0,
+			expmovavg: 0f64,
+			triggered: SensorsChange::None,
+		}
+	}
+}
+
 #[derive(Debug)]
 struct Sensor {
 	// static data
@@ -33,7 +98,9 @@ struct Sensor {
 
 pub struct Sensors {
 	init: bool,
-	sensors: [Sensor; 12],
+	sensors: [Sensor; SENSORS],
+	/// last values of all channels, also of the ignored sensors
+	raw: [u16; SENSORS],
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -46,56 +113,14 @@ pub enum SensorsChange {
 const BELL_JUMP: f64 = 40f64;
 const ALARM_JUMP: f64 = 60f64;
 
-impl Config<'_> {
-	fn get_sensor_element_option<T: FromStr + Default>(&mut self, nr: u8, name: &str) -> Option<T> {
-		self.get_option::<T>(&format!("sensors/#{}/{}", nr, name).to_string())
-	}
-
-	fn get_sensor_element<T: FromStr + Default>(&mut self, nr: u8, name: &str) -> T {
-		match self.get_sensor_element_option::<T>(nr, name) {
-			Some(value) => value,
-			None => T::default(),
-		}
-	}
-
-	fn get_sensor(&mut self, nr: u8) -> Sensor {
-		Sensor {
-			loc: self.get_sensor_element::<String>(nr, "loc"),
-			quality: self.get_sensor_element::<String>(nr, "quality"),
-			pin: self.get_sensor_element::<String>(nr, "pin"),
-
-			chat: self.get_sensor_element_option::<u16>(nr, "chat"),
-			alarm: self.get_sensor_element_option::<u16>(nr, "alarm"),
-
-			min: self.get_sensor_element::<u16>(nr, "min"),
-			avg: self.get_sensor_element::<u16>(nr, "avg"),
-			max: self.get_sensor_element::<u16>(nr, "max"),
-
-			value: 0,
-			expmovavg: 0f64,
-			triggered: SensorsChange::None,
-		}
-	}
-}
-
 impl Sensors {
-	pub fn new(config: &mut Config) -> Self {
+	/// Sensors missing in `config` are ignored.
+	pub fn new(config: &[SensorConfig]) -> Self {
+		let unused = SensorConfig::default();
 		Self {
 			init: false,
-			sensors: [
-				config.get_sensor(0),
-				config.get_sensor(1),
-				config.get_sensor(2),
-				config.get_sensor(3),
-				config.get_sensor(4),
-				config.get_sensor(5),
-				config.get_sensor(6),
-				config.get_sensor(7),
-				config.get_sensor(8),
-				config.get_sensor(9),
-				config.get_sensor(10),
-				config.get_sensor(11),
-			],
+			sensors: std::array::from_fn(|i| config.get(i).unwrap_or(&unused).into()),
+			raw: [0; SENSORS],
 		}
 	}
 
@@ -147,6 +172,7 @@ impl Sensors {
 			.map(|s| s.parse().unwrap())
 			.collect();
 		assert!(values.len() == 12);
+		self.raw.copy_from_slice(&values);
 
 		let mut ret = SensorsChange::None;
 		for i in 0..12 {
@@ -252,10 +278,32 @@ impl Sensors {
 		ret
 	}
 
+	/// Values of all channels, exponential moving averages of the used sensors.
+	pub fn readings(&self) -> Vec<Reading> {
+		let mut readings = Vec::new();
+		for (i, sensor) in self.sensors.iter().enumerate() {
+			readings.push(Reading::new(
+				"sensors",
+				&format!("mq135_{}", i),
+				self.raw[i] as f64,
+				"",
+			));
+			if !sensor.loc.is_empty() {
+				readings.push(Reading::new(
+					"sensors",
+					&format!("mq135_{}_ema", i),
+					sensor.expmovavg,
+					"",
+				));
+			}
+		}
+		readings
+	}
+
 	pub async fn get_background_task(
 		mut self,
 		device_path: String,
-		nextcloud_sender: Sender<NextcloudEvent>,
+		bus: EventBus,
 		//state_mutex: Arc<Mutex<Config<'_>>>,
 		//pid: u32,
 	) -> Result<Never, ModuleError> {
@@ -264,30 +312,22 @@ impl Sensors {
 
 		let mut lines = reader.lines();
 		while let Some(line) = lines.next_line().await? {
-			match self.update(line.clone()) {
+			let change = self.update(line.clone());
+			for reading in self.readings() {
+				bus.send(reading);
+			}
+			match change {
 				SensorsChange::None => (),
 				SensorsChange::Alarm(w) => {
-					nextcloud_sender
-						.send(NextcloudEvent::Chat(
-							NextcloudChat::Default,
-							gettext!("Fire Alarm {}", w),
-						))
-						.await?;
+					bus.send(Event::AlarmRaised(w.clone()));
+					bus.send(Notification::alarm("Sensors", gettext!("Fire Alarm {}", w)));
 					/*let mut state = state_mutex.lock().await;
 					state.set("alarm/fire", &w.to_string());
 					kill(nix::unistd::Pid::from_raw(pid as i32), Signal::SIGHUP)?;
-					spawn(exec_ssh_command(format!(
-						"kdb set user:/state/libelektra/opensesame/#0/current/alarm/fire \"{}\"",
-						w
-					)));*/
+					bus.send(PeerMessage::Alarm { reason: w.clone() });*/
 				}
 				SensorsChange::Chat(w) => {
-					nextcloud_sender
-						.send(NextcloudEvent::Chat(
-							NextcloudChat::Default,
-							gettext!("Fire Chat {}", w),
-						))
-						.await?;
+					bus.send(Notification::alarm("Sensors", gettext!("Fire Chat {}", w)));
 				}
 			}
 		}
@@ -295,6 +335,45 @@ impl Sensors {
 	}
 }
 
+pub struct SensorsModule;
+
+impl Module for SensorsModule {
+	fn name(&self) -> &'static str {
+		"Sensors"
+	}
+
+	fn enabled(&self, config: &mut Config) -> Result<bool, ConfigError> {
+		config.value::<bool>("sensors/enable")
+	}
+
+	fn hardware(&self) -> bool {
+		true
+	}
+
+	fn config_keys(&self) -> &'static [&'static str] {
+		&["sensors"]
+	}
+
+	fn check_config(&self, config: &mut Config) -> Result<(), Vec<ConfigError>> {
+		config.check::<SensorsConfig>("")
+	}
+
+	fn start(&self, context: Context) -> BoxFuture<'static, Result<Never, ModuleError>> {
+		async move {
+			let config: SensorsConfig = context.config("").await?;
+			Sensors::get_background_task(
+				Sensors::new(&config.sensors),
+				config.device,
+				context.bus,
+				/*state_mutex.clone(),
+				id(),*/
+			)
+			.await
+		}
+		.boxed()
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	// Note this useful idiom: importing names from outer (for mod tests) scope.
@@ -305,6 +384,22 @@ mod tests {
 
 	const CONFIG_PARENT: &str = "/sw/libelektra/opensesame/#0/current";
 
+	#[test]
+	fn test_readings() {
+		let mut sensors = Sensors::new(&[SensorConfig {
+			loc: String::from("kitchen"),
+			..Default::default()
+		}]);
+		sensors.update(String::from("100 1 2 3 4 5 6 7 8 9 10 11"));
+		sensors.update(String::from("200 1 2 3 4 5 6 7 8 9 10 11"));
+		let readings = sensors.readings();
+		assert_eq!(readings.len(), SENSORS + 1);
+		assert_eq!(readings[0], Reading::new("sensors", "mq135_0", 200.0, ""));
+		assert_eq!(readings[1].name, "mq135_0_ema");
+		assert!((readings[1].value - 160.0).abs() < 1e-9);
+		assert_eq!(readings[12], Reading::new("sensors", "mq135_11", 11.0, ""));
+	}
+
 	#[ignore]
 	#[test]
 	#[serial]
@@ -325,7 +420,8 @@ mod tests {
 		config.add("sensors/#10/loc", "all 722");
 		config.add("sensors/#11/loc", "all 1555");
 
-		let mut sensors = Sensors::new(&mut config);
+		let mut sensors =
+			Sensors::new(&config.deserialize::<Vec<SensorConfig>>("sensors").unwrap());
 		assert_eq!(sensors.update("77       194    127     98     82      81       0       100     79      39      722      1555".to_string()), SensorsChange::None);
 		assert_eq!(sensors.update("77       329    179     138    82      127      0       200     79      39      722      1555".to_string()), SensorsChange::Chat("Sensor #1 recorded up/down Quality:  Pin:  Chat: 0 Alarm: 0 Min: 0 Avg: 0 Max: 0 Text: chat jump expmovavg: 275 prev_expmovavg: 194 Value: 329".to_string()));
 		assert_eq!(sensors.update("100      268    165     119    82      122      0       300     78      39      722      1555".to_string()), SensorsChange::None);
@@ -355,7 +451,8 @@ mod tests {
 		config.add("sensors/#10/loc", "10");
 		config.add("sensors/#11/loc", "11");
 
-		let mut sensors = Sensors::new(&mut config);
+		let mut sensors =
+			Sensors::new(&config.deserialize::<Vec<SensorConfig>>("sensors").unwrap());
 		assert_eq!(
 			sensors.update("152	237	279	275	177	166	90	440	59	370	423	9".to_string()),
 			SensorsChange::None
@@ -448,7 +545,8 @@ mod tests {
 		config.add("sensors/#10/loc", "10");
 		config.add("sensors/#11/loc", "11");
 
-		let mut sensors = Sensors::new(&mut config);
+		let mut sensors =
+			Sensors::new(&config.deserialize::<Vec<SensorConfig>>("sensors").unwrap());
 		assert_eq!(
 			sensors.update("148	219	260	266	257	242	71	441	44	344	486	5".to_string()),
 			SensorsChange::None
@@ -591,7 +689,8 @@ mod tests {
 		use std::io::{prelude::*, BufReader};
 		let reader = BufReader::new(file);
 
-		let mut sensors = Sensors::new(&mut config);
+		let mut sensors =
+			Sensors::new(&config.deserialize::<Vec<SensorConfig>>("sensors").unwrap());
 
 		for l in reader.lines() {
 			let line = l.unwrap();
@@ -1,135 +1,78 @@
-use std::sync::Arc;
-
-use futures::never::Never;
+use futures::{future::BoxFuture, never::Never, FutureExt};
 use gettextrs::gettext;
+use serde::Deserialize;
 use signal::unix::signal;
 use tokio::{
 	select,
 	signal::{self, unix::SignalKind},
-	spawn,
-	sync::{mpsc::Sender, Mutex},
 };
 
 use crate::{
 	audio::AudioEvent,
+	bus::{Event, EventBus},
 	buttons::CommandToButtons,
-	config::Config,
+	config::{Config, ConfigError, ConfigHandle},
 	environment::EnvEvent,
-	nextcloud::{NextcloudChat, NextcloudEvent},
+	module::{Context, Module},
+	nextcloud::NextcloudChat,
+	notify::Notification,
+	peer::PeerMessage,
 	ping::PingEvent,
-	ssh::exec_ssh_command,
+	state::StateStore,
 	types::ModuleError,
 };
 
-pub struct Signals<'a> {
-	ping_sender: Sender<PingEvent>,
+pub struct Signals {
+	bus: EventBus,
 	ping_enabled: bool,
-	command_sender: Sender<CommandToButtons>,
 	buttons_enabled: bool,
-	nextcloud_sender: Sender<NextcloudEvent>,
-	environment_sender: Sender<EnvEvent>,
 	environment_enabled: bool,
-	audio_sender: Sender<AudioEvent>,
 	alarm_not_active: bool,
-	startup_time: String,
-	config_mutex: Arc<Mutex<Config<'a>>>,
-	state_mutex: Arc<Mutex<Config<'a>>>,
+	config: ConfigHandle,
+	state: StateStore,
 }
 
-unsafe impl<'a> Send for Signals<'a> {}
-
-impl<'a> Signals<'a> {
+impl Signals {
 	pub fn new(
-		config_mutex: Arc<Mutex<Config<'a>>>,
-		state_mutex: Arc<Mutex<Config<'a>>>,
+		config: ConfigHandle,
+		state: StateStore,
 		ping_enabled: bool,
 		buttons_enabled: bool,
 		environment_enabled: bool,
-		startup_time: String,
-		ping_sender: Sender<PingEvent>,
-		command_sender: Sender<CommandToButtons>,
-		nextcloud_sender: Sender<NextcloudEvent>,
-		environment_sender: Sender<EnvEvent>,
-		audio_sender: Sender<AudioEvent>,
+		bus: EventBus,
 	) -> Self {
 		Self {
-			ping_sender,
-			command_sender,
-			nextcloud_sender,
-			environment_sender,
-			audio_sender,
+			bus,
 			alarm_not_active: true,
-			startup_time,
 			ping_enabled,
 			buttons_enabled,
 			environment_enabled,
-			config_mutex,
-			state_mutex,
+			config,
+			state,
 		}
 	}
 
-	async fn sigterm(&mut self) -> Result<(), ModuleError> {
-		//send message to environment to remember_baseline and ENV need to use a Mutex of State
-		//How to enable env to use message recv, if it waits one minute or more for the next loop iteration?
-		//Two different loops? but they then have different states if a restore baseline is called?
-		//And only execute if env is enabled, otherwise we get an error
-		self.environment_sender
-			.send(EnvEvent::RememberBaseline)
-			.await?;
-		Ok(())
-	}
-
-	async fn sighup(&mut self) -> Result<(), ModuleError> {
-		self.nextcloud_sender
-			.send(NextcloudEvent::Chat(
-				NextcloudChat::Ping,
-				gettext!(
-					"👋 reloading config&state for opensesame {} {}",
-					env!("CARGO_PKG_VERSION"),
-					self.startup_time
-				),
-			))
-			.await?;
-
-		let mut config = self.config_mutex.lock().await;
-		let mut state = self.state_mutex.lock().await;
-		config.sync();
-		state.sync();
+	/// SIGHUP is handled in `main`, which sends `ConfigChanged` after the configuration got reloaded
+	async fn reload(&mut self) -> Result<(), ModuleError> {
+		self.state.sync().await?;
 		if self.environment_enabled {
-			self.environment_sender
-				.send(EnvEvent::RestoreBaseline)
-				.await?;
+			self.bus.send(EnvEvent::RestoreBaseline);
 		}
-		self.nextcloud_sender
-			.send(NextcloudEvent::Chat(
-				NextcloudChat::Ping,
-				gettext!(
-					"👋 reloaded config&state for opensesame {} {}",
-					env!("CARGO_PKG_VERSION"),
-					self.startup_time
-				),
-			))
-			.await?;
-
-		if let Some(alarm) = state.get_option::<String>("alarm/fire") {
+
+		if let Some(alarm) = self.state.get_option::<String>("alarm/fire").await? {
 			if self.alarm_not_active {
-				self.nextcloud_sender
-					.send(NextcloudEvent::Chat(
-						NextcloudChat::Default,
-						gettext!("🚨 Fire Alarm! Fire Alarm! Fire ALARM! ⏰. {}", alarm),
-					))
-					.await?;
+				self.bus.send(Event::AlarmRaised(alarm.clone()));
+				self.bus.send(Notification::alarm(
+					"Signals",
+					gettext!("🚨 Fire Alarm! Fire Alarm! Fire ALARM! ⏰. {}", alarm),
+				));
 				if self.buttons_enabled {
-					self.command_sender
-						.send(CommandToButtons::RingBellAlarm(10))
-						.await?;
+					self.bus.send(CommandToButtons::RingBellAlarm(10));
 				}
-				if config.get_bool("garage/enable") {
-					self.audio_sender.send(AudioEvent::FireAlarm).await?;
-					spawn(exec_ssh_command(format!(
-						"kdb set user:/state/libelektra/opensesame/#0/current/alarm/fire \"{}\"",
-						alarm
-					)));
+				if self.config.get_option::<bool>("garage/enable").await? == Some(true) {
+					self.bus.send(AudioEvent::FireAlarm);
+					// the other opensesame instances also get the fire alarm
+					self.bus.send(PeerMessage::Alarm { reason: alarm });
 				};
 				self.alarm_not_active = false;
 			}
@@ -141,39 +84,31 @@ impl<'a> Signals<'a> {
 	}
 
 	async fn sigalarm(&mut self) -> Result<(), ModuleError> {
+		self.bus.send(Event::AlarmRaised(String::from("SIGALRM")));
 		if self.buttons_enabled {
-			self.command_sender
-				.send(CommandToButtons::RingBellAlarm(20))
-				.await?;
+			self.bus.send(CommandToButtons::RingBellAlarm(20));
 		}
-		self.audio_sender.send(AudioEvent::FireAlarm).await?;
-		self.nextcloud_sender
-			.send(NextcloudEvent::Chat(
-				NextcloudChat::Default,
-				gettext("🚨 Received alarm"),
-			))
-			.await?;
+		self.bus.send(AudioEvent::FireAlarm);
+		self.bus
+			.send(Notification::alarm("Signals", gettext("🚨 Received alarm")));
 		Ok(())
 	}
 
 	async fn sigusr1(&mut self) -> Result<(), ModuleError> {
-		self.ping_sender.send(PingEvent::SendPing).await?;
+		self.bus.send(PingEvent::SendPing);
 		Ok(())
 	}
 
 	async fn sigusr2(&mut self) -> Result<(), ModuleError> {
 		if self.buttons_enabled {
-			self.command_sender
-				.send(CommandToButtons::RingBell(20, 0))
-				.await?;
+			self.bus.send(CommandToButtons::RingBell(20, 0));
 		}
-		self.audio_sender.send(AudioEvent::Bell).await?;
-		self.nextcloud_sender
-			.send(NextcloudEvent::Chat(
-				NextcloudChat::Default,
-				gettext("🔔 Received bell"),
-			))
-			.await?;
+		self.bus.send(AudioEvent::Bell);
+		self.bus.send(Notification::info(
+			"Signals",
+			NextcloudChat::Default,
+			gettext("🔔 Received bell"),
+		));
 		Ok(())
 	}
 
@@ -181,8 +116,7 @@ impl<'a> Signals<'a> {
 		let mut sig_usr1 = signal(SignalKind::user_defined1())?;
 		let mut sig_usr2 = signal(signal::unix::SignalKind::user_defined2())?;
 		let mut sig_alarm = signal(signal::unix::SignalKind::alarm())?;
-		let mut sig_hanghup = signal(signal::unix::SignalKind::hangup())?;
-		let mut sig_term = signal(signal::unix::SignalKind::terminate())?;
+		let mut events = self.bus.subscribe();
 
 		loop {
 			select! {
@@ -197,15 +131,55 @@ impl<'a> Signals<'a> {
 				_ = sig_alarm.recv() => {
 					self.sigalarm().await?;
 				}
-				_ = sig_hanghup.recv() => {
-					self.sighup().await?;
-				}
-				_ = sig_term.recv() => {
-					if self.environment_enabled {
-						self.sigterm().await?;
+				Some(event) = events.recv() => {
+					if let Event::ConfigChanged(_) = event {
+						self.reload().await?;
 					}
 				}
 			}
 		}
 	}
 }
+
+#[derive(Deserialize)]
+struct SignalsConfig {
+	#[serde(rename = "ping/enable")]
+	ping_enable: bool,
+	#[serde(rename = "buttons/enable")]
+	buttons_enable: bool,
+	#[serde(rename = "environment/enable")]
+	environment_enable: bool,
+}
+
+pub struct SignalsModule;
+
+impl Module for SignalsModule {
+	fn name(&self) -> &'static str {
+		"Signals"
+	}
+
+	fn enabled(&self, _config: &mut Config) -> Result<bool, ConfigError> {
+		Ok(true)
+	}
+
+	fn config_keys(&self) -> &'static [&'static str] {
+		&["ping/enable", "buttons/enable", "environment/enable"]
+	}
+
+	fn start(&self, context: Context) -> BoxFuture<'static, Result<Never, ModuleError>> {
+		async move {
+			let config: SignalsConfig = context.config("").await?;
+			Signals::new(
+				context.config_handle,
+				context.state,
+				config.ping_enable,
+				config.buttons_enable,
+				config.environment_enable,
+				context.bus,
+			)
+			.get_background_task()
+			.await
+		}
+		.boxed()
+	}
+}
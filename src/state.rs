@@ -0,0 +1,215 @@
+//! Writes to the state, reduced as much as possible for the SD card.
+//!
+//! Every Elektra write stores the whole state (and calls `reload-opensesame`),
+//! so updates are collected and written together with `flush`, periodically
+//! (`state/flush/interval`) and on shutdown. Repeated updates of a key only
+//! keep the last value, values which did not change are not written at all.
+//! Until then, the updates are kept in a file in the runtime directory (tmpfs),
+//! so that they survive restarts of opensesame.
+
+use std::{
+	collections::BTreeMap,
+	fs,
+	path::PathBuf,
+	sync::{Arc, Mutex},
+};
+
+use serde::de::DeserializeOwned;
+
+use crate::{
+	config::{convert, ConfigError, ConfigHandle},
+	log,
+	log::Level,
+};
+
+/// reported in the ping
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StateStats {
+	/// calls of `set`
+	pub updates: u64,
+	/// actual writes to Elektra
+	pub writes: u64,
+}
+
+#[derive(Default)]
+struct Pending {
+	values: BTreeMap<String, String>,
+	stats: StateStats,
+}
+
+#[derive(Clone)]
+pub struct StateStore {
+	handle: ConfigHandle,
+	pending: Arc<Mutex<Pending>>,
+	/// in tmpfs, e.g. /run/opensesame/0-current.state.json
+	volatile: PathBuf,
+}
+
+impl StateStore {
+	/// Picks up updates which were not flushed before the last exit.
+	pub fn new(handle: ConfigHandle, volatile: PathBuf) -> Self {
+		let values = fs::read_to_string(&volatile)
+			.ok()
+			.and_then(|json| serde_json::from_str(&json).ok())
+			.unwrap_or_default();
+		Self {
+			handle,
+			pending: Arc::new(Mutex::new(Pending {
+				values,
+				stats: StateStats::default(),
+			})),
+			volatile,
+		}
+	}
+
+	/// Rereads the state from Elektra, updates not yet flushed are kept.
+	pub async fn sync(&self) -> Result<(), ConfigError> {
+		self.handle.sync().await
+	}
+
+	pub async fn get_option<T: DeserializeOwned + Send + 'static>(
+		&self,
+		name: &str,
+	) -> Result<Option<T>, ConfigError> {
+		let pending = self.pending.lock().unwrap().values.get(name).cloned();
+		match pending {
+			Some(value) => Ok(convert(name, value)),
+			None => self.handle.get_option(name).await,
+		}
+	}
+
+	/// Written with the next `flush`.
+	pub fn set(&self, name: &str, value: &str) {
+		let mut pending = self.pending.lock().unwrap();
+		pending.stats.updates += 1;
+		pending.values.insert(name.to_string(), value.to_string());
+		self.save_volatile(&pending.values);
+	}
+
+	/// For updates others must see immediately, e.g. `alarm/fire`,
+	/// which triggers the alarm via `reload-opensesame`.
+	pub async fn set_now(&self, name: &str, value: &str) -> Result<(), ConfigError> {
+		self.set(name, value);
+		self.flush().await
+	}
+
+	/// Removes the key right away, e.g. `alarm/fire` to end the alarm.
+	pub async fn remove_now(&self, name: &str) -> Result<(), ConfigError> {
+		{
+			let mut pending = self.pending.lock().unwrap();
+			pending.stats.updates += 1;
+			pending.values.remove(name);
+			self.save_volatile(&pending.values);
+		}
+		self.handle.remove(name).await?;
+		self.pending.lock().unwrap().stats.writes += 1;
+		Ok(())
+	}
+
+	/// Writes all updates with one Elektra call, if anything changed.
+	/// Updates stay pending until they got written.
+	pub async fn flush(&self) -> Result<(), ConfigError> {
+		let values = self.pending.lock().unwrap().values.clone();
+		if values.is_empty() {
+			return Ok(());
+		}
+		let written = self.handle.set_changed(values.clone()).await?;
+		let mut pending = self.pending.lock().unwrap();
+		if written {
+			pending.stats.writes += 1;
+		}
+		// unless they got updated again in the meantime
+		for (name, value) in values {
+			if pending.values.get(&name) == Some(&value) {
+				pending.values.remove(&name);
+			}
+		}
+		self.save_volatile(&pending.values);
+		Ok(())
+	}
+
+	pub fn stats(&self) -> StateStats {
+		self.pending.lock().unwrap().stats
+	}
+
+	fn save_volatile(&self, values: &BTreeMap<String, String>) {
+		let result = if values.is_empty() {
+			fs::remove_file(&self.volatile).or_else(|error| match error.kind() {
+				std::io::ErrorKind::NotFound => Ok(()),
+				_ => Err(error),
+			})
+		} else {
+			fs::write(&self.volatile, serde_json::to_string(values).unwrap())
+		};
+		if let Err(error) = result {
+			log!(
+				Level::Warning,
+				"Could not save state to {}: {}",
+				self.volatile.display(),
+				error
+			);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{path::Path, process, time::Duration};
+
+	use super::*;
+	use crate::config::Config;
+
+	/// not the state of opensesame, whose writes call `reload-opensesame`
+	const TEST_PARENT: &str = "/tests/opensesame/state";
+
+	fn store(volatile: &Path) -> StateStore {
+		StateStore::new(
+			ConfigHandle::new(Config::new(TEST_PARENT), Duration::from_secs(5)),
+			volatile.to_path_buf(),
+		)
+	}
+
+	#[tokio::test]
+	async fn test_coalesce() {
+		let volatile =
+			std::env::temp_dir().join(format!("opensesame-{}.state.json", process::id()));
+		let _ = fs::remove_file(&volatile);
+		let state = store(&volatile);
+		state.remove_now("test").await.unwrap();
+		state.set("test/state/counter", "1");
+		state.set("test/state/counter", "2");
+		assert_eq!(
+			state.get_option::<u64>("test/state/counter").await,
+			Ok(Some(2))
+		);
+		assert!(volatile.exists());
+
+		// survives restarts
+		let state = store(&volatile);
+		state.flush().await.unwrap();
+		assert!(!volatile.exists());
+		assert_eq!(
+			state.stats(),
+			StateStats {
+				updates: 0,
+				writes: 1
+			}
+		);
+
+		// unchanged values are not written again
+		state.set("test/state/counter", "2");
+		state.flush().await.unwrap();
+		assert_eq!(
+			state.stats(),
+			StateStats {
+				updates: 1,
+				writes: 1
+			}
+		);
+		assert_eq!(
+			state.get_option::<u64>("test/state/counter").await,
+			Ok(Some(2))
+		);
+		state.remove_now("test").await.unwrap();
+	}
+}
@@ -0,0 +1,134 @@
+//! Local HTTP server for tests, e.g. in place of Nextcloud: records all
+//! requests and answers with the queued responses, afterwards with 200 `{}`.
+
+use std::{
+	collections::VecDeque,
+	convert::Infallible,
+	net::SocketAddr,
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use hyper::{
+	body,
+	service::{make_service_fn, service_fn},
+	Body, HeaderMap, Request, Response, Server,
+};
+use tokio::{spawn, time::sleep};
+
+const WAIT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug)]
+pub struct Recorded {
+	pub method: String,
+	/// with the query, e.g. `/chat/abc?limit=100`
+	pub path: String,
+	pub headers: HeaderMap,
+	pub body: String,
+}
+
+struct Queued {
+	status: u16,
+	headers: Vec<(String, String)>,
+	body: String,
+}
+
+#[derive(Clone, Default)]
+struct Shared {
+	requests: Arc<Mutex<Vec<Recorded>>>,
+	responses: Arc<Mutex<VecDeque<Queued>>>,
+}
+
+pub struct Stub {
+	/// e.g. `http://127.0.0.1:40000`, without a slash at the end
+	pub url: String,
+	shared: Shared,
+}
+
+async fn answer(shared: Shared, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+	let (parts, request_body) = request.into_parts();
+	let bytes = body::to_bytes(request_body).await.unwrap_or_default();
+	shared.requests.lock().unwrap().push(Recorded {
+		method: parts.method.to_string(),
+		path: parts
+			.uri
+			.path_and_query()
+			.map_or_else(String::new, |path| path.to_string()),
+		headers: parts.headers,
+		body: String::from_utf8_lossy(&bytes).to_string(),
+	});
+	let queued = shared
+		.responses
+		.lock()
+		.unwrap()
+		.pop_front()
+		.unwrap_or_else(|| Queued {
+			status: 200,
+			headers: Vec::new(),
+			body: String::from("{}"),
+		});
+	let mut response = Response::builder()
+		.status(queued.status)
+		.header("Content-Type", "application/json");
+	for (name, value) in queued.headers {
+		response = response.header(name, value);
+	}
+	Ok(response.body(Body::from(queued.body)).unwrap())
+}
+
+impl Stub {
+	pub async fn start() -> Self {
+		let shared = Shared::default();
+		let service_shared = shared.clone();
+		let make_service = make_service_fn(move |_connection| {
+			let shared = service_shared.clone();
+			async move {
+				Ok::<_, Infallible>(service_fn(move |request| answer(shared.clone(), request)))
+			}
+		});
+		let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+		let url = format!("http://{}", server.local_addr());
+		spawn(server);
+		Self { url, shared }
+	}
+
+	/// Answer to the next request without queued response.
+	pub fn respond(&self, status: u16, body: &str) {
+		self.respond_with(status, &[], body);
+	}
+
+	/// Like `respond`, with additional headers, e.g. `Retry-After`.
+	pub fn respond_with(&self, status: u16, headers: &[(&str, &str)], body: &str) {
+		self.shared.responses.lock().unwrap().push_back(Queued {
+			status,
+			headers: headers
+				.iter()
+				.map(|(name, value)| (name.to_string(), value.to_string()))
+				.collect(),
+			body: body.to_string(),
+		});
+	}
+
+	pub fn requests(&self) -> Vec<Recorded> {
+		self.shared.requests.lock().unwrap().clone()
+	}
+
+	/// Waits until at least `count` requests arrived, panics after some seconds.
+	pub async fn wait_for(&self, count: usize) -> Vec<Recorded> {
+		let mut waited = Duration::ZERO;
+		loop {
+			let requests = self.requests();
+			if requests.len() >= count {
+				return requests;
+			}
+			assert!(
+				waited < WAIT,
+				"only {} of {} requests",
+				requests.len(),
+				count
+			);
+			sleep(Duration::from_millis(10)).await;
+			waited += Duration::from_millis(10);
+		}
+	}
+}
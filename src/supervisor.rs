@@ -0,0 +1,442 @@
+use std::{
+	any::Any,
+	collections::BTreeMap,
+	fmt,
+	future::Future,
+	panic::{self, AssertUnwindSafe},
+	sync::{Arc, Mutex},
+};
+
+use futures::never::Never;
+use gettextrs::gettext;
+use serde::{Deserialize, Serialize};
+use tokio::{
+	select, spawn,
+	sync::mpsc::{self, UnboundedSender},
+	task::JoinHandle,
+	time::{self, sleep, Duration, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+	bus::EventBus,
+	config::{Config, ConfigError},
+	module::Context,
+	nextcloud::NextcloudChat,
+	notify::Notification,
+	types::ModuleError,
+};
+
+// a module running at least this long is considered healthy again
+const STABLE_RUNTIME: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModuleState {
+	Running,
+	Restarting,
+	Disabled,
+	Stopped,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ModuleHealth {
+	pub state: ModuleState,
+	pub failures: u32,
+	pub restarts: u32,
+	pub last_error: Option<String>,
+}
+
+/// Health of all supervised modules, shared with everyone who wants to report it
+pub type Health = Arc<Mutex<BTreeMap<&'static str, ModuleHealth>>>;
+
+#[derive(Deserialize)]
+struct SupervisorConfig {
+	#[serde(rename = "restart/max")]
+	max_failures: u32,
+	#[serde(rename = "backoff/initial")]
+	initial_backoff: u64,
+	#[serde(rename = "backoff/max")]
+	max_backoff: u64,
+	#[serde(rename = "shutdown/timeout")]
+	shutdown_timeout: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Policy {
+	max_failures: u32,
+	initial_backoff: Duration,
+	max_backoff: Duration,
+}
+
+impl Policy {
+	/// exponential back-off: initial, 2x initial, 4x initial, ... up to max
+	fn backoff(&self, failures: u32) -> Duration {
+		let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+		self.initial_backoff
+			.saturating_mul(factor)
+			.min(self.max_backoff)
+	}
+}
+
+/// What the supervisor can be asked to do with a running module
+enum Control {
+	Restart,
+	Stop,
+}
+
+struct Task {
+	control: UnboundedSender<Control>,
+	// None once the task finished, a finished JoinHandle must not be polled again
+	handle: Option<JoinHandle<()>>,
+}
+
+pub struct Supervisor {
+	policy: Policy,
+	health: Health,
+	bus: EventBus,
+	shutdown: CancellationToken,
+	shutdown_timeout: Duration,
+	tasks: BTreeMap<&'static str, Task>,
+}
+
+fn panic_reason(payload: Box<dyn Any + Send>) -> String {
+	if let Some(reason) = payload.downcast_ref::<&str>() {
+		format!("panic: {}", reason)
+	} else if let Some(reason) = payload.downcast_ref::<String>() {
+		format!("panic: {}", reason)
+	} else {
+		String::from("panic")
+	}
+}
+
+impl Supervisor {
+	pub fn new(config: &mut Config, context: &Context) -> Result<Self, Vec<ConfigError>> {
+		let config: SupervisorConfig = config.deserialize("supervisor")?;
+		Ok(Self {
+			policy: Policy {
+				max_failures: config.max_failures,
+				initial_backoff: Duration::from_secs(config.initial_backoff),
+				max_backoff: Duration::from_secs(config.max_backoff),
+			},
+			health: context.health.clone(),
+			bus: context.bus.clone(),
+			shutdown: context.shutdown.clone(),
+			shutdown_timeout: Duration::from_secs(config.shutdown_timeout),
+			tasks: BTreeMap::new(),
+		})
+	}
+
+	fn update(health: &Health, name: &'static str, state: ModuleState, error: Option<String>) {
+		let mut health = health.lock().unwrap();
+		let entry = health.entry(name).or_insert(ModuleHealth {
+			state,
+			failures: 0,
+			restarts: 0,
+			last_error: None,
+		});
+		entry.state = state;
+		match state {
+			ModuleState::Running => (),
+			ModuleState::Restarting => entry.restarts += 1,
+			ModuleState::Disabled | ModuleState::Stopped => (),
+		}
+		if error.is_some() {
+			entry.failures += 1;
+			entry.last_error = error;
+		}
+	}
+
+	/// Cancels the module's token and gives it `timeout` to finish, then aborts it.
+	async fn stop<T>(handle: &mut JoinHandle<T>, stop: &CancellationToken, timeout: Duration) {
+		stop.cancel();
+		if time::timeout(timeout, &mut *handle).await.is_err() {
+			handle.abort();
+			// wait until the module is dropped, so that Drop impls did their job
+			let _ = handle.await;
+		}
+	}
+
+	/// Runs the future created by `factory` and creates a new one whenever it exits or panics.
+	/// After `supervisor/restart/max` consecutive failures the module stays disabled.
+	/// The factory gets a token which is cancelled when the module should stop
+	/// (shutdown, restart or stop); the module then gets `supervisor/shutdown/timeout` to finish.
+	pub fn spawn<F, Fut>(&mut self, name: &'static str, mut factory: F)
+	where
+		F: FnMut(CancellationToken) -> Fut + Send + 'static,
+		Fut: Future<Output = Result<Never, ModuleError>> + Send + 'static,
+	{
+		let policy = self.policy;
+		let health = self.health.clone();
+		let bus = self.bus.clone();
+		let shutdown = self.shutdown.clone();
+		let shutdown_timeout = self.shutdown_timeout;
+		let (control, mut control_receiver) = mpsc::unbounded_channel();
+		let handle = spawn(async move {
+			let mut failures = 0;
+			loop {
+				Supervisor::update(&health, name, ModuleState::Running, None);
+				let started = Instant::now();
+				let stop = shutdown.child_token();
+				let reason = match panic::catch_unwind(AssertUnwindSafe(|| factory(stop.clone()))) {
+					Ok(future) => {
+						let mut handle = spawn(future);
+						let result = select! {
+							result = &mut handle => result,
+							control = control_receiver.recv() => {
+								Supervisor::stop(&mut handle, &stop, shutdown_timeout).await;
+								match control {
+									Some(Control::Restart) => continue,
+									Some(Control::Stop) | None => {
+										Supervisor::update(&health, name, ModuleState::Stopped, None);
+										return;
+									}
+								}
+							}
+							_ = shutdown.cancelled() => {
+								Supervisor::stop(&mut handle, &stop, shutdown_timeout).await;
+								Supervisor::update(&health, name, ModuleState::Stopped, None);
+								return;
+							}
+						};
+						if shutdown.is_cancelled() {
+							Supervisor::update(&health, name, ModuleState::Stopped, None);
+							return;
+						}
+						match result {
+							Ok(Ok(never)) => match never {},
+							Ok(Err(error)) => error.to_string(),
+							Err(join_error) if join_error.is_panic() => {
+								panic_reason(join_error.into_panic())
+							}
+							Err(join_error) => join_error.to_string(),
+						}
+					}
+					Err(payload) => panic_reason(payload),
+				};
+
+				if started.elapsed() >= STABLE_RUNTIME {
+					failures = 0;
+				}
+				failures += 1;
+
+				if failures >= policy.max_failures {
+					Supervisor::update(&health, name, ModuleState::Disabled, Some(reason.clone()));
+					bus.send(Notification::warning(
+						"Supervisor",
+						NextcloudChat::Ping,
+						gettext!(
+							"⛔ Module {} disabled after {} failures: {}",
+							name,
+							failures,
+							reason
+						),
+					));
+					return;
+				}
+
+				let backoff = policy.backoff(failures);
+				Supervisor::update(&health, name, ModuleState::Restarting, Some(reason.clone()));
+				bus.send(Notification::warning(
+					"Supervisor",
+					NextcloudChat::Ping,
+					gettext!(
+						"⚠️ Module {} exited: {}. Restarting in {} s",
+						name,
+						reason,
+						backoff.as_secs()
+					),
+				));
+				select! {
+					_ = sleep(backoff) => (),
+					control = control_receiver.recv() => match control {
+						// restart right away
+						Some(Control::Restart) => (),
+						Some(Control::Stop) | None => {
+							Supervisor::update(&health, name, ModuleState::Stopped, None);
+							return;
+						}
+					},
+					_ = shutdown.cancelled() => {
+						Supervisor::update(&health, name, ModuleState::Stopped, None);
+						return;
+					}
+				}
+			}
+		});
+		self.tasks.insert(
+			name,
+			Task {
+				control,
+				handle: Some(handle),
+			},
+		);
+	}
+
+	/// Whether the module is supervised, i.e. neither disabled nor stopped.
+	pub fn is_running(&self, name: &str) -> bool {
+		self.tasks
+			.get(name)
+			.and_then(|task| task.handle.as_ref())
+			.is_some_and(|handle| !handle.is_finished())
+	}
+
+	/// Restarts the module, e.g. to apply a changed configuration.
+	pub fn restart(&self, name: &str) {
+		if let Some(task) = self.tasks.get(name) {
+			let _ = task.control.send(Control::Restart);
+		}
+	}
+
+	/// Stops the module without restarting it.
+	pub fn stop_module(&self, name: &str) {
+		if let Some(task) = self.tasks.get(name) {
+			let _ = task.control.send(Control::Stop);
+		}
+	}
+
+	/// Waits until all modules are disabled or stopped, i.e., usually forever.
+	/// Can be cancelled at any time, e.g. by a signal, and called again or followed by `shutdown`.
+	pub async fn wait(&mut self) {
+		futures::future::join_all(self.tasks.values_mut().map(|task| async move {
+			if let Some(handle) = &mut task.handle {
+				let _ = handle.await;
+			}
+			task.handle = None;
+		}))
+		.await;
+	}
+
+	/// Tells all modules to stop and waits until they did.
+	pub async fn shutdown(self) {
+		self.shutdown.cancel();
+		futures::future::join_all(self.tasks.into_values().filter_map(|task| task.handle)).await;
+	}
+}
+
+impl fmt::Display for ModuleHealth {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self.state {
+			ModuleState::Running => write!(f, "running")?,
+			ModuleState::Restarting => write!(f, "restarting")?,
+			ModuleState::Disabled => write!(f, "disabled")?,
+			ModuleState::Stopped => write!(f, "stopped")?,
+		}
+		if self.restarts > 0 {
+			write!(f, " ({} restarts)", self.restarts)?;
+		}
+		if let Some(error) = &self.last_error {
+			write!(f, " last error: {}", error)?;
+		}
+		Ok(())
+	}
+}
+
+/// One line per module, e.g. for `\status`
+pub fn health_report(health: &Health) -> String {
+	health
+		.lock()
+		.unwrap()
+		.iter()
+		.map(|(name, module_health)| format!("{}: {}", name, module_health))
+		.collect::<Vec<String>>()
+		.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_backoff() {
+		let policy = Policy {
+			max_failures: 5,
+			initial_backoff: Duration::from_secs(10),
+			max_backoff: Duration::from_secs(60),
+		};
+		assert_eq!(policy.backoff(1), Duration::from_secs(10));
+		assert_eq!(policy.backoff(2), Duration::from_secs(20));
+		assert_eq!(policy.backoff(3), Duration::from_secs(40));
+		assert_eq!(policy.backoff(4), Duration::from_secs(60));
+		assert_eq!(policy.backoff(100), Duration::from_secs(60));
+	}
+
+	fn supervisor() -> Supervisor {
+		Supervisor {
+			policy: Policy {
+				max_failures: 5,
+				initial_backoff: Duration::from_secs(10),
+				max_backoff: Duration::from_secs(60),
+			},
+			health: Health::default(),
+			bus: EventBus::new(),
+			shutdown: CancellationToken::new(),
+			shutdown_timeout: Duration::from_millis(10),
+			tasks: BTreeMap::new(),
+		}
+	}
+
+	async fn cooperative(stop: CancellationToken) -> Result<Never, ModuleError> {
+		stop.cancelled().await;
+		Err(ModuleError::new(String::from("stopped")))
+	}
+
+	#[tokio::test]
+	async fn test_shutdown() {
+		let mut supervisor = supervisor();
+		let health = supervisor.health.clone();
+		supervisor.spawn("Cooperative", cooperative);
+		supervisor.spawn("Stubborn", |_stop| futures::future::pending());
+		sleep(Duration::from_millis(10)).await;
+		supervisor.shutdown().await;
+		let health = health.lock().unwrap();
+		assert_eq!(health["Cooperative"].state, ModuleState::Stopped);
+		assert_eq!(health["Stubborn"].state, ModuleState::Stopped);
+		assert_eq!(health["Cooperative"].failures, 0);
+	}
+
+	#[tokio::test]
+	async fn test_restart_and_stop() {
+		let mut supervisor = supervisor();
+		let starts = Arc::new(Mutex::new(0));
+		let counter = starts.clone();
+		supervisor.spawn("Module", move |stop| {
+			*counter.lock().unwrap() += 1;
+			cooperative(stop)
+		});
+		sleep(Duration::from_millis(10)).await;
+		supervisor.restart("Module");
+		sleep(Duration::from_millis(10)).await;
+		assert_eq!(*starts.lock().unwrap(), 2);
+		assert!(supervisor.is_running("Module"));
+
+		supervisor.stop_module("Module");
+		sleep(Duration::from_millis(10)).await;
+		assert!(!supervisor.is_running("Module"));
+		assert_eq!(supervisor.health.lock().unwrap()["Module"].failures, 0);
+		supervisor.wait().await;
+	}
+
+	#[tokio::test]
+	async fn test_shutdown_after_interrupted_wait() {
+		let mut supervisor = supervisor();
+		supervisor.spawn("Stopped", cooperative);
+		supervisor.spawn("Running", cooperative);
+		sleep(Duration::from_millis(10)).await;
+		supervisor.stop_module("Stopped");
+		// like a signal arriving while waiting: "Stopped" finishes, "Running" does not
+		select! {
+			_ = supervisor.wait() => panic!("Running should still run"),
+			_ = sleep(Duration::from_millis(50)) => (),
+		}
+		assert!(supervisor.tasks["Stopped"].handle.is_none());
+		supervisor.shutdown().await;
+	}
+
+	#[test]
+	fn test_panic_reason() {
+		assert_eq!(panic_reason(Box::new("oops")), "panic: oops");
+		assert_eq!(panic_reason(Box::new(String::from("oops"))), "panic: oops");
+		assert_eq!(panic_reason(Box::new(42)), "panic");
+	}
+}
@@ -3,6 +3,8 @@ use nix::errno::Errno;
 use std::{error::Error, fmt};
 use tokio::sync::mpsc::error::SendError;
 
+use crate::config::ConfigError;
+
 #[derive(Debug, Clone)]
 pub struct ModuleError {
 	reason: String,
@@ -22,6 +24,29 @@ impl fmt::Display for ModuleError {
 	}
 }
 
+impl From<ConfigError> for ModuleError {
+	fn from(error: ConfigError) -> Self {
+		ModuleError {
+			reason: format!("Configuration: {}", error),
+		}
+	}
+}
+
+impl From<Vec<ConfigError>> for ModuleError {
+	fn from(errors: Vec<ConfigError>) -> Self {
+		ModuleError {
+			reason: format!(
+				"Invalid configuration: {}",
+				errors
+					.iter()
+					.map(ConfigError::to_string)
+					.collect::<Vec<String>>()
+					.join(", ")
+			),
+		}
+	}
+}
+
 // TODO: These are hacks for now:
 
 impl<T> From<SendError<T>> for ModuleError {
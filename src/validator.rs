@@ -1,6 +1,38 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-use crate::config::Config;
+use serde::Deserialize;
+
+/// longer sequences get rejected while typing
+pub const MAX_SEQUENCE_LENGTH: usize = 10;
+
+/// PIN sequence as written in the configuration, e.g. `[7, 7, 13, 13]`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct Sequence(Vec<u8>);
+
+impl TryFrom<String> for Sequence {
+	type Error = String;
+
+	fn try_from(value: String) -> Result<Self, Self::Error> {
+		value
+			.replace(&['(', ')', ',', '[', ']'][..], "")
+			.split_whitespace()
+			.map(|s| s.parse())
+			.collect::<Result<Vec<u8>, _>>()
+			.map(Sequence)
+			.map_err(|_| format!("'{}' is not a sequence like [7, 7, 13, 13]", value))
+	}
+}
+
+impl Sequence {
+	pub fn len(&self) -> usize {
+		self.0.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.0.is_empty()
+	}
+}
 
 pub struct Validator {
 	users: HashMap<Vec<u8>, String>,
@@ -16,9 +48,13 @@ pub enum Validation {
 }
 
 impl Validator {
-	pub fn new(config: &mut Config) -> Self {
+	/// `users` maps the user name to their sequence, as in the `validator` configuration.
+	pub fn new(users: &BTreeMap<String, Sequence>) -> Self {
 		Self {
-			users: config.get_hash_map_vec_u8("validator"),
+			users: users
+				.iter()
+				.map(|(user, sequence)| (sequence.0.clone(), user.clone()))
+				.collect(),
 			timeout: 0,
 		}
 	}
@@ -27,7 +63,7 @@ impl Validator {
 		if !sequence.is_empty() {
 			self.timeout += 1;
 		}
-		if sequence.len() > 10 {
+		if sequence.len() > MAX_SEQUENCE_LENGTH {
 			sequence.clear();
 			self.timeout = 0;
 			return Validation::SequenceTooLong;
@@ -51,6 +87,7 @@ impl Validator {
 mod tests {
 	// Note this useful idiom: importing names from outer (for mod tests) scope.
 	use super::*;
+	use crate::config::Config;
 	use std::{env, vec};
 
 	const CONFIG_PARENT: &str = "/sw/libelektra/opensesame/#0/current";
@@ -74,7 +111,7 @@ mod tests {
 		config.cut("validator");
 		config.add("validator/1234", "[14, 15, 13, 15, 11, 15, 7, 15]");
 
-		let mut validator = Validator::new(&mut config);
+		let mut validator = Validator::new(&config.deserialize("validator").unwrap());
 
 		// validator.users.insert(vec![14, 15, 13, 15, 11, 15, 7, 15], "1234".to_string());
 		assert_eq!(validator.timeout, 0);
@@ -95,7 +132,7 @@ mod tests {
 	fn test_validate_timeout() {
 		//whitespace after comma; otherwise an error occurs
 		let mut config: Config = setup_test_env("[7, 7, 13, 13]");
-		let mut validator = Validator::new(&mut config);
+		let mut validator = Validator::new(&config.deserialize("validator").unwrap());
 
 		for x in 1..1001 {
 			if x < 1001 {
@@ -116,7 +153,7 @@ mod tests {
 	#[test]
 	fn test_validate_empty() {
 		let mut config: Config = setup_test_env("[7, 3, 4, 2, 4]");
-		let mut validator = Validator::new(&mut config);
+		let mut validator = Validator::new(&config.deserialize("validator").unwrap());
 
 		for _x in 1..2000 {
 			assert_eq!(validator.validate(&mut vec![]), Validation::None);
@@ -127,7 +164,7 @@ mod tests {
 	#[test]
 	fn test_validate_increment_seq() {
 		let mut config: Config = setup_test_env("[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]");
-		let mut validator = Validator::new(&mut config);
+		let mut validator = Validator::new(&config.deserialize("validator").unwrap());
 
 		let mut seq: Vec<u8> = vec![];
 
@@ -149,7 +186,7 @@ mod tests {
 	#[test]
 	fn test_validate_seq_to_long() {
 		let mut config: Config = setup_test_env("[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13]");
-		let mut validator = Validator::new(&mut config);
+		let mut validator = Validator::new(&config.deserialize("validator").unwrap());
 
 		let mut seq: Vec<u8> = vec![];
 
@@ -165,4 +202,14 @@ mod tests {
 			}
 		}
 	}
+
+	#[test]
+	fn test_invalid_sequence() {
+		let mut config: Config = setup_test_env("[7, 7, 300]");
+		let errors = config
+			.deserialize::<BTreeMap<String, Sequence>>("validator")
+			.unwrap_err();
+		assert_eq!(errors.len(), 1);
+		assert_eq!(errors[0].key, "validator/test");
+	}
 }
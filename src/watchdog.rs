@@ -1,23 +1,73 @@
-use futures::never::Never;
+use futures::{future::BoxFuture, never::Never, FutureExt};
+use serde::Deserialize;
+use std::time::Duration;
 
-use tokio::{fs::File, io::AsyncWriteExt, time::Interval};
+use tokio::{
+	fs::File,
+	io::AsyncWriteExt,
+	select,
+	time::{interval, Interval},
+};
+use tokio_util::sync::CancellationToken;
 
-use crate::types::ModuleError;
+use crate::{
+	config::{
+		validate::{exists, in_range, Validate},
+		Config, ConfigError,
+	},
+	module::{Context, Module},
+	types::ModuleError,
+};
 
 pub const SAFE_TIMEOUT: u64 = 15 * 1000; // safe to wait if trigger was done just before
 
+#[derive(Deserialize)]
+pub struct WatchdogConfig {
+	/// in seconds
+	pub interval: u64,
+	pub path: String,
+}
+
+impl Validate for WatchdogConfig {
+	fn validate(&self) -> Vec<ConfigError> {
+		[
+			exists("watchdog/path", &self.path),
+			// must trigger well before the hardware resets
+			in_range(
+				"watchdog/interval",
+				self.interval,
+				1..=SAFE_TIMEOUT / 1000 - 1,
+			),
+		]
+		.into_iter()
+		.flatten()
+		.collect()
+	}
+}
+
 pub struct Watchdog {}
 
 impl Watchdog {
 	pub async fn get_background_task(
 		path: String,
 		mut interval: Interval,
+		shutdown: CancellationToken,
 	) -> Result<Never, ModuleError> {
 		let mut handle = File::create(path)
 			.await
 			.map_err(|_| ModuleError::new(String::from("could not open watchdog")))?;
 		loop {
-			interval.tick().await;
+			select! {
+				_ = interval.tick() => (),
+				_ = shutdown.cancelled() => {
+					// magic close: disarm the watchdog, we stop on purpose
+					handle
+						.write_all(b"V")
+						.await
+						.map_err(|_| ModuleError::new(String::from("could not disarm watchdog")))?;
+					return Err(ModuleError::new(String::from("watchdog shut down")));
+				}
+			}
 			handle
 				.write_all(b"a")
 				.await
@@ -44,3 +94,36 @@ impl Watchdog {
 		Err(ModuleError::new(String::from("doesn't run for loop")))
 	}
 }
+
+pub struct WatchdogModule;
+
+impl Module for WatchdogModule {
+	fn name(&self) -> &'static str {
+		"Watchdog"
+	}
+
+	fn enabled(&self, config: &mut Config) -> Result<bool, ConfigError> {
+		config.value::<bool>("watchdog/enable")
+	}
+
+	fn hardware(&self) -> bool {
+		true
+	}
+
+	fn config_keys(&self) -> &'static [&'static str] {
+		&["watchdog"]
+	}
+
+	fn check_config(&self, config: &mut Config) -> Result<(), Vec<ConfigError>> {
+		config.check::<WatchdogConfig>("watchdog")
+	}
+
+	fn start(&self, context: Context) -> BoxFuture<'static, Result<Never, ModuleError>> {
+		async move {
+			let config: WatchdogConfig = context.config("watchdog").await?;
+			let interval = interval(Duration::from_secs(config.interval));
+			Watchdog::get_background_task(config.path, interval, context.shutdown).await
+		}
+		.boxed()
+	}
+}
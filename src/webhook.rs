@@ -0,0 +1,630 @@
+//! POSTs selected events as JSON to configurable URLs, see doc/Webhook.md.
+//!
+//! The body of each hook is rendered from its template and signed with
+//! HMAC-SHA256 if the hook has a secret. Deliveries stay queued per hook until
+//! the server accepted them, retries back off exponentially.
+
+use std::{collections::VecDeque, sync::Mutex, time::Duration};
+
+use chrono::Local;
+use futures::{future::BoxFuture, never::Never, FutureExt};
+use gettextrs::gettext;
+use hmac::{Hmac, Mac};
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::{
+	select,
+	sync::Notify,
+	time::{sleep_until, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+	bus::{Event, EventBus},
+	config::{
+		all_errors,
+		validate::{in_range, url, Validate},
+		Config, ConfigError,
+	},
+	log,
+	log::Level,
+	module::{Context, Module},
+	nextcloud::NextcloudEvent,
+	notify::{NotifyConfig, Severity},
+	readings::unix_time,
+	routing::{Destination, Routing},
+	types::ModuleError,
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const INITIAL_RETRY: Duration = Duration::from_secs(5);
+const MAX_RETRY: Duration = Duration::from_secs(10 * 60);
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+/// header with `sha256=<hex>` of the body
+pub const SIGNATURE: &str = "X-Opensesame-Signature";
+/// header with the event, e.g. `door`
+pub const EVENT: &str = "X-Opensesame-Event";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Trigger {
+	/// `DoorOpened`
+	Door,
+	/// `BellPressed`
+	Bell,
+	/// `AlarmRaised`
+	Alarm,
+	/// `BatteryLow`
+	Battery,
+	/// notifications routed to `webhook`, see doc/Routing.md
+	Notification,
+}
+
+impl Trigger {
+	fn name(&self) -> &'static str {
+		match self {
+			Trigger::Door => "door",
+			Trigger::Bell => "bell",
+			Trigger::Alarm => "alarm",
+			Trigger::Battery => "battery",
+			Trigger::Notification => "notification",
+		}
+	}
+}
+
+/// `webhook/hook/#<n>`
+#[derive(Clone, Debug, Deserialize)]
+pub struct Hook {
+	pub url: String,
+	/// what gets posted to the URL
+	pub events: Vec<Trigger>,
+	/// body with placeholders like `{text}`, empty for the default JSON
+	pub template: String,
+	/// key of the HMAC-SHA256 signature, empty to not sign
+	pub secret: String,
+}
+
+#[derive(Deserialize)]
+pub struct WebhookConfig {
+	pub hook: Vec<Hook>,
+	/// deliveries kept per hook while its server is unreachable
+	#[serde(rename = "queue/size")]
+	pub queue_size: u32,
+}
+
+impl Validate for WebhookConfig {
+	fn validate(&self) -> Vec<ConfigError> {
+		self.hook
+			.iter()
+			.enumerate()
+			.filter_map(|(index, hook)| url(&format!("webhook/hook/#{}/url", index), &hook.url))
+			.chain(in_range("webhook/queue/size", self.queue_size, 1..=100_000))
+			.collect()
+	}
+}
+
+/// The default body, also the values of the placeholders.
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+	event: Trigger,
+	text: String,
+	/// only for notifications
+	#[serde(skip_serializing_if = "Option::is_none")]
+	module: Option<&'a str>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	severity: Option<Severity>,
+	instance: &'a str,
+	/// unix time
+	time: u64,
+}
+
+/// JSON string without the quotes, so placeholders can be used inside strings of the template.
+fn escape(value: &str) -> String {
+	let quoted = serde_json::to_string(value).unwrap();
+	quoted[1..quoted.len() - 1].to_string()
+}
+
+/// Value of the placeholder `{name}`, None if there is no such placeholder.
+fn placeholder(name: &str, payload: &Payload) -> Option<String> {
+	Some(match name {
+		"event" => payload.event.name().to_string(),
+		"text" => escape(&payload.text),
+		"module" => escape(payload.module.unwrap_or_default()),
+		"severity" => payload
+			.severity
+			.map(|severity| serde_json::to_value(severity).unwrap())
+			.and_then(|severity| severity.as_str().map(String::from))
+			.unwrap_or_default(),
+		"instance" => escape(payload.instance),
+		"time" => payload.time.to_string(),
+		_ => return None,
+	})
+}
+
+/// In one pass, so that placeholders in the values, e.g. in the text, stay as they are.
+fn render(template: &str, payload: &Payload) -> String {
+	if template.is_empty() {
+		return serde_json::to_string(payload).unwrap();
+	}
+	let mut body = String::with_capacity(template.len());
+	let mut rest = template;
+	while let Some(start) = rest.find('{') {
+		body.push_str(&rest[..start]);
+		rest = &rest[start..];
+		let value = rest
+			.find('}')
+			.and_then(|end| Some((end, placeholder(&rest[1..end], payload)?)));
+		match value {
+			Some((end, value)) => {
+				body.push_str(&value);
+				rest = &rest[end + 1..];
+			}
+			// e.g. the braces of a JSON object
+			None => {
+				body.push('{');
+				rest = &rest[1..];
+			}
+		}
+	}
+	body.push_str(rest);
+	body
+}
+
+fn sign(secret: &str, body: &str) -> String {
+	let mut mac =
+		HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+	mac.update(body.as_bytes());
+	format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Delivery {
+	event: Trigger,
+	body: String,
+}
+
+struct Target {
+	hook: Hook,
+	queue: VecDeque<Delivery>,
+	backoff: Duration,
+	/// None if the last delivery succeeded
+	retry: Option<Instant>,
+}
+
+/// The server will never accept the delivery, e.g. a wrong URL or a broken template.
+fn rejected(status: StatusCode) -> bool {
+	status.is_client_error()
+		&& status != StatusCode::REQUEST_TIMEOUT
+		&& status != StatusCode::TOO_MANY_REQUESTS
+}
+
+pub struct Webhook {
+	/// queued by the event loop, sent by `delivery_loop`
+	targets: Mutex<Vec<Target>>,
+	/// wakes `delivery_loop` when something got queued
+	queued: Notify,
+	capacity: usize,
+	routing: Routing,
+	instance: String,
+	client: Client,
+	/// log instead of sending, see `--dry-run`
+	dry_run: bool,
+}
+
+impl Webhook {
+	pub fn new(config: WebhookConfig, routing: Routing, context: &Context) -> Self {
+		Self {
+			targets: Mutex::new(
+				config
+					.hook
+					.into_iter()
+					.map(|hook| Target {
+						hook,
+						queue: VecDeque::new(),
+						backoff: INITIAL_RETRY,
+						retry: None,
+					})
+					.collect(),
+			),
+			queued: Notify::new(),
+			capacity: config.queue_size.max(1) as usize,
+			routing,
+			instance: context.instance.to_string(),
+			client: Client::new(),
+			dry_run: context.options.dry_run,
+		}
+	}
+
+	/// What gets posted for an event on the bus, None for most events.
+	fn payload(&self, event: &Event) -> Option<Payload<'_>> {
+		let (event, text, module, severity) = match event {
+			Event::DoorOpened(by) => (Trigger::Door, gettext!("🤗 Opened for {}", by), None, None),
+			Event::BellPressed => (Trigger::Bell, gettext("🔔 Bell pressed"), None, None),
+			Event::AlarmRaised(reason) => {
+				(Trigger::Alarm, gettext!("🚨 Alarm: {}", reason), None, None)
+			}
+			Event::BatteryLow(capacity) => (
+				Trigger::Battery,
+				gettext!("🪫 Battery low at {}%", capacity),
+				None,
+				None,
+			),
+			Event::Nextcloud(NextcloudEvent::Chat(notification))
+				if self.routing.routes_to(
+					notification,
+					Destination::Webhook,
+					Local::now().time(),
+				) =>
+			{
+				(
+					Trigger::Notification,
+					notification.text.clone(),
+					Some(notification.module),
+					Some(notification.severity),
+				)
+			}
+			_ => return None,
+		};
+		Some(Payload {
+			event,
+			text,
+			module,
+			severity,
+			instance: &self.instance,
+			time: unix_time(),
+		})
+	}
+
+	/// Queues the event for all hooks which want it.
+	fn queue(&self, event: &Event) {
+		let Some(payload) = self.payload(event) else {
+			return;
+		};
+		let mut targets = self.targets.lock().unwrap();
+		let deliveries = targets
+			.iter()
+			.map(|target| {
+				target
+					.hook
+					.events
+					.contains(&payload.event)
+					.then(|| Delivery {
+						event: payload.event,
+						body: render(&target.hook.template, &payload),
+					})
+			})
+			.collect::<Vec<Option<Delivery>>>();
+		for (target, delivery) in targets.iter_mut().zip(deliveries) {
+			let Some(delivery) = delivery else {
+				continue;
+			};
+			target.queue.push_back(delivery);
+			if target.queue.len() > self.capacity {
+				if let Some(dropped) = target.queue.pop_front() {
+					log!(
+						Level::Warning,
+						"Webhook queue of {} full, dropped {}",
+						target.hook.url,
+						dropped.body
+					);
+				}
+			}
+			self.queued.notify_one();
+		}
+	}
+
+	async fn post(&self, hook: &Hook, delivery: &Delivery) -> Result<(), reqwest::Error> {
+		let mut request = self
+			.client
+			.post(&hook.url)
+			.timeout(SEND_TIMEOUT)
+			.header("Content-Type", "application/json")
+			.header(EVENT, delivery.event.name());
+		if !hook.secret.is_empty() {
+			request = request.header(SIGNATURE, sign(&hook.secret, &delivery.body));
+		}
+		request
+			.body(delivery.body.clone())
+			.send()
+			.await?
+			.error_for_status()
+			.map(|_| ())
+	}
+
+	/// Sends the queued deliveries of all hooks which are due, in order per hook.
+	async fn deliver(&self) {
+		let hooks = self.targets.lock().unwrap().len();
+		for index in 0..hooks {
+			loop {
+				let (hook, delivery) = {
+					let targets = self.targets.lock().unwrap();
+					let target = &targets[index];
+					if matches!(target.retry, Some(retry) if retry > Instant::now()) {
+						break;
+					}
+					let Some(delivery) = target.queue.front().cloned() else {
+						break;
+					};
+					(target.hook.clone(), delivery)
+				};
+				let result = if self.dry_run {
+					log!(
+						Level::Info,
+						"Dry run, not posted to {}: {}",
+						hook.url,
+						delivery.body
+					);
+					Ok(())
+				} else {
+					self.post(&hook, &delivery).await
+				};
+				let mut targets = self.targets.lock().unwrap();
+				let target = &mut targets[index];
+				if let Err(error) = result {
+					if error.status().is_some_and(rejected) {
+						log!(
+							Level::Warning,
+							"Webhook {} rejected {}: {}",
+							hook.url,
+							delivery.body,
+							error
+						);
+					} else {
+						log!(
+							Level::Warning,
+							"Couldn't post to webhook {}, retrying in {:?}: {}",
+							hook.url,
+							target.backoff,
+							error
+						);
+						target.retry = Some(Instant::now() + target.backoff);
+						target.backoff = (target.backoff * 2).min(MAX_RETRY);
+						break;
+					}
+				}
+				// unless it got dropped from the full queue meanwhile
+				if target.queue.front() == Some(&delivery) {
+					target.queue.pop_front();
+				}
+				target.backoff = INITIAL_RETRY;
+				target.retry = None;
+			}
+		}
+	}
+
+	/// earliest retry of a hook with queued deliveries
+	fn next_retry(&self) -> Option<Instant> {
+		self.targets
+			.lock()
+			.unwrap()
+			.iter()
+			.filter(|target| !target.queue.is_empty())
+			.filter_map(|target| target.retry)
+			.min()
+	}
+
+	/// Posts whatever got queued, apart from the event loop, so that a slow or
+	/// unreachable server never holds up the bus.
+	async fn delivery_loop(&self) -> Never {
+		loop {
+			self.deliver().await;
+			let retry = self.next_retry();
+			select! {
+				_ = self.queued.notified() => {}
+				_ = sleep_until(retry.unwrap_or_else(Instant::now)), if retry.is_some() => {}
+			}
+		}
+	}
+
+	pub async fn get_background_task(
+		self,
+		bus: EventBus,
+		shutdown: CancellationToken,
+	) -> Result<Never, ModuleError> {
+		let mut events = bus.subscribe();
+		let events = async {
+			loop {
+				select! {
+					event = events.recv() => match event {
+						Some(event) => self.queue(&event),
+						None => break,
+					},
+					_ = shutdown.cancelled() => {
+						return ModuleError::new(String::from("Webhook shut down"));
+					}
+				}
+			}
+			ModuleError::new(String::from("webhook background task exited"))
+		};
+		select! {
+			error = events => Err(error),
+			never = self.delivery_loop() => match never {},
+		}
+	}
+}
+
+pub struct WebhookModule;
+
+impl Module for WebhookModule {
+	fn name(&self) -> &'static str {
+		"Webhook"
+	}
+
+	fn enabled(&self, config: &mut Config) -> Result<bool, ConfigError> {
+		config.value::<bool>("webhook/enable")
+	}
+
+	fn config_keys(&self) -> &'static [&'static str] {
+		&["webhook", "notify"]
+	}
+
+	fn check_config(&self, config: &mut Config) -> Result<(), Vec<ConfigError>> {
+		all_errors([
+			config.check::<WebhookConfig>("webhook"),
+			config.check::<NotifyConfig>("notify"),
+		])
+	}
+
+	fn start(&self, context: Context) -> BoxFuture<'static, Result<Never, ModuleError>> {
+		async move {
+			let notify: NotifyConfig = context.config("notify").await?;
+			let webhook: WebhookConfig = context.config("webhook").await?;
+			Webhook::new(webhook, Routing::new(&notify.route), &context)
+				.get_background_task(context.bus, context.shutdown)
+				.await
+		}
+		.boxed()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeMap;
+
+	use serde_json::{json, Value};
+
+	use super::*;
+	use crate::{
+		cli::Options, instance::Instance, nextcloud::NextcloudChat, notify::Notification,
+		routing::Route, stub::Stub,
+	};
+
+	async fn webhook(url: &str) -> Webhook {
+		let context = Context::new(
+			Instance::default(),
+			String::from("today"),
+			Default::default(),
+			Options::default(),
+		)
+		.await
+		.unwrap();
+		let routing = Routing::new(&BTreeMap::from([(
+			String::from("#0"),
+			Route {
+				severity: Severity::Warning,
+				module: None,
+				to: Destination::Webhook,
+				quiet: None,
+			},
+		)]));
+		Webhook::new(
+			WebhookConfig {
+				hook: vec![
+					Hook {
+						url: format!("{}/door", url),
+						events: vec![Trigger::Door, Trigger::Alarm],
+						template: String::new(),
+						secret: String::from("secret"),
+					},
+					Hook {
+						url: format!("{}/chat", url),
+						events: vec![Trigger::Bell, Trigger::Notification],
+						template: String::from(r#"{"text": "{event} from {module}: {text}"}"#),
+						secret: String::new(),
+					},
+				],
+				queue_size: 2,
+			},
+			routing,
+			&context,
+		)
+	}
+
+	#[test]
+	fn test_render() {
+		let payload = Payload {
+			event: Trigger::Notification,
+			text: String::from("Say \"hello\""),
+			module: Some("Battery"),
+			severity: Some(Severity::Warning),
+			instance: "#0/current",
+			time: 1_000,
+		};
+		assert_eq!(
+			serde_json::from_str::<Value>(&render("", &payload)).unwrap(),
+			json!({
+				"event": "notification",
+				"text": "Say \"hello\"",
+				"module": "Battery",
+				"severity": "warning",
+				"instance": "#0/current",
+				"time": 1_000,
+			})
+		);
+		assert_eq!(
+			render(r#"{"msg": "{severity}: {text}", "at": {time}}"#, &payload),
+			r#"{"msg": "warning: Say \"hello\"", "at": 1000}"#
+		);
+
+		let payload = Payload {
+			text: String::from("{instance} at {time} {unknown"),
+			..payload
+		};
+		assert_eq!(
+			render(r#"{"text": "{text}", "from": "{instance}"}"#, &payload),
+			r##"{"text": "{instance} at {time} {unknown", "from": "#0/current"}"##
+		);
+	}
+
+	#[tokio::test]
+	async fn test_deliver() {
+		let stub = Stub::start().await;
+		let webhook = webhook(&stub.url).await;
+		webhook.queue(&Event::DoorOpened(String::from("anna")));
+		webhook.queue(&Event::BellPressed);
+		webhook.queue(&Event::LightsOff);
+		webhook.queue(&Notification::info("Buttons", NextcloudChat::Default, "💡").into());
+		webhook.queue(&Notification::warning("Battery", NextcloudChat::Default, "🪫").into());
+		assert_eq!(webhook.targets.lock().unwrap()[0].queue.len(), 1);
+		assert_eq!(webhook.targets.lock().unwrap()[1].queue.len(), 2);
+
+		stub.respond(503, "{}");
+		stub.respond(400, "{}");
+		webhook.deliver().await;
+		assert_eq!(webhook.targets.lock().unwrap()[0].queue.len(), 1);
+		assert!(webhook.targets.lock().unwrap()[0].retry.is_some());
+		assert!(webhook.targets.lock().unwrap()[1].queue.is_empty());
+
+		// not due yet
+		webhook.deliver().await;
+		assert_eq!(stub.requests().len(), 3);
+		webhook.targets.lock().unwrap()[0].retry = Some(Instant::now());
+		webhook.deliver().await;
+		assert!(webhook.targets.lock().unwrap()[0].queue.is_empty());
+		assert!(webhook.targets.lock().unwrap()[0].retry.is_none());
+
+		let requests = stub.requests();
+		assert_eq!(requests.len(), 4);
+		assert_eq!(requests[0].path, "/door");
+		assert_eq!(requests[1].path, "/chat");
+		assert_eq!(requests[1].headers[EVENT], "bell");
+		assert_eq!(
+			requests[2].body,
+			r#"{"text": "notification from Battery: 🪫"}"#
+		);
+		assert!(!requests[2].headers.contains_key(SIGNATURE));
+		assert_eq!(requests[3].body, requests[0].body);
+		assert_eq!(
+			requests[3].headers[SIGNATURE],
+			sign("secret", &requests[3].body).as_str()
+		);
+		let body: Value = serde_json::from_str(&requests[3].body).unwrap();
+		assert_eq!(body["event"], "door");
+		assert_eq!(body["text"], "🤗 Opened for anna");
+		assert!(body.get("module").is_none());
+	}
+
+	#[tokio::test]
+	async fn test_full() {
+		let webhook = webhook("http://127.0.0.1:9").await;
+		for by in ["anna", "bernd", "clara"] {
+			webhook.queue(&Event::DoorOpened(by.to_string()));
+		}
+		let targets = webhook.targets.lock().unwrap();
+		let queue = &targets[0].queue;
+		assert_eq!(queue.len(), 2);
+		assert!(queue[0].body.contains("bernd"));
+	}
+}